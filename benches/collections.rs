@@ -0,0 +1,36 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use virtual_exec::Interpreter;
+use virtual_exec_parser::parser;
+use virtual_exec_type::exec_ctx::RsValue;
+
+/// Reads and element writes on a collection bound to a variable. Each of them used to copy the
+/// whole collection, so their cost grew with its length.
+///
+/// Binding the collection to a second variable is deliberately left out: with value semantics the
+/// next write then has to copy it. Runs that create no objects skip the cycle collection, so the
+/// cost is the same at every length.
+fn collection_access(c: &mut Criterion) {
+    let module = parser::parse(&"x = items[7]; items[3] = x + 1; n = items[0] + items[-1];\n".repeat(50))
+        .unwrap();
+    let mut group = c.benchmark_group("collection_access");
+    for len in [10, 1_000, 100_000] {
+        let mut interp = Interpreter::new();
+        interp.set("items", RsValue::Vector((0..len).map(RsValue::Int).collect()));
+        group.bench_with_input(BenchmarkId::from_parameter(len), &len, |b, _| {
+            b.iter(|| interp.run_module(black_box(&module)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// Nested blocks evaluated straight from the borrowed AST.
+fn nested_blocks(c: &mut Criterion) {
+    let body = "a = a + 1; if a > 0 { b = a * 2; { c = b - a; }; }\n".repeat(20);
+    let module = parser::parse(&format!("a = 0; {}", format!("{{ {body} }};\n").repeat(10))).unwrap();
+    c.bench_function("nested_blocks", |b| {
+        b.iter(|| Interpreter::new().run_module(black_box(&module)).unwrap())
+    });
+}
+
+criterion_group!(benches, collection_access, nested_blocks);
+criterion_main!(benches);
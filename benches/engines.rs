@@ -0,0 +1,29 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::cell::RefCell;
+use std::rc::Rc;
+use virtual_exec_parser::parser;
+use virtual_exec_type::ast::core::ASTNode;
+use virtual_exec_type::exec_ctx::ExecutionContext;
+use virtual_exec_type::vm::Program;
+
+/// The same module run by the tree-walker and by the bytecode VM, each in a fresh context.
+fn engines(c: &mut Criterion) {
+    let code = "a = 3; b = [1, 2, 3]; c = 0;\n".to_string()
+        + &"c = c + a * b[1] - a % 2; b[0] = c; if c > 100 && a != 0 { d = -c; } else { d = c; }\n"
+            .repeat(200);
+    let module = parser::parse(&code).unwrap();
+    let program = Program::compile(&module);
+    let context = || Rc::new(RefCell::new(ExecutionContext::builder().build()));
+
+    let mut group = c.benchmark_group("engines");
+    group.bench_function("tree_walk", |b| {
+        b.iter(|| black_box(&module).eval(context()).unwrap())
+    });
+    group.bench_function("bytecode", |b| {
+        b.iter(|| black_box(&program).run(context()).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, engines);
+criterion_main!(benches);
@@ -0,0 +1,131 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use virtual_exec::Interpreter;
+use virtual_exec_macro::SandboxObject;
+use virtual_exec_type::ast::core::{BinaryOperator, UnaryOperator};
+use virtual_exec_type::base::{Upcast, Value, ValueContainer, ValueKind, ValueTag};
+use virtual_exec_type::builtin::{VirPyFloat, VirPyInt};
+use virtual_exec_type::error::SandboxExecutionError;
+use virtual_exec_type::op::{
+    unwrap_result, OpAddImpl, OpAndImpl, OpDivImpl, OpLtImpl, OpModImpl, OpMulImpl,
+    OpNeImpl, OpNegImpl, OpSubImpl, OperatorTable,
+};
+use virtual_exec_type::register_op_add;
+
+/// Host types with their own `+`, as an embedder would register them, so that the registry holds
+/// more than the builtin implementations.
+macro_rules! units {
+    ($($unit:ident),*) => {
+        $(
+            #[derive(Clone, SandboxObject)]
+            struct $unit {
+                value: f64,
+            }
+
+            register_op_add!($unit, $unit, ValueKind::Float, |a: $unit, b: $unit| Ok(
+                VirPyFloat::new(a.value + b.value)
+            ));
+        )*
+    };
+}
+
+units!(Meters, Seconds, Grams, Kelvins, Amperes, Candelas, Moles, Radians);
+
+/// How `op_add` used to dispatch: trying every registered implementation in turn.
+fn linear_add<'ctx>(lhs: Value<'ctx>, rhs: Value<'ctx>) -> Option<ValueKind> {
+    inventory::iter::<OpAddImpl>
+        .into_iter()
+        .find_map(|implementation| (implementation.function)(lhs, rhs))
+}
+
+fn dispatch(c: &mut Criterion) {
+    let operators = OperatorTable::default();
+    let cases = [
+        ("int", ValueKind::Int(VirPyInt::new(3)), ValueKind::Int(VirPyInt::new(4))),
+        ("float_int", ValueKind::Float(VirPyFloat::new(0.5)), ValueKind::Int(VirPyInt::new(4))),
+        ("string", ValueKind::String("a".to_string()), ValueKind::String("b".to_string())),
+        ("native", Meters { value: 1.0 }.from_value(), Meters { value: 2.0 }.from_value()),
+        // Nothing matches, so a scan has to try every implementation
+        ("undefined", ValueKind::Bool(true), ValueKind::Int(VirPyInt::new(4))),
+    ];
+    let mut group = c.benchmark_group("add_dispatch");
+    for (name, lhs, rhs) in cases {
+        let lhs = ValueContainer::new(lhs);
+        let rhs = ValueContainer::new(rhs);
+        group.bench_function(format!("linear_scan/{name}"), |b| {
+            b.iter(|| linear_add(black_box(&lhs), black_box(&rhs)))
+        });
+        group.bench_function(format!("context_table/{name}"), |b| {
+            b.iter(|| operators.eval_binary(BinaryOperator::Add, black_box(&lhs), black_box(&rhs)))
+        });
+    }
+    group.finish();
+}
+
+/// A table dispatching every operator by scanning all of its registered implementations, the way
+/// `linear_add` does for `+`.
+fn linear_table() -> OperatorTable {
+    let mut table = OperatorTable::empty();
+    macro_rules! linear {
+        ($($op:ident => $impl_ty:ident),* $(,)?) => {
+            $(
+                for lhs in ValueTag::ALL {
+                    for rhs in ValueTag::ALL {
+                        table.register(BinaryOperator::$op, lhs, rhs, |lhs, rhs| {
+                            inventory::iter::<$impl_ty>
+                                .into_iter()
+                                .find_map(|implementation| (implementation.function)(lhs, rhs))
+                                .map_or(
+                                    Err(SandboxExecutionError::UndefinedOperatorMethodError),
+                                    unwrap_result,
+                                )
+                        });
+                    }
+                }
+            )*
+        };
+    }
+    linear!(
+        Add => OpAddImpl,
+        Subtract => OpSubImpl,
+        Multiply => OpMulImpl,
+        Divide => OpDivImpl,
+        Modulo => OpModImpl,
+        And => OpAndImpl,
+        NotEq => OpNeImpl,
+        Lt => OpLtImpl,
+    );
+    for operand in ValueTag::ALL {
+        table.register_unary(UnaryOperator::Negative, operand, |rhs| {
+            inventory::iter::<OpNegImpl>
+                .into_iter()
+                .find_map(|implementation| (implementation.function)(rhs))
+                .map_or(Err(SandboxExecutionError::UndefinedOperatorMethodError), unwrap_result)
+        });
+    }
+    table
+}
+
+fn numeric_script(c: &mut Criterion) {
+    let code = "a = 3; b = 2.5; c = 0; d = 0.0;\n".to_string()
+        + &"c = c + a * a - a % 2; d = d + b * a / 4 + -b; ok = c < 100 && d != 0;\n"
+            .repeat(200);
+    let linear = linear_table();
+    let mut group = c.benchmark_group("numeric_script");
+    group.bench_function("linear_scan", |b| {
+        b.iter(|| {
+            let mut interp = Interpreter::new();
+            *interp.operators_mut() = linear.clone();
+            interp.run(black_box(&code)).unwrap();
+        })
+    });
+    group.bench_function("context_table", |b| {
+        b.iter(|| {
+            let mut interp = Interpreter::new();
+            interp.run(black_box(&code)).unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, dispatch, numeric_script);
+criterion_main!(benches);
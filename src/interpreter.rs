@@ -0,0 +1,168 @@
+use std::cell::{RefCell, RefMut};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use virtual_exec_parser::parser;
+use virtual_exec_type::ast::core::{ASTNode, Module};
+use virtual_exec_type::ast::optimize::optimize;
+use virtual_exec_type::base::ValueKind;
+use virtual_exec_type::builtin::VirPyObject;
+use virtual_exec_type::exec_ctx::{
+    rs_value_to_value_kind, value_kind_to_rs_value, ExecOptions, ExecutionContext, RsValue,
+};
+use virtual_exec_type::native::NativeFunction;
+use virtual_exec_type::op::OperatorTable;
+use virtual_exec_type::vm::Program;
+
+use crate::{CompiledScript, ExecError};
+
+/// A reusable session around one [`ExecutionContext`].
+///
+/// Variables given to [`Interpreter::set`] are visible to the next run, and the globals left by
+/// a run stay visible to the runs after it. Every run gets the full operation and time budget of
+/// the [`ExecOptions`] the interpreter was created with.
+///
+/// ```
+/// use virtual_exec::Interpreter;
+/// use virtual_exec_type::exec_ctx::RsValue;
+///
+/// let mut interp = Interpreter::new();
+/// interp.set("a", RsValue::Int(2));
+/// interp.run("b = a * 21;").unwrap();
+/// assert_eq!(interp.get("b"), Some(RsValue::Int(42)));
+/// ```
+pub struct Interpreter {
+    ctx: Rc<RefCell<ExecutionContext>>,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::with_options(ExecOptions::default())
+    }
+
+    pub fn with_options(options: ExecOptions) -> Self {
+        let ctx = ExecutionContext::builder().options(options).build();
+        Self {
+            ctx: Rc::new(RefCell::new(ctx)),
+        }
+    }
+
+    /// Binds a global variable, replacing any previous value.
+    pub fn set(&mut self, name: impl Into<String>, value: RsValue) {
+        self.set_value(name, rs_value_to_value_kind(value));
+    }
+
+    /// Binds a global variable to an object and returns a handle to it. The handle shares its
+    /// attributes with the script, so whatever the script assigns to them can be read back from
+    /// it after the run.
+    pub fn set_object(
+        &mut self,
+        name: impl Into<String>,
+        fields: BTreeMap<String, RsValue>,
+    ) -> VirPyObject {
+        let obj = VirPyObject::new();
+        for (key, value) in fields {
+            obj.set(key, rs_value_to_value_kind(value));
+        }
+        self.set_value(name, ValueKind::Object(obj.clone()));
+        obj
+    }
+
+    /// Binds a global variable to a sandbox value as is.
+    pub fn set_value(&mut self, name: impl Into<String>, value: ValueKind) {
+        self.ctx.borrow_mut().set_global(name, value);
+    }
+
+    /// Reads a global variable, `None` if it is not bound.
+    pub fn get(&self, name: &str) -> Option<RsValue> {
+        let ctx = self.ctx.borrow();
+        let value = ctx.get(name).ok()?;
+        let value = value_kind_to_rs_value(&value.borrow().kind);
+        Some(value)
+    }
+
+    /// Reads a global variable straight into any deserializable type.
+    ///
+    /// ```
+    /// # #[cfg(feature = "serde")]
+    /// # {
+    /// use virtual_exec::Interpreter;
+    ///
+    /// let mut interp = Interpreter::new();
+    /// interp.run("pair = [1, 2.5];").unwrap();
+    /// let pair: (i64, f64) = interp.get_as("pair").unwrap();
+    /// assert_eq!(pair, (1, 2.5));
+    /// # }
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn get_as<T: virtual_exec_type::export::DeserializeOwned>(
+        &self,
+        name: &str,
+    ) -> Result<T, ExecError> {
+        let ctx = self.ctx.borrow();
+        let value = ctx.get(name)?;
+        let value = virtual_exec_type::export::from_value(&value.borrow().kind)?;
+        Ok(value)
+    }
+
+    /// The operators available to the runs of this interpreter, which can be changed between
+    /// runs without affecting other interpreters.
+    pub fn operators_mut(&mut self) -> RefMut<'_, OperatorTable> {
+        RefMut::map(self.ctx.borrow_mut(), |ctx| &mut ctx.operators)
+    }
+
+    /// Lets the scripts run by this interpreter call `function` as `name(args)`. Async functions
+    /// can only be called from [`Interpreter::run_async`].
+    pub fn register_function(&mut self, name: impl Into<String>, function: NativeFunction) {
+        self.ctx.borrow_mut().register_function(name, function);
+    }
+
+    /// Every variable currently bound.
+    pub fn globals(&self) -> BTreeMap<String, RsValue> {
+        self.ctx.borrow().to_btreemap()
+    }
+
+    /// Parses and runs `code`.
+    pub fn run(&mut self, code: &str) -> Result<(), ExecError> {
+        let module = parser::parse(code)?;
+        self.run_module(&module)
+    }
+
+    /// Runs a script parsed once with [`CompiledScript::new`], with the variables of this
+    /// interpreter as its inputs.
+    pub fn run_script(&mut self, script: &CompiledScript) -> Result<(), ExecError> {
+        self.run_module(script.module())
+    }
+
+    /// Optimizes `module` with [`optimize`] for the operators of this interpreter, to run it once
+    /// or many times with [`Interpreter::run_module`]. Optimized code consumes less TTL. Folded
+    /// operators are not looked up again, so the module is only meant for this interpreter, and
+    /// only as long as its operators stay the same.
+    pub fn optimize(&self, module: Module) -> Module {
+        optimize(module, &self.ctx.borrow().operators)
+    }
+
+    /// Runs a prebuilt module, such as one from `virtual_exec_macro::parse!`.
+    ///
+    /// When the run fails, the bindings it made before the error are kept, unless the interpreter
+    /// was created with [`ExecOptions::transactional`].
+    pub fn run_module(&mut self, module: &Module) -> Result<(), ExecError> {
+        self.ctx.borrow_mut().reset_budget();
+        module.eval(self.ctx.clone())?;
+        Ok(())
+    }
+
+    /// Parses and runs `code` on the bytecode VM, yielding to the executor every
+    /// [`ExecOptions::yield_every`] operations and awaiting the async functions it calls.
+    pub async fn run_async(&mut self, code: &str) -> Result<(), ExecError> {
+        let program = Program::compile(&parser::parse(code)?);
+        self.ctx.borrow_mut().reset_budget();
+        program.run_async(self.ctx.clone()).await?;
+        Ok(())
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
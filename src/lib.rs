@@ -1,13 +1,19 @@
+#![forbid(unsafe_code)]
+
+mod interpreter;
+mod script;
+
+pub use interpreter::Interpreter;
+pub use script::{CompiledScript, ScriptCache};
+
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::rc::Rc;
-use bumpalo::Bump;
 use virtual_exec_parser::error::ParseError;
 use virtual_exec_parser::parser;
-use virtual_exec_type::ast::core::ASTNode;
-use virtual_exec_type::builtin::Mapping;
-use virtual_exec_type::exec_ctx::{ExecutionContext, RsValue};
+use virtual_exec_type::exec_ctx::{ExecOptions, ExecutionContext, Limits, RsValue};
 use virtual_exec_type::error::SandboxExecutionError;
+use virtual_exec_type::vm::Program;
 
 /// The unified error type for the `vir_py-rs` library.
 #[derive(Debug)]
@@ -16,6 +22,9 @@ pub enum ExecError {
     Parse(ParseError),
     /// An error that occurred during the execution phase.
     Execution(SandboxExecutionError),
+    /// A variable did not have the shape of the type it was read into.
+    #[cfg(feature = "serde")]
+    Deserialize(virtual_exec_type::export::DeserializeError),
 }
 
 impl From<ParseError> for ExecError {
@@ -24,6 +33,13 @@ impl From<ParseError> for ExecError {
     }
 }
 
+#[cfg(feature = "serde")]
+impl From<virtual_exec_type::export::DeserializeError> for ExecError {
+    fn from(e: virtual_exec_type::export::DeserializeError) -> Self {
+        ExecError::Deserialize(e)
+    }
+}
+
 impl From<SandboxExecutionError> for ExecError {
     fn from(e: SandboxExecutionError) -> Self {
         ExecError::Execution(e)
@@ -40,19 +56,49 @@ impl From<SandboxExecutionError> for ExecError {
 /// # Returns
 ///
 /// A `Result` which is either:
-/// * `Ok(HashMap<String, PyValue>)` - A dictionary of the final state of all variables.
+/// * `Ok(BTreeMap<String, RsValue>)` - The final state of all variables, in sorted order.
 /// * `Err(Error)` - An error that occurred during parsing or execution.
-pub fn exec(code: &str, ttl: i64) -> Result<HashMap<String, RsValue>, ExecError> {
-    // 1. Parse the code into an AST.
-    let module = parser::parse(code)?;
+pub fn exec(code: &str, ttl: i64) -> Result<BTreeMap<String, RsValue>, ExecError> {
+    exec_with_options(code, &ttl_options(ttl))
+}
 
-    let arena = Rc::new(RefCell::new(Bump::new()));
-    let global_scope = Rc::new(RefCell::new(Mapping { mapping: HashMap::new() }));
-    let mapping = vec![global_scope];
-    let ctx = Rc::new(RefCell::new(ExecutionContext::new(arena, ttl, mapping)));
+fn ttl_options(ttl: i64) -> ExecOptions {
+    ExecOptions {
+        limits: Limits {
+            max_operations: Some(ttl),
+            ..Limits::default()
+        },
+        ..ExecOptions::default()
+    }
+}
+
+/// Executes a string of Python-like code under the given [`ExecOptions`].
+///
+/// Each limit in [`ExecOptions::limits`] that is exceeded aborts the execution with its own
+/// `SandboxExecutionError`, while unset limits are unbounded.
+pub fn exec_with_options(code: &str, options: &ExecOptions) -> Result<BTreeMap<String, RsValue>, ExecError> {
+    CompiledScript::new(code)?.run(BTreeMap::new(), options)
+}
 
-    module.eval(ctx.clone())?;
+/// Executes a string of Python-like code as [`exec`] does, yielding to the executor every
+/// [`ExecOptions::yield_every`] operations so that long scripts do not starve other tasks.
+pub async fn exec_async(code: &str, ttl: i64) -> Result<BTreeMap<String, RsValue>, ExecError> {
+    exec_async_with_options(code, &ttl_options(ttl)).await
+}
+
+/// Executes a string of Python-like code under the given [`ExecOptions`] on the bytecode VM,
+/// yielding to the executor between slices of [`ExecOptions::yield_every`] operations.
+///
+/// The returned future is not `Send`: it is meant for a single-threaded executor such as a
+/// `LocalSet`.
+pub async fn exec_async_with_options(
+    code: &str,
+    options: &ExecOptions,
+) -> Result<BTreeMap<String, RsValue>, ExecError> {
+    let program = Program::compile(&parser::parse(code)?);
+    let ctx = Rc::new(RefCell::new(ExecutionContext::builder().options(options.clone()).build()));
+    program.run_async(ctx.clone()).await?;
 
-    let final_state = ctx.borrow().to_hashmap();
+    let final_state = ctx.borrow().to_btreemap();
     Ok(final_state)
 }
@@ -0,0 +1,167 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::rc::Rc;
+use virtual_exec_parser::parser;
+use virtual_exec_type::ast::binary::{self, DecodeError};
+use virtual_exec_type::ast::core::{ASTNode, Module};
+use virtual_exec_type::exec_ctx::{rs_value_to_value_kind, ExecOptions, ExecutionContext, RsValue};
+
+use crate::ExecError;
+
+/// A parsed script, to be run any number of times without parsing it again.
+///
+/// ```
+/// use std::collections::BTreeMap;
+/// use virtual_exec::CompiledScript;
+/// use virtual_exec_type::exec_ctx::{ExecOptions, RsValue};
+///
+/// let script = CompiledScript::new("total = price * quantity;").unwrap();
+/// for quantity in 1..=3 {
+///     let inputs = BTreeMap::from([
+///         ("price".to_string(), RsValue::Int(5)),
+///         ("quantity".to_string(), RsValue::Int(quantity)),
+///     ]);
+///     let state = script.run(inputs, &ExecOptions::default()).unwrap();
+///     assert_eq!(state.get("total"), Some(&RsValue::Int(5 * quantity)));
+/// }
+/// ```
+pub struct CompiledScript {
+    module: Module,
+}
+
+impl CompiledScript {
+    pub fn new(code: &str) -> Result<Self, ExecError> {
+        Ok(Self::from_module(parser::parse(code)?))
+    }
+
+    /// Wraps a prebuilt module, such as one from `virtual_exec_macro::parse!`.
+    pub fn from_module(module: Module) -> Self {
+        Self { module }
+    }
+
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    /// Encodes the script with [`binary::encode`], to be stored and loaded again with
+    /// [`CompiledScript::from_bytes`], possibly by another process.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        binary::encode(&self.module)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Ok(Self::from_module(binary::decode(bytes)?))
+    }
+
+    /// Runs the script in a fresh context holding `inputs`, and returns every variable bound at
+    /// the end of the run.
+    pub fn run(
+        &self,
+        inputs: BTreeMap<String, RsValue>,
+        options: &ExecOptions,
+    ) -> Result<BTreeMap<String, RsValue>, ExecError> {
+        let mut builder = ExecutionContext::builder().options(options.clone());
+        for (name, value) in inputs {
+            builder = builder.variable(name, rs_value_to_value_kind(value));
+        }
+        let ctx = Rc::new(RefCell::new(builder.build()));
+        self.module.eval(ctx.clone())?;
+
+        let final_state = ctx.borrow().to_btreemap();
+        Ok(final_state)
+    }
+}
+
+/// A bounded cache of [`CompiledScript`]s, keyed by the hash of their source text. Once full,
+/// the least recently used script makes room for the next one.
+///
+/// ```
+/// use std::num::NonZeroUsize;
+/// use virtual_exec::ScriptCache;
+///
+/// let mut cache = ScriptCache::new(NonZeroUsize::new(64).unwrap());
+/// let first = cache.get_or_compile("a = 1;").unwrap();
+/// let second = cache.get_or_compile("a = 1;").unwrap();
+/// assert!(std::rc::Rc::ptr_eq(&first, &second));
+/// ```
+pub struct ScriptCache {
+    capacity: NonZeroUsize,
+    entries: HashMap<u64, Entry>,
+    /// The key of every entry by the time it was last used, the least recent first.
+    recency: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+struct Entry {
+    /// Kept to tell apart sources whose hashes collide.
+    source: String,
+    script: Rc<CompiledScript>,
+    last_used: u64,
+}
+
+impl ScriptCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Returns the cached script for `code`, parsing and caching it on a miss. Scripts that fail
+    /// to parse are not cached.
+    pub fn get_or_compile(&mut self, code: &str) -> Result<Rc<CompiledScript>, ExecError> {
+        let mut hasher = DefaultHasher::new();
+        code.hash(&mut hasher);
+        let key = hasher.finish();
+        self.clock += 1;
+
+        if let Some(entry) = self.entries.get_mut(&key)
+            && entry.source == code
+        {
+            self.recency.remove(&entry.last_used);
+            self.recency.insert(self.clock, key);
+            entry.last_used = self.clock;
+            return Ok(entry.script.clone());
+        }
+
+        let script = Rc::new(CompiledScript::new(code)?);
+        if let Some(replaced) = self.entries.remove(&key) {
+            self.recency.remove(&replaced.last_used);
+        } else if self.entries.len() == self.capacity.get()
+            && let Some((_, evicted)) = self.recency.pop_first()
+        {
+            self.entries.remove(&evicted);
+        }
+        self.recency.insert(self.clock, key);
+        self.entries.insert(
+            key,
+            Entry {
+                source: code.to_string(),
+                script: script.clone(),
+                last_used: self.clock,
+            },
+        );
+        Ok(script)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn capacity(&self) -> NonZeroUsize {
+        self.capacity
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+}
@@ -0,0 +1,255 @@
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::num::NonZeroU32;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use virtual_exec::{exec_async_with_options, ExecError, Interpreter};
+use virtual_exec_parser::parser::parse;
+use virtual_exec_type::ast::core::ASTNode;
+use virtual_exec_type::base::ValueKind;
+use virtual_exec_type::builtin::VirPyInt;
+use virtual_exec_type::error::SandboxExecutionError;
+use virtual_exec_type::exec_ctx::{ExecOptions, ExecutionContext, RsValue, ScopeMode};
+use virtual_exec_type::native::NativeFunction;
+use virtual_exec_type::vm::Program;
+
+struct NoopWake;
+
+impl Wake for NoopWake {
+    fn wake(self: Arc<Self>) {}
+}
+
+/// Polls every future in turn until all are ready, returning their outputs and how many times
+/// each was polled.
+fn run_all<T>(mut futures: Vec<Pin<Box<dyn Future<Output = T> + '_>>>) -> Vec<(T, usize)> {
+    let waker = Waker::from(Arc::new(NoopWake));
+    let mut cx = Context::from_waker(&waker);
+    let mut outputs: Vec<Option<(T, usize)>> = futures.iter().map(|_| None).collect();
+    let mut polls = vec![0; futures.len()];
+    while outputs.iter().any(Option::is_none) {
+        for (i, future) in futures.iter_mut().enumerate() {
+            if outputs[i].is_some() {
+                continue;
+            }
+            polls[i] += 1;
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                outputs[i] = Some((output, polls[i]));
+            }
+        }
+    }
+    outputs.into_iter().map(Option::unwrap).collect()
+}
+
+fn block_on<T>(future: impl Future<Output = T>) -> (T, usize) {
+    run_all(vec![Box::pin(future)]).pop().unwrap()
+}
+
+/// Ready on its second poll, like a future waiting on I/O.
+struct Later {
+    output: Option<Result<ValueKind, SandboxExecutionError>>,
+    polled: bool,
+}
+
+fn later(output: Result<ValueKind, SandboxExecutionError>) -> Later {
+    Later {
+        output: Some(output),
+        polled: false,
+    }
+}
+
+impl Future for Later {
+    type Output = Result<ValueKind, SandboxExecutionError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if !self.polled {
+            self.polled = true;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        Poll::Ready(self.output.take().unwrap())
+    }
+}
+
+fn int(value: i64) -> ValueKind {
+    ValueKind::Int(VirPyInt::new(value))
+}
+
+fn options(yield_every: u32) -> ExecOptions {
+    ExecOptions {
+        yield_every: NonZeroU32::new(yield_every),
+        ..ExecOptions::default()
+    }
+}
+
+fn long_script(statements: usize) -> String {
+    "x = 0;".to_string() + &"x = x + 1;".repeat(statements)
+}
+
+/// `double(n)` resolves to `2 * n` on its second poll, and fails on negative numbers.
+fn double() -> NativeFunction {
+    NativeFunction::new_async(|args: Vec<ValueKind>| match &args[..] {
+        [ValueKind::Int(n)] if n.value >= 0 => later(Ok(int(n.value * 2))),
+        _ => later(Err(SandboxExecutionError::InvalidTypeError)),
+    })
+}
+
+#[test]
+fn test_long_scripts_yield_every_n_operations() {
+    let code = long_script(100);
+    let (state, polls) = block_on(exec_async_with_options(&code, &options(10)));
+    assert_eq!(state.unwrap().get("x"), Some(&RsValue::Int(100)));
+    assert!(polls > 20, "{polls}");
+
+    let (state, polls) = block_on(exec_async_with_options(&code, &options(u32::MAX)));
+    assert_eq!(state.unwrap().get("x"), Some(&RsValue::Int(100)));
+    assert_eq!(polls, 1);
+}
+
+#[test]
+fn test_async_runs_consume_the_same_ttl() {
+    let module = parse(&long_script(50)).unwrap();
+    let program = Program::compile(&module);
+    let context = |ttl| {
+        Rc::new(RefCell::new(ExecutionContext::builder().options(options(7)).ttl(ttl).build()))
+    };
+
+    let tree = context(10_000);
+    module.eval(tree.clone()).unwrap();
+    let vm = context(10_000);
+    block_on(program.run_async(vm.clone())).0.unwrap();
+    assert_eq!(tree.borrow().ttl, vm.borrow().ttl);
+
+    assert!(matches!(
+        block_on(program.run_async(context(40))).0,
+        Err(SandboxExecutionError::TimeoutError)
+    ));
+}
+
+#[test]
+fn test_async_functions_are_awaited() {
+    let mut interp = Interpreter::new();
+    interp.register_function("double", double());
+    let (result, polls) = block_on(interp.run_async("a = double(21); b = double(a) + 1;"));
+    result.unwrap();
+    assert!(polls >= 3, "{polls}");
+    assert_eq!(interp.get("a"), Some(RsValue::Int(42)));
+    assert_eq!(interp.get("b"), Some(RsValue::Int(85)));
+
+    // A failed call ends the run, and leaves the interpreter usable
+    let (result, _) = block_on(interp.run_async("{ c = double(-1); };"));
+    assert!(matches!(result, Err(ExecError::Execution(SandboxExecutionError::InvalidTypeError))));
+    let (result, _) = block_on(interp.run_async("c = double(1);"));
+    result.unwrap();
+    assert_eq!(interp.get("c"), Some(RsValue::Int(2)));
+}
+
+#[test]
+fn test_sync_functions_run_in_both_engines() {
+    let add = NativeFunction::new(|args: Vec<ValueKind>| match &args[..] {
+        [ValueKind::Int(a), ValueKind::Int(b)] => Ok(int(a.value + b.value)),
+        _ => Err(SandboxExecutionError::InvalidTypeError),
+    });
+    let mut interp = Interpreter::new();
+    interp.register_function("add", add);
+    interp.run("a = add(1, 2);").unwrap();
+    block_on(interp.run_async("b = add(a, 4);")).0.unwrap();
+    assert_eq!(interp.get("a"), Some(RsValue::Int(3)));
+    assert_eq!(interp.get("b"), Some(RsValue::Int(7)));
+    assert!(matches!(
+        interp.run("c = add(1);"),
+        Err(ExecError::Execution(SandboxExecutionError::InvalidTypeError))
+    ));
+}
+
+#[test]
+fn test_async_functions_need_an_async_run() {
+    let mut interp = Interpreter::new();
+    interp.register_function("double", double());
+    assert!(matches!(
+        interp.run("a = double(1);"),
+        Err(ExecError::Execution(SandboxExecutionError::AsyncFunctionError))
+    ));
+
+    let ctx = Rc::new(RefCell::new(ExecutionContext::builder().function("double", double()).build()));
+    let program = Program::compile(&parse("{ a = double(1); };").unwrap());
+    assert!(matches!(program.run(ctx.clone()), Err(SandboxExecutionError::AsyncFunctionError)));
+    assert!(ctx.borrow().get("a").is_err());
+}
+
+#[test]
+fn test_unknown_functions_are_reference_errors() {
+    let (result, _) = block_on(exec_async_with_options("a = missing(1);", &ExecOptions::default()));
+    assert!(matches!(
+        result,
+        Err(ExecError::Execution(SandboxExecutionError::ReferenceNotExistError(name))) if name == "missing"
+    ));
+    assert!(matches!(
+        Interpreter::new().run("a = missing(1);"),
+        Err(ExecError::Execution(SandboxExecutionError::ReferenceNotExistError(name))) if name == "missing"
+    ));
+}
+
+#[test]
+fn test_scripts_interleave_on_one_thread() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let calls = Rc::new(Cell::new(0));
+    let logger = || {
+        let log = log.clone();
+        let calls = calls.clone();
+        NativeFunction::new(move |args: Vec<ValueKind>| {
+            calls.set(calls.get() + 1);
+            if let [ValueKind::Int(n)] = &args[..] {
+                log.borrow_mut().push(n.value);
+            }
+            Ok(ValueKind::None)
+        })
+    };
+    let mut first = Interpreter::with_options(options(10));
+    let mut second = Interpreter::with_options(options(10));
+    first.register_function("log", logger());
+    second.register_function("log", logger());
+
+    let body = long_script(20);
+    let first_code = format!("log(1); {body} log(2);");
+    let second_code = format!("log(3); {body} log(4);");
+    let results = run_all(vec![
+        Box::pin(first.run_async(&first_code)),
+        Box::pin(second.run_async(&second_code)),
+    ]);
+    for (result, _) in results {
+        result.unwrap();
+    }
+    assert_eq!(calls.get(), 4);
+    assert_eq!(*log.borrow(), vec![1, 3, 2, 4]);
+}
+
+#[test]
+fn test_dropped_runs_are_abandoned() {
+    let options = ExecOptions {
+        scope_mode: ScopeMode::Block,
+        transactional: true,
+        ..options(5)
+    };
+    let ctx = Rc::new(RefCell::new(
+        ExecutionContext::builder().options(options).variable("x", int(7)).build(),
+    ));
+    let program = Program::compile(&parse(&format!("{{ {} }};", long_script(20))).unwrap());
+
+    let waker = Waker::from(Arc::new(NoopWake));
+    let mut future = Box::pin(program.run_async(ctx.clone()));
+    assert!(future.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+    assert_eq!(ctx.borrow().scope_count(), 2);
+    let x = ctx.borrow().get("x").unwrap();
+    assert!(!matches!(x.borrow().kind, ValueKind::Int(VirPyInt { value: 7 })));
+    drop(future);
+
+    // The block scope is popped and the writes of the run undone
+    assert_eq!(ctx.borrow().scope_count(), 1);
+    let x = ctx.borrow().get("x").unwrap();
+    assert!(matches!(x.borrow().kind, ValueKind::Int(VirPyInt { value: 7 })));
+    block_on(Program::compile(&parse("y = 1;").unwrap()).run_async(ctx.clone())).0.unwrap();
+    assert_eq!(ctx.borrow().scope_count(), 1);
+    assert!(ctx.borrow().get("y").is_ok());
+}
@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use virtual_exec::CompiledScript;
+use virtual_exec_macro::parse;
+use virtual_exec_parser::parser;
+use virtual_exec_type::ast::binary::{decode, encode, DecodeError, FORMAT_VERSION, MAGIC, MAX_NESTING};
+use virtual_exec_type::ast::core::{Expr, Literal, MatchArm, Module, Node, Pattern, PatternError, Span, Stmt};
+use virtual_exec_type::exec_ctx::{ExecOptions, RsValue};
+
+const SCRIPTS: &[&str] = &[
+    "a = 3; b = 2.5; c = a * a - a % 2 + -b; d = !(a < b) && a >= 1 || None; e = a << 2 ^ 1 >> 1;",
+    "items = [1, \"two\", true, None]; x, *rest = items; items[0] = items[-1]; del items[0], x;",
+    "obj.total = obj.add(1, 2).value; { global g; nonlocal h; g = 1; }; pass;",
+    "if a == 1 { b = 2; } else { if c { d = 3; } } if e { pass; }",
+    r#"
+        match subject {
+            200 | 201 => { route = "ok"; }
+            [first, *rest] if first > 1 => { kind = 1; }
+            [_, *_] => { kind = 2; }
+            pair @ (b, c) => { total = b + c; }
+            Cart { total: t, items: [first, *_] } => { big = t; }
+            { "limit": 1.5, **others } => { found = others; }
+            _ => { route = "fail"; }
+        }
+    "#,
+];
+
+fn round_trip(module: &Module) -> Module {
+    let bytes = encode(module);
+    let decoded = decode(&bytes).unwrap();
+    assert_eq!(encode(&decoded), bytes);
+    decoded
+}
+
+#[test]
+fn test_modules_round_trip() {
+    for code in SCRIPTS {
+        let module = parser::parse(code).unwrap();
+        let decoded = round_trip(&module);
+        assert_eq!(format!("{:?}", module.body), format!("{:?}", decoded.body), "{code}");
+    }
+    // Parentheses are kept by the macro
+    let module = parse!(a = (1 + (b)) * 2;);
+    assert_eq!(format!("{:?}", module.body), format!("{:?}", round_trip(&module).body));
+}
+
+#[test]
+fn test_spans_and_special_floats_round_trip() {
+    let span = |row| Some(Span { row, col: 2, length: u64::MAX });
+    let literal = |value| Node {
+        kind: Expr::Literal(value),
+        span: span(3),
+    };
+    let module = Module {
+        body: vec![Node {
+            kind: Stmt::Assign {
+                targets: vec![Node {
+                    kind: Expr::Variable("é".to_string()),
+                    span: None,
+                }],
+                value: Node {
+                    kind: Expr::Collection(vec![
+                        literal(Literal::Float(f64::NAN)),
+                        literal(Literal::Float(-0.0)),
+                        literal(Literal::Int(i64::MIN)),
+                    ]),
+                    span: span(1),
+                },
+            },
+            span: span(0),
+        }],
+        span: span(7),
+    };
+    let decoded = round_trip(&module);
+    assert_eq!(decoded.span.map(|span| span.row), Some(7));
+    let Stmt::Assign { value, .. } = &decoded.body[0].kind else {
+        panic!("expected an assignment");
+    };
+    assert_eq!(value.span.map(|span| (span.row, span.col, span.length)), Some((1, 2, u64::MAX)));
+    let Expr::Collection(items) = &value.kind else {
+        panic!("expected a collection");
+    };
+    assert!(matches!(items[0].kind, Expr::Literal(Literal::Float(v)) if v.is_nan()));
+    assert!(matches!(items[1].kind, Expr::Literal(Literal::Float(v)) if v.is_sign_negative()));
+    assert!(matches!(items[2].kind, Expr::Literal(Literal::Int(i64::MIN))));
+}
+
+#[test]
+fn test_stored_scripts_run_without_the_parser() {
+    let bytes = CompiledScript::new("total = price * quantity;").unwrap().to_bytes();
+    let script = CompiledScript::from_bytes(&bytes).unwrap();
+    let inputs = BTreeMap::from([
+        ("price".to_string(), RsValue::Int(4)),
+        ("quantity".to_string(), RsValue::Int(3)),
+    ]);
+    let state = script.run(inputs, &ExecOptions::default()).unwrap();
+    assert_eq!(state.get("total"), Some(&RsValue::Int(12)));
+}
+
+#[test]
+fn test_reject_other_formats_and_versions() {
+    assert_eq!(decode(b"").err(), Some(DecodeError::NotAModule));
+    assert_eq!(decode(b"\x7fELF\x01\x00").err(), Some(DecodeError::NotAModule));
+
+    let mut future = encode(&parser::parse("a = 1;").unwrap());
+    future[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+    assert_eq!(
+        decode(&future).err(),
+        Some(DecodeError::UnsupportedVersion {
+            found: FORMAT_VERSION + 1,
+            supported: FORMAT_VERSION,
+        })
+    );
+}
+
+#[test]
+fn test_reject_malformed_blobs() {
+    for code in SCRIPTS {
+        let bytes = encode(&parser::parse(code).unwrap());
+        for len in 0..bytes.len() {
+            assert!(decode(&bytes[..len]).is_err(), "{code} cut at {len}");
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(decode(&trailing).err(), Some(DecodeError::TrailingBytes));
+        // Flipping any byte either yields another valid module or an error, never a panic
+        for at in 0..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[at] ^= 0xff;
+            let _ = decode(&corrupt);
+        }
+    }
+
+    let header = [&MAGIC[..], &FORMAT_VERSION.to_le_bytes()].concat();
+    let blob = |body: &[u8]| [&header[..], body].concat();
+    assert_eq!(
+        decode(&blob(&[1, 0, 0, 0, 42])).err(),
+        Some(DecodeError::InvalidTag { node: "statement", tag: 42 })
+    );
+    // A length larger than the blob is caught before anything is allocated for it
+    assert_eq!(decode(&blob(&[0xff, 0xff, 0xff, 0xff])).err(), Some(DecodeError::UnexpectedEnd));
+    // `Stmt::Expression` of a variable named "\xff"
+    assert_eq!(
+        decode(&blob(&[1, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0xff, 0, 0, 0])).err(),
+        Some(DecodeError::InvalidUtf8)
+    );
+}
+
+#[test]
+fn test_reject_patterns_the_parser_rejects() {
+    let module = |pattern: Pattern| Module {
+        body: vec![Node {
+            kind: Stmt::Match {
+                subject: Node { kind: Expr::Literal(Literal::Int(1)), span: None },
+                arms: vec![MatchArm { pattern, guard: None, body: vec![] }],
+            },
+            span: None,
+        }],
+        span: None,
+    };
+    let capture = |name: &str| Pattern::Capture(name.to_string());
+    let star = |name: &str| Pattern::Star(Some(name.to_string()));
+    let cases = [
+        (Pattern::Or(vec![capture("x"), capture("y")]), PatternError::OrPatternNames),
+        (
+            Pattern::Sequence(vec![Pattern::Or(vec![capture("x"), Pattern::Wildcard])]),
+            PatternError::OrPatternNames,
+        ),
+        (Pattern::Sequence(vec![star("a"), star("b")]), PatternError::SeveralStars),
+        (star("rest"), PatternError::StarOutsideSequence),
+        (Pattern::Binding("all".to_string(), Box::new(star("rest"))), PatternError::StarOutsideSequence),
+        (
+            Pattern::Sequence(vec![Pattern::Sequence(vec![Pattern::Star(None), Pattern::Star(None)])]),
+            PatternError::SeveralStars,
+        ),
+    ];
+    for (pattern, error) in cases {
+        assert_eq!(decode(&encode(&module(pattern))).err(), Some(DecodeError::InvalidPattern(error)));
+    }
+
+    let valid = Pattern::Or(vec![
+        Pattern::Sequence(vec![capture("x"), star("rest")]),
+        Pattern::Sequence(vec![star("rest"), capture("x")]),
+    ]);
+    assert!(decode(&encode(&module(valid))).is_ok());
+}
+
+#[test]
+fn test_reject_nesting_deeper_than_the_limit() {
+    let nested = |depth| {
+        let mut expr = Node {
+            kind: Expr::Literal(Literal::None),
+            span: None,
+        };
+        for _ in 0..depth {
+            expr = Node {
+                kind: Expr::Starred(Box::new(expr)),
+                span: None,
+            };
+        }
+        Module {
+            body: vec![Node {
+                kind: Stmt::Expression(expr),
+                span: None,
+            }],
+            span: None,
+        }
+    };
+    // The statement and the literal count as one level each
+    assert!(decode(&encode(&nested(MAX_NESTING - 2))).is_ok());
+    assert_eq!(
+        decode(&encode(&nested(MAX_NESTING - 1))).err(),
+        Some(DecodeError::NestingTooDeep)
+    );
+}
@@ -0,0 +1,133 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use virtual_exec::{exec_with_options, ExecError, Interpreter};
+use virtual_exec_parser::parser::parse;
+use virtual_exec_type::base::ValueKind;
+use virtual_exec_type::builtin::VirPyFloat;
+use virtual_exec_type::error::SandboxExecutionError;
+use virtual_exec_type::exec_ctx::{Deterministic, ExecOptions, ExecutionContext, RsValue};
+use virtual_exec_type::native::NativeFunction;
+use virtual_exec_type::vm::Program;
+
+const SCRIPT: &str = "a = random(); b = random(); c = [random(), time()]; t = time();";
+
+fn deterministic(seed: u64) -> ExecOptions {
+    ExecOptions {
+        deterministic: Some(Deterministic {
+            seed,
+            time: 1_700_000_000.5,
+        }),
+        ..ExecOptions::default()
+    }
+}
+
+fn float(value: Option<&RsValue>) -> f64 {
+    match value {
+        Some(RsValue::Float(v)) => *v,
+        other => panic!("expected a float, got {other:?}"),
+    }
+}
+
+#[test]
+fn test_seeded_runs_draw_the_same_numbers() {
+    let first = exec_with_options(SCRIPT, &deterministic(7)).unwrap();
+    assert_eq!(exec_with_options(SCRIPT, &deterministic(7)).unwrap(), first);
+    assert_ne!(exec_with_options(SCRIPT, &deterministic(8)).unwrap(), first);
+
+    let (a, b) = (float(first.get("a")), float(first.get("b")));
+    assert_ne!(a, b);
+    assert!((0.0..1.0).contains(&a) && (0.0..1.0).contains(&b));
+    assert_eq!(float(first.get("t")), 1_700_000_000.5);
+
+    // Both engines share the context's generator
+    let ctx = |options| Rc::new(RefCell::new(ExecutionContext::builder().options(options).build()));
+    let vm = ctx(deterministic(7));
+    Program::compile(&parse(SCRIPT).unwrap()).run(vm.clone()).unwrap();
+    assert_eq!(vm.borrow().to_btreemap(), first);
+}
+
+#[test]
+fn test_sources_only_exist_in_deterministic_mode() {
+    for code in ["a = random();", "t = time();"] {
+        assert!(matches!(
+            Interpreter::new().run(code),
+            Err(ExecError::Execution(SandboxExecutionError::ReferenceNotExistError(_)))
+        ));
+    }
+    assert!(matches!(
+        Interpreter::with_options(deterministic(7)).run("a = random(1);"),
+        Err(ExecError::Execution(SandboxExecutionError::ArgumentCountError {
+            expected: 0,
+            actual: 1
+        }))
+    ));
+}
+
+#[test]
+fn test_host_functions_replace_the_sources() {
+    let mut interp = Interpreter::with_options(deterministic(7));
+    interp.register_function(
+        "time",
+        NativeFunction::new(|_| Ok(ValueKind::Float(VirPyFloat::new(42.0)))),
+    );
+    interp.run("t = time(); r = random();").unwrap();
+    assert_eq!(interp.get("t"), Some(RsValue::Float(42.0)));
+    let expected = exec_with_options("r = random();", &deterministic(7)).unwrap();
+    assert_eq!(interp.get("r").as_ref(), expected.get("r"));
+}
+
+#[test]
+fn test_operators_produce_the_canonical_nan() {
+    let code = "big = 1e308 * 10.0; n = big - big; m = -n;";
+    let state = exec_with_options(code, &deterministic(0)).unwrap();
+    assert_eq!(float(state.get("n")).to_bits(), f64::NAN.to_bits());
+    assert_eq!(float(state.get("m")).to_bits(), f64::NAN.to_bits());
+
+    let ctx = Rc::new(RefCell::new(
+        ExecutionContext::builder().options(deterministic(0)).build(),
+    ));
+    Program::compile(&parse(code).unwrap()).run(ctx.clone()).unwrap();
+    assert_eq!(float(ctx.borrow().to_btreemap().get("m")).to_bits(), f64::NAN.to_bits());
+}
+
+#[test]
+fn test_names_are_listed_in_sorted_order() {
+    let state = exec_with_options(
+        "zeta = 1; alpha = 2.5; mid = [0.1, 1e300, false, None, \"q\"];",
+        &ExecOptions::default(),
+    )
+    .unwrap();
+    let names: Vec<_> = state.keys().map(String::as_str).collect();
+    assert_eq!(names, ["alpha", "mid", "zeta"]);
+    let mut interp = Interpreter::new();
+    interp.set_object("obj", BTreeMap::new());
+    interp.run("obj.zeta = 1; obj.alpha = 2; obj.mid = 3; b = obj; a = 1;").unwrap();
+    let globals = interp.globals();
+    assert_eq!(globals.keys().map(String::as_str).collect::<Vec<_>>(), ["a", "b", "obj"]);
+    let RsValue::Object(fields) = &globals["obj"] else {
+        panic!("expected an object, got {:?}", globals["obj"]);
+    };
+    assert_eq!(fields.keys().map(String::as_str).collect::<Vec<_>>(), ["alpha", "mid", "zeta"]);
+    let ctx = ExecutionContext::builder()
+        .variable("zeta", ValueKind::None)
+        .variable("alpha", ValueKind::None)
+        .variable("mid", ValueKind::None)
+        .build();
+    let names: Vec<_> = ctx.to_btreemap().into_keys().collect();
+    assert_eq!(names, ["alpha", "mid", "zeta"]);
+    let global = ctx.mapping.last().unwrap().borrow();
+    let names: Vec<_> = global.sorted().into_iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["alpha", "mid", "zeta"]);
+
+    let object = RsValue::Object(BTreeMap::from([
+        ("zeta".to_string(), state["zeta"].clone()),
+        ("alpha".to_string(), state["alpha"].clone()),
+        ("mid".to_string(), state["mid"].clone()),
+    ]));
+    assert_eq!(
+        object.to_string(),
+        "{alpha: 2.5, mid: [0.1, 1e300, false, None, \"q\"], zeta: 1}"
+    );
+    assert_eq!(RsValue::Float(1.0).to_string(), "1.0");
+}
@@ -1,5 +1,6 @@
-use virtual_exec::exec;
+use virtual_exec::{exec, ExecError};
 use virtual_exec_type::exec_ctx::RsValue;
+use virtual_exec_type::error::SandboxExecutionError;
 
 #[test]
 fn test_simple_assignment() {
@@ -8,3 +9,169 @@ fn test_simple_assignment() {
     assert_eq!(result.get("a"), Some(&RsValue::Int(1)));
     assert_eq!(result.get("d"), Some(&RsValue::Int(4)));
 }
+
+#[test]
+fn test_tuple_swap() {
+    let code = "a = 1; b = 2; a, b = b, a;";
+    let result = exec(code, 100).unwrap();
+    assert_eq!(result.get("a"), Some(&RsValue::Int(2)));
+    assert_eq!(result.get("b"), Some(&RsValue::Int(1)));
+}
+
+#[test]
+fn test_starred_unpacking() {
+    let code = "items = [1, 2, 3, 4]; x, *rest = items; *init, (y, z) = 0, [5, 6];";
+    let result = exec(code, 200).unwrap();
+    assert_eq!(result.get("x"), Some(&RsValue::Int(1)));
+    assert_eq!(
+        result.get("rest"),
+        Some(&RsValue::Vector(vec![RsValue::Int(2), RsValue::Int(3), RsValue::Int(4)]))
+    );
+    assert_eq!(result.get("init"), Some(&RsValue::Vector(vec![RsValue::Int(0)])));
+    assert_eq!(result.get("z"), Some(&RsValue::Int(6)));
+}
+
+#[test]
+fn test_chained_assignment() {
+    let result = exec("a = b = 0; a += 1;", 100).unwrap();
+    assert_eq!(result.get("a"), Some(&RsValue::Int(1)));
+    assert_eq!(result.get("b"), Some(&RsValue::Int(0)));
+}
+
+#[test]
+fn test_unpacking_length_mismatch() {
+    let result = exec("a, b = 1, 2, 3;", 100);
+    assert!(matches!(
+        result,
+        Err(ExecError::Execution(SandboxExecutionError::UnpackingError { expected: 2, actual: 3 }))
+    ));
+    let result = exec("a, b, *c = [1];", 100);
+    assert!(matches!(
+        result,
+        Err(ExecError::Execution(SandboxExecutionError::UnpackingError { expected: 2, actual: 1 }))
+    ));
+}
+
+#[test]
+fn test_del_and_pass() {
+    let code = "a = 1; b = 2; x = [1, 2, 3]; del a; del x[0]; x[-1] = 9; if b == 2 { pass; }";
+    let result = exec(code, 200).unwrap();
+    assert_eq!(result.get("a"), None);
+    assert_eq!(result.get("x"), Some(&RsValue::Vector(vec![RsValue::Int(2), RsValue::Int(9)])));
+    assert!(matches!(
+        exec("del missing;", 100),
+        Err(ExecError::Execution(SandboxExecutionError::ReferenceNotExistError(_)))
+    ));
+}
+
+#[test]
+fn test_nested_subscript_assignment() {
+    let result = exec("m = [[1, 2], [3, 4]]; m[1][0] = 7; v = m[1][0] + m[0][-1];", 200).unwrap();
+    assert_eq!(result.get("v"), Some(&RsValue::Int(9)));
+    assert!(matches!(
+        exec("m = [1]; m[3] = 1;", 100),
+        Err(ExecError::Execution(SandboxExecutionError::SubscriptKeyError))
+    ));
+}
+
+#[test]
+fn test_collections_are_copied_on_write() {
+    let result = exec("a = [[1, 2], 3]; b = a; b[0][1] = 9; c = a[0]; c[0] = 5;", 300).unwrap();
+    let vector = |items: Vec<i64>| RsValue::Vector(items.into_iter().map(RsValue::Int).collect());
+    assert_eq!(result.get("a"), Some(&RsValue::Vector(vec![vector(vec![1, 2]), RsValue::Int(3)])));
+    assert_eq!(result.get("b"), Some(&RsValue::Vector(vec![vector(vec![1, 9]), RsValue::Int(3)])));
+    assert_eq!(result.get("c"), Some(&vector(vec![5, 2])));
+}
+
+#[test]
+fn test_scoped_block_charges_each_statement() {
+    // `a = 1;` costs 3 plus 1 for the enclosing body, whether that is the module or a block
+    let scoped = "{ a = 1; a = 1; };";
+    assert!(exec(scoped, 10).is_ok());
+    assert!(matches!(
+        exec(scoped, 9),
+        Err(ExecError::Execution(SandboxExecutionError::TimeoutError))
+    ));
+}
+
+#[test]
+fn test_match_literals_and_guards() {
+    let code = r#"
+        route = "";
+        code = 404;
+        match code {
+            200 | 201 => { route = "ok"; }
+            c if c >= 500 => { route = "retry"; }
+            -1 => { route = "invalid"; }
+            _ => { route = "fail"; }
+        }
+    "#;
+    let result = exec(code, 500).unwrap();
+    assert_eq!(result.get("route"), Some(&RsValue::String("fail".to_string())));
+}
+
+#[test]
+fn test_or_patterns_bind_the_same_names() {
+    let code = "match [1, 2] { [x, 1] | [2, y] => { pass; } }";
+    assert!(matches!(exec(code, 100), Err(ExecError::Parse(_))));
+    let code = "match [1, 2] { [x, _] | [_, x] | (x, y) => { pass; } }";
+    assert!(matches!(exec(code, 100), Err(ExecError::Parse(_))));
+
+    let code = "match [1, 2] { [x, 1] | [_, x] => { found = x; } }";
+    let result = exec(code, 100).unwrap();
+    assert_eq!(result.get("found"), Some(&RsValue::Int(2)));
+}
+
+#[test]
+fn test_python_mode_match_and_case() {
+    let code = r#"
+        event = ["move", 3, 4];
+        match event:
+            case ["quit"]:
+                kind = 0;
+            case ["move", x, y] if x > 1:
+                kind = 1;
+                match y:
+                    case 4 | 5:
+                        far = true;
+                    case _:
+                        far = false;
+                steps = x + y;
+            case _:
+                kind = 2;
+        done = kind;
+    "#;
+    let result = exec(code, 500).unwrap();
+    assert_eq!(result.get("kind"), Some(&RsValue::Int(1)));
+    assert_eq!(result.get("far"), Some(&RsValue::Bool(true)));
+    assert_eq!(result.get("steps"), Some(&RsValue::Int(7)));
+    // Not indented deeper than `match`, so it runs after the match
+    assert_eq!(result.get("done"), Some(&RsValue::Int(1)));
+
+    let result = exec("match 2: case 1: a = 1; case 2: a = 2; b = 3;", 100).unwrap();
+    assert_eq!(result.get("a"), Some(&RsValue::Int(2)));
+    assert_eq!(result.get("b"), Some(&RsValue::Int(3)));
+
+    assert!(matches!(exec("match 1:\ncase 1:\n    a = 1;", 100), Err(ExecError::Parse(_))));
+    assert!(matches!(exec("match 1:\n    case 1:\n    a = 1;", 100), Err(ExecError::Parse(_))));
+}
+
+#[test]
+fn test_match_sequences() {
+    let code = r#"
+        event = ["move", 3, 4, 5];
+        match event {
+            ["quit"] => { kind = 0; }
+            ["move", x, *rest] if x > 1 => { kind = 1; }
+            [_, *_] => { kind = 2; }
+        }
+        match (1, (2, 3)) {
+            (a, pair @ (b, c)) => { total = a + b + c; }
+        }
+    "#;
+    let result = exec(code, 500).unwrap();
+    assert_eq!(result.get("kind"), Some(&RsValue::Int(1)));
+    assert_eq!(result.get("rest"), Some(&RsValue::Vector(vec![RsValue::Int(4), RsValue::Int(5)])));
+    assert_eq!(result.get("total"), Some(&RsValue::Int(6)));
+    assert_eq!(result.get("pair"), Some(&RsValue::Vector(vec![RsValue::Int(2), RsValue::Int(3)])));
+}
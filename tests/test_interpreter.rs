@@ -0,0 +1,135 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use virtual_exec::{ExecError, Interpreter};
+use virtual_exec_macro::parse;
+use virtual_exec_type::ast::core::{ASTNode, BinaryOperator, Module};
+use virtual_exec_type::error::SandboxExecutionError;
+use virtual_exec_type::base::{ValueKind, ValueTag};
+use virtual_exec_type::exec_ctx::{
+    value_kind_to_rs_value, ExecOptions, ExecutionContext, Limits, RsValue, ScopeMode,
+};
+use virtual_exec_type::op::OperatorTable;
+use virtual_exec_type::vm::Program;
+
+#[test]
+fn test_inputs_are_visible_to_the_script() {
+    let mut interp = Interpreter::new();
+    interp.set("a", RsValue::Int(1));
+    interp.set("items", RsValue::Vector(vec![RsValue::Int(2), RsValue::Int(3)]));
+    interp.set("obj", RsValue::Object(BTreeMap::from([("x".to_string(), RsValue::Float(0.5))])));
+    interp.run("first, second = items; b = a + first + second + obj.x;").unwrap();
+    assert_eq!(interp.get("b"), Some(RsValue::Float(6.5)));
+    assert_eq!(interp.get("missing"), None);
+}
+
+#[test]
+fn test_session_is_reused_across_runs() {
+    let mut interp = Interpreter::new();
+    interp.run("count = 0;").unwrap();
+    for _ in 0..3 {
+        interp.run("count += 1;").unwrap();
+    }
+    interp.set("count", RsValue::Int(interp.get("count").map_or(0, |c| match c {
+        RsValue::Int(c) => c * 10,
+        _ => 0,
+    })));
+    interp.run_module(&parse!(count = count + 1;)).unwrap();
+    assert_eq!(interp.get("count"), Some(RsValue::Int(31)));
+    assert_eq!(interp.globals().len(), 1);
+}
+
+#[test]
+fn test_each_run_gets_a_fresh_budget() {
+    let options = ExecOptions {
+        limits: Limits { max_operations: Some(20), ..Limits::default() },
+        ..ExecOptions::default()
+    };
+    let mut interp = Interpreter::with_options(options);
+    for _ in 0..10 {
+        interp.run("a = 1 + 2;").unwrap();
+    }
+    let result = interp.run("a = 1; b = 2; c = 3; d = 4; e = 5; f = 6;");
+    assert!(matches!(result, Err(ExecError::Execution(SandboxExecutionError::TimeoutError))));
+    // Bindings made before the budget ran out are kept
+    assert_eq!(interp.get("b"), Some(RsValue::Int(2)));
+}
+
+#[test]
+fn test_object_inputs_are_shared_with_the_host() {
+    let mut interp = Interpreter::new();
+    let fields = BTreeMap::from([
+        ("count".to_string(), RsValue::Int(1)),
+        ("inner".to_string(), RsValue::Object(BTreeMap::new())),
+    ]);
+    let obj = interp.set_object("cfg", fields);
+    interp.run("cfg.count = cfg.count + 1; cfg.inner.flag = true; alias = cfg; alias.name = \"x\";").unwrap();
+
+    let expected = BTreeMap::from([
+        ("count".to_string(), RsValue::Int(2)),
+        ("name".to_string(), RsValue::String("x".to_string())),
+        ("inner".to_string(), RsValue::Object(BTreeMap::from([("flag".to_string(), RsValue::Bool(true))]))),
+    ]);
+    assert_eq!(value_kind_to_rs_value(&ValueKind::Object(obj)), RsValue::Object(expected));
+}
+
+#[test]
+fn test_failed_update_keeps_the_value() {
+    let mut interp = Interpreter::new();
+    interp.run("a = [1, [2]];").unwrap();
+    assert!(interp.run("a[1][5] = 3;").is_err());
+    assert_eq!(
+        interp.get("a"),
+        Some(RsValue::Vector(vec![RsValue::Int(1), RsValue::Vector(vec![RsValue::Int(2)])]))
+    );
+}
+
+#[test]
+fn test_self_referencing_objects_are_exported() {
+    let mut interp = Interpreter::new();
+    interp.set("cfg", RsValue::Object(BTreeMap::from([("n".to_string(), RsValue::Int(1))])));
+    interp.run("cfg.me = cfg; pair = [cfg, cfg];").unwrap();
+
+    let expected = RsValue::Object(BTreeMap::from([
+        ("n".to_string(), RsValue::Int(1)),
+        ("me".to_string(), RsValue::None),
+    ]));
+    assert_eq!(interp.get("cfg"), Some(expected.clone()));
+    // An object held twice without a cycle is copied in full both times
+    assert_eq!(interp.globals().get("pair"), Some(&RsValue::Vector(vec![expected.clone(), expected])));
+}
+
+#[test]
+fn test_panicking_runs_leave_no_scope_behind() {
+    let options = ExecOptions {
+        scope_mode: ScopeMode::Block,
+        limits: Limits { max_nesting_depth: Some(8), ..Limits::default() },
+        ..ExecOptions::default()
+    };
+    let mut operators = OperatorTable::default();
+    operators.register(BinaryOperator::Subtract, ValueTag::Int, ValueTag::Int, |_, _| panic!("boom"));
+    let failing = virtual_exec_parser::parser::parse("if true { { y = 1 - 1; }; }").unwrap();
+    let next = virtual_exec_parser::parser::parse("z = 5;").unwrap();
+
+    let mut interp = Interpreter::with_options(options.clone());
+    *interp.operators_mut() = operators.clone();
+    let result = interp.run_module(&failing);
+    assert!(matches!(result, Err(ExecError::Execution(SandboxExecutionError::GenericPanicRewindError))));
+    interp.run_module(&next).unwrap();
+    assert_eq!(interp.get("z"), Some(RsValue::Int(5)));
+
+    for vm in [false, true] {
+        let ctx = Rc::new(RefCell::new(
+            ExecutionContext::builder().options(options.clone()).operators(operators.clone()).build(),
+        ));
+        let run = |module: &Module| match vm {
+            false => module.eval(ctx.clone()),
+            true => Program::compile(module).run(ctx.clone()),
+        };
+        assert!(matches!(run(&failing), Err(SandboxExecutionError::GenericPanicRewindError)));
+        // Neither the block scopes nor the nesting depth of the failed run are left behind
+        assert_eq!(ctx.borrow().scope_count(), 1);
+        run(&next).unwrap();
+        assert_eq!(ctx.borrow().to_btreemap().get("z"), Some(&RsValue::Int(5)));
+    }
+}
@@ -0,0 +1,175 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use virtual_exec::{exec_with_options, ExecError, Interpreter};
+use virtual_exec_parser::parser::parse;
+use virtual_exec_type::error::SandboxExecutionError;
+use virtual_exec_type::base::ValueKind;
+use virtual_exec_type::exec_ctx::{
+    ExecOptions, ExecutionContext, Limits, RsValue, DEADLINE_CHECK_EVERY,
+};
+use virtual_exec_type::native::NativeFunction;
+use virtual_exec_type::vm::Program;
+
+fn run(code: &str, limits: Limits) -> Result<std::collections::BTreeMap<String, RsValue>, ExecError> {
+    let options = ExecOptions { limits, ..ExecOptions::default() };
+    exec_with_options(code, &options)
+}
+
+fn expect_error(code: &str, limits: Limits, expected: SandboxExecutionError) {
+    match run(code, limits) {
+        Err(ExecError::Execution(err)) => assert_eq!(
+            std::mem::discriminant(&err),
+            std::mem::discriminant(&expected),
+            "expected {:?}, got {:?}", expected, err
+        ),
+        other => panic!("expected {:?}, got {:?}", expected, other),
+    }
+}
+
+#[test]
+fn test_unbounded_by_default() {
+    let result = run("a = [1, 2, 3]; s = \"abc\" + \"def\";", Limits::default()).unwrap();
+    assert_eq!(result.get("s"), Some(&RsValue::String("abcdef".to_string())));
+}
+
+#[test]
+fn test_each_limit_has_its_own_error() {
+    expect_error(
+        "a = 1 + 2;",
+        Limits { max_operations: Some(3), ..Limits::default() },
+        SandboxExecutionError::TimeoutError,
+    );
+    expect_error(
+        "a = 1 + (2 + 3);",
+        Limits { max_nesting_depth: Some(3), ..Limits::default() },
+        SandboxExecutionError::NestingDepthExceededError,
+    );
+    expect_error(
+        "a = [1, 2, 3];",
+        Limits { max_collection_length: Some(2), ..Limits::default() },
+        SandboxExecutionError::CollectionLengthExceededError,
+    );
+    expect_error(
+        "s = \"abc\" + \"def\";",
+        Limits { max_string_length: Some(4), ..Limits::default() },
+        SandboxExecutionError::StringLengthExceededError,
+    );
+    expect_error(
+        "a = 1; b = 2;",
+        Limits { max_memory_bytes: Some(16), ..Limits::default() },
+        SandboxExecutionError::MemoryLimitExceededError,
+    );
+    expect_error(
+        "a = 1;",
+        Limits { max_duration: Some(Duration::ZERO), ..Limits::default() },
+        SandboxExecutionError::DeadlineExceededError,
+    );
+}
+
+#[test]
+fn test_deadline_is_checked_while_the_script_runs() {
+    let options = ExecOptions {
+        limits: Limits { max_duration: Some(Duration::from_millis(20)), ..Limits::default() },
+        ..ExecOptions::default()
+    };
+    let mut interp = Interpreter::with_options(options);
+    interp.register_function("wait", NativeFunction::new(|_| {
+        std::thread::sleep(Duration::from_millis(40));
+        Ok(ValueKind::None)
+    }));
+    // The clock is read on the first unit, then only once every `DEADLINE_CHECK_EVERY` units
+    let code = format!("wait(); {}", "a = 1; ".repeat(DEADLINE_CHECK_EVERY as usize));
+    let result = interp.run(&code);
+    assert!(matches!(result, Err(ExecError::Execution(SandboxExecutionError::DeadlineExceededError))));
+}
+
+#[test]
+fn test_limits_allow_code_within_bounds() {
+    let limits = Limits {
+        max_operations: Some(100),
+        max_nesting_depth: Some(4),
+        max_collection_length: Some(3),
+        max_string_length: Some(6),
+        max_memory_bytes: Some(1 << 20),
+        max_duration: Some(Duration::from_secs(10)),
+    };
+    let result = run("a = [1, 2, 3]; s = \"abc\" + \"def\";", limits).unwrap();
+    assert_eq!(result.get("a").map(|a| matches!(a, RsValue::Vector(v) if v.len() == 3)), Some(true));
+}
+
+#[test]
+fn test_string_growth_counts_towards_memory() {
+    let code = format!("s = \"0123456789\"; {}", "s = s + s; ".repeat(10));
+    let code = code.as_str();
+    let generous = Limits { max_memory_bytes: Some(1 << 20), ..Limits::default() };
+    assert!(run(code, generous).is_ok());
+    // The final string alone is 10 KiB, so it cannot fit even though its predecessors are freed
+    let tight = Limits { max_memory_bytes: Some(8 * 1024), ..Limits::default() };
+    expect_error(code, tight, SandboxExecutionError::MemoryLimitExceededError);
+}
+
+#[test]
+fn test_released_values_are_reclaimed() {
+    // Each round builds a 2.5 KiB string and drops it again, 25 KiB in total
+    let round = format!("s = \"0123456789\"; {}del s; ", "s = s + s; ".repeat(8));
+    let code = round.repeat(10);
+    let limits = Limits { max_memory_bytes: Some(8 * 1024), ..Limits::default() };
+    assert!(run(&code, limits).is_ok());
+}
+
+#[test]
+fn test_element_updates_are_charged_in_place() {
+    // Each round grows one element of a collection to 2.5 KiB and drops the collection again
+    let round = format!("a = [\"0123456789\", 1]; {}del a; ", "a[0] = a[0] + a[0]; ".repeat(8));
+    let code = round.repeat(10);
+    let limits = Limits { max_memory_bytes: Some(8 * 1024), ..Limits::default() };
+    assert!(run(&code, limits).is_ok());
+    let limits = Limits { max_memory_bytes: Some(2 * 1024), ..Limits::default() };
+    expect_error(&code, limits, SandboxExecutionError::MemoryLimitExceededError);
+}
+
+#[test]
+fn test_aliases_share_the_memory_of_a_collection() {
+    let options = ExecOptions {
+        limits: Limits { max_memory_bytes: Some(60 * 1024), ..Limits::default() },
+        ..ExecOptions::default()
+    };
+    let mut interp = Interpreter::with_options(options);
+    interp.set("items", RsValue::Vector((0..1000).map(RsValue::Int).collect()));
+    interp.run("n = 1;").unwrap();
+    interp.run("b = items; n = 2;").unwrap();
+    interp.run("c = items; d = b; n = 3;").unwrap();
+    // Writing through an alias copies the collection, which does count
+    assert!(matches!(
+        interp.run("b[0] = 1; c[0] = 1; d[0] = 1; n = 4;"),
+        Err(ExecError::Execution(SandboxExecutionError::MemoryLimitExceededError))
+    ));
+}
+
+#[test]
+fn test_values_holding_a_collection_twice_are_sized_once() {
+    // Each statement doubles the references to the first collection, but only adds one
+    let code = format!("a = [1]; {}", "a = [a, a]; ".repeat(30));
+    let options = ExecOptions {
+        limits: Limits {
+            max_operations: Some(1000),
+            max_memory_bytes: Some(1 << 20),
+            ..Limits::default()
+        },
+        ..ExecOptions::default()
+    };
+    let unbounded = ExecOptions {
+        limits: Limits { max_operations: Some(1000), ..Limits::default() },
+        ..ExecOptions::default()
+    };
+    let program = Program::compile(&parse(&code).unwrap());
+    let start = Instant::now();
+    for options in [options, unbounded] {
+        let mut interp = Interpreter::with_options(options.clone());
+        interp.run(&code).unwrap();
+        let ctx = ExecutionContext::builder().options(options).build();
+        program.run(Rc::new(RefCell::new(ctx))).unwrap();
+    }
+    assert!(start.elapsed() < Duration::from_secs(5), "took {:?}", start.elapsed());
+}
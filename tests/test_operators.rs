@@ -0,0 +1,130 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use virtual_exec::{ExecError, Interpreter};
+use virtual_exec_macro::parse;
+use virtual_exec_type::ast::core::{ASTNode, BinaryOperator, UnaryOperator};
+use virtual_exec_type::base::{ValueContainer, ValueKind, ValueTag};
+use virtual_exec_type::builtin::VirPyInt;
+use virtual_exec_type::error::SandboxExecutionError;
+use virtual_exec_type::exec_ctx::{ExecutionContext, RsValue};
+use virtual_exec_type::op::{err_op_bnot, op_bnot, OperatorTable};
+
+#[test]
+fn test_unregister_only_affects_its_own_context() {
+    let mut restricted = Interpreter::new();
+    assert!(restricted
+        .operators_mut()
+        .unregister(BinaryOperator::Add, ValueTag::String, ValueTag::String));
+    let mut regular = Interpreter::new();
+
+    let code = "s = \"a\" + \"b\";";
+    assert!(matches!(
+        restricted.run(code),
+        Err(ExecError::Execution(SandboxExecutionError::UndefinedOperatorMethodError))
+    ));
+    regular.run(code).unwrap();
+    assert_eq!(regular.get("s"), Some(RsValue::String("ab".to_string())));
+    // Other implementations of the same operator are kept
+    restricted.run("n = 1 + 2;").unwrap();
+    assert_eq!(restricted.get("n"), Some(RsValue::Int(3)));
+}
+
+#[test]
+fn test_register_custom_operators() {
+    let mut interp = Interpreter::new();
+    interp.operators_mut().register(
+        BinaryOperator::Multiply,
+        ValueTag::String,
+        ValueTag::Int,
+        |lhs, rhs| {
+            let count = rhs.as_int().unwrap().value;
+            if count < 0 {
+                return Err(SandboxExecutionError::InvalidTypeError);
+            }
+            Ok(ValueKind::String(lhs.as_string().unwrap().repeat(count as usize)))
+        },
+    );
+    interp.operators_mut().register_unary(UnaryOperator::Negative, ValueTag::String, |rhs| {
+        Ok(ValueKind::String(rhs.as_string().unwrap().chars().rev().collect()))
+    });
+    interp.run("s = \"ab\" * 3; r = -\"abc\";").unwrap();
+    assert_eq!(interp.get("s"), Some(RsValue::String("ababab".to_string())));
+    assert_eq!(interp.get("r"), Some(RsValue::String("cba".to_string())));
+    assert!(matches!(
+        interp.run("s = \"ab\" * -1;"),
+        Err(ExecError::Execution(SandboxExecutionError::InvalidTypeError))
+    ));
+    assert!(Interpreter::new().run("s = \"ab\" * 3;").is_err());
+}
+
+#[test]
+fn test_bitwise_not() {
+    let mut interp = Interpreter::new();
+    interp.run("a = ~5; b = ~-1 + 1;").unwrap();
+    assert_eq!(interp.get("a"), Some(RsValue::Int(-6)));
+    assert_eq!(interp.get("b"), Some(RsValue::Int(1)));
+    assert!(matches!(
+        interp.run("c = ~true;"),
+        Err(ExecError::Execution(SandboxExecutionError::UndefinedOperatorMethodError))
+    ));
+
+    // The free functions dispatch through the default table, seeded by `register_op_bnot!`
+    let five = ValueContainer::new(ValueKind::Int(VirPyInt::new(5)));
+    assert!(matches!(op_bnot(&five), Some(ValueKind::Int(v)) if v.value == -6));
+    let flag = ValueContainer::new(ValueKind::Bool(true));
+    assert!(op_bnot(&flag).is_none());
+    assert!(matches!(err_op_bnot(&flag), Err(SandboxExecutionError::UndefinedOperatorMethodError)));
+}
+
+#[test]
+fn test_builder_with_empty_table() {
+    let mut operators = OperatorTable::empty();
+    operators.register(BinaryOperator::Add, ValueTag::Int, ValueTag::Int, |lhs, rhs| {
+        Ok(ValueKind::Int(VirPyInt::new(lhs.as_int().unwrap().value + rhs.as_int().unwrap().value)))
+    });
+    assert!(operators.contains(BinaryOperator::Add, ValueTag::Int, ValueTag::Int));
+    assert!(!operators.contains(BinaryOperator::Subtract, ValueTag::Int, ValueTag::Int));
+
+    let ctx = Rc::new(RefCell::new(ExecutionContext::builder().operators(operators).build()));
+    parse!(a = 1 + 2;).eval(ctx.clone()).unwrap();
+    assert_eq!(ctx.borrow().to_btreemap().get("a"), Some(&RsValue::Int(3)));
+    let result = parse!(b = 2 - 1;).eval(ctx.clone());
+    assert!(matches!(result, Err(SandboxExecutionError::UndefinedOperatorMethodError)));
+}
+
+#[test]
+fn test_variant_lists_cover_every_index() {
+    for (idx, tag) in ValueTag::ALL.into_iter().enumerate() {
+        assert_eq!(tag as usize, idx);
+    }
+    for (idx, op) in BinaryOperator::ALL.into_iter().enumerate() {
+        assert_eq!(op as usize, idx);
+    }
+    for (idx, op) in UnaryOperator::ALL.into_iter().enumerate() {
+        assert_eq!(op as usize, idx);
+    }
+    let values = [
+        ValueKind::Int(VirPyInt::new(1)),
+        ValueKind::Bool(true),
+        ValueKind::String(String::new()),
+        ValueKind::Collection(Rc::new(Vec::new())),
+        ValueKind::None,
+    ];
+    for value in values {
+        assert!((value.tag() as usize) < ValueTag::ALL.len());
+    }
+    // Every slot of the tables exists
+    let mut table = OperatorTable::default();
+    for op in BinaryOperator::ALL {
+        for lhs in ValueTag::ALL {
+            for rhs in ValueTag::ALL {
+                table.unregister(op, lhs, rhs);
+            }
+        }
+    }
+    for op in UnaryOperator::ALL {
+        for operand in ValueTag::ALL {
+            table.unregister_unary(op, operand);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use virtual_exec::{ExecError, Interpreter};
+use virtual_exec_parser::parser::parse;
+use virtual_exec_type::ast::core::{ASTNode, BinaryOperator, Expr, Literal, Module, Stmt};
+use virtual_exec_type::ast::optimize::optimize;
+use virtual_exec_type::base::ValueTag;
+use virtual_exec_type::error::SandboxExecutionError;
+use virtual_exec_type::exec_ctx::{ExecOptions, ExecutionContext, RsValue, ScopeMode};
+use virtual_exec_type::op::OperatorTable;
+
+fn optimized(code: &str) -> Module {
+    optimize(parse(code).unwrap(), &OperatorTable::default())
+}
+
+/// The values assigned by the statements of `module`, which must all be assignments.
+fn assigned(module: &Module) -> Vec<&Expr> {
+    module
+        .body
+        .iter()
+        .map(|stmt| match &stmt.kind {
+            Stmt::Assign { value, .. } => &value.kind,
+            other => panic!("expected an assignment, got {other:?}"),
+        })
+        .collect()
+}
+
+/// Runs `module` in a fresh context, returning the result, the variables and the TTL used.
+fn run(module: &Module, options: &ExecOptions) -> (String, Vec<(String, RsValue)>, i64) {
+    let ctx = Rc::new(RefCell::new(
+        ExecutionContext::builder().options(options.clone()).ttl(10_000).build(),
+    ));
+    let result = module.eval(ctx.clone());
+    let ctx = ctx.borrow();
+    let mut variables: Vec<_> = ctx.to_btreemap().into_iter().collect();
+    variables.sort_by(|a, b| a.0.cmp(&b.0));
+    (format!("{result:?}"), variables, 10_000 - ctx.ttl)
+}
+
+#[test]
+fn test_fold_literal_operators() {
+    let module = optimized("a = 1 + 2 * 3; b = -4; c = \"x\" + \"y\"; d = !(1 < 2); e = 1.5 * 2;");
+    let values = assigned(&module);
+    assert!(matches!(values[0], Expr::Literal(Literal::Int(7))));
+    assert!(matches!(values[1], Expr::Literal(Literal::Int(-4))));
+    assert!(matches!(values[2], Expr::Literal(Literal::String(s)) if s == "xy"));
+    assert!(matches!(values[3], Expr::Literal(Literal::Bool(false))));
+    assert!(matches!(values[4], Expr::Literal(Literal::Float(f)) if *f == 3.0));
+}
+
+#[test]
+fn test_fold_short_circuits_on_a_literal_left_operand() {
+    let module = optimized("a = false && x; b = true || x; c = None || x; d = true && x + 1;");
+    let values = assigned(&module);
+    assert!(matches!(values[0], Expr::Literal(Literal::Bool(false))));
+    assert!(matches!(values[1], Expr::Literal(Literal::Bool(true))));
+    assert!(matches!(values[2], Expr::Variable(name) if name == "x"));
+    assert!(matches!(values[3], Expr::BinaryOp { op: BinaryOperator::Add, .. }));
+}
+
+#[test]
+fn test_operators_that_fail_are_left_to_the_script() {
+    let module = optimized("a = 1 / 0; b = [1] + [2]; c = x + 1 * 2;");
+    let values = assigned(&module);
+    assert!(matches!(values[0], Expr::BinaryOp { op: BinaryOperator::Divide, .. }));
+    assert!(matches!(values[1], Expr::BinaryOp { op: BinaryOperator::Add, .. }));
+    let Expr::BinaryOp { right, .. } = values[2] else {
+        panic!("expected `x + 2`, got {:?}", values[2]);
+    };
+    assert!(matches!(right.kind, Expr::Literal(Literal::Int(2))));
+}
+
+#[test]
+fn test_operators_that_panic_are_left_to_the_script() {
+    let code = "a = 9223372036854775807 + 1;";
+    let module = optimized(code);
+    assert!(matches!(assigned(&module)[0], Expr::BinaryOp { op: BinaryOperator::Add, .. }));
+    let options = ExecOptions::default();
+    let (result, ..) = run(&module, &options);
+    assert_eq!(result, run(&parse(code).unwrap(), &options).0);
+    assert!(result.contains("GenericPanicRewindError"));
+}
+
+#[test]
+fn test_eliminate_dead_branches() {
+    let module = optimized(
+        "if false { a = 1; } if None { b = 1; } else { b = 2; } if 1 == 1 { c = 3; } if x { d = 4; }",
+    );
+    let kinds: Vec<_> = module.body.iter().map(|stmt| &stmt.kind).collect();
+    assert_eq!(kinds.len(), 3);
+    assert!(matches!(kinds[0], Stmt::Scoped(body) if body.len() == 1));
+    assert!(matches!(kinds[1], Stmt::Scoped(body) if body.len() == 1));
+    assert!(matches!(kinds[2], Stmt::If { .. }));
+}
+
+#[test]
+fn test_fold_with_the_operators_of_the_context() {
+    let mut operators = OperatorTable::default();
+    operators.unregister(BinaryOperator::Add, ValueTag::Int, ValueTag::Int);
+    let module = optimize(parse("a = 1 + 2;").unwrap(), &operators);
+    assert!(matches!(assigned(&module)[0], Expr::BinaryOp { .. }));
+
+    let mut interp = Interpreter::new();
+    interp
+        .operators_mut()
+        .register(BinaryOperator::Add, ValueTag::Int, ValueTag::Int, |_, _| {
+            Err(SandboxExecutionError::InvalidTypeError)
+        });
+    let module = interp.optimize(parse("a = 1 + 2;").unwrap());
+    assert!(matches!(
+        interp.run_module(&module),
+        Err(ExecError::Execution(SandboxExecutionError::InvalidTypeError))
+    ));
+}
+
+#[test]
+fn test_optimized_scripts_end_in_the_same_state_for_less_ttl() {
+    let scripts = [
+        "a = (1 + 2) * 3; b = [a, -a, \"s\" + \"t\"]; c = a > 5 && !false;",
+        "x = 1; if 2 > 1 { x = x + 10 * 2; } else { x = 0; } if false || None { y = 1; }",
+        "x = 1; if true { y = x; { z = 3 - 1; }; } w = [x, y];",
+        "match 1 + 1 { 2 if true && 2 > 1 => { hit = 1 + 1; } _ => { hit = 0; } }",
+        "a = [1, 2]; a[0 + 1] = 3 * 3; del a[2 - 2]; b, *c = [1 + 1, 2, 3];",
+        "a = 1; b = a + (2 + 3) / 0;",
+        "a = 1 + 1; b = missing + 2 * 2;",
+    ];
+    for scope_mode in [ScopeMode::Function, ScopeMode::Block] {
+        let options = ExecOptions {
+            scope_mode,
+            ..ExecOptions::default()
+        };
+        for code in scripts {
+            let (result, variables, ttl) = run(&parse(code).unwrap(), &options);
+            let (optimized_result, optimized_variables, optimized_ttl) =
+                run(&optimized(code), &options);
+            assert_eq!(result, optimized_result, "{code}");
+            assert_eq!(variables, optimized_variables, "{code}");
+            assert!(optimized_ttl < ttl, "{code}: {optimized_ttl} >= {ttl}");
+        }
+    }
+}
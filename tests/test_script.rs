@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::rc::Rc;
+use virtual_exec::{CompiledScript, ExecError, Interpreter, ScriptCache};
+use virtual_exec_type::error::SandboxExecutionError;
+use virtual_exec_type::exec_ctx::{ExecOptions, Limits, RsValue};
+
+fn cache(capacity: usize) -> ScriptCache {
+    ScriptCache::new(NonZeroUsize::new(capacity).unwrap())
+}
+
+#[test]
+fn test_script_runs_with_different_inputs() {
+    let script = CompiledScript::new("b = a * 2; a = a + 1;").unwrap();
+    for a in 0..3 {
+        let state = script
+            .run(BTreeMap::from([("a".to_string(), RsValue::Int(a))]), &ExecOptions::default())
+            .unwrap();
+        assert_eq!(state.get("a"), Some(&RsValue::Int(a + 1)));
+        assert_eq!(state.get("b"), Some(&RsValue::Int(a * 2)));
+    }
+
+    let limited = ExecOptions {
+        limits: Limits {
+            max_operations: Some(3),
+            ..Limits::default()
+        },
+        ..ExecOptions::default()
+    };
+    assert!(matches!(
+        script.run(BTreeMap::from([("a".to_string(), RsValue::Int(1))]), &limited),
+        Err(ExecError::Execution(SandboxExecutionError::TimeoutError))
+    ));
+}
+
+#[test]
+fn test_interpreter_runs_a_script() {
+    let script = CompiledScript::new("count = count + 1;").unwrap();
+    let mut interp = Interpreter::new();
+    interp.set("count", RsValue::Int(0));
+    for _ in 0..3 {
+        interp.run_script(&script).unwrap();
+    }
+    assert_eq!(interp.get("count"), Some(RsValue::Int(3)));
+}
+
+#[test]
+fn test_cache_reuses_scripts_per_source() {
+    let mut cache = cache(4);
+    let first = cache.get_or_compile("a = 1 + 1;").unwrap();
+    let again = cache.get_or_compile("a = 1 + 1;").unwrap();
+    let other = cache.get_or_compile("a = 2;").unwrap();
+
+    assert!(Rc::ptr_eq(&first, &again));
+    assert!(!Rc::ptr_eq(&first, &other));
+    assert_eq!(cache.len(), 2);
+
+    for script in [first, other] {
+        let state = script.run(BTreeMap::new(), &ExecOptions::default()).unwrap();
+        assert_eq!(state.get("a"), Some(&RsValue::Int(2)));
+    }
+}
+
+#[test]
+fn test_cache_evicts_the_least_recently_used_script() {
+    let mut cache = cache(2);
+    let a = cache.get_or_compile("a = 1;").unwrap();
+    let b = cache.get_or_compile("b = 1;").unwrap();
+    // Using `a` leaves `b` as the least recently used
+    cache.get_or_compile("a = 1;").unwrap();
+    cache.get_or_compile("c = 1;").unwrap();
+    assert_eq!(cache.len(), 2);
+
+    assert!(Rc::ptr_eq(&a, &cache.get_or_compile("a = 1;").unwrap()));
+    assert!(!Rc::ptr_eq(&b, &cache.get_or_compile("b = 1;").unwrap()));
+    assert_eq!(cache.len(), 2);
+
+    cache.clear();
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_cache_skips_scripts_that_fail_to_parse() {
+    let mut cache = cache(2);
+    assert!(matches!(
+        cache.get_or_compile("a = ;"),
+        Err(ExecError::Parse(_))
+    ));
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_objects_in_shared_collections_survive_cycle_collection() {
+    let code = "x = [p]; o.me = o; o.l = x; del o; del p;";
+    let field = || BTreeMap::from([("field".to_string(), RsValue::Int(1))]);
+    let expected = Some(RsValue::Vector(vec![RsValue::Object(field())]));
+
+    let inputs = BTreeMap::from([
+        ("p".to_string(), RsValue::Object(field())),
+        ("o".to_string(), RsValue::Object(BTreeMap::new())),
+    ]);
+    let state = CompiledScript::new(code).unwrap().run(inputs, &ExecOptions::default()).unwrap();
+    assert_eq!(state.get("x"), expected.as_ref());
+
+    let mut interp = Interpreter::new();
+    interp.set_object("p", field());
+    interp.set_object("o", BTreeMap::new());
+    interp.run(code).unwrap();
+    assert_eq!(interp.get("x"), expected);
+}
@@ -0,0 +1,84 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use virtual_exec::{ExecError, Interpreter};
+use virtual_exec_type::export::{from_value, Export};
+use virtual_exec_type::exec_ctx::{rs_value_to_value_kind, RsValue};
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct Order {
+    id: i64,
+    total: f64,
+    tags: Vec<String>,
+    note: Option<String>,
+    status: Status,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+enum Status {
+    Open,
+    Closed,
+}
+
+#[test]
+fn test_rs_value_json_round_trip() {
+    let json = r#"{"id": 7, "ratio": 0.5, "ok": true, "items": [1, "two", null]}"#;
+    let value: RsValue = serde_json::from_str(json).unwrap();
+    let expected = RsValue::Object(BTreeMap::from([
+        ("id".to_string(), RsValue::Int(7)),
+        ("ratio".to_string(), RsValue::Float(0.5)),
+        ("ok".to_string(), RsValue::Bool(true)),
+        (
+            "items".to_string(),
+            RsValue::Vector(vec![RsValue::Int(1), RsValue::String("two".to_string()), RsValue::None]),
+        ),
+    ]));
+    assert_eq!(value, expected);
+    let back: RsValue = serde_json::from_str(&serde_json::to_string(&value).unwrap()).unwrap();
+    assert_eq!(back, expected);
+}
+
+#[test]
+fn test_deserialize_struct_from_script_output() {
+    let mut interp = Interpreter::new();
+    let input: RsValue = serde_json::from_str(r#"{"id": 1, "total": 0}"#).unwrap();
+    interp.set("order", input);
+    interp
+        .run("order.total = order.total + 12.5; order.tags = [\"a\", \"b\"]; order.note = None; order.status = \"Closed\";")
+        .unwrap();
+
+    let order = rs_value_to_value_kind(interp.get("order").unwrap());
+    let order: Order = from_value(&order).unwrap();
+    assert_eq!(
+        order,
+        Order { id: 1, total: 12.5, tags: vec!["a".to_string(), "b".to_string()], note: None, status: Status::Closed }
+    );
+    let open = rs_value_to_value_kind(RsValue::String("Open".to_string()));
+    let status: Result<Status, _> = open.export();
+    assert_eq!(status, Ok(Status::Open));
+}
+
+#[test]
+fn test_shape_mismatch_is_an_error() {
+    let value = rs_value_to_value_kind(RsValue::Vector(vec![RsValue::Int(1)]));
+    assert!(from_value::<(i64, i64)>(&value).is_err());
+    assert!(from_value::<String>(&value).is_err());
+}
+
+#[test]
+fn test_interpreter_get_as() {
+    let mut interp = Interpreter::new();
+    interp.run("pair = [1, 2.5];").unwrap();
+    let pair: (i64, f64) = interp.get_as("pair").unwrap();
+    assert_eq!(pair, (1, 2.5));
+    assert!(interp.get_as::<i64>("missing").is_err());
+}
+
+#[test]
+fn test_cyclic_objects_fail_to_deserialize() {
+    let mut interp = Interpreter::new();
+    interp.set("cfg", RsValue::Object(BTreeMap::from([("n".to_string(), RsValue::Int(1))])));
+    interp.run("cfg.me = cfg; pair = [cfg.n, cfg.n];").unwrap();
+    let err = interp.get_as::<serde_json::Value>("cfg").unwrap_err();
+    assert!(matches!(err, ExecError::Deserialize(e) if e.to_string() == "cyclic object"));
+    assert_eq!(interp.get_as::<Vec<i64>>("pair").unwrap(), vec![1, 1]);
+}
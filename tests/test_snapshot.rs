@@ -0,0 +1,251 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use virtual_exec_macro::SandboxObject;
+use virtual_exec_parser::parser::parse;
+use virtual_exec_type::base::ValueKind;
+use virtual_exec_type::builtin::{VirPyInt, VirPyObject};
+use virtual_exec_type::error::SandboxExecutionError;
+use virtual_exec_type::exec_ctx::{Deterministic, ExecOptions, ExecutionContext, ScopeMode};
+use virtual_exec_type::native::NativeObject;
+use virtual_exec_type::vm::snapshot::{FORMAT_VERSION, MAGIC};
+use virtual_exec_type::vm::{Program, Run, Snapshot, SnapshotError, Status};
+
+#[derive(Debug, Clone, SandboxObject)]
+struct Handle {
+    id: i64,
+}
+
+const CORPUS: &[&str] = &[
+    "a = 1; b = a + 2; c = [a, b, \"s\"]; d = c; d[0] = 10; e = c[0] + d[0];",
+    "obj.x = 1; alias = obj; alias.y = [obj.x, 2]; obj.x = obj.x + alias.y[1]; del alias.y;",
+    "x = 1; { y = x + 1; { global x; x = y * 10; }; z = x; }; w = [x, z];",
+    "a = random(); b = random(); c = random(); d = random();",
+    "if a_flag { r = 1; } else { r = 2; } { s = r; { nonlocal s; s = s + 1; }; };",
+    r#"
+        match [1, 2, 3] {
+            [first, *rest] if first > 0 => { head = first; tail = rest; }
+            _ => { head = None; }
+        }
+        total = head + tail[0] + tail[1];
+    "#,
+];
+
+fn context(options: &ExecOptions, ttl: i64) -> Rc<RefCell<ExecutionContext>> {
+    let obj = VirPyObject::new();
+    obj.set("x".to_string(), ValueKind::Int(VirPyInt::new(0)));
+    Rc::new(RefCell::new(
+        ExecutionContext::builder()
+            .options(options.clone())
+            .ttl(ttl)
+            .variable("obj", ValueKind::Object(obj))
+            .variable("a_flag", ValueKind::Bool(false))
+            .build(),
+    ))
+}
+
+fn state(ctx: &Rc<RefCell<ExecutionContext>>) -> String {
+    let mut variables: Vec<_> = ctx.borrow().to_btreemap().into_iter().collect();
+    variables.sort_by(|a, b| a.0.cmp(&b.0));
+    format!("{variables:?}")
+}
+
+/// Runs `code` `slice` units of TTL at a time, moving the run to a fresh context and program
+/// through its encoded snapshot at every suspension. Returns the result, the final state and
+/// the TTL consumed.
+fn run_through_snapshots(code: &str, options: &ExecOptions, slice: i64) -> (String, String, i64) {
+    let module = parse(code).unwrap();
+    let mut ctx = context(options, slice);
+    let mut run = Run::new(Rc::new(Program::compile(&module)), ctx.clone());
+    let mut granted = slice;
+    let result = loop {
+        match run.resume() {
+            Ok(Status::Suspended) => {
+                let bytes = run.snapshot().unwrap().to_bytes();
+                assert_eq!(run.snapshot().unwrap().to_bytes(), bytes, "{code}");
+                let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+                ctx = context(options, 0);
+                run = Run::restore(Rc::new(Program::compile(&module)), ctx.clone(), &snapshot).unwrap();
+                ctx.borrow_mut().top_up(slice);
+                granted += slice;
+            }
+            result => break result,
+        }
+    };
+    let ttl = granted - ctx.borrow().ttl;
+    (format!("{result:?}"), state(&ctx), ttl)
+}
+
+#[test]
+fn test_restored_runs_end_as_a_single_run_would() {
+    for scope_mode in [ScopeMode::Function, ScopeMode::Block] {
+        let options = ExecOptions {
+            scope_mode,
+            deterministic: Some(Deterministic::default()),
+            ..ExecOptions::default()
+        };
+        for code in CORPUS {
+            let ctx = context(&options, 10_000);
+            let result = Program::compile(&parse(code).unwrap()).run(ctx.clone());
+            let expected = (
+                format!("{:?}", result.map(|_| Status::Finished)),
+                state(&ctx),
+                10_000 - ctx.borrow().ttl,
+            );
+            for slice in [1, 2, 3, 7] {
+                assert_eq!(run_through_snapshots(code, &options, slice), expected, "{code} by {slice}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_restored_transactional_runs_roll_back_every_write() {
+    let code = r#"
+        a = 1; obj.x = 5; obj.y = [obj.x]; del a_flag;
+        { global g; g = 1; }; a = 2;
+        items = [1]; items[0] = 2; c = a / 0;
+    "#;
+    for scope_mode in [ScopeMode::Function, ScopeMode::Block] {
+        let options = ExecOptions {
+            scope_mode,
+            transactional: true,
+            ..ExecOptions::default()
+        };
+        let before = state(&context(&options, 0));
+        for slice in [1, 2, 3, 7] {
+            let (result, after, _) = run_through_snapshots(code, &options, slice);
+            assert_eq!(result, format!("{:?}", Err::<Status, _>(SandboxExecutionError::DivideByZeroError)));
+            assert_eq!(after, before, "{scope_mode:?} by {slice}");
+        }
+    }
+}
+
+#[test]
+fn test_aliases_and_cycles_survive_a_restore() {
+    let code = "alias = obj; obj.me = obj; items = [obj, alias]; obj.x = 1; obj.x = 2;";
+    let module = parse(code).unwrap();
+    let options = ExecOptions::default();
+    let ctx = context(&options, 12);
+    let mut run = Run::new(Rc::new(Program::compile(&module)), ctx.clone());
+    assert_eq!(run.resume().unwrap(), Status::Suspended);
+    // Suspended once the cycle is made, and before `obj.x` is set
+    let obj = ctx.borrow().get("obj").unwrap();
+    assert!(matches!(&obj.borrow().kind, ValueKind::Object(obj) if obj.get("me").is_some()));
+    assert!(matches!(&obj.borrow().kind, ValueKind::Object(obj)
+        if matches!(obj.get("x").unwrap().borrow().kind, ValueKind::Int(VirPyInt { value: 0 }))));
+    let snapshot = Snapshot::from_bytes(&run.snapshot().unwrap().to_bytes()).unwrap();
+    assert_eq!(snapshot.ttl(), ctx.borrow().ttl);
+
+    let restored = context(&options, 0);
+    let mut run = Run::restore(Rc::new(Program::compile(&module)), restored.clone(), &snapshot).unwrap();
+    restored.borrow_mut().top_up(1_000);
+    assert_eq!(run.resume().unwrap(), Status::Finished);
+
+    let ctx = restored.borrow();
+    let object = |name: &str| match &ctx.get(name).unwrap().borrow().kind {
+        ValueKind::Object(obj) => obj.clone(),
+        other => panic!("expected an object, got {other:?}"),
+    };
+    let obj = object("obj");
+    assert!(Rc::ptr_eq(&obj.mapping, &object("alias").mapping));
+    let me = obj.get("me").unwrap();
+    assert!(matches!(&me.borrow().kind, ValueKind::Object(me) if Rc::ptr_eq(&obj.mapping, &me.mapping)));
+    assert!(matches!(obj.get("x").unwrap().borrow().kind, ValueKind::Int(VirPyInt { value: 2 })));
+}
+
+#[test]
+fn test_only_suspended_runs_of_sandbox_values_are_captured() {
+    let program = Rc::new(Program::compile(&parse("a = 1;").unwrap()));
+    let ctx = context(&ExecOptions::default(), 1_000);
+    let mut run = Run::new(program.clone(), ctx.clone());
+    run.resume().unwrap();
+    assert_eq!(run.snapshot().err(), Some(SnapshotError::Finished));
+
+    let native = Rc::new(RefCell::new(
+        ExecutionContext::builder()
+            .ttl(1)
+            .variable("handle", ValueKind::Native(NativeObject::new(Handle { id: 42 })))
+            .build(),
+    ));
+    let mut run = Run::new(program, native);
+    assert_eq!(run.resume().unwrap(), Status::Suspended);
+    assert_eq!(run.snapshot().err(), Some(SnapshotError::UnsupportedValue("native object")));
+}
+
+#[test]
+fn test_restore_rejects_other_programs() {
+    let ctx = context(&ExecOptions::default(), 3);
+    let mut run = Run::new(Rc::new(Program::compile(&parse("a = 1; b = 2;").unwrap())), ctx);
+    assert_eq!(run.resume().unwrap(), Status::Suspended);
+    let snapshot = run.snapshot().unwrap();
+
+    let other = Rc::new(Program::compile(&parse("a = 1; b = 3;").unwrap()));
+    assert_eq!(
+        Run::restore(other, context(&ExecOptions::default(), 0), &snapshot).err(),
+        Some(SnapshotError::ProgramMismatch)
+    );
+
+    // Programs differing only in an operator or a pattern are told apart, while the same source
+    // compiled again is accepted
+    let source = "a = 1; b = a + 2; match b { [x, *_] => { pass; } }";
+    let ctx = context(&ExecOptions::default(), 3);
+    let mut run = Run::new(Rc::new(Program::compile(&parse(source).unwrap())), ctx);
+    assert_eq!(run.resume().unwrap(), Status::Suspended);
+    let snapshot = run.snapshot().unwrap();
+    for other in [
+        "a = 1; b = a - 2; match b { [x, *_] => { pass; } }",
+        "a = 1; b = a + 2; match b { [x, *y] => { pass; } }",
+    ] {
+        let other = Rc::new(Program::compile(&parse(other).unwrap()));
+        assert_eq!(
+            Run::restore(other, context(&ExecOptions::default(), 0), &snapshot).err(),
+            Some(SnapshotError::ProgramMismatch)
+        );
+    }
+    let same = Rc::new(Program::compile(&parse(source).unwrap()));
+    assert!(Run::restore(same, context(&ExecOptions::default(), 0), &snapshot).is_ok());
+}
+
+#[test]
+fn test_reject_malformed_snapshots() {
+    // Free of arithmetic, so that no value a corrupt snapshot holds can overflow
+    let module = parse("a = 1; { b = [a, 2]; { c = [b, [a, b]]; d = c; }; e = [d, c]; };").unwrap();
+    let options = ExecOptions {
+        scope_mode: ScopeMode::Block,
+        ..ExecOptions::default()
+    };
+    let mut run = Run::new(Rc::new(Program::compile(&module)), context(&options, 12));
+    assert_eq!(run.resume().unwrap(), Status::Suspended);
+    let bytes = run.snapshot().unwrap().to_bytes();
+
+    assert_eq!(Snapshot::from_bytes(b"VXAM\x01\x00").err(), Some(SnapshotError::NotASnapshot));
+    let mut future = bytes.clone();
+    future[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+    assert_eq!(
+        Snapshot::from_bytes(&future).err(),
+        Some(SnapshotError::UnsupportedVersion {
+            found: FORMAT_VERSION + 1,
+            supported: FORMAT_VERSION,
+        })
+    );
+    for len in 0..bytes.len() {
+        assert!(Snapshot::from_bytes(&bytes[..len]).is_err(), "cut at {len}");
+    }
+    // Setting any byte to any value either yields a snapshot that restores and runs, or an
+    // error, but never a state that makes the VM panic
+    let program = Rc::new(Program::compile(&module));
+    for (at, byte) in (0..bytes.len()).flat_map(|at| (0..=u8::MAX).map(move |byte| (at, byte))) {
+        let mut corrupt = bytes.clone();
+        corrupt[at] = byte;
+        if let Ok(snapshot) = Snapshot::from_bytes(&corrupt)
+            && let Ok(mut run) = Run::restore(program.clone(), context(&options, 0), &snapshot)
+        {
+            run.context().borrow_mut().top_up(1_000);
+            let result = run.resume();
+            assert!(
+                !matches!(result, Err(SandboxExecutionError::GenericPanicRewindError)),
+                "{byte} at {at}"
+            );
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use virtual_exec::{ExecError, Interpreter};
+use virtual_exec_parser::parser::parse;
+use virtual_exec_type::ast::core::ASTNode;
+use virtual_exec_type::base::ValueKind;
+use virtual_exec_type::builtin::VirPyInt;
+use virtual_exec_type::error::SandboxExecutionError;
+use virtual_exec_type::builtin::VirPyObject;
+use virtual_exec_type::exec_ctx::{
+    rs_value_to_value_kind, ExecOptions, ExecutionContext, Limits, RsValue, ScopeMode,
+};
+use virtual_exec_type::vm::{Program, Run, Status};
+
+fn transactional(scope_mode: ScopeMode) -> ExecOptions {
+    ExecOptions {
+        scope_mode,
+        transactional: true,
+        ..ExecOptions::default()
+    }
+}
+
+/// An interpreter holding a few variables and an object injected by the host.
+fn interpreter(options: ExecOptions) -> (Interpreter, VirPyObject) {
+    let mut interp = Interpreter::with_options(options);
+    interp.set("a", RsValue::Int(1));
+    interp.set("doomed", RsValue::Int(2));
+    let nested = RsValue::Vector(vec![RsValue::Int(2)]);
+    interp.set("items", RsValue::Vector(vec![RsValue::Int(1), nested]));
+    let obj = interp.set_object(
+        "obj",
+        BTreeMap::from([
+            ("x".to_string(), RsValue::Int(0)),
+            ("list".to_string(), RsValue::Vector(vec![RsValue::Int(0)])),
+        ]),
+    );
+    (interp, obj)
+}
+
+const FAILING: &str = r#"
+    a = 2; b = 3; del doomed;
+    items[0] = 9; items[1][0] += 1;
+    obj.x = 5; obj.y = 1; obj.list[0] = 7; obj["x"] += 1;
+    { global g; g = 1; };
+    c = a / 0;
+"#;
+
+#[test]
+fn test_failed_runs_leave_no_trace() {
+    for scope_mode in [ScopeMode::Function, ScopeMode::Block] {
+        let (mut interp, obj) = interpreter(transactional(scope_mode));
+        let globals = interp.globals();
+        let fields = obj.mapping.borrow().mapping.len();
+        assert!(matches!(
+            interp.run(FAILING),
+            Err(ExecError::Execution(SandboxExecutionError::DivideByZeroError))
+        ));
+        assert_eq!(interp.globals(), globals);
+        assert_eq!(obj.mapping.borrow().mapping.len(), fields);
+        assert!(obj.get("y").is_none());
+        let x = obj.get("x").unwrap();
+        assert!(matches!(x.borrow().kind, ValueKind::Int(VirPyInt { value: 0 })));
+    }
+
+    // Without transactions, the writes made before the error stay
+    let (mut interp, obj) = interpreter(ExecOptions::default());
+    assert!(interp.run(FAILING).is_err());
+    assert_eq!(interp.get("a"), Some(RsValue::Int(2)));
+    assert_eq!(interp.get("doomed"), None);
+    assert!(obj.get("y").is_some());
+}
+
+#[test]
+fn test_successful_runs_are_committed() {
+    let (mut interp, obj) = interpreter(transactional(ScopeMode::Function));
+    interp.run("a = a + 1; obj.x = 5; items[0] = 3;").unwrap();
+    let committed = interp.globals();
+    assert!(interp.run("a = 10; obj.x = 6; items[0] = 4; missing;").is_err());
+    assert_eq!(interp.globals(), committed);
+    assert_eq!(interp.get("a"), Some(RsValue::Int(2)));
+    assert!(matches!(obj.get("x").unwrap().borrow().kind, ValueKind::Int(VirPyInt { value: 5 })));
+}
+
+const GROWING: &str = "big = [1, 2, 3, 4, 5, 6, 7, 8]; a = \"grown\"; big[0] = missing;";
+
+#[test]
+fn test_rollback_releases_the_memory_of_the_failed_run() {
+    let (mut interp, _) = interpreter(transactional(ScopeMode::Function));
+    let before = interp.globals();
+    assert!(interp.run(GROWING).is_err());
+    assert_eq!(interp.globals(), before);
+
+    let ctx = Rc::new(RefCell::new(
+        ExecutionContext::builder()
+            .options(transactional(ScopeMode::Function))
+            .variable("a", ValueKind::Int(VirPyInt::new(1)))
+            .build(),
+    ));
+    let used = ctx.borrow().memory_used();
+    let module = parse(GROWING).unwrap();
+    assert!(Program::compile(&module).run(ctx.clone()).is_err());
+    assert_eq!(ctx.borrow().memory_used(), used);
+}
+
+fn context(options: ExecOptions) -> Rc<RefCell<ExecutionContext>> {
+    let (interp, _) = interpreter(options.clone());
+    let mut builder = ExecutionContext::builder().options(options);
+    for (name, value) in interp.globals() {
+        builder = builder.variable(name, rs_value_to_value_kind(value));
+    }
+    Rc::new(RefCell::new(builder.build()))
+}
+
+#[test]
+fn test_both_engines_roll_back_alike() {
+    let module = parse(FAILING).unwrap();
+    let program = Program::compile(&module);
+    for scope_mode in [ScopeMode::Function, ScopeMode::Block] {
+        let tree = context(transactional(scope_mode));
+        let before = tree.borrow().to_btreemap();
+        assert!(matches!(module.eval(tree.clone()), Err(SandboxExecutionError::DivideByZeroError)));
+        assert_eq!(tree.borrow().to_btreemap(), before);
+
+        let vm = context(transactional(scope_mode));
+        assert!(matches!(program.run(vm.clone()), Err(SandboxExecutionError::DivideByZeroError)));
+        assert_eq!(vm.borrow().to_btreemap(), before);
+    }
+}
+
+#[test]
+fn test_suspended_runs_roll_back_when_dropped_or_failing() {
+    let program = Rc::new(Program::compile(&parse(FAILING).unwrap()));
+    let ctx = context(ExecOptions {
+        limits: Limits {
+            max_operations: Some(30),
+            ..Limits::default()
+        },
+        ..transactional(ScopeMode::Block)
+    });
+    let before = ctx.borrow().to_btreemap();
+    let mut run = Run::new(program.clone(), ctx.clone());
+    assert_eq!(run.resume().unwrap(), Status::Suspended);
+    // Writes are visible while the run is suspended
+    assert_ne!(ctx.borrow().to_btreemap(), before);
+    drop(run);
+    assert_eq!(ctx.borrow().to_btreemap(), before);
+
+    let mut run = Run::new(program, ctx.clone());
+    let result = loop {
+        match run.resume() {
+            Ok(Status::Suspended) => ctx.borrow_mut().top_up(30),
+            result => break result,
+        }
+    };
+    assert!(matches!(result, Err(SandboxExecutionError::DivideByZeroError)));
+    assert_eq!(ctx.borrow().to_btreemap(), before);
+}
+
+#[test]
+fn test_host_transactions() {
+    let ctx = context(ExecOptions::default());
+    let before = ctx.borrow().to_btreemap();
+    let module = parse("a = 5; items[0] = 2; obj.x = 1;").unwrap();
+
+    ctx.borrow_mut().begin_transaction();
+    module.eval(ctx.clone()).unwrap();
+    ctx.borrow_mut().set_global("host", ValueKind::Bool(true));
+    ctx.borrow_mut().rollback();
+    assert_eq!(ctx.borrow().to_btreemap(), before);
+
+    ctx.borrow_mut().begin_transaction();
+    module.eval(ctx.clone()).unwrap();
+    ctx.borrow_mut().commit();
+    ctx.borrow_mut().rollback();
+    let a = ctx.borrow().get("a").unwrap();
+    assert!(matches!(a.borrow().kind, ValueKind::Int(VirPyInt { value: 5 })));
+}
@@ -0,0 +1,367 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use virtual_exec_macro::{sandbox_methods, SandboxObject};
+use virtual_exec_parser::parser::parse;
+use virtual_exec_type::ast::core::{ASTNode, Module};
+use virtual_exec_type::base::{Upcast, ValueKind};
+use virtual_exec_type::exec_ctx::{
+    rs_value_to_value_kind, ExecOptions, ExecutionContext, Limits, RsValue, ScopeMode,
+};
+use virtual_exec_type::native::NativeFunction;
+use virtual_exec_type::vm::{Instruction, Program, Run, Status};
+
+#[derive(Debug, Clone, PartialEq, SandboxObject)]
+#[sandbox(methods)]
+struct Cart {
+    total: f64,
+    items: Vec<String>,
+}
+
+#[sandbox_methods]
+impl Cart {
+    pub fn add(&mut self, price: f64) -> f64 {
+        self.total += price;
+        self.total
+    }
+}
+
+/// Scripts run on both engines, covering every statement and expression, and the errors they
+/// can raise part way through.
+const CORPUS: &[&str] = &[
+    "a = 3; b = 2.5; c = a * a - a % 2 + -b; d = (a + 1) / 2; e = a << 2 | 1; f = !(a < b);",
+    "a = None && missing; b = true || missing; c = false || 3; d = true && (4); e = 1 && 2;",
+    "items = [1, 2, 3]; more = [0, *items, 4]; x, *rest = more; *init, (y, z) = 0, [5, 6];",
+    "m = [[1, 2], [3, 4]]; m[1][0] = 7; m[-1][-1] = m[0][0] + m[1][0]; del m[0]; s = \"abc\"[1];",
+    "a = b = [1]; a[0] = 2; (c) = a; [d, e] = [*b, 3]; a, b = 1, 2, 3;",
+    "config.limit = config.limit + 1; config[\"name\"] = \"b\"; config.tags[0] = \"z\"; del config[\"old\"];",
+    "t = config.tags; del config.limit; config.limit.x = 1;",
+    "cart.total = cart.total + 1; t = cart.add(2.5); cart.items[0] = \"z\"; n = (cart).add(1);",
+    "carts = [cart, cart]; carts[1].add(3); u = carts[1].total; v = carts[0].total; carts[0].nothing(1);",
+    "k = [cart]; k[0].items[1] = \"y\"; w = k[0].items; k[0].total = 1; k[5].total = 2;",
+    "x = 1; if x == 1 { y = 2; } else { y = 3; } { z = x + y; }; if None { q = 1; } if 1 { q = 2; }",
+    "x = 0; { global g; g = x; { w = 1; }; }; del x; del x;",
+    "x = 1; { x = x + 1; y = x; { y = y + x; z = [x, y]; }; }; w = [x, y];",
+    "{ a = 1; b = a; }; { a = 2; b = [a, b]; }; a = 3; { a = a + 1; { a = a * 2; }; }; c = [a, b];",
+    "x = 1; { global x; x = x + 1; { x = x + 1; y = x; }; }; { z = x; }; w = y;",
+    "v = 1; del v; { v = 2; u = v; del v; { v = 3; }; t = v; }; v = 4; { s = v; };",
+    "x = 1; { a = x; { nonlocal a; a = a + 1; b = a; }; c = [a, b]; del a; }; { { nonlocal x; x = 2; }; };",
+    "{ a = 1; { nonlocal a; del a; }; b = a; };",
+    "{ a = 1; global a; };",
+    "match [1, 2] { [a, b] => { { c = a + b; }; a = c; } } d = a;",
+    r#"
+        route = "";
+        match 404 {
+            200 | 201 => { route = "ok"; }
+            c if c >= 500 => { route = "retry"; }
+            c if c == 0 => { route = "never"; }
+            _ => { route = "fail"; }
+        }
+    "#,
+    r#"
+        match ["move", 3, 4, 5] {
+            ["quit"] => { kind = 0; }
+            ["move", x, *rest] if x > 1 => { kind = 1; }
+            [_, *_] => { kind = 2; }
+        }
+        match (1, (2, 3)) {
+            (a, pair @ (b, c)) => { total = a + b + c; }
+        }
+        match cart {
+            Cart { total: t } if t > 100 => { big = t; }
+            Cart { items: [first, *_] } => { first_item = first; }
+        }
+        match config {
+            { "limit": limit, **others } => { found = limit; }
+        }
+    "#,
+    "a = [1, 2]; b = [*a, 3]; c = [*a, *b, *c];",
+    "match 1 { c if c => { pass; } }",
+    "a = \"x\"; b = a + a; c = b + b + b;",
+    "a = 1; *b = a;",
+    "a = [1]; a.x = 1;",
+    "f(1);",
+    "a = 1 / 0;",
+    "a = *b;",
+    "1 = a;",
+];
+
+fn context(options: &ExecOptions, ttl: i64) -> Rc<RefCell<ExecutionContext>> {
+    let config = BTreeMap::from([
+        ("limit".to_string(), RsValue::Int(5)),
+        ("name".to_string(), RsValue::String("a".to_string())),
+        ("old".to_string(), RsValue::Bool(true)),
+        (
+            "tags".to_string(),
+            RsValue::Vector(vec![RsValue::String("t".to_string())]),
+        ),
+    ]);
+    let cart = Cart {
+        total: 10.0,
+        items: vec!["a".to_string(), "b".to_string()],
+    };
+    let mut options = options.clone();
+    options.limits.max_operations = Some(ttl);
+    Rc::new(RefCell::new(
+        ExecutionContext::builder()
+            .options(options)
+            .variable("config", rs_value_to_value_kind(RsValue::Object(config)))
+            .variable("cart", cart.from_value())
+            .build(),
+    ))
+}
+
+/// Runs `module` on both engines, each in a fresh context, and checks that they end in the same
+/// state. Returns the TTL the run used.
+fn assert_same(code: &str, module: &Module, program: &Program, options: &ExecOptions, ttl: i64) -> i64 {
+    let tree = context(options, ttl);
+    let tree_result = module.eval(tree.clone());
+    let vm = context(options, ttl);
+    let vm_result = program.run(vm.clone());
+
+    let (tree, vm) = (tree.borrow(), vm.borrow());
+    let setup = format!("{code:?} with TTL {ttl} under {options:?}");
+    assert_eq!(format!("{tree_result:?}"), format!("{vm_result:?}"), "{setup}");
+    assert_eq!(tree.to_btreemap(), vm.to_btreemap(), "{setup}");
+    assert_eq!(tree.ttl, vm.ttl, "{setup}");
+    assert_eq!(tree.memory_used(), vm.memory_used(), "{setup}");
+    assert_eq!(tree.scope_count(), vm.scope_count(), "{setup}");
+    ttl - tree.ttl
+}
+
+/// Runs the corpus with every TTL up to what each script needs, so that both engines are stopped
+/// at every point a script can time out.
+fn compare_engines(options: ExecOptions) {
+    for code in CORPUS {
+        let module = parse(code).unwrap();
+        let program = Program::compile(&module);
+        let used = assert_same(code, &module, &program, &options, 10_000);
+        for ttl in 0..=used {
+            assert_same(code, &module, &program, &options, ttl);
+        }
+    }
+}
+
+#[test]
+fn test_engines_agree() {
+    compare_engines(ExecOptions::default());
+}
+
+#[test]
+fn test_engines_agree_with_block_scopes() {
+    compare_engines(ExecOptions {
+        scope_mode: ScopeMode::Block,
+        ..ExecOptions::default()
+    });
+}
+
+#[test]
+fn test_engines_agree_on_limits() {
+    for max_nesting_depth in 0..8 {
+        compare_engines(ExecOptions {
+            limits: Limits {
+                max_nesting_depth: Some(max_nesting_depth),
+                ..Limits::default()
+            },
+            ..ExecOptions::default()
+        });
+    }
+    for max_memory_bytes in [0, 1_000, 2_000, 4_000, 8_000] {
+        compare_engines(ExecOptions {
+            limits: Limits {
+                max_memory_bytes: Some(max_memory_bytes),
+                max_collection_length: Some(3),
+                max_string_length: Some(3),
+                ..Limits::default()
+            },
+            ..ExecOptions::default()
+        });
+    }
+}
+
+#[test]
+fn test_engines_agree_on_host_functions_using_the_context() {
+    let code = "a = 1; b = bump(); a = a + b; { c = bump(); seen = seen + c; }; d = [a, seen];";
+    let module = parse(code).unwrap();
+    let program = Program::compile(&module);
+    for scope_mode in [ScopeMode::Function, ScopeMode::Block] {
+        let options = ExecOptions { scope_mode, ..ExecOptions::default() };
+        let (tree, vm) = (context(&options, 10_000), context(&options, 10_000));
+        for ctx in [&tree, &vm] {
+            // Reads `a` and rebinds `seen`, with the context borrowed as the host would
+            let weak = Rc::downgrade(ctx);
+            let bump = NativeFunction::new(move |_| {
+                let ctx = weak.upgrade().unwrap();
+                let a = ctx.borrow().get("a")?.borrow().kind.clone();
+                ctx.borrow_mut().set_global("seen", a.clone());
+                Ok(a)
+            });
+            ctx.borrow_mut().register_function("bump", bump);
+        }
+        module.eval(tree.clone()).unwrap();
+        program.run(vm.clone()).unwrap();
+        assert_eq!(tree.borrow().to_btreemap(), vm.borrow().to_btreemap());
+        assert_eq!(
+            vm.borrow().to_btreemap().get("d"),
+            Some(&RsValue::Vector(vec![RsValue::Int(2), RsValue::Int(4)]))
+        );
+    }
+}
+
+#[test]
+fn test_block_variables_keep_their_names_for_the_host() {
+    let code = "a = 1; { b = a + 1; { c = b + 1; peek(); }; };";
+    let module = parse(code).unwrap();
+    let program = Program::compile(&module);
+    let options = ExecOptions { scope_mode: ScopeMode::Block, ..ExecOptions::default() };
+    let (tree, vm) = (context(&options, 10_000), context(&options, 10_000));
+    let mut seen = Vec::new();
+    for ctx in [&tree, &vm] {
+        let peeked = Rc::new(RefCell::new(None));
+        let (weak, into) = (Rc::downgrade(ctx), peeked.clone());
+        let peek = NativeFunction::new(move |_| {
+            let ctx = weak.upgrade().unwrap();
+            let ctx = ctx.borrow();
+            let named = ctx.mapping.iter().any(|scope| scope.borrow().mapping.contains_key("c"));
+            *into.borrow_mut() = Some((ctx.to_btreemap(), named));
+            Ok(ValueKind::None)
+        });
+        ctx.borrow_mut().register_function("peek", peek);
+        seen.push(peeked);
+    }
+    module.eval(tree.clone()).unwrap();
+    program.run(vm.clone()).unwrap();
+
+    let (tree_seen, tree_named) = seen[0].take().unwrap();
+    let (vm_seen, vm_named) = seen[1].take().unwrap();
+    assert_eq!(tree_seen, vm_seen);
+    assert_eq!(vm_seen.get("c"), Some(&RsValue::Int(3)));
+    // The tree-walker binds block variables by name, while the VM keeps them in slots
+    assert!(tree_named);
+    assert!(!vm_named);
+    assert_eq!(tree.borrow().to_btreemap(), vm.borrow().to_btreemap());
+    assert_eq!(vm.borrow().to_btreemap().get("c"), None);
+}
+
+#[test]
+fn test_variables_resolve_to_the_outermost_scope_mentioning_them() {
+    let program = Program::compile(&parse("x = 1; { y = x; { z = x + y; }; };").unwrap());
+    let loads: Vec<_> = program
+        .code()
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::Load(address) => {
+                Some((program.names()[address.index as usize].as_str(), address.depth))
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(loads, [("x", 1), ("x", 2), ("y", 1)]);
+}
+
+#[test]
+fn test_program_runs_many_times() {
+    let program = Program::compile(&parse("total = total + n; n = n + 1;").unwrap());
+    let ctx = Rc::new(RefCell::new(
+        ExecutionContext::builder()
+            .ttl(1000)
+            .variable("total", rs_value_to_value_kind(RsValue::Int(0)))
+            .variable("n", rs_value_to_value_kind(RsValue::Int(1)))
+            .build(),
+    ));
+    for _ in 0..4 {
+        program.run(ctx.clone()).unwrap();
+    }
+    assert_eq!(ctx.borrow().to_btreemap().get("total"), Some(&RsValue::Int(10)));
+}
+
+/// Runs `program` in slices of `slice` units of TTL, returning the result and the TTL used.
+fn run_in_slices(
+    program: &Rc<Program>,
+    ctx: &Rc<RefCell<ExecutionContext>>,
+    slice: i64,
+) -> (String, i64) {
+    let mut run = Run::new(program.clone(), ctx.clone());
+    let mut granted = slice;
+    let result = loop {
+        match run.resume() {
+            Ok(Status::Suspended) => {
+                ctx.borrow_mut().top_up(slice);
+                granted += slice;
+            }
+            result => break result.map(|_| ValueKind::None),
+        }
+    };
+    (format!("{result:?}"), granted - ctx.borrow().ttl)
+}
+
+#[test]
+fn test_suspended_runs_end_as_a_single_run_would() {
+    for scope_mode in [ScopeMode::Function, ScopeMode::Block] {
+        let options = ExecOptions {
+            scope_mode,
+            ..ExecOptions::default()
+        };
+        for code in CORPUS {
+            let module = parse(code).unwrap();
+            let program = Rc::new(Program::compile(&module));
+            let tree = context(&options, 10_000);
+            let tree_result = format!("{:?}", module.eval(tree.clone()));
+            let tree = tree.borrow();
+            for slice in [1, 2, 3, 5, 8] {
+                let ctx = context(&options, slice);
+                let (result, used) = run_in_slices(&program, &ctx, slice);
+                let ctx = ctx.borrow();
+                let setup = format!("{code:?} in slices of {slice} under {options:?}");
+                assert_eq!(tree_result, result, "{setup}");
+                assert_eq!(tree.to_btreemap(), ctx.to_btreemap(), "{setup}");
+                assert_eq!(10_000 - tree.ttl, used, "{setup}");
+                assert_eq!(tree.memory_used(), ctx.memory_used(), "{setup}");
+                assert_eq!(tree.scope_count(), ctx.scope_count(), "{setup}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_runs_are_time_sliced_on_one_thread() {
+    let module = parse("n = 0; n = n + 1; n = n + 1; n = n + 1;").unwrap();
+    let program = Rc::new(Program::compile(&module));
+    let contexts: Vec<_> = (0..3)
+        .map(|_| Rc::new(RefCell::new(ExecutionContext::builder().ttl(0).build())))
+        .collect();
+    let mut runs: Vec<_> = contexts
+        .iter()
+        .map(|ctx| Run::new(program.clone(), ctx.clone()))
+        .collect();
+    let mut rounds = 0;
+    while runs.iter().any(|run| !run.is_finished()) {
+        for run in &mut runs {
+            run.context().borrow_mut().top_up(4);
+            run.resume().unwrap();
+        }
+        rounds += 1;
+    }
+    assert!(rounds > 3, "every run got the same small slices");
+    for ctx in contexts {
+        assert_eq!(ctx.borrow().to_btreemap().get("n"), Some(&RsValue::Int(3)));
+    }
+}
+
+#[test]
+fn test_dropping_a_suspended_run_pops_its_blocks() {
+    let ctx = context(
+        &ExecOptions {
+            scope_mode: ScopeMode::Block,
+            ..ExecOptions::default()
+        },
+        4,
+    );
+    let scopes = ctx.borrow().scope_count();
+    let program = Program::compile(&parse("{ { a = 1; b = 2; }; };").unwrap());
+    let mut run = Run::new(Rc::new(program), ctx.clone());
+    assert_eq!(run.resume().unwrap(), Status::Suspended);
+    assert!(ctx.borrow().scope_count() > scopes);
+    drop(run);
+    assert_eq!(ctx.borrow().scope_count(), scopes);
+}
@@ -1,9 +1,14 @@
-use proc_macro2::{Span, TokenStream as TokenStream2};
+#![forbid(unsafe_code)]
+
+mod sandbox_object;
+
+use proc_macro2::TokenStream as TokenStream2;
 use proc_macro::TokenStream;
 use quote::{quote, ToTokens};
-use syn::parse_macro_input;
-use virtual_exec_parser::tokenizer::{Block, Stmt, Expr, Atom, TopLevelBlock};
-use virtual_exec_type::ast::core::{BinaryOperator, UnaryOperator, Literal};
+use syn::parse::{Parse, ParseStream};
+use syn::{parse_macro_input, Token};
+use virtual_exec_parser::tokenizer::{Stmt, Expr, Atom, TopLevelBlock};
+use virtual_exec_type::ast::core::{BinaryOperator, UnaryOperator, Literal, Pattern};
 
 fn literal_to_token(lit: Literal) -> impl ToTokens {
     match lit {
@@ -15,6 +20,56 @@ fn literal_to_token(lit: Literal) -> impl ToTokens {
     }
 }
 
+fn pattern_to_token(pattern: Pattern) -> TokenStream2 {
+    fn pairs_to_token(pairs: Vec<(String, Pattern)>) -> TokenStream2 {
+        let pairs = pairs.into_iter().map(|(key, pattern)| {
+            let pattern = pattern_to_token(pattern);
+            quote! { (#key.to_string(), #pattern) }
+        });
+        quote! { vec![#(#pairs),*] }
+    }
+    fn name_to_token(name: Option<String>) -> TokenStream2 {
+        match name {
+            Some(name) => quote! { Some(#name.to_string()) },
+            None => quote! { None },
+        }
+    }
+
+    match pattern {
+        Pattern::Literal(l) => {
+            let lit_token = literal_to_token(l);
+            quote! { ::virtual_exec_type::ast::core::Pattern::Literal(#lit_token) }
+        }
+        Pattern::Capture(name) => quote! { ::virtual_exec_type::ast::core::Pattern::Capture(#name.to_string()) },
+        Pattern::Wildcard => quote! { ::virtual_exec_type::ast::core::Pattern::Wildcard },
+        Pattern::Sequence(items) => {
+            let items = items.into_iter().map(pattern_to_token);
+            quote! { ::virtual_exec_type::ast::core::Pattern::Sequence(vec![#(#items),*]) }
+        }
+        Pattern::Star(name) => {
+            let name = name_to_token(name);
+            quote! { ::virtual_exec_type::ast::core::Pattern::Star(#name) }
+        }
+        Pattern::Mapping { entries, rest } => {
+            let entries = pairs_to_token(entries);
+            let rest = name_to_token(rest);
+            quote! { ::virtual_exec_type::ast::core::Pattern::Mapping { entries: #entries, rest: #rest } }
+        }
+        Pattern::Class { name, fields } => {
+            let fields = pairs_to_token(fields);
+            quote! { ::virtual_exec_type::ast::core::Pattern::Class { name: #name.to_string(), fields: #fields } }
+        }
+        Pattern::Or(alternatives) => {
+            let alternatives = alternatives.into_iter().map(pattern_to_token);
+            quote! { ::virtual_exec_type::ast::core::Pattern::Or(vec![#(#alternatives),*]) }
+        }
+        Pattern::Binding(name, inner) => {
+            let inner = pattern_to_token(*inner);
+            quote! { ::virtual_exec_type::ast::core::Pattern::Binding(#name.to_string(), Box::new(#inner)) }
+        }
+    }
+}
+
 fn binary_op_to_token(op: BinaryOperator) -> impl ToTokens {
     match op {
         BinaryOperator::Add => quote! { ::virtual_exec_type::ast::core::BinaryOperator::Add },
@@ -43,6 +98,7 @@ fn unary_op_to_token(op: UnaryOperator) -> impl ToTokens {
         UnaryOperator::Positive => quote! { ::virtual_exec_type::ast::core::UnaryOperator::Positive },
         UnaryOperator::Negative => quote! { ::virtual_exec_type::ast::core::UnaryOperator::Negative },
         UnaryOperator::Not => quote! { ::virtual_exec_type::ast::core::UnaryOperator::Not },
+        UnaryOperator::BitwiseNot => quote! { ::virtual_exec_type::ast::core::UnaryOperator::BitwiseNot },
     }
 }
 
@@ -57,12 +113,20 @@ fn atom_to_token(atom: Atom) -> TokenStream2 {
         }
         Atom::Paren(expr) => {
             let expr_token = expr_to_token(*expr);
-            return quote! {
+            quote! {
                 ::virtual_exec_type::ast::core::Expr::Wrapped(
                     Box::new(#expr_token)
                 )
             }
         }
+        Atom::Collection(items) => {
+            let items_token = items.into_iter().map(expr_to_token);
+            quote! {
+                ::virtual_exec_type::ast::core::Expr::Collection(vec![
+                    #(#items_token),*
+                ])
+            }
+        }
     }
 }
 
@@ -91,6 +155,41 @@ fn expr_to_token(expr: Expr) -> impl ToTokens {
                 }
             }
         }
+        Expr::Starred(inner) => {
+            let inner_token = expr_to_token(*inner);
+            quote! {
+                ::virtual_exec_type::ast::core::Expr::Starred(Box::new(#inner_token))
+            }
+        }
+        Expr::Attribute(value, attr) => {
+            let value_token = expr_to_token(*value);
+            quote! {
+                ::virtual_exec_type::ast::core::Expr::Attribute {
+                    value: Box::new(#value_token),
+                    attr: #attr.to_string(),
+                }
+            }
+        }
+        Expr::Subscript(value, slice) => {
+            let value_token = expr_to_token(*value);
+            let slice_token = expr_to_token(*slice);
+            quote! {
+                ::virtual_exec_type::ast::core::Expr::Subscript {
+                    value: Box::new(#value_token),
+                    slice: Box::new(#slice_token),
+                }
+            }
+        }
+        Expr::Call(function, args) => {
+            let function_token = expr_to_token(*function);
+            let args_token = args.into_iter().map(expr_to_token);
+            quote! {
+                ::virtual_exec_type::ast::core::Expr::Call {
+                    function: Box::new(#function_token),
+                    args: vec![#(#args_token),*],
+                }
+            }
+        }
     };
     quote! {
         ::virtual_exec_type::ast::core::Node {
@@ -124,13 +223,13 @@ fn stmt_to_token(stmt: Stmt) -> impl ToTokens {
                 }
             }
         }
-        Stmt::Assign { target, value } => {
-            let target_token = expr_to_token(target);
+        Stmt::Assign { targets, value } => {
+            let targets_token = targets.into_iter().map(expr_to_token);
             let value_token = expr_to_token(value);
             quote! {
                 ::virtual_exec_type::ast::core::Node {
                     kind: ::virtual_exec_type::ast::core::Stmt::Assign {
-                        target: #target_token,
+                        targets: vec![#(#targets_token),*],
                         value: #value_token,
                     },
                     span: None,
@@ -159,6 +258,46 @@ fn stmt_to_token(stmt: Stmt) -> impl ToTokens {
                 }
             }
         },
+        Stmt::Delete(targets) => {
+            let targets_token = targets.into_iter().map(expr_to_token);
+            stmt_kind_to_node(quote! {
+                ::virtual_exec_type::ast::core::Stmt::Delete(vec![#(#targets_token),*])
+            })
+        }
+        Stmt::Pass => stmt_kind_to_node(quote! { ::virtual_exec_type::ast::core::Stmt::Pass }),
+        Stmt::Global(names) => stmt_kind_to_node(quote! {
+            ::virtual_exec_type::ast::core::Stmt::Global(vec![#(#names.to_string()),*])
+        }),
+        Stmt::Nonlocal(names) => stmt_kind_to_node(quote! {
+            ::virtual_exec_type::ast::core::Stmt::Nonlocal(vec![#(#names.to_string()),*])
+        }),
+        Stmt::Match { subject, arms } => {
+            let subject_token = expr_to_token(subject);
+            let arms_token = arms.into_iter().map(|arm| {
+                let pattern = pattern_to_token(arm.pattern);
+                let guard = match arm.guard {
+                    Some(guard) => {
+                        let guard = expr_to_token(guard);
+                        quote! { Some(#guard) }
+                    }
+                    None => quote! { None },
+                };
+                let body = stmts_to_token(arm.body.stmts);
+                quote! {
+                    ::virtual_exec_type::ast::core::MatchArm {
+                        pattern: #pattern,
+                        guard: #guard,
+                        body: #body,
+                    }
+                }
+            });
+            stmt_kind_to_node(quote! {
+                ::virtual_exec_type::ast::core::Stmt::Match {
+                    subject: #subject_token,
+                    arms: vec![#(#arms_token),*],
+                }
+            })
+        }
         Stmt::Scoped(block) => {
             let stmts = stmts_to_token(block.stmts);
             quote! {
@@ -171,6 +310,15 @@ fn stmt_to_token(stmt: Stmt) -> impl ToTokens {
     }
 }
 
+fn stmt_kind_to_node(kind: TokenStream2) -> TokenStream2 {
+    quote! {
+        ::virtual_exec_type::ast::core::Node {
+            kind: #kind,
+            span: None,
+        }
+    }
+}
+
 fn block_to_token(v: TopLevelBlock) -> impl ToTokens {
     let body = stmts_to_token(v.stmts);
     quote! {
@@ -181,9 +329,91 @@ fn block_to_token(v: TopLevelBlock) -> impl ToTokens {
     }
 }
 
+/// Parses a script at compile time into a `Module`. Parse errors, such as an or-pattern whose
+/// alternatives bind different names, fail the build:
+///
+/// ```compile_fail
+/// let module = virtual_exec_macro::parse!(match 1 { x | 2 => { pass; } });
+/// ```
 #[proc_macro]
 pub fn parse(input: TokenStream) -> TokenStream {
     let output = parse_macro_input!(input as TopLevelBlock);
     let token_content = block_to_token(output);
     quote! { #token_content }.into()
 }
+
+/// Input of [`parse_optimized!`]: the operator table to fold with, a `;`, then the script.
+struct OptimizedBlock {
+    operators: syn::Expr,
+    block: TopLevelBlock,
+}
+
+impl Parse for OptimizedBlock {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let operators = input.parse()?;
+        input.parse::<Token![;]>()?;
+        Ok(OptimizedBlock { operators, block: input.parse()? })
+    }
+}
+
+/// Like [`parse!`], with the module passed through `virtual_exec_type::ast::optimize::optimize`
+/// for the operator table given before the `;`, which should be the table of the context that
+/// runs the module. Folding happens where the macro is expanded, as the table only exists then.
+///
+/// ```
+/// let operators = virtual_exec_type::op::OperatorTable::default();
+/// let module = virtual_exec_macro::parse_optimized!(operators; a = (1 + 2) * 3; if false { a = 0; });
+/// assert_eq!(module.body.len(), 1);
+/// ```
+#[proc_macro]
+pub fn parse_optimized(input: TokenStream) -> TokenStream {
+    let OptimizedBlock { operators, block } = parse_macro_input!(input as OptimizedBlock);
+    let token_content = block_to_token(block);
+    quote! {
+        ::virtual_exec_type::ast::optimize::optimize(#token_content, &#operators)
+    }
+    .into()
+}
+
+/// Exposes a struct with named fields to scripts as a native object.
+///
+/// Every field is readable and writable unless marked `#[sandbox(readonly)]` or
+/// `#[sandbox(hidden)]`. Field types must implement `Upcast` and, when writable, `FromValueKind`.
+/// `#[sandbox(name = "...")]` changes the name matched by class patterns, and
+/// `#[sandbox(methods)]` forwards method calls to the `#[sandbox_methods]` impl of the struct.
+/// The struct must implement `Clone`, as scripts work on copies of it.
+#[proc_macro_derive(SandboxObject, attributes(sandbox))]
+pub fn derive_sandbox_object(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as syn::DeriveInput);
+    sandbox_object::derive(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Binds every `pub` method with a `self` receiver in an inherent impl so that scripts can call
+/// it on a `#[sandbox(methods)]` object. Use `#[sandbox(skip)]` to keep a method out.
+///
+/// Bound methods take `&self` or `&mut self`, as the object stays in the script. Their arguments
+/// are converted with `FromValueKind` and their return value with `Upcast`; `Result` returns are
+/// not supported, so a fallible method has to be skipped.
+///
+/// ```compile_fail
+/// use virtual_exec_macro::sandbox_methods;
+///
+/// #[derive(Clone)]
+/// struct Counter(i64);
+///
+/// #[sandbox_methods]
+/// impl Counter {
+///     pub fn into_inner(self) -> i64 {
+///         self.0
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn sandbox_methods(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as syn::ItemImpl);
+    sandbox_object::methods(item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
@@ -0,0 +1,301 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{Attribute, Data, DeriveInput, Fields, FnArg, ImplItem, ItemImpl, LitStr, Visibility};
+
+#[derive(Default)]
+struct FieldOptions {
+    readonly: bool,
+    hidden: bool,
+}
+
+fn field_options(attrs: &[Attribute]) -> syn::Result<FieldOptions> {
+    let mut options = FieldOptions::default();
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("sandbox")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("readonly") {
+                options.readonly = true;
+                Ok(())
+            } else if meta.path.is_ident("hidden") {
+                options.hidden = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `readonly` or `hidden`"))
+            }
+        })?;
+    }
+    Ok(options)
+}
+
+struct StructOptions {
+    name: Option<String>,
+    methods: bool,
+}
+
+fn struct_options(attrs: &[Attribute]) -> syn::Result<StructOptions> {
+    let mut options = StructOptions {
+        name: None,
+        methods: false,
+    };
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("sandbox")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                options.name = Some(meta.value()?.parse::<LitStr>()?.value());
+                Ok(())
+            } else if meta.path.is_ident("methods") {
+                options.methods = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `name = \"...\"` or `methods`"))
+            }
+        })?;
+    }
+    Ok(options)
+}
+
+/// Expands `#[derive(SandboxObject)]`.
+pub(crate) fn derive(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let ident = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            input.generics.span(),
+            "SandboxObject cannot be derived for generic types",
+        ));
+    }
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new(
+            input.span(),
+            "SandboxObject can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new(
+            data.fields.span(),
+            "SandboxObject requires named fields",
+        ));
+    };
+    let options = struct_options(&input.attrs)?;
+    let type_name = options.name.unwrap_or_else(|| ident.to_string());
+
+    let mut visible = Vec::new();
+    let mut writable = Vec::new();
+    let mut readonly = Vec::new();
+    for field in &fields.named {
+        let field_ident = field.ident.clone().expect("named field");
+        let field_options = field_options(&field.attrs)?;
+        if field_options.hidden {
+            continue;
+        }
+        let name = field_ident.to_string();
+        visible.push((name.clone(), field_ident.clone()));
+        if field_options.readonly {
+            readonly.push(name);
+        } else {
+            writable.push((name, field_ident));
+        }
+    }
+
+    let attribute_names = visible.iter().map(|(name, _)| name);
+    let getters = visible.iter().map(|(name, field)| {
+        quote! {
+            #name => ::core::option::Option::Some(
+                ::virtual_exec_type::base::Upcast::from_value(&self.#field),
+            ),
+        }
+    });
+    let setters = writable.iter().map(|(name, field)| {
+        quote! {
+            #name => {
+                self.#field = ::virtual_exec_type::native::FromValueKind::from_value_kind(value)?;
+                ::core::result::Result::Ok(())
+            }
+        }
+    });
+    let readonly_arm = (!readonly.is_empty()).then(|| {
+        quote! {
+            #(#readonly)|* => ::core::result::Result::Err(
+                ::virtual_exec_type::error::SandboxExecutionError::ReadOnlyAttributeError,
+            ),
+        }
+    });
+    let call_method = options.methods.then(|| {
+        quote! {
+            fn call_method(
+                &mut self,
+                name: &str,
+                args: ::std::vec::Vec<::virtual_exec_type::base::ValueKind>,
+            ) -> ::virtual_exec_type::error::Result<::virtual_exec_type::base::ValueKind> {
+                <Self as ::virtual_exec_type::native::SandboxMethods>::call_method(self, name, args)
+            }
+        }
+    });
+
+    Ok(quote! {
+        impl ::virtual_exec_type::native::SandboxObject for #ident {
+            fn type_name(&self) -> &'static str {
+                #type_name
+            }
+
+            fn as_any(&self) -> &dyn ::std::any::Any {
+                self
+            }
+
+            fn clone_object(&self) -> ::std::boxed::Box<dyn ::virtual_exec_type::native::SandboxObject> {
+                ::std::boxed::Box::new(::core::clone::Clone::clone(self))
+            }
+
+            fn attribute_names(&self) -> ::std::vec::Vec<&'static str> {
+                ::std::vec![#(#attribute_names),*]
+            }
+
+            fn get_attribute(&self, name: &str) -> ::core::option::Option<::virtual_exec_type::base::ValueKind> {
+                match name {
+                    #(#getters)*
+                    _ => ::core::option::Option::None,
+                }
+            }
+
+            fn set_attribute(
+                &mut self,
+                name: &str,
+                value: ::virtual_exec_type::base::ValueKind,
+            ) -> ::virtual_exec_type::error::Result<()> {
+                let _ = &value;
+                match name {
+                    #(#setters)*
+                    #readonly_arm
+                    _ => ::core::result::Result::Err(
+                        ::virtual_exec_type::error::SandboxExecutionError::AttributeNotFoundError,
+                    ),
+                }
+            }
+
+            #call_method
+        }
+
+        impl<'ctx> ::virtual_exec_type::base::Downcast<'ctx> for #ident {
+            fn from_value(value: ::virtual_exec_type::base::Value<'ctx>) -> ::core::option::Option<&'ctx Self> {
+                value.as_native()?.downcast_ref::<Self>()
+            }
+        }
+
+        impl ::virtual_exec_type::base::Tagged for #ident {
+            const TAG: ::virtual_exec_type::base::ValueTag = ::virtual_exec_type::base::ValueTag::Native;
+        }
+
+        impl ::virtual_exec_type::base::Upcast for #ident {
+            fn from_value(&self) -> ::virtual_exec_type::base::ValueKind {
+                ::virtual_exec_type::base::ValueKind::Native(
+                    ::virtual_exec_type::native::NativeObject::new(::core::clone::Clone::clone(self)),
+                )
+            }
+        }
+
+        impl ::virtual_exec_type::native::FromValueKind for #ident {
+            fn from_value_kind(
+                value: ::virtual_exec_type::base::ValueKind,
+            ) -> ::virtual_exec_type::error::Result<Self> {
+                match value {
+                    ::virtual_exec_type::base::ValueKind::Native(native) => native
+                        .downcast_ref::<Self>()
+                        .cloned()
+                        .ok_or(::virtual_exec_type::error::SandboxExecutionError::InvalidTypeError),
+                    _ => ::core::result::Result::Err(
+                        ::virtual_exec_type::error::SandboxExecutionError::InvalidTypeError,
+                    ),
+                }
+            }
+        }
+    })
+}
+
+fn is_skipped(attrs: &[Attribute]) -> syn::Result<bool> {
+    let mut skip = false;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("sandbox")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                skip = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `skip`"))
+            }
+        })?;
+    }
+    Ok(skip)
+}
+
+/// Expands `#[sandbox_methods]`, binding every `pub` method with a `self` receiver.
+pub(crate) fn methods(mut item: ItemImpl) -> syn::Result<TokenStream2> {
+    if !item.generics.params.is_empty() || item.trait_.is_some() {
+        return Err(syn::Error::new(
+            item.span(),
+            "#[sandbox_methods] expects an inherent impl of a non-generic type",
+        ));
+    }
+    let mut arms = Vec::new();
+    for impl_item in &mut item.items {
+        let ImplItem::Fn(method) = impl_item else {
+            continue;
+        };
+        let skipped = is_skipped(&method.attrs)?;
+        method.attrs.retain(|attr| !attr.path().is_ident("sandbox"));
+        let Some(receiver) = method.sig.receiver() else {
+            continue;
+        };
+        if skipped || !matches!(method.vis, Visibility::Public(_)) {
+            continue;
+        }
+        if receiver.reference.is_none() {
+            return Err(syn::Error::new(
+                method.sig.span(),
+                "#[sandbox_methods] methods must take `&self` or `&mut self`",
+            ));
+        }
+
+        let method_ident = &method.sig.ident;
+        let name = method_ident.to_string();
+        let mut arg_idents = Vec::new();
+        let mut arg_types = Vec::new();
+        for (idx, input) in method.sig.inputs.iter().enumerate() {
+            if let FnArg::Typed(arg) = input {
+                arg_idents.push(format_ident!("arg{}", idx));
+                arg_types.push(arg.ty.clone());
+            }
+        }
+        let count = arg_idents.len();
+        arms.push(quote! {
+            #name => {
+                ::virtual_exec_type::native::expect_arguments(&args, #count)?;
+                let mut args = args.into_iter();
+                #(
+                    let #arg_idents: #arg_types = ::virtual_exec_type::native::FromValueKind::from_value_kind(
+                        args.next().unwrap_or(::virtual_exec_type::base::ValueKind::None),
+                    )?;
+                )*
+                let result = self.#method_ident(#(#arg_idents),*);
+                ::core::result::Result::Ok(::virtual_exec_type::base::Upcast::from_value(&result))
+            }
+        });
+    }
+
+    let self_ty = &item.self_ty;
+    Ok(quote! {
+        #item
+
+        impl ::virtual_exec_type::native::SandboxMethods for #self_ty {
+            fn call_method(
+                &mut self,
+                name: &str,
+                args: ::std::vec::Vec<::virtual_exec_type::base::ValueKind>,
+            ) -> ::virtual_exec_type::error::Result<::virtual_exec_type::base::ValueKind> {
+                let _ = &args;
+                match name {
+                    #(#arms)*
+                    _ => ::core::result::Result::Err(
+                        ::virtual_exec_type::error::SandboxExecutionError::AttributeNotFoundError,
+                    ),
+                }
+            }
+        }
+    })
+}
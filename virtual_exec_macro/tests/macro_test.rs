@@ -1,12 +1,12 @@
-use std::cell::{RefCell, Ref};
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::rc::Rc;
-use bumpalo::Bump;
-use virtual_exec_macro::parse;
-use virtual_exec_type::ast::core::ASTNode;
-use virtual_exec_type::base::{Value, ValueContainer, ValueKind};
-use virtual_exec_type::builtin::Mapping;
-use virtual_exec_type::exec_ctx::ExecutionContext;
+use virtual_exec_macro::{parse, parse_optimized};
+use virtual_exec_type::ast::core::{ASTNode, BinaryOperator, Expr, Literal, Module, Stmt};
+use virtual_exec_type::base::{ValueContainer, ValueKind, ValueTag};
+use virtual_exec_type::builtin::{Mapping, VirPyInt, VirPyObject};
+use virtual_exec_type::exec_ctx::{ExecOptions, ExecutionContext, RsValue, ScopeMode};
+use virtual_exec_type::op::OperatorTable;
 
 #[test]
 fn test_simple_assignment_and_expr() {
@@ -15,26 +15,15 @@ fn test_simple_assignment_and_expr() {
         a = a + 5;
         a;
     );
-    let arena_rc = Rc::new(RefCell::new(Bump::new()));
-    let mut global_scope = Mapping { mapping: HashMap::new() };
-
-    let initial_value: Value<'static> = {
-        let arena_borrow: Ref<Bump> = arena_rc.borrow();
-        let arena_ref: &Bump = &arena_borrow;
-        let long_lived_arena: &'static Bump = unsafe { std::mem::transmute(arena_ref) };
-        ValueContainer::new(ValueKind::None, long_lived_arena)
-    };
-
-    global_scope.mapping.insert("a".to_string(), Rc::new(RefCell::new(initial_value)));
-
-    let mapping = vec![Rc::new(RefCell::new(global_scope))];
-    let ctx = Rc::new(RefCell::new(ExecutionContext::new(arena_rc.clone(), 1000, mapping.clone())));
+    let ctx = Rc::new(RefCell::new(
+        ExecutionContext::builder().ttl(1000).variable("a", ValueKind::None).build(),
+    ));
 
-    let result = module.eval(ctx);
+    let result = module.eval(ctx.clone());
 
     assert!(result.is_ok(), "Evaluation failed: {:?}", result.err());
 
-    let value = (&mapping).get(0).unwrap().borrow().mapping.get("a").unwrap().borrow().kind.clone();
+    let value = ctx.borrow().get("a").unwrap().borrow().kind.clone();
 
     match value {
         ValueKind::Int(i) => assert_eq!(i.value, 15),
@@ -52,26 +41,15 @@ fn test_more() {
         }
         a;
     );
-    let arena_rc = Rc::new(RefCell::new(Bump::new()));
-    let mut global_scope = Mapping { mapping: HashMap::new() };
-
-    let initial_value: Value<'static> = {
-        let arena_borrow: Ref<Bump> = arena_rc.borrow();
-        let arena_ref: &Bump = &arena_borrow;
-        let long_lived_arena: &'static Bump = unsafe { std::mem::transmute(arena_ref) };
-        ValueContainer::new(ValueKind::None, long_lived_arena)
-    };
-
-    global_scope.mapping.insert("a".to_string(), Rc::new(RefCell::new(initial_value)));
+    let ctx = Rc::new(RefCell::new(
+        ExecutionContext::builder().ttl(1000).variable("a", ValueKind::None).build(),
+    ));
 
-    let mapping = vec![Rc::new(RefCell::new(global_scope))];
-    let ctx = Rc::new(RefCell::new(ExecutionContext::new(arena_rc.clone(), 1000, mapping.clone())));
-
-    let result = module.eval(ctx);
+    let result = module.eval(ctx.clone());
 
     assert!(result.is_ok(), "Evaluation failed: {:?}", result.err());
 
-    let value = (&mapping).get(0).unwrap().borrow().mapping.get("a").unwrap().borrow().kind.clone();
+    let value = ctx.borrow().get("a").unwrap().borrow().kind.clone();
 
     match value {
         ValueKind::Int(i) => assert_eq!(i.value, 2),
@@ -89,28 +67,17 @@ fn test_timeout() {
         }
         a;
     );
-    let arena_rc = Rc::new(RefCell::new(Bump::new()));
-    let mut global_scope = Mapping { mapping: HashMap::new() };
-
-    let initial_value: Value<'static> = {
-        let arena_borrow: Ref<Bump> = arena_rc.borrow();
-        let arena_ref: &Bump = &arena_borrow;
-        let long_lived_arena: &'static Bump = unsafe { std::mem::transmute(arena_ref) };
-        ValueContainer::new(ValueKind::None, long_lived_arena)
-    };
+    let ctx = Rc::new(RefCell::new(
+        ExecutionContext::builder().ttl(15).variable("a", ValueKind::None).build(),
+    ));
 
-    global_scope.mapping.insert("a".to_string(), Rc::new(RefCell::new(initial_value)));
+    let result = module.eval(ctx.clone());
 
-    let mapping = vec![Rc::new(RefCell::new(global_scope))];
-    let ctx = Rc::new(RefCell::new(ExecutionContext::new(arena_rc.clone(), 15, mapping.clone())));
-
-    let result = module.eval(ctx);
-
-    assert!((&result).is_err(), "Evaluation successful when TimeoutError is expected: {:?}", result.ok());
-    assert!(match (result.clone().err()) {
-        Some(virtual_exec_type::error::SandboxExecutionError::TimeoutError) => true,
-        _ => false
-    }, "Expected TimeoutError, but got {:?}", result.err());
+    assert!(result.is_err(), "Evaluation successful when TimeoutError is expected: {:?}", result.ok());
+    assert!(
+        matches!(result, Err(virtual_exec_type::error::SandboxExecutionError::TimeoutError)),
+        "Expected TimeoutError, but got {:?}", result.err()
+    );
 }
 
 #[test]
@@ -123,29 +90,214 @@ fn test_if_fail_path() {
         }
         a;
     );
-    let arena_rc = Rc::new(RefCell::new(Bump::new()));
-    let mut global_scope = Mapping { mapping: HashMap::new() };
-
-    let initial_value: Value<'static> = {
-        let arena_borrow: Ref<Bump> = arena_rc.borrow();
-        let arena_ref: &Bump = &arena_borrow;
-        let long_lived_arena: &'static Bump = unsafe { std::mem::transmute(arena_ref) };
-        ValueContainer::new(ValueKind::None, long_lived_arena)
-    };
-
-    global_scope.mapping.insert("a".to_string(), Rc::new(RefCell::new(initial_value)));
-
-    let mapping = vec![Rc::new(RefCell::new(global_scope))];
-    let ctx = Rc::new(RefCell::new(ExecutionContext::new(arena_rc.clone(), 1000, mapping.clone())));
+    let ctx = Rc::new(RefCell::new(
+        ExecutionContext::builder().ttl(1000).variable("a", ValueKind::None).build(),
+    ));
 
-    let result = module.eval(ctx);
+    let result = module.eval(ctx.clone());
 
     assert!(result.is_ok(), "Evaluation failed: {:?}", result.err());
 
-    let value = (&mapping).get(0).unwrap().borrow().mapping.get("a").unwrap().borrow().kind.clone();
+    let value = ctx.borrow().get("a").unwrap().borrow().kind.clone();
 
     match value {
         ValueKind::Int(i) => assert_eq!(i.value, 15),
         _ => panic!("Expected an integer result, but got {:?}", value),
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_unpacking_assignment() {
+    let module = parse!(
+        a, *b = [1, 2, 3];
+        c = d = a;
+    );
+    let ctx = Rc::new(RefCell::new(ExecutionContext::builder().ttl(1000).build()));
+
+    let result = module.eval(ctx.clone());
+    assert!(result.is_ok(), "Evaluation failed: {:?}", result.err());
+
+    let state = ctx.borrow().to_btreemap();
+    assert_eq!(state.get("d"), Some(&RsValue::Int(1)));
+    assert_eq!(state.get("b"), Some(&RsValue::Vector(vec![RsValue::Int(2), RsValue::Int(3)])));
+}
+
+#[test]
+fn test_global_and_nonlocal() {
+    let module = parse!(
+        global g;
+        nonlocal n;
+        g = 1;
+        n = n + 1;
+        local = g + n;
+    );
+    let enclosing_scope = Rc::new(RefCell::new(Mapping::new()));
+    let local_scope = Rc::new(RefCell::new(Mapping::new()));
+    enclosing_scope.borrow_mut().mapping.insert(
+        "n".to_string(),
+        Rc::new(RefCell::new(ValueContainer::new(ValueKind::Int(VirPyInt::new(10))))),
+    );
+
+    let ctx = Rc::new(RefCell::new(
+        ExecutionContext::builder()
+            .ttl(1000)
+            .scope(enclosing_scope.clone())
+            .scope(local_scope.clone())
+            .build(),
+    ));
+
+    let result = module.eval(ctx.clone());
+    assert!(result.is_ok(), "Evaluation failed: {:?}", result.err());
+
+    let global_scope = ctx.borrow().mapping.last().unwrap().clone();
+    assert!(global_scope.borrow().mapping.contains_key("g"));
+    assert!(!local_scope.borrow().mapping.contains_key("g"));
+    let n = enclosing_scope.borrow().mapping.get("n").unwrap().borrow().kind.clone();
+    assert!(matches!(n, ValueKind::Int(i) if i.value == 11));
+    let local = local_scope.borrow().mapping.get("local").unwrap().borrow().kind.clone();
+    assert!(matches!(local, ValueKind::Int(i) if i.value == 12));
+}
+
+#[test]
+fn test_delete_attribute_and_key() {
+    let module = parse!(
+        obj.b = obj.a + 1;
+        del obj.a;
+        obj["c"] = 3;
+        del obj["b"];
+    );
+    let obj = VirPyObject::new();
+    obj.set("a".to_string(), ValueKind::Int(VirPyInt::new(1)));
+    let ctx = Rc::new(RefCell::new(
+        ExecutionContext::builder().ttl(1000).variable("obj", ValueKind::Object(obj.clone())).build(),
+    ));
+
+    let result = module.eval(ctx);
+    assert!(result.is_ok(), "Evaluation failed: {:?}", result.err());
+
+    assert!(obj.get("a").is_none());
+    assert!(obj.get("b").is_none());
+    assert!(matches!(obj.get("c").unwrap().borrow().kind, ValueKind::Int(i) if i.value == 3));
+}
+
+fn run_with_scope_mode(module: &Module, scope_mode: ScopeMode) -> BTreeMap<String, RsValue> {
+    let options = ExecOptions { scope_mode, ..ExecOptions::default() };
+    let ctx = Rc::new(RefCell::new(ExecutionContext::builder().ttl(1000).options(options).build()));
+    let result = module.eval(ctx.clone());
+    assert!(result.is_ok(), "Evaluation failed: {:?}", result.err());
+    ctx.borrow().to_btreemap()
+}
+
+#[test]
+fn test_block_scope_mode() {
+    let module = parse!(
+        x = 1;
+        {
+            y = 2;
+            x = x + y;
+        };
+        if x == 3 {
+            z = 1;
+        }
+    );
+
+    let block = run_with_scope_mode(&module, ScopeMode::Block);
+    assert_eq!(block.get("x"), Some(&RsValue::Int(3)));
+    assert_eq!(block.get("y"), None);
+    assert_eq!(block.get("z"), None);
+
+    let function = run_with_scope_mode(&module, ScopeMode::Function);
+    assert_eq!(function.get("x"), Some(&RsValue::Int(3)));
+    assert_eq!(function.get("y"), Some(&RsValue::Int(2)));
+    assert_eq!(function.get("z"), Some(&RsValue::Int(1)));
+}
+
+#[test]
+fn test_match_class_and_mapping_patterns() {
+    let module = parse!(
+        match point {
+            Point { x: 0, y } => { axis = y; }
+            Point { .. } => { axis = None; }
+        }
+        match point {
+            { "x": 0, **others } => { rest = others; }
+        }
+        match point {
+            Point { x: 1, y: v } | Point { y: v, .. } => { picked = v; }
+        }
+        match point:
+            case Point { x: 0 } if false:
+                mode = 0;
+            case Point { x: 0 }:
+                mode = 1;
+    );
+    let point = VirPyObject::with_class("Point");
+    point.set("x".to_string(), ValueKind::Int(VirPyInt::new(0)));
+    point.set("y".to_string(), ValueKind::Int(VirPyInt::new(7)));
+    let ctx = Rc::new(RefCell::new(
+        ExecutionContext::builder().ttl(1000).variable("point", ValueKind::Object(point.clone())).build(),
+    ));
+
+    let result = module.eval(ctx.clone());
+    assert!(result.is_ok(), "Evaluation failed: {:?}", result.err());
+
+    let state = ctx.borrow().to_btreemap();
+    assert_eq!(state.get("axis"), Some(&RsValue::Int(7)));
+    let rest = BTreeMap::from([("y".to_string(), RsValue::Int(7))]);
+    assert_eq!(state.get("rest"), Some(&RsValue::Object(rest)));
+    assert_eq!(state.get("picked"), Some(&RsValue::Int(7)));
+    assert_eq!(state.get("mode"), Some(&RsValue::Int(1)));
+}
+
+#[test]
+fn test_collect_reference_cycles() {
+    let module = parse!(
+        obj.me = obj;
+        obj.payload = "0123456789";
+        del obj;
+    );
+    let obj = VirPyObject::new();
+    let ctx = Rc::new(RefCell::new(
+        ExecutionContext::builder().ttl(1000).variable("obj", ValueKind::Object(obj.clone())).build(),
+    ));
+
+    let result = module.eval(ctx.clone());
+    assert!(result.is_ok(), "Evaluation failed: {:?}", result.err());
+
+    // Only the host handle is left, so the cycle is still reachable
+    assert!(obj.get("me").is_some());
+    // Its attributes are counted until a collection finds it unreachable from the scopes
+    assert!(ctx.borrow().memory_used() > 0);
+    assert_eq!(ctx.borrow_mut().collect_cycles(), 0);
+    assert_eq!(ctx.borrow().memory_used(), 0);
+
+    let handle = Rc::downgrade(&obj.mapping);
+    drop(obj);
+    assert_eq!(ctx.borrow_mut().collect_cycles(), 1);
+    assert!(handle.upgrade().is_none());
+}
+
+#[test]
+fn test_parse_optimized() {
+    let operators = OperatorTable::default();
+    let module = parse_optimized!(operators;
+        a = (1 + 2) * 3;
+        if false {
+            a = 0;
+        }
+    );
+    assert_eq!(module.body.len(), 1);
+    let Stmt::Assign { value, .. } = &module.body[0].kind else {
+        panic!("expected an assignment, got {:?}", module.body[0].kind);
+    };
+    assert!(matches!(value.kind, Expr::Literal(Literal::Int(9))));
+
+    // Folds with the given table only, so an unregistered operator is left for the run
+    let mut operators = OperatorTable::default();
+    operators.unregister(BinaryOperator::Multiply, ValueTag::Int, ValueTag::Int);
+    let module = parse_optimized!(operators; a = (1 + 2) * 3;);
+    let Stmt::Assign { value, .. } = &module.body[0].kind else {
+        panic!("expected an assignment, got {:?}", module.body[0].kind);
+    };
+    assert!(matches!(value.kind, Expr::BinaryOp { .. }));
+}
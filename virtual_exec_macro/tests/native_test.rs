@@ -0,0 +1,103 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use virtual_exec_macro::{parse, sandbox_methods, SandboxObject};
+use virtual_exec_type::ast::core::{ASTNode, Module};
+use virtual_exec_type::base::{Upcast, ValueKind};
+use virtual_exec_type::error::SandboxExecutionError;
+use virtual_exec_type::exec_ctx::{ExecutionContext, RsValue};
+use virtual_exec_type::native::FromValueKind;
+
+#[derive(Debug, Clone, PartialEq, SandboxObject)]
+#[sandbox(methods)]
+struct Order {
+    total: f64,
+    #[sandbox(readonly)]
+    id: i64,
+    #[sandbox(hidden)]
+    secret: String,
+    items: Vec<String>,
+}
+
+#[sandbox_methods]
+impl Order {
+    pub fn discount(&mut self, rate: f64) -> f64 {
+        self.total *= 1.0 - rate;
+        self.total
+    }
+
+    pub fn item_count(&self) -> i64 {
+        self.items.len() as i64
+    }
+
+    #[sandbox(skip)]
+    pub fn leak(&self) -> String {
+        self.secret.clone()
+    }
+}
+
+fn order() -> Order {
+    Order {
+        total: 90.0,
+        id: 1,
+        secret: "hunter2".to_string(),
+        items: vec!["a".to_string(), "b".to_string()],
+    }
+}
+
+fn run(module: &Module, order: &Order) -> (Rc<RefCell<ExecutionContext>>, Result<ValueKind, SandboxExecutionError>) {
+    let ctx = Rc::new(RefCell::new(
+        ExecutionContext::builder().ttl(1000).variable("order", order.from_value()).build(),
+    ));
+    let result = module.eval(ctx.clone());
+    (ctx, result)
+}
+
+#[test]
+fn test_attributes_and_methods() {
+    let module = parse!(
+        order.total = order.total + 10;
+        half = order.discount(0.5);
+        count = order.item_count();
+        id = order.id;
+        match order {
+            Order { id: 1, total } => { matched = total; }
+        }
+    );
+    let host = order();
+    let (ctx, result) = run(&module, &host);
+    assert!(result.is_ok(), "Evaluation failed: {:?}", result.err());
+
+    let state = ctx.borrow().to_btreemap();
+    assert_eq!(state.get("half"), Some(&RsValue::Float(50.0)));
+    assert_eq!(state.get("count"), Some(&RsValue::Int(2)));
+    assert_eq!(state.get("id"), Some(&RsValue::Int(1)));
+    assert_eq!(state.get("matched"), Some(&RsValue::Float(50.0)));
+
+    let updated = ctx.borrow().get("order").unwrap().borrow().kind.clone();
+    let updated = Order::from_value_kind(updated).unwrap();
+    assert_eq!(updated, Order { total: 50.0, ..order() });
+    // The host's own value is never modified
+    assert_eq!(host, order());
+    assert_eq!(host.leak(), "hunter2");
+    // Hidden attributes are not exported either
+    assert!(matches!(state.get("order"), Some(RsValue::Object(fields)) if !fields.contains_key("secret")));
+}
+
+#[test]
+fn test_access_control() {
+    let cases = [
+        (parse!(order.id = 2;), SandboxExecutionError::ReadOnlyAttributeError),
+        (parse!(s = order.secret;), SandboxExecutionError::AttributeNotFoundError),
+        (parse!(order.secret = "x";), SandboxExecutionError::AttributeNotFoundError),
+        (parse!(s = order.leak();), SandboxExecutionError::AttributeNotFoundError),
+        (parse!(order.total = "free";), SandboxExecutionError::InvalidTypeError),
+        (parse!(order.discount();), SandboxExecutionError::ArgumentCountError { expected: 1, actual: 0 }),
+    ];
+    for (module, expected) in cases {
+        let (_, result) = run(&module, &order());
+        match result {
+            Err(err) => assert_eq!(std::mem::discriminant(&err), std::mem::discriminant(&expected)),
+            Ok(_) => panic!("expected {:?}", expected),
+        }
+    }
+}
@@ -1,3 +1,5 @@
+#![forbid(unsafe_code)]
+
 pub mod token;
 pub mod error;
 pub mod parser;
@@ -5,9 +5,9 @@ use crate::error::ParseError;
 fn convert_stmt(stmt: tokenizer::Stmt) -> Result<final_ast::Node<final_ast::Stmt>, ParseError> {
     let kind = match stmt {
         tokenizer::Stmt::Expr(expr) => final_ast::Stmt::Expression(convert_expr(expr)),
-        tokenizer::Stmt::Assign { target, value } => {
+        tokenizer::Stmt::Assign { targets, value } => {
             final_ast::Stmt::Assign {
-                target: convert_expr(target),
+                targets: targets.into_iter().map(convert_expr).collect(),
                 value: convert_expr(value),
             }
         }
@@ -15,6 +15,25 @@ fn convert_stmt(stmt: tokenizer::Stmt) -> Result<final_ast::Node<final_ast::Stmt
             let body = block.stmts.into_iter().map(convert_stmt).collect::<Result<_, _>>()?;
             final_ast::Stmt::Scoped(body)
         }
+        tokenizer::Stmt::Delete(targets) => {
+            final_ast::Stmt::Delete(targets.into_iter().map(convert_expr).collect())
+        }
+        tokenizer::Stmt::Pass => final_ast::Stmt::Pass,
+        tokenizer::Stmt::Global(names) => final_ast::Stmt::Global(names),
+        tokenizer::Stmt::Nonlocal(names) => final_ast::Stmt::Nonlocal(names),
+        tokenizer::Stmt::Match { subject, arms } => final_ast::Stmt::Match {
+            subject: convert_expr(subject),
+            arms: arms
+                .into_iter()
+                .map(|arm| {
+                    Ok(final_ast::MatchArm {
+                        pattern: arm.pattern,
+                        guard: arm.guard.map(convert_expr),
+                        body: arm.body.stmts.into_iter().map(convert_stmt).collect::<Result<_, _>>()?,
+                    })
+                })
+                .collect::<Result<_, ParseError>>()?,
+        },
         tokenizer::Stmt::If { test, body, otherwise } => {
             let final_test = convert_expr(test);
             let final_body = body.stmts.into_iter().map(convert_stmt).collect::<Result<_, _>>()?;
@@ -39,6 +58,9 @@ fn convert_expr(expr: tokenizer::Expr) -> final_ast::Node<final_ast::Expr> {
             tokenizer::Atom::Literal(l) => final_ast::Expr::Literal(l),
             tokenizer::Atom::Variable(v) => final_ast::Expr::Variable(v),
             tokenizer::Atom::Paren(expr_in_paren) => return convert_expr(*expr_in_paren),
+            tokenizer::Atom::Collection(items) => {
+                final_ast::Expr::Collection(items.into_iter().map(convert_expr).collect())
+            }
         },
         tokenizer::Expr::Binary(left, op, right) => final_ast::Expr::BinaryOp {
             left: Box::new(convert_expr(*left)),
@@ -49,6 +71,19 @@ fn convert_expr(expr: tokenizer::Expr) -> final_ast::Node<final_ast::Expr> {
             op,
             operand: Box::new(convert_expr(*operand)),
         },
+        tokenizer::Expr::Starred(inner) => final_ast::Expr::Starred(Box::new(convert_expr(*inner))),
+        tokenizer::Expr::Attribute(value, attr) => final_ast::Expr::Attribute {
+            value: Box::new(convert_expr(*value)),
+            attr,
+        },
+        tokenizer::Expr::Subscript(value, slice) => final_ast::Expr::Subscript {
+            value: Box::new(convert_expr(*value)),
+            slice: Box::new(convert_expr(*slice)),
+        },
+        tokenizer::Expr::Call(function, args) => final_ast::Expr::Call {
+            function: Box::new(convert_expr(*function)),
+            args: args.into_iter().map(convert_expr).collect(),
+        },
     };
     final_ast::Node { kind, span: None }
 }
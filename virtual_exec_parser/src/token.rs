@@ -8,6 +8,9 @@ custom_keyword!(elif);
 custom_keyword!(del);
 custom_keyword!(pass);
 custom_keyword!(is);
+custom_keyword!(global);
+custom_keyword!(nonlocal);
+custom_keyword!(case);
 
 custom_punctuation!(StarStar, **);
 custom_punctuation!(Eq, ==);
@@ -1,6 +1,6 @@
 use crate::token;
 use syn::parse::{Parse, ParseStream, Result};
-use syn::{braced, parenthesized, Ident, Lit, Token};
+use syn::{braced, bracketed, parenthesized, Ident, Lit, Token};
 use virtual_exec_type::ast::core as final_ast;
 
 #[derive(Clone)]
@@ -27,7 +27,7 @@ pub struct Block {
 pub enum Stmt {
     Expr(Expr),
     Assign {
-        target: Expr,
+        targets: Vec<Expr>,
         value: Expr,
     },
     If {
@@ -35,7 +35,22 @@ pub enum Stmt {
         body: Block,
         otherwise: Option<Block>,
     },
-    Scoped(Block)
+    Scoped(Block),
+    Delete(Vec<Expr>),
+    Pass,
+    Global(Vec<String>),
+    Nonlocal(Vec<String>),
+    Match {
+        subject: Expr,
+        arms: Vec<MatchArm>,
+    },
+}
+
+#[derive(Clone)]
+pub struct MatchArm {
+    pub pattern: final_ast::Pattern,
+    pub guard: Option<Expr>,
+    pub body: Block,
 }
 
 #[derive(Clone)]
@@ -43,6 +58,10 @@ pub enum Expr {
     Atom(Atom),
     Binary(Box<Expr>, final_ast::BinaryOperator, Box<Expr>),
     Unary(final_ast::UnaryOperator, Box<Expr>),
+    Starred(Box<Expr>),
+    Attribute(Box<Expr>, String),
+    Subscript(Box<Expr>, Box<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
 }
 
 #[derive(Clone)]
@@ -50,6 +69,7 @@ pub enum Atom {
     Literal(final_ast::Literal),
     Variable(String),
     Paren(Box<Expr>),
+    Collection(Vec<Expr>),
 }
 
 // --- Parser Implementation ---
@@ -71,9 +91,41 @@ impl Parse for Stmt {
         if input.peek(Token![if]) {
             return parse_if_statement(input);
         }
+        if input.peek(Token![match]) {
+            return parse_match_statement(input);
+        }
+        if input.peek(syn::token::Brace) {
+            let stmts = input.parse::<Block>()?;
+            input.parse::<Token![;]>()?;
+            return Ok(Stmt::Scoped(stmts));
+        }
+        if input.peek(token::pass) {
+            input.parse::<token::pass>()?;
+            input.parse::<Token![;]>()?;
+            return Ok(Stmt::Pass);
+        }
+        if input.peek(token::del) {
+            input.parse::<token::del>()?;
+            let targets = match parse_expr_list(input)? {
+                Expr::Atom(Atom::Collection(items)) => items,
+                target => vec![target],
+            };
+            input.parse::<Token![;]>()?;
+            return Ok(Stmt::Delete(targets));
+        }
+        if input.peek(token::global) {
+            input.parse::<token::global>()?;
+            let names = parse_name_list(input)?;
+            return Ok(Stmt::Global(names));
+        }
+        if input.peek(token::nonlocal) {
+            input.parse::<token::nonlocal>()?;
+            let names = parse_name_list(input)?;
+            return Ok(Stmt::Nonlocal(names));
+        }
 
         let fork = input.fork();
-        let _ = fork.parse::<Expr>()?;
+        let _ = parse_expr_list(&fork)?;
 
         if fork.peek(Token![=])
             || fork.peek(token::PlusAssign) || fork.peek(token::MinusAssign)
@@ -82,26 +134,33 @@ impl Parse for Stmt {
             || fork.peek(token::BitOrAssign) || fork.peek(token::BitXorAssign)
             || fork.peek(token::LeftShiftAssign) || fork.peek(token::RightShiftAssign)
         {
-            let target = input.parse::<Expr>()?;
+            let target = parse_expr_list(input)?;
             let op: AssignOp = input.parse()?;
-            let value = input.parse::<Expr>()?;
+            let mut targets = vec![target];
+            let mut value = parse_expr_list(input)?;
+            if let AssignOp::Assign = op {
+                // Chained assignment: every `=` but the last one introduces another target
+                while input.peek(Token![=]) {
+                    input.parse::<Token![=]>()?;
+                    targets.push(value);
+                    value = parse_expr_list(input)?;
+                }
+            }
             input.parse::<Token![;]>()?;
 
             let final_value = match map_assign_op_to_binary_op(op) {
                 None => value,
-                Some(binary_op) => Expr::Binary(Box::new(target.clone()), binary_op, Box::new(value))
+                Some(_) if matches!(targets[0], Expr::Atom(Atom::Collection(_))) => {
+                    return Err(input.error("augmented assignment cannot unpack into multiple targets"))
+                }
+                Some(binary_op) => Expr::Binary(Box::new(targets[0].clone()), binary_op, Box::new(value))
             };
-            
-            Ok(Stmt::Assign { target, value: final_value })
 
-        }
-        else if input.peek(syn::token::Brace) {
-            let stmts = input.parse::<Block>()?;
-            input.parse::<Token![;]>()?;
-            Ok(Stmt::Scoped(stmts))
+            Ok(Stmt::Assign { targets, value: final_value })
+
         }
         else {
-            let expr = input.parse::<Expr>()?;
+            let expr = parse_expr_list(input)?;
             input.parse::<Token![;]>()?;
             Ok(Stmt::Expr(expr))
         }
@@ -127,12 +186,240 @@ fn parse_if_statement(input: ParseStream) -> Result<Stmt> {
     Ok(Stmt::If { test, body, otherwise })
 }
 
+fn parse_match_statement(input: ParseStream) -> Result<Stmt> {
+    let column = input.span().start().column;
+    input.parse::<Token![match]>()?;
+    let subject = input.parse::<Expr>()?;
+    if input.peek(Token![:]) {
+        input.parse::<Token![:]>()?;
+        return parse_case_arms(input, subject, column);
+    }
+    let content;
+    braced!(content in input);
+    let mut arms = Vec::new();
+    while !content.is_empty() {
+        let pattern = parse_pattern(&content)?;
+        let guard = if content.peek(Token![if]) {
+            content.parse::<Token![if]>()?;
+            Some(content.parse::<Expr>()?)
+        } else {
+            None
+        };
+        content.parse::<Token![=>]>()?;
+        let body = content.parse::<Block>()?;
+        if content.peek(Token![,]) {
+            content.parse::<Token![,]>()?;
+        }
+        arms.push(MatchArm { pattern, guard, body });
+    }
+    Ok(Stmt::Match { subject, arms })
+}
+
+/// Parses the arms of a Python-mode `match subject:`, where each arm is `case pattern:` or
+/// `case pattern if guard:` followed by its body. As in Python, indentation delimits them: a body
+/// runs until the next `case` or the first statement not indented deeper than its `case`, and the
+/// match ends at the first token not indented deeper than the `match` keyword.
+fn parse_case_arms(input: ParseStream, subject: Expr, column: usize) -> Result<Stmt> {
+    let mut arms = Vec::new();
+    while !input.is_empty() && input.span().start().column > column && input.peek(token::case) {
+        let case_column = input.span().start().column;
+        input.parse::<token::case>()?;
+        let pattern = parse_pattern(input)?;
+        let guard = if input.peek(Token![if]) {
+            input.parse::<Token![if]>()?;
+            Some(input.parse::<Expr>()?)
+        } else {
+            None
+        };
+        input.parse::<Token![:]>()?;
+        let mut stmts = Vec::new();
+        while !input.is_empty()
+            && input.span().start().column > case_column
+            && !input.peek(token::case)
+        {
+            stmts.push(input.parse()?);
+        }
+        if stmts.is_empty() {
+            return Err(input.error("expected an indented statement after `case ...:`"));
+        }
+        arms.push(MatchArm { pattern, guard, body: Block { stmts } });
+    }
+    if arms.is_empty() {
+        return Err(input.error("expected an indented `case` after `match ...:`"));
+    }
+    Ok(Stmt::Match { subject, arms })
+}
+
+fn parse_pattern(input: ParseStream) -> Result<final_ast::Pattern> {
+    let first = parse_pattern_atom(input)?;
+    if !input.peek(Token![|]) || input.peek(Token![||]) {
+        return Ok(first);
+    }
+    let mut alternatives = vec![first];
+    while input.peek(Token![|]) {
+        input.parse::<Token![|]>()?;
+        let span = input.span();
+        let alternative = parse_pattern_atom(input)?;
+        // A name bound by only some alternatives would be left unbound when the others match
+        if alternative.bound_names() != alternatives[0].bound_names() {
+            return Err(syn::Error::new(span, final_ast::PatternError::OrPatternNames));
+        }
+        alternatives.push(alternative);
+    }
+    Ok(final_ast::Pattern::Or(alternatives))
+}
+
+fn parse_pattern_atom(input: ParseStream) -> Result<final_ast::Pattern> {
+    if input.peek(Token![_]) {
+        input.parse::<Token![_]>()?;
+        Ok(final_ast::Pattern::Wildcard)
+    } else if input.peek(Token![-]) {
+        input.parse::<Token![-]>()?;
+        match input.parse::<Lit>()? {
+            Lit::Int(i) => Ok(final_ast::Pattern::Literal(final_ast::Literal::Int(-i.base10_parse::<i64>()?))),
+            Lit::Float(f) => Ok(final_ast::Pattern::Literal(final_ast::Literal::Float(-f.base10_parse::<f64>()?))),
+            _ => Err(input.error("expected a numeric literal after `-`")),
+        }
+    } else if input.peek(token::None) || input.peek(Lit) {
+        match input.parse::<Atom>()? {
+            Atom::Literal(literal) => Ok(final_ast::Pattern::Literal(literal)),
+            _ => unreachable!(),
+        }
+    } else if input.peek(syn::token::Paren) {
+        let content;
+        parenthesized!(content in input);
+        let (items, trailing_comma) = parse_pattern_items(&content)?;
+        if items.len() == 1 && !trailing_comma && !matches!(items[0], final_ast::Pattern::Star(_)) {
+            return Ok(items.into_iter().next().unwrap());
+        }
+        Ok(final_ast::Pattern::Sequence(items))
+    } else if input.peek(syn::token::Bracket) {
+        let content;
+        bracketed!(content in input);
+        Ok(final_ast::Pattern::Sequence(parse_pattern_items(&content)?.0))
+    } else if input.peek(syn::token::Brace) {
+        let content;
+        braced!(content in input);
+        let mut entries = Vec::new();
+        let mut rest = None;
+        while !content.is_empty() {
+            if content.peek(token::StarStar) {
+                content.parse::<token::StarStar>()?;
+                rest = Some(content.parse::<Ident>()?.to_string());
+            } else {
+                let key: syn::LitStr = content.parse()?;
+                content.parse::<Token![:]>()?;
+                entries.push((key.value(), parse_pattern(&content)?));
+            }
+            if content.is_empty() {
+                break;
+            }
+            content.parse::<Token![,]>()?;
+        }
+        Ok(final_ast::Pattern::Mapping { entries, rest })
+    } else if input.peek(Ident) {
+        let name = input.parse::<Ident>()?.to_string();
+        if input.peek(Token![@]) {
+            input.parse::<Token![@]>()?;
+            return Ok(final_ast::Pattern::Binding(name, Box::new(parse_pattern_atom(input)?)));
+        }
+        if !input.peek(syn::token::Brace) {
+            return Ok(final_ast::Pattern::Capture(name));
+        }
+        let content;
+        braced!(content in input);
+        let mut fields = Vec::new();
+        while !content.is_empty() {
+            if content.peek(Token![..]) {
+                content.parse::<Token![..]>()?;
+                break;
+            }
+            let field = content.parse::<Ident>()?.to_string();
+            let pattern = if content.peek(Token![:]) {
+                content.parse::<Token![:]>()?;
+                parse_pattern(&content)?
+            } else {
+                final_ast::Pattern::Capture(field.clone())
+            };
+            fields.push((field, pattern));
+            if content.is_empty() {
+                break;
+            }
+            content.parse::<Token![,]>()?;
+        }
+        Ok(final_ast::Pattern::Class { name, fields })
+    } else {
+        Err(input.error("expected a pattern"))
+    }
+}
+
+/// Parses the comma separated contents of a sequence pattern, noting whether a trailing comma
+/// turned `(a,)` into a one element sequence.
+fn parse_pattern_items(input: ParseStream) -> Result<(Vec<final_ast::Pattern>, bool)> {
+    let mut items = Vec::new();
+    let mut trailing_comma = false;
+    while !input.is_empty() {
+        if input.peek(Token![*]) {
+            input.parse::<Token![*]>()?;
+            if input.peek(Token![_]) {
+                input.parse::<Token![_]>()?;
+                items.push(final_ast::Pattern::Star(None));
+            } else {
+                items.push(final_ast::Pattern::Star(Some(input.parse::<Ident>()?.to_string())));
+            }
+        } else {
+            items.push(parse_pattern(input)?);
+        }
+        trailing_comma = false;
+        if input.is_empty() {
+            break;
+        }
+        input.parse::<Token![,]>()?;
+        trailing_comma = true;
+    }
+    if items.iter().filter(|item| matches!(item, final_ast::Pattern::Star(_))).count() > 1 {
+        return Err(input.error(final_ast::PatternError::SeveralStars));
+    }
+    Ok((items, trailing_comma))
+}
+
 impl Parse for Expr {
     fn parse(input: ParseStream) -> Result<Self> {
         parse_expr_with_precedence(input, 0)
     }
 }
 
+fn parse_name_list(input: ParseStream) -> Result<Vec<String>> {
+    let names = syn::punctuated::Punctuated::<Ident, Token![,]>::parse_separated_nonempty(input)?;
+    input.parse::<Token![;]>()?;
+    Ok(names.into_iter().map(|ident| ident.to_string()).collect())
+}
+
+/// Parses `a, *b, c`, producing a collection when there is more than a single plain expression.
+fn parse_expr_list(input: ParseStream) -> Result<Expr> {
+    let first = parse_list_item(input)?;
+    if !input.peek(Token![,]) && !matches!(first, Expr::Starred(_)) {
+        return Ok(first);
+    }
+    let mut items = vec![first];
+    while input.peek(Token![,]) {
+        input.parse::<Token![,]>()?;
+        if input.is_empty() || input.peek(Token![;]) || input.peek(Token![=]) {
+            break;
+        }
+        items.push(parse_list_item(input)?);
+    }
+    Ok(Expr::Atom(Atom::Collection(items)))
+}
+
+fn parse_list_item(input: ParseStream) -> Result<Expr> {
+    if input.peek(Token![*]) {
+        input.parse::<Token![*]>()?;
+        return Ok(Expr::Starred(Box::new(input.parse()?)));
+    }
+    input.parse()
+}
+
 fn parse_expr_with_precedence(input: ParseStream, min_bp: u8) -> Result<Expr> {
     let mut lhs = if input.peek(Token![!]) {
         input.parse::<Token![!]>()?;
@@ -142,8 +429,12 @@ fn parse_expr_with_precedence(input: ParseStream, min_bp: u8) -> Result<Expr> {
         input.parse::<Token![-]>()?;
         let rhs = parse_expr_with_precedence(input, prefix_binding_power(&final_ast::UnaryOperator::Negative))?;
         Expr::Unary(final_ast::UnaryOperator::Negative, Box::new(rhs))
+    } else if input.peek(Token![~]) {
+        input.parse::<Token![~]>()?;
+        let rhs = parse_expr_with_precedence(input, prefix_binding_power(&final_ast::UnaryOperator::BitwiseNot))?;
+        Expr::Unary(final_ast::UnaryOperator::BitwiseNot, Box::new(rhs))
     } else {
-        Expr::Atom(input.parse()?)
+        parse_postfix(input, Expr::Atom(input.parse()?))?
     };
 
     loop {
@@ -158,11 +449,32 @@ fn parse_expr_with_precedence(input: ParseStream, min_bp: u8) -> Result<Expr> {
     Ok(lhs)
 }
 
+fn parse_postfix(input: ParseStream, mut expr: Expr) -> Result<Expr> {
+    loop {
+        if input.peek(Token![.]) && !input.peek(Token![..]) {
+            input.parse::<Token![.]>()?;
+            let attr: Ident = input.parse()?;
+            expr = Expr::Attribute(Box::new(expr), attr.to_string());
+        } else if input.peek(syn::token::Bracket) {
+            let content;
+            bracketed!(content in input);
+            expr = Expr::Subscript(Box::new(expr), Box::new(content.parse()?));
+        } else if input.peek(syn::token::Paren) {
+            let content;
+            parenthesized!(content in input);
+            let args = syn::punctuated::Punctuated::<Expr, Token![,]>::parse_terminated(&content)?;
+            expr = Expr::Call(Box::new(expr), args.into_iter().collect());
+        } else {
+            return Ok(expr);
+        }
+    }
+}
+
 impl Parse for Atom {
     fn parse(input: ParseStream) -> Result<Self> {
         if input.peek(token::None) {
             input.parse::<token::None>()?;
-            return Ok(Atom::Literal(final_ast::Literal::None));
+            Ok(Atom::Literal(final_ast::Literal::None))
         }
         else if input.peek(Lit) {
             let lit: Lit = input.parse()?;
@@ -180,7 +492,25 @@ impl Parse for Atom {
         } else if input.peek(syn::token::Paren) {
             let content;
             parenthesized!(content in input);
-            Ok(Atom::Paren(Box::new(content.parse()?)))
+            if content.is_empty() {
+                return Ok(Atom::Collection(Vec::new()));
+            }
+            match parse_expr_list(&content)? {
+                Expr::Atom(Atom::Collection(items)) => Ok(Atom::Collection(items)),
+                expr => Ok(Atom::Paren(Box::new(expr))),
+            }
+        } else if input.peek(syn::token::Bracket) {
+            let content;
+            bracketed!(content in input);
+            let mut items = Vec::new();
+            while !content.is_empty() {
+                items.push(parse_list_item(&content)?);
+                if content.is_empty() {
+                    break;
+                }
+                content.parse::<Token![,]>()?;
+            }
+            Ok(Atom::Collection(items))
         } else {
             Err(input.error("expected a literal, an identifier, or a parenthesized expression"))
         }
@@ -228,7 +558,7 @@ fn map_assign_op_to_binary_op(op: AssignOp) -> Option<final_ast::BinaryOperator>
 
 fn prefix_binding_power(op: &final_ast::UnaryOperator) -> u8 {
     match op {
-        final_ast::UnaryOperator::Not => 9,
+        final_ast::UnaryOperator::Not | final_ast::UnaryOperator::BitwiseNot => 9,
         final_ast::UnaryOperator::Negative | final_ast::UnaryOperator::Positive => 9,
     }
 }
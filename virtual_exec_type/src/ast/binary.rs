@@ -0,0 +1,647 @@
+//! A versioned binary encoding of [`Module`], so that a script parsed once can be stored and run
+//! later without parsing it again.
+//!
+//! An encoded module starts with [`MAGIC`] and the little-endian `u16` [`FORMAT_VERSION`] it was
+//! written with, followed by the module itself. Every node is a one-byte tag followed by its
+//! fields in declaration order, spans included. Integers are little-endian and fixed-width,
+//! strings and lists are prefixed by their length as a `u32`. A format version only ever changes
+//! when the encoding of an existing node does, and [`decode`] rejects the versions it does not know.
+
+use crate::ast::core::{
+    BinaryOperator, Expr, Literal, MatchArm, Module, Node, Pattern, PatternError, Span, Stmt,
+    UnaryOperator,
+};
+use std::fmt;
+
+/// The bytes every encoded module starts with.
+pub const MAGIC: [u8; 4] = *b"VXAM";
+
+/// The version of the format written by [`encode`], the only one [`decode`] reads.
+pub const FORMAT_VERSION: u16 = 1;
+
+/// How deeply nodes can be nested in a module [`decode`] accepts, so that decoding a crafted
+/// blob cannot overflow the stack.
+pub const MAX_NESTING: usize = 512;
+
+/// Why [`decode`] rejected a blob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The blob does not start with [`MAGIC`].
+    NotAModule,
+    /// The blob was written in a format version this build does not read.
+    UnsupportedVersion { found: u16, supported: u16 },
+    /// The blob ends in the middle of a node.
+    UnexpectedEnd,
+    /// A tag does not name any variant of the node being read.
+    InvalidTag { node: &'static str, tag: u8 },
+    InvalidUtf8,
+    /// Nodes are nested deeper than [`MAX_NESTING`].
+    NestingTooDeep,
+    /// Bytes are left over after the module.
+    TrailingBytes,
+    /// A pattern breaks a rule the parser enforces, so no source gives this module.
+    InvalidPattern(PatternError),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::NotAModule => f.write_str("not an encoded module"),
+            DecodeError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "module encoded with format version {found}, but only version {supported} is supported"
+            ),
+            DecodeError::UnexpectedEnd => f.write_str("encoded module is truncated"),
+            DecodeError::InvalidTag { node, tag } => write!(f, "invalid {node} tag {tag}"),
+            DecodeError::InvalidUtf8 => f.write_str("encoded string is not valid UTF-8"),
+            DecodeError::NestingTooDeep => {
+                write!(f, "nodes are nested deeper than {MAX_NESTING} levels")
+            }
+            DecodeError::TrailingBytes => f.write_str("unexpected bytes after the module"),
+            DecodeError::InvalidPattern(e) => write!(f, "invalid pattern: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub fn encode(module: &Module) -> Vec<u8> {
+    let mut encoder = Encoder { bytes: Vec::new() };
+    encoder.bytes.extend_from_slice(&MAGIC);
+    encoder.bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    encoder.stmts(&module.body);
+    encoder.span(module.span);
+    encoder.bytes
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Module, DecodeError> {
+    let mut decoder = Decoder::new(bytes);
+    if decoder.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(DecodeError::NotAModule);
+    }
+    let version = u16::from_le_bytes(decoder.array()?);
+    if version != FORMAT_VERSION {
+        return Err(DecodeError::UnsupportedVersion {
+            found: version,
+            supported: FORMAT_VERSION,
+        });
+    }
+    let module = Module {
+        body: decoder.stmts()?,
+        span: decoder.span()?,
+    };
+    if !decoder.bytes.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+    Ok(module)
+}
+
+/// The operators in the order of their tags, which must never change within a format version.
+const BINARY_OPERATORS: [BinaryOperator; 18] = [
+    BinaryOperator::Add,
+    BinaryOperator::Subtract,
+    BinaryOperator::Multiply,
+    BinaryOperator::Divide,
+    BinaryOperator::And,
+    BinaryOperator::Or,
+    BinaryOperator::Xor,
+    BinaryOperator::Modulo,
+    BinaryOperator::BitwiseAnd,
+    BinaryOperator::BitwiseOr,
+    BinaryOperator::Eq,
+    BinaryOperator::NotEq,
+    BinaryOperator::Lt,
+    BinaryOperator::Lte,
+    BinaryOperator::Gt,
+    BinaryOperator::Gte,
+    BinaryOperator::LeftShift,
+    BinaryOperator::RightShift,
+];
+
+const UNARY_OPERATORS: [UnaryOperator; 4] = [
+    UnaryOperator::Positive,
+    UnaryOperator::Negative,
+    UnaryOperator::Not,
+    UnaryOperator::BitwiseNot,
+];
+
+/// Writes the primitives of the format, shared with [`crate::vm::snapshot`].
+pub(crate) struct Encoder {
+    pub(crate) bytes: Vec<u8>,
+}
+
+impl Encoder {
+    pub(crate) fn tag(&mut self, tag: u8) {
+        self.bytes.push(tag);
+    }
+
+    /// Operators are written as their position in the table of their kind.
+    fn operator<T: PartialEq>(&mut self, operators: &[T], op: &T) {
+        let tag = operators.iter().position(|candidate| candidate == op);
+        self.tag(tag.expect("every operator is listed") as u8);
+    }
+
+    pub(crate) fn binary_operator(&mut self, op: &BinaryOperator) {
+        self.operator(&BINARY_OPERATORS, op);
+    }
+
+    pub(crate) fn unary_operator(&mut self, op: &UnaryOperator) {
+        self.operator(&UNARY_OPERATORS, op);
+    }
+
+    pub(crate) fn len(&mut self, len: usize) {
+        let len = u32::try_from(len).expect("modules are smaller than 4 GiB");
+        self.bytes.extend_from_slice(&len.to_le_bytes());
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn str(&mut self, value: &str) {
+        self.len(value.len());
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    pub(crate) fn strs(&mut self, values: &[String]) {
+        self.len(values.len());
+        for value in values {
+            self.str(value);
+        }
+    }
+
+    fn span(&mut self, span: Option<Span>) {
+        match span {
+            None => self.tag(0),
+            Some(span) => {
+                self.tag(1);
+                self.u64(span.row);
+                self.u64(span.col);
+                self.u64(span.length);
+            }
+        }
+    }
+
+    fn stmts(&mut self, stmts: &[Node<Stmt>]) {
+        self.len(stmts.len());
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Node<Stmt>) {
+        match &stmt.kind {
+            Stmt::Expression(expr) => {
+                self.tag(0);
+                self.expr(expr);
+            }
+            Stmt::Assign { targets, value } => {
+                self.tag(1);
+                self.exprs(targets);
+                self.expr(value);
+            }
+            Stmt::If {
+                test,
+                body,
+                otherwise,
+            } => {
+                self.tag(2);
+                self.expr(test);
+                self.stmts(body);
+                match otherwise {
+                    None => self.tag(0),
+                    Some(otherwise) => {
+                        self.tag(1);
+                        self.stmts(otherwise);
+                    }
+                }
+            }
+            Stmt::Scoped(body) => {
+                self.tag(3);
+                self.stmts(body);
+            }
+            Stmt::Delete(targets) => {
+                self.tag(4);
+                self.exprs(targets);
+            }
+            Stmt::Pass => self.tag(5),
+            Stmt::Global(names) => {
+                self.tag(6);
+                self.strs(names);
+            }
+            Stmt::Nonlocal(names) => {
+                self.tag(7);
+                self.strs(names);
+            }
+            Stmt::Match { subject, arms } => {
+                self.tag(8);
+                self.expr(subject);
+                self.len(arms.len());
+                for arm in arms {
+                    self.pattern(&arm.pattern);
+                    match &arm.guard {
+                        None => self.tag(0),
+                        Some(guard) => {
+                            self.tag(1);
+                            self.expr(guard);
+                        }
+                    }
+                    self.stmts(&arm.body);
+                }
+            }
+        }
+        self.span(stmt.span);
+    }
+
+    fn exprs(&mut self, exprs: &[Node<Expr>]) {
+        self.len(exprs.len());
+        for expr in exprs {
+            self.expr(expr);
+        }
+    }
+
+    fn expr(&mut self, expr: &Node<Expr>) {
+        match &expr.kind {
+            Expr::Literal(literal) => {
+                self.tag(0);
+                self.literal(literal);
+            }
+            Expr::Variable(name) => {
+                self.tag(1);
+                self.str(name);
+            }
+            Expr::BinaryOp { left, op, right } => {
+                self.tag(2);
+                self.expr(left);
+                self.binary_operator(op);
+                self.expr(right);
+            }
+            Expr::UnaryOp { op, operand } => {
+                self.tag(3);
+                self.unary_operator(op);
+                self.expr(operand);
+            }
+            Expr::Wrapped(inner) => {
+                self.tag(4);
+                self.expr(inner);
+            }
+            Expr::Collection(items) => {
+                self.tag(5);
+                self.exprs(items);
+            }
+            Expr::Starred(inner) => {
+                self.tag(6);
+                self.expr(inner);
+            }
+            Expr::Attribute { value, attr } => {
+                self.tag(7);
+                self.expr(value);
+                self.str(attr);
+            }
+            Expr::Subscript { value, slice } => {
+                self.tag(8);
+                self.expr(value);
+                self.expr(slice);
+            }
+            Expr::Call { function, args } => {
+                self.tag(9);
+                self.expr(function);
+                self.exprs(args);
+            }
+        }
+        self.span(expr.span);
+    }
+
+    pub(crate) fn literal(&mut self, literal: &Literal) {
+        match literal {
+            Literal::Int(v) => {
+                self.tag(0);
+                self.bytes.extend_from_slice(&v.to_le_bytes());
+            }
+            Literal::Float(v) => {
+                self.tag(1);
+                self.u64(v.to_bits());
+            }
+            Literal::String(v) => {
+                self.tag(2);
+                self.str(v);
+            }
+            Literal::Bool(v) => {
+                self.tag(3);
+                self.tag(*v as u8);
+            }
+            Literal::None => self.tag(4),
+        }
+    }
+
+    pub(crate) fn patterns(&mut self, patterns: &[Pattern]) {
+        self.len(patterns.len());
+        for pattern in patterns {
+            self.pattern(pattern);
+        }
+    }
+
+    fn fields(&mut self, fields: &[(String, Pattern)]) {
+        self.len(fields.len());
+        for (name, pattern) in fields {
+            self.str(name);
+            self.pattern(pattern);
+        }
+    }
+
+    fn pattern(&mut self, pattern: &Pattern) {
+        match pattern {
+            Pattern::Literal(literal) => {
+                self.tag(0);
+                self.literal(literal);
+            }
+            Pattern::Capture(name) => {
+                self.tag(1);
+                self.str(name);
+            }
+            Pattern::Wildcard => self.tag(2),
+            Pattern::Sequence(patterns) => {
+                self.tag(3);
+                self.patterns(patterns);
+            }
+            Pattern::Star(name) => {
+                self.tag(4);
+                match name {
+                    None => self.tag(0),
+                    Some(name) => {
+                        self.tag(1);
+                        self.str(name);
+                    }
+                }
+            }
+            Pattern::Mapping { entries, rest } => {
+                self.tag(5);
+                self.fields(entries);
+                match rest {
+                    None => self.tag(0),
+                    Some(rest) => {
+                        self.tag(1);
+                        self.str(rest);
+                    }
+                }
+            }
+            Pattern::Class { name, fields } => {
+                self.tag(6);
+                self.str(name);
+                self.fields(fields);
+            }
+            Pattern::Or(patterns) => {
+                self.tag(7);
+                self.patterns(patterns);
+            }
+            Pattern::Binding(name, inner) => {
+                self.tag(8);
+                self.str(name);
+                self.pattern(inner);
+            }
+        }
+    }
+}
+
+/// Reads the primitives of the format, shared with [`crate::vm::snapshot`].
+pub(crate) struct Decoder<'a> {
+    pub(crate) bytes: &'a [u8],
+    /// How many nodes enclose the one being read.
+    depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, depth: 0 }
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < len {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+
+    pub(crate) fn tag(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn flag(&mut self, node: &'static str) -> Result<bool, DecodeError> {
+        match self.tag()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(DecodeError::InvalidTag { node, tag }),
+        }
+    }
+
+    /// A list length, checked against the bytes left so that a corrupt length cannot make the
+    /// decoder allocate more than the blob could hold.
+    pub(crate) fn len(&mut self) -> Result<usize, DecodeError> {
+        let len = u32::from_le_bytes(self.array()?) as usize;
+        if len > self.bytes.len() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        Ok(len)
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn string(&mut self) -> Result<String, DecodeError> {
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>, DecodeError> {
+        (0..self.len()?).map(|_| self.string()).collect()
+    }
+
+    fn span(&mut self) -> Result<Option<Span>, DecodeError> {
+        if !self.flag("span")? {
+            return Ok(None);
+        }
+        Ok(Some(Span {
+            row: self.u64()?,
+            col: self.u64()?,
+            length: self.u64()?,
+        }))
+    }
+
+    /// Reads one nested node with `read`, failing once nodes are nested too deep.
+    pub(crate) fn nested<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<T, DecodeError>,
+    ) -> Result<T, DecodeError> {
+        if self.depth == MAX_NESTING {
+            return Err(DecodeError::NestingTooDeep);
+        }
+        self.depth += 1;
+        let result = read(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn stmts(&mut self) -> Result<Vec<Node<Stmt>>, DecodeError> {
+        (0..self.len()?).map(|_| self.nested(Self::stmt)).collect()
+    }
+
+    fn stmt(&mut self) -> Result<Node<Stmt>, DecodeError> {
+        let kind = match self.tag()? {
+            0 => Stmt::Expression(self.expr()?),
+            1 => Stmt::Assign {
+                targets: self.exprs()?,
+                value: self.expr()?,
+            },
+            2 => Stmt::If {
+                test: self.expr()?,
+                body: self.stmts()?,
+                otherwise: match self.flag("else")? {
+                    true => Some(self.stmts()?),
+                    false => None,
+                },
+            },
+            3 => Stmt::Scoped(self.stmts()?),
+            4 => Stmt::Delete(self.exprs()?),
+            5 => Stmt::Pass,
+            6 => Stmt::Global(self.strings()?),
+            7 => Stmt::Nonlocal(self.strings()?),
+            8 => Stmt::Match {
+                subject: self.expr()?,
+                arms: (0..self.len()?)
+                    .map(|_| {
+                        let pattern = self.pattern()?;
+                        pattern.validate().map_err(DecodeError::InvalidPattern)?;
+                        Ok(MatchArm {
+                            pattern,
+                            guard: match self.flag("guard")? {
+                                true => Some(self.expr()?),
+                                false => None,
+                            },
+                            body: self.stmts()?,
+                        })
+                    })
+                    .collect::<Result<_, DecodeError>>()?,
+            },
+            tag => return Err(DecodeError::InvalidTag { node: "statement", tag }),
+        };
+        Ok(Node {
+            kind,
+            span: self.span()?,
+        })
+    }
+
+    fn exprs(&mut self) -> Result<Vec<Node<Expr>>, DecodeError> {
+        (0..self.len()?).map(|_| self.expr()).collect()
+    }
+
+    fn boxed(&mut self) -> Result<Box<Node<Expr>>, DecodeError> {
+        Ok(Box::new(self.expr()?))
+    }
+
+    fn expr(&mut self) -> Result<Node<Expr>, DecodeError> {
+        self.nested(Self::expr_node)
+    }
+
+    fn expr_node(&mut self) -> Result<Node<Expr>, DecodeError> {
+        let kind = match self.tag()? {
+            0 => Expr::Literal(self.literal()?),
+            1 => Expr::Variable(self.string()?),
+            2 => Expr::BinaryOp {
+                left: self.boxed()?,
+                op: match self.tag()? {
+                    tag if (tag as usize) < BINARY_OPERATORS.len() => BINARY_OPERATORS[tag as usize],
+                    tag => return Err(DecodeError::InvalidTag { node: "binary operator", tag }),
+                },
+                right: self.boxed()?,
+            },
+            3 => Expr::UnaryOp {
+                op: match self.tag()? {
+                    tag if (tag as usize) < UNARY_OPERATORS.len() => UNARY_OPERATORS[tag as usize],
+                    tag => return Err(DecodeError::InvalidTag { node: "unary operator", tag }),
+                },
+                operand: self.boxed()?,
+            },
+            4 => Expr::Wrapped(self.boxed()?),
+            5 => Expr::Collection(self.exprs()?),
+            6 => Expr::Starred(self.boxed()?),
+            7 => Expr::Attribute {
+                value: self.boxed()?,
+                attr: self.string()?,
+            },
+            8 => Expr::Subscript {
+                value: self.boxed()?,
+                slice: self.boxed()?,
+            },
+            9 => Expr::Call {
+                function: self.boxed()?,
+                args: self.exprs()?,
+            },
+            tag => return Err(DecodeError::InvalidTag { node: "expression", tag }),
+        };
+        Ok(Node {
+            kind,
+            span: self.span()?,
+        })
+    }
+
+    fn literal(&mut self) -> Result<Literal, DecodeError> {
+        Ok(match self.tag()? {
+            0 => Literal::Int(i64::from_le_bytes(self.array()?)),
+            1 => Literal::Float(f64::from_bits(self.u64()?)),
+            2 => Literal::String(self.string()?),
+            3 => Literal::Bool(self.flag("bool")?),
+            4 => Literal::None,
+            tag => return Err(DecodeError::InvalidTag { node: "literal", tag }),
+        })
+    }
+
+    fn patterns(&mut self) -> Result<Vec<Pattern>, DecodeError> {
+        (0..self.len()?).map(|_| self.pattern()).collect()
+    }
+
+    fn fields(&mut self) -> Result<Vec<(String, Pattern)>, DecodeError> {
+        (0..self.len()?)
+            .map(|_| Ok((self.string()?, self.pattern()?)))
+            .collect()
+    }
+
+    fn optional_string(&mut self, node: &'static str) -> Result<Option<String>, DecodeError> {
+        match self.flag(node)? {
+            true => Ok(Some(self.string()?)),
+            false => Ok(None),
+        }
+    }
+
+    fn pattern(&mut self) -> Result<Pattern, DecodeError> {
+        self.nested(Self::pattern_node)
+    }
+
+    fn pattern_node(&mut self) -> Result<Pattern, DecodeError> {
+        Ok(match self.tag()? {
+            0 => Pattern::Literal(self.literal()?),
+            1 => Pattern::Capture(self.string()?),
+            2 => Pattern::Wildcard,
+            3 => Pattern::Sequence(self.patterns()?),
+            4 => Pattern::Star(self.optional_string("star pattern")?),
+            5 => Pattern::Mapping {
+                entries: self.fields()?,
+                rest: self.optional_string("mapping pattern")?,
+            },
+            6 => Pattern::Class {
+                name: self.string()?,
+                fields: self.fields()?,
+            },
+            7 => Pattern::Or(self.patterns()?),
+            8 => Pattern::Binding(self.string()?, Box::new(self.pattern()?)),
+            tag => return Err(DecodeError::InvalidTag { node: "pattern", tag }),
+        })
+    }
+}
@@ -1,9 +1,11 @@
 use crate::base::{ValueContainer, ValueKind};
-use crate::builtin::{VirPyFloat, VirPyInt};
+use crate::builtin::{Declaration, VirPyFloat, VirPyInt, VirPyObject};
 use crate::error::SandboxExecutionError;
-use crate::exec_ctx::{ExecutionContext, Result};
-use crate::op::*;
-use std::cell::{Ref, RefCell};
+use crate::exec_ctx::{ExecutionContext, Result, ScopeMode};
+use crate::native::NativeFunction;
+use crate::stdlib;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
 use std::panic::catch_unwind;
 use std::rc::Rc;
 
@@ -15,24 +17,12 @@ pub struct Span {
 }
 
 pub trait ASTNode {
-    type Output<'ctx>; // = ValueKind<'ctx>; // Oh cool that this is a unstable feature?
-    fn eval<'ctx>(&self, ctx: Rc<RefCell<ExecutionContext<'ctx>>>) -> Result<Self::Output<'ctx>>;
+    type Output;
+    fn eval(&self, ctx: Rc<RefCell<ExecutionContext>>) -> Result<Self::Output>;
 
     fn get_callsite(&self) -> Option<Span>;
 }
 
-fn with_arena<'ctx, F, R>(ctx: &Rc<RefCell<ExecutionContext<'ctx>>>, f: F) -> R
-where
-    F: FnOnce(&'ctx bumpalo::Bump) -> R,
-{
-    let arena_rc = { ctx.borrow().arena.clone() };
-    let arena_borrow: Ref<bumpalo::Bump> = arena_rc.borrow();
-    let arena_ref: &bumpalo::Bump = &arena_borrow;
-    let arena_ref_ctx: &'ctx bumpalo::Bump = unsafe { std::mem::transmute(arena_ref) };
-
-    f(arena_ref_ctx)
-}
-
 #[derive(Debug, Clone)]
 pub struct Node<T>
 where
@@ -48,15 +38,22 @@ pub struct Module {
 }
 
 impl ASTNode for Module {
-    type Output<'ctx> = ValueKind<'ctx>;
-    fn eval<'ctx>(&self, ctx: Rc<RefCell<ExecutionContext<'ctx>>>) -> Result<ValueKind<'ctx>> {
+    type Output = ValueKind;
+    fn eval(&self, ctx: Rc<RefCell<ExecutionContext>>) -> Result<ValueKind> {
+        ctx.borrow_mut().start_clock();
+        ctx.borrow_mut().begin_run();
         let result = catch_unwind(std::panic::AssertUnwindSafe(|| {
-            for stmt in self.body.clone() {
-                stmt.kind.eval(ctx.clone())?;
-                ctx.borrow_mut().consume_one()?;
-            }
+            eval_body(&self.body, &ctx)?;
             Ok(ValueKind::None)
         }));
+        // Reference cycles between objects are the only garbage `Rc` cannot free on its own
+        if let Ok(mut ctx) = ctx.try_borrow_mut() {
+            if result.is_err() {
+                ctx.unwind_run();
+            }
+            ctx.end_run(matches!(result, Ok(Ok(_))));
+            ctx.collect_cycles_if_due();
+        }
 
         match result {
             Ok(Ok(value)) => Ok(value),
@@ -84,18 +81,22 @@ pub enum Expr {
         operand: Box<Node<Expr>>,
     },
     Wrapped(Box<Node<Expr>>),
-    // Call {
-    //     function: Box<Node<Expr>>,
-    //     args: Vec<Node<Expr>>,
-    // },
-    // Attribute {
-    //     value: Box<Node<Expr>>,
-    //     attr: String,
-    // },
-    // Subscript {
-    //     value: Box<Node<Expr>>,
-    //     slice: Box<Node<Expr>>,
-    // },
+    Collection(Vec<Node<Expr>>),
+    Starred(Box<Node<Expr>>),
+    Attribute {
+        value: Box<Node<Expr>>,
+        attr: String,
+    },
+    Subscript {
+        value: Box<Node<Expr>>,
+        slice: Box<Node<Expr>>,
+    },
+    /// Calls a method of a native object (`obj.method(args)`) or a host function registered with
+    /// the context (`function(args)`).
+    Call {
+        function: Box<Node<Expr>>,
+        args: Vec<Node<Expr>>,
+    },
     // Range {
     //     lower: Option<i64>,
     //     upper: Option<i64>,
@@ -104,23 +105,33 @@ pub enum Expr {
 }
 
 impl ASTNode for Expr {
-    type Output<'ctx> = ValueKind<'ctx>;
+    type Output = ValueKind;
 
-    fn eval<'ctx>(&self, ctx: Rc<RefCell<ExecutionContext<'ctx>>>) -> Result<Self::Output<'ctx>> {
+    fn eval(&self, ctx: Rc<RefCell<ExecutionContext>>) -> Result<Self::Output> {
+        ctx.borrow_mut().enter_frame()?;
+        let result = self.eval_frame(ctx.clone());
+        ctx.borrow_mut().leave_frame();
+        let value = result?;
+        ctx.borrow_mut().account_value(&value)?;
+        Ok(value)
+    }
+
+    fn get_callsite(&self) -> Option<Span> {
+        todo!()
+    }
+}
+
+impl Expr {
+    fn eval_frame(&self, ctx: Rc<RefCell<ExecutionContext>>) -> Result<ValueKind> {
         ctx.borrow_mut().consume_one()?;
         match self {
             Expr::Literal(l) => l.eval(ctx),
             Expr::Variable(v) => Ok(ctx.borrow().get(v)?.borrow().kind.clone()),
             Expr::UnaryOp { op, operand } => {
                 let rhs_kind = operand.kind.eval(ctx.clone())?;
-                with_arena(&ctx, |arena| {
-                    let rhs = ValueContainer::new(rhs_kind, arena);
-                    match op {
-                        UnaryOperator::Negative => Ok(err_op_neg(rhs, arena)?.kind.clone()),
-                        UnaryOperator::Positive => Ok(err_op_pos(rhs, arena)?.kind.clone()),
-                        UnaryOperator::Not => Ok(err_op_not(rhs, arena)?.kind.clone()),
-                    }
-                })
+                let rhs = &ValueContainer::new(rhs_kind);
+                let ctx = ctx.borrow();
+                ctx.operators.eval_unary(*op, rhs).map(|value| ctx.settle(value))
             }
             Expr::BinaryOp { left, op, right } => {
                 let lhs_kind = left.kind.eval(ctx.clone())?;
@@ -139,40 +150,57 @@ impl ASTNode for Expr {
                     _ => {}
                 }
                 let rhs_kind = right.kind.eval(ctx.clone())?;
-                with_arena(&ctx, |arena| {
-                    let lhs = ValueContainer::new(lhs_kind, arena);
-                    let rhs = ValueContainer::new(rhs_kind, arena);
-                    match op {
-                        BinaryOperator::Add => Ok(err_op_add(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::Subtract => Ok(err_op_sub(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::Multiply => Ok(err_op_mul(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::Divide => Ok(err_op_div(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::And => Ok(err_op_and(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::Or => Ok(err_op_or(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::Xor => Ok(err_op_bxor(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::Modulo => Ok(err_op_moduls(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::BitwiseAnd => {
-                            Ok(err_op_band(lhs, rhs, arena)?.kind.clone())
-                        }
-                        BinaryOperator::BitwiseOr => Ok(err_op_bor(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::Eq => Ok(err_op_eq(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::NotEq => Ok(err_op_ne(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::Lt => Ok(err_op_lt(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::Lte => Ok(err_op_le(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::Gt => Ok(err_op_gt(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::Gte => Ok(err_op_ge(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::LeftShift => Ok(err_op_bsl(lhs, rhs, arena)?.kind.clone()),
-                        BinaryOperator::RightShift => Ok(err_op_bsr(lhs, rhs, arena)?.kind.clone()),
-                    }
-                })
+                let lhs = &ValueContainer::new(lhs_kind);
+                let rhs = &ValueContainer::new(rhs_kind);
+                let ctx = ctx.borrow();
+                ctx.operators.eval_binary(*op, lhs, rhs).map(|value| ctx.settle(value))
             }
             Expr::Wrapped(expr) => expr.kind.eval(ctx.clone()),
+            Expr::Collection(items) => {
+                let mut values = Vec::with_capacity(items.len());
+                for item in items {
+                    match &item.kind {
+                        Expr::Starred(inner) => match inner.kind.eval(ctx.clone())? {
+                            ValueKind::Collection(spread) => values.extend(spread.iter().cloned()),
+                            _ => return Err(SandboxExecutionError::InvalidTypeError),
+                        },
+                        kind => values.push(kind.eval(ctx.clone())?),
+                    }
+                }
+                Ok(ValueKind::Collection(Rc::new(values)))
+            }
+            Expr::Starred(_) => Err(SandboxExecutionError::InvalidSyntaxError),
+            Expr::Attribute { value, attr } => match value.kind.eval(ctx.clone())? {
+                ValueKind::Object(obj) => match obj.get(attr) {
+                    Some(v) => Ok(v.borrow().kind.clone()),
+                    None => Err(SandboxExecutionError::AttributeNotFoundError),
+                },
+                ValueKind::Native(native) => native
+                    .get_attribute(attr)
+                    .ok_or(SandboxExecutionError::AttributeNotFoundError),
+                _ => Err(SandboxExecutionError::AttributeNotFoundError),
+            },
+            Expr::Call { function, args } => {
+                let eval_args = || {
+                    args.iter()
+                        .map(|arg| arg.kind.eval(ctx.clone()))
+                        .collect::<Result<Vec<_>>>()
+                };
+                match &function.kind {
+                    Expr::Attribute { value: receiver, attr } => {
+                        call_method(&receiver.kind, attr, eval_args()?, &ctx)
+                    }
+                    Expr::Variable(name) => call_function(name, eval_args()?, &ctx),
+                    _ => Err(SandboxExecutionError::InvalidTypeError),
+                }
+            }
+            Expr::Subscript { value, slice } => {
+                let container = value.kind.eval(ctx.clone())?;
+                let key = slice.kind.eval(ctx.clone())?;
+                get_item(container, &key)
+            }
         }
     }
-
-    fn get_callsite(&self) -> Option<Span> {
-        todo!()
-    }
 }
 
 #[derive(Debug, Clone)]
@@ -185,17 +213,11 @@ pub enum Literal {
 }
 
 impl ASTNode for Literal {
-    type Output<'ctx> = ValueKind<'ctx>;
+    type Output = ValueKind;
 
-    fn eval<'ctx>(&self, ctx: Rc<RefCell<ExecutionContext<'ctx>>>) -> Result<Self::Output<'ctx>> {
+    fn eval(&self, ctx: Rc<RefCell<ExecutionContext>>) -> Result<Self::Output> {
         ctx.borrow_mut().consume_one()?;
-        match self {
-            Literal::Bool(v) => Ok(ValueKind::Bool(*v)),
-            Literal::None => Ok(ValueKind::None),
-            Literal::Int(v) => Ok(ValueKind::Int(VirPyInt::new(*v))),
-            Literal::Float(v) => Ok(ValueKind::Float(VirPyFloat::new(*v))),
-            Literal::String(v) => Ok(ValueKind::String(v.clone())),
-        }
+        Ok(self.value())
     }
 
     fn get_callsite(&self) -> Option<Span> {
@@ -203,7 +225,19 @@ impl ASTNode for Literal {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Literal {
+    pub(crate) fn value(&self) -> ValueKind {
+        match self {
+            Literal::Bool(v) => ValueKind::Bool(*v),
+            Literal::None => ValueKind::None,
+            Literal::Int(v) => ValueKind::Int(VirPyInt::new(*v)),
+            Literal::Float(v) => ValueKind::Float(VirPyFloat::new(*v)),
+            Literal::String(v) => ValueKind::String(v.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum BinaryOperator {
     Add,
     Subtract,
@@ -225,18 +259,53 @@ pub enum BinaryOperator {
     RightShift
 }
 
-#[derive(Debug, Clone, Copy)]
+impl BinaryOperator {
+    /// Every operator, in the order of their discriminants.
+    pub const ALL: [BinaryOperator; 18] = [
+        BinaryOperator::Add,
+        BinaryOperator::Subtract,
+        BinaryOperator::Multiply,
+        BinaryOperator::Divide,
+        BinaryOperator::And,
+        BinaryOperator::Or,
+        BinaryOperator::Xor,
+        BinaryOperator::Modulo,
+        BinaryOperator::BitwiseAnd,
+        BinaryOperator::BitwiseOr,
+        BinaryOperator::Eq,
+        BinaryOperator::NotEq,
+        BinaryOperator::Lt,
+        BinaryOperator::Lte,
+        BinaryOperator::Gt,
+        BinaryOperator::Gte,
+        BinaryOperator::LeftShift,
+        BinaryOperator::RightShift,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum UnaryOperator {
     Positive,
     Negative,
     Not,
+    BitwiseNot,
+}
+
+impl UnaryOperator {
+    /// Every operator, in the order of their discriminants.
+    pub const ALL: [UnaryOperator; 4] = [
+        UnaryOperator::Positive,
+        UnaryOperator::Negative,
+        UnaryOperator::Not,
+        UnaryOperator::BitwiseNot,
+    ];
 }
 
 #[derive(Debug, Clone)]
 pub enum Stmt {
     Expression(Node<Expr>),
     Assign {
-        target: Node<Expr>, // temp since no a[b] or a.b right now
+        targets: Vec<Node<Expr>>, // `a = b = 0` has two targets, assigned left to right
         value: Node<Expr>,
     },
     If {
@@ -244,7 +313,15 @@ pub enum Stmt {
         body: Vec<Node<Stmt>>,
         otherwise: Option<Vec<Node<Stmt>>>,
     },
-    Scoped(Vec<Node<Stmt>>)
+    Scoped(Vec<Node<Stmt>>),
+    Delete(Vec<Node<Expr>>),
+    Pass,
+    Global(Vec<String>),
+    Nonlocal(Vec<String>),
+    Match {
+        subject: Node<Expr>,
+        arms: Vec<MatchArm>,
+    },
     // FunctionDef {
     //     name: String,
     //     args: Vec<String>,
@@ -271,29 +348,167 @@ pub enum Stmt {
     // Continue,
 }
 
+#[derive(Debug, Clone)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub guard: Option<Node<Expr>>,
+    pub body: Vec<Node<Stmt>>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// `1`, `"text"`, `true`, `None`
+    Literal(Literal),
+    /// `name`, binds the subject
+    Capture(String),
+    /// `_`
+    Wildcard,
+    /// `(a, b)` or `[a, *rest]`, matching a collection
+    Sequence(Vec<Pattern>),
+    /// `*rest` or `*_` inside a sequence pattern, binding the remaining items
+    Star(Option<String>),
+    /// `{ "key": pattern, **rest }`, matching the keys of an object
+    Mapping {
+        entries: Vec<(String, Pattern)>,
+        rest: Option<String>,
+    },
+    /// `Point { x: 0, y }`, matching an object by class name and attributes
+    Class {
+        name: String,
+        fields: Vec<(String, Pattern)>,
+    },
+    /// `a | b`
+    Or(Vec<Pattern>),
+    /// `name @ pattern`
+    Binding(String, Box<Pattern>),
+}
+
+impl Pattern {
+    /// The names the pattern binds when it matches. Every alternative of an or-pattern binds the
+    /// same names, which the parser and [`Pattern::validate`] check.
+    pub fn bound_names(&self) -> BTreeSet<&str> {
+        let mut names = BTreeSet::new();
+        self.collect_names(&mut names);
+        names
+    }
+
+    fn collect_names<'a>(&'a self, names: &mut BTreeSet<&'a str>) {
+        match self {
+            Pattern::Capture(name) | Pattern::Star(Some(name)) => {
+                names.insert(name);
+            }
+            Pattern::Binding(name, inner) => {
+                names.insert(name);
+                inner.collect_names(names);
+            }
+            Pattern::Sequence(patterns) => {
+                patterns.iter().for_each(|pattern| pattern.collect_names(names));
+            }
+            Pattern::Or(alternatives) => {
+                if let Some(first) = alternatives.first() {
+                    first.collect_names(names);
+                }
+            }
+            Pattern::Mapping { entries, rest } => {
+                entries.iter().for_each(|(_, pattern)| pattern.collect_names(names));
+                if let Some(rest) = rest {
+                    names.insert(rest);
+                }
+            }
+            Pattern::Class { fields, .. } => {
+                fields.iter().for_each(|(_, pattern)| pattern.collect_names(names));
+            }
+            Pattern::Literal(_) | Pattern::Wildcard | Pattern::Star(None) => {}
+        }
+    }
+
+    /// Checks the rules the parser enforces while reading a pattern, for patterns that were built
+    /// another way, such as by [`crate::ast::binary::decode`].
+    pub fn validate(&self) -> std::result::Result<(), PatternError> {
+        if let Pattern::Star(_) = self {
+            return Err(PatternError::StarOutsideSequence);
+        }
+        self.validate_nested()
+    }
+
+    fn validate_nested(&self) -> std::result::Result<(), PatternError> {
+        match self {
+            Pattern::Sequence(patterns) => {
+                let stars = patterns.iter().filter(|pattern| matches!(pattern, Pattern::Star(_)));
+                if stars.count() > 1 {
+                    return Err(PatternError::SeveralStars);
+                }
+                patterns.iter().try_for_each(|pattern| match pattern {
+                    Pattern::Star(_) => Ok(()),
+                    pattern => pattern.validate(),
+                })
+            }
+            Pattern::Or(alternatives) => {
+                if let Some(first) = alternatives.first() {
+                    let names = first.bound_names();
+                    if alternatives.iter().any(|alternative| alternative.bound_names() != names) {
+                        return Err(PatternError::OrPatternNames);
+                    }
+                }
+                alternatives.iter().try_for_each(Pattern::validate)
+            }
+            Pattern::Binding(_, inner) => inner.validate(),
+            Pattern::Mapping { entries: fields, .. } | Pattern::Class { fields, .. } => {
+                fields.iter().try_for_each(|(_, pattern)| pattern.validate())
+            }
+            Pattern::Literal(_) | Pattern::Capture(_) | Pattern::Wildcard | Pattern::Star(_) => Ok(()),
+        }
+    }
+}
+
+/// A rule of the pattern syntax broken by a pattern, as reported by [`Pattern::validate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatternError {
+    /// The alternatives of an or-pattern bind different names, so a name bound by only some of
+    /// them would be left unbound when the others match.
+    OrPatternNames,
+    /// A sequence pattern has more than one starred item.
+    SeveralStars,
+    /// A starred pattern is not an item of a sequence pattern.
+    StarOutsideSequence,
+}
+
+impl std::fmt::Display for PatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PatternError::OrPatternNames => "every alternative of an or-pattern must bind the same names",
+            PatternError::SeveralStars => "multiple starred names in sequence pattern",
+            PatternError::StarOutsideSequence => "starred patterns are only allowed inside a sequence pattern",
+        })
+    }
+}
+
 impl ASTNode for Stmt {
-    type Output<'ctx> = ValueKind<'ctx>;
+    type Output = ValueKind;
+
+    fn eval(&self, ctx: Rc<RefCell<ExecutionContext>>) -> Result<Self::Output> {
+        ctx.borrow_mut().enter_frame()?;
+        let result = self.eval_frame(ctx.clone());
+        ctx.borrow_mut().leave_frame();
+        result
+    }
 
-    fn eval<'ctx>(&self, ctx: Rc<RefCell<ExecutionContext<'ctx>>>) -> Result<Self::Output<'ctx>> {
+    fn get_callsite(&self) -> Option<Span> {
+        todo!()
+    }
+}
+
+impl Stmt {
+    fn eval_frame(&self, ctx: Rc<RefCell<ExecutionContext>>) -> Result<ValueKind> {
         ctx.borrow_mut().consume_one()?;
         match self {
             Stmt::Expression(expr) => {
                 expr.kind.eval(ctx.clone())?;
             }
-            Stmt::Assign { target, value } => {
+            Stmt::Assign { targets, value } => {
                 let value_kind = value.kind.eval(ctx.clone())?;
-                match &target.kind {
-                    Expr::Variable(name) => {
-                        with_arena(&ctx, |arena| {
-                            let value_container = ValueContainer::new(value_kind, arena);
-                            ctx.borrow_mut()
-                                .get_ignore_missing(&name, value_container)?;
-                            Ok::<(), SandboxExecutionError>(())
-                        })?;
-                    }
-                    _ => {
-                        Err(SandboxExecutionError::InvalidSyntaxError)?
-                    }
+                for target in targets {
+                    assign_target(&target.kind, value_kind.clone(), &ctx)?;
                 }
             }
             Stmt::If {
@@ -303,33 +518,541 @@ impl ASTNode for Stmt {
             } => {
                 let value_kind = test.kind.eval(ctx.clone())?;
                 match value_kind {
-                    ValueKind::Bool(true) => {
-                        for stmt in body.clone() {
-                            stmt.kind.eval(ctx.clone())?;
-                            ctx.borrow_mut().consume_one()?;
-                        }
-                    }
+                    ValueKind::Bool(true) => eval_block(body, &ctx)?,
                     ValueKind::Bool(false) | ValueKind::None => {
                         if let Some(otherwise) = otherwise {
-                            for stmt in otherwise.clone() {
-                                stmt.kind.eval(ctx.clone())?;
-                                ctx.borrow_mut().consume_one()?;
-                            }
+                            eval_block(otherwise, &ctx)?;
                         }
                     }
                     _ => return Err(SandboxExecutionError::InvalidTypeError),
                 }
             },
-            Stmt::Scoped(scoped) => {
-                for stmt in scoped.clone() {
-                    stmt.kind.eval(ctx.clone())?;
+            Stmt::Scoped(scoped) => eval_block(scoped, &ctx)?,
+            Stmt::Delete(targets) => {
+                for target in targets {
+                    delete_target(&target.kind, &ctx)?;
+                }
+            }
+            Stmt::Pass => {}
+            Stmt::Global(names) => {
+                for name in names {
+                    ctx.borrow_mut().declare(name, Declaration::Global)?;
+                }
+            }
+            Stmt::Nonlocal(names) => {
+                for name in names {
+                    ctx.borrow_mut().declare(name, Declaration::Nonlocal)?;
+                }
+            }
+            Stmt::Match { subject, arms } => {
+                let subject = subject.kind.eval(ctx.clone())?;
+                let scoped = ctx.borrow().options.scope_mode == ScopeMode::Block;
+                for arm in arms {
+                    ctx.borrow_mut().consume_one()?;
+                    let mut bindings = Vec::new();
+                    if !match_pattern(&arm.pattern, &subject, &mut bindings) {
+                        continue;
+                    }
+                    if scoped {
+                        ctx.borrow_mut().push_scope();
+                    }
+                    let result = eval_arm(arm, bindings, &ctx);
+                    if scoped {
+                        ctx.borrow_mut().pop_scope();
+                    }
+                    if result? {
+                        break;
+                    }
                 }
             }
         };
         Ok(ValueKind::None)
     }
+}
 
-    fn get_callsite(&self) -> Option<Span> {
-        todo!()
+/// Runs `body` in the current scope, charging one unit per statement on top of the statement's own
+/// cost. Shared by `Module` and every block so that they account for TTL the same way.
+fn eval_body(body: &[Node<Stmt>], ctx: &Rc<RefCell<ExecutionContext>>) -> Result<()> {
+    for stmt in body {
+        stmt.kind.eval(ctx.clone())?;
+        ctx.borrow_mut().consume_one()?;
+    }
+    Ok(())
+}
+
+/// Runs a nested block, giving it its own scope under [`ScopeMode::Block`].
+fn eval_block(body: &[Node<Stmt>], ctx: &Rc<RefCell<ExecutionContext>>) -> Result<()> {
+    let scoped = ctx.borrow().options.scope_mode == ScopeMode::Block;
+    if !scoped {
+        return eval_body(body, ctx);
+    }
+    ctx.borrow_mut().push_scope();
+    let result = eval_body(body, ctx);
+    ctx.borrow_mut().pop_scope();
+    result
+}
+
+/// Binds the captures of a matched arm and runs its body if the guard passes, returning whether
+/// the arm was taken.
+fn eval_arm(
+    arm: &MatchArm,
+    bindings: Vec<(String, ValueKind)>,
+    ctx: &Rc<RefCell<ExecutionContext>>,
+) -> Result<bool> {
+    for (name, value) in bindings {
+        assign_target(&Expr::Variable(name), value, ctx)?;
+    }
+    if let Some(guard) = &arm.guard {
+        match guard.kind.eval(ctx.clone())? {
+            ValueKind::Bool(true) => {}
+            ValueKind::Bool(false) | ValueKind::None => return Ok(false),
+            _ => return Err(SandboxExecutionError::InvalidTypeError),
+        }
+    }
+    eval_body(&arm.body, ctx)?;
+    Ok(true)
+}
+
+fn literal_matches(literal: &Literal, value: &ValueKind) -> bool {
+    match (literal, value) {
+        (Literal::Int(l), ValueKind::Int(v)) => *l == v.value,
+        (Literal::Int(l), ValueKind::Float(v)) => *l as f64 == v.value,
+        (Literal::Float(l), ValueKind::Float(v)) => *l == v.value,
+        (Literal::Float(l), ValueKind::Int(v)) => *l == v.value as f64,
+        (Literal::String(l), ValueKind::String(v)) => l == v,
+        (Literal::Bool(l), ValueKind::Bool(v)) => l == v,
+        (Literal::None, ValueKind::None) => true,
+        _ => false,
+    }
+}
+
+/// Tests `value` against `pattern`, appending the captured names to `bindings` on success.
+/// On failure `bindings` may hold partial captures and should be discarded.
+pub fn match_pattern(
+    pattern: &Pattern,
+    value: &ValueKind,
+    bindings: &mut Vec<(String, ValueKind)>,
+) -> bool {
+    match pattern {
+        Pattern::Literal(literal) => literal_matches(literal, value),
+        Pattern::Capture(name) => {
+            bindings.push((name.clone(), value.clone()));
+            true
+        }
+        Pattern::Wildcard => true,
+        Pattern::Star(_) => false,
+        Pattern::Binding(name, inner) => {
+            if !match_pattern(inner, value, bindings) {
+                return false;
+            }
+            bindings.push((name.clone(), value.clone()));
+            true
+        }
+        Pattern::Or(alternatives) => alternatives.iter().any(|alternative| {
+            let mut attempt = Vec::new();
+            if match_pattern(alternative, value, &mut attempt) {
+                bindings.append(&mut attempt);
+                return true;
+            }
+            false
+        }),
+        Pattern::Sequence(patterns) => {
+            let ValueKind::Collection(items) = value else {
+                return false;
+            };
+            let star_idx = patterns.iter().position(|p| matches!(p, Pattern::Star(_)));
+            match star_idx {
+                None => {
+                    items.len() == patterns.len()
+                        && patterns
+                            .iter()
+                            .zip(items.iter())
+                            .all(|(pattern, item)| match_pattern(pattern, item, bindings))
+                }
+                Some(idx) => {
+                    let after = patterns.len() - idx - 1;
+                    if items.len() < patterns.len() - 1 {
+                        return false;
+                    }
+                    let rest_end = items.len() - after;
+                    let head_matches = patterns[..idx]
+                        .iter()
+                        .zip(&items[..idx])
+                        .all(|(pattern, item)| match_pattern(pattern, item, bindings));
+                    let tail_matches = patterns[idx + 1..]
+                        .iter()
+                        .zip(&items[rest_end..])
+                        .all(|(pattern, item)| match_pattern(pattern, item, bindings));
+                    if let Pattern::Star(Some(name)) = &patterns[idx] {
+                        let rest = items[idx..rest_end].to_vec();
+                        bindings.push((name.clone(), ValueKind::Collection(Rc::new(rest))));
+                    }
+                    head_matches && tail_matches
+                }
+            }
+        }
+        Pattern::Mapping { entries, rest } => {
+            let ValueKind::Object(obj) = value else {
+                return false;
+            };
+            if !match_attributes(obj, entries, bindings) {
+                return false;
+            }
+            if let Some(rest) = rest {
+                let remaining = VirPyObject::new();
+                for (key, item) in obj.mapping.borrow().mapping.iter() {
+                    if !entries.iter().any(|(entry, _)| entry == key) {
+                        remaining.set(key.clone(), item.borrow().kind.clone());
+                    }
+                }
+                bindings.push((rest.clone(), ValueKind::Object(remaining)));
+            }
+            true
+        }
+        Pattern::Class { name, fields } => match value {
+            ValueKind::Object(obj) => {
+                obj.class_name.as_ref() == Some(name) && match_attributes(obj, fields, bindings)
+            }
+            ValueKind::Native(native) => {
+                native.type_name() == name
+                    && fields.iter().all(|(key, pattern)| match native.get_attribute(key) {
+                        Some(item) => match_pattern(pattern, &item, bindings),
+                        None => false,
+                    })
+            }
+            _ => false,
+        },
+    }
+}
+
+fn match_attributes(
+    obj: &VirPyObject,
+    entries: &[(String, Pattern)],
+    bindings: &mut Vec<(String, ValueKind)>,
+) -> bool {
+    entries.iter().all(|(key, pattern)| match obj.get(key) {
+        Some(item) => match_pattern(pattern, &item.borrow().kind, bindings),
+        None => false,
+    })
+}
+
+fn assign_target(
+    target: &Expr,
+    value: ValueKind,
+    ctx: &Rc<RefCell<ExecutionContext>>,
+) -> Result<()> {
+    match target {
+        Expr::Variable(name) => {
+            ctx.borrow_mut().get_ignore_missing(name, value)?;
+            Ok(())
+        }
+        Expr::Wrapped(inner) => assign_target(&inner.kind, value, ctx),
+        Expr::Attribute { value: obj, attr } => match obj.kind.eval(ctx.clone())? {
+            ValueKind::Object(obj) => {
+                ctx.borrow_mut().set_attribute(&obj, attr.clone(), value);
+                Ok(())
+            }
+            // Native values are copied on write, so the updated copy replaces the original
+            ValueKind::Native(_) => update_place(&obj.kind, ctx, Box::new(move |native| {
+                let ValueKind::Native(current) = native else {
+                    return Err(SandboxExecutionError::AttributeNotFoundError);
+                };
+                *current = current.with_attribute(attr, value)?;
+                Ok(())
+            })),
+            _ => Err(SandboxExecutionError::AttributeNotFoundError),
+        },
+        Expr::Subscript { value: container, slice } => {
+            let key = slice.kind.eval(ctx.clone())?;
+            update_place(&container.kind, ctx, Box::new(move |container| {
+                set_item(container, &key, Some(value), &mut ctx.borrow_mut())
+            }))
+        }
+        Expr::Collection(targets) => {
+            let ValueKind::Collection(values) = value else {
+                return Err(SandboxExecutionError::InvalidTypeError);
+            };
+            let values = Rc::unwrap_or_clone(values);
+            let values = unpack(targets.len(), star_index(targets)?, values)?;
+            for (target, value) in targets.iter().zip(values) {
+                match &target.kind {
+                    Expr::Starred(inner) => assign_target(&inner.kind, value, ctx)?,
+                    kind => assign_target(kind, value, ctx)?,
+                }
+            }
+            Ok(())
+        }
+        _ => Err(SandboxExecutionError::InvalidSyntaxError),
+    }
+}
+
+/// Calls `method` on the native object `receiver` evaluates to. The method runs on a copy, which
+/// is stored back to `receiver` when it is an assignable place so that mutating methods stick.
+fn call_method(
+    receiver: &Expr,
+    method: &str,
+    args: Vec<ValueKind>,
+    ctx: &Rc<RefCell<ExecutionContext>>,
+) -> Result<ValueKind> {
+    match receiver {
+        Expr::Wrapped(inner) => call_method(&inner.kind, method, args, ctx),
+        Expr::Variable(_) | Expr::Attribute { .. } | Expr::Subscript { .. } => {
+            let mut result = None;
+            update_place(receiver, ctx, Box::new(|native| {
+                let (updated, value) = call_native(native, method, args)?;
+                *native = updated;
+                result = Some(value);
+                Ok(())
+            }))?;
+            Ok(result.unwrap_or(ValueKind::None))
+        }
+        _ => {
+            let native = receiver.eval(ctx.clone())?;
+            Ok(call_native(&native, method, args)?.1)
+        }
+    }
+}
+
+/// Calls the host function registered as `name`, which must not be async.
+fn call_function(
+    name: &str,
+    args: Vec<ValueKind>,
+    ctx: &Rc<RefCell<ExecutionContext>>,
+) -> Result<ValueKind> {
+    if let Some(result) = stdlib::call(&mut ctx.borrow_mut(), name, &args) {
+        return result;
+    }
+    let function = native_function(&ctx.borrow(), name)?;
+    match function {
+        NativeFunction::Sync(function) => function(args),
+        NativeFunction::Async(_) => Err(SandboxExecutionError::AsyncFunctionError),
+    }
+}
+
+/// The host function scripts call as `name`.
+pub(crate) fn native_function(ctx: &ExecutionContext, name: &str) -> Result<NativeFunction> {
+    ctx.functions
+        .get(name)
+        .cloned()
+        .ok_or_else(|| SandboxExecutionError::ReferenceNotExistError(name.to_string()))
+}
+
+/// Calls `method` on a copy of `native`, returning the updated copy and the method's result.
+pub(crate) fn call_native(
+    native: &ValueKind,
+    method: &str,
+    args: Vec<ValueKind>,
+) -> Result<(ValueKind, ValueKind)> {
+    let ValueKind::Native(native) = native else {
+        return Err(SandboxExecutionError::AttributeNotFoundError);
+    };
+    let (updated, result) = native.call_method(method, args)?;
+    Ok((ValueKind::Native(updated), result))
+}
+
+/// Returns the position of the starred target among `targets`, if any. Only one is allowed.
+pub(crate) fn star_index(targets: &[Node<Expr>]) -> Result<Option<usize>> {
+    let mut starred = targets
+        .iter()
+        .enumerate()
+        .filter(|(_, target)| matches!(target.kind, Expr::Starred(_)))
+        .map(|(idx, _)| idx);
+    let star_idx = starred.next();
+    if starred.next().is_some() {
+        return Err(SandboxExecutionError::InvalidSyntaxError);
+    }
+    Ok(star_idx)
+}
+
+/// Splits `values` so that there is exactly one value per target, with the starred target (if
+/// any) receiving the surplus as a collection.
+pub(crate) fn unpack(
+    targets: usize,
+    star_idx: Option<usize>,
+    mut values: Vec<ValueKind>,
+) -> Result<Vec<ValueKind>> {
+    match star_idx {
+        None if values.len() != targets => Err(SandboxExecutionError::UnpackingError {
+            expected: targets,
+            actual: values.len(),
+        }),
+        None => Ok(values),
+        Some(_) if values.len() < targets - 1 => Err(SandboxExecutionError::UnpackingError {
+            expected: targets - 1,
+            actual: values.len(),
+        }),
+        Some(idx) => {
+            let after = targets - idx - 1;
+            let mut tail = values.split_off(values.len() - after);
+            let rest = values.split_off(idx);
+            values.push(ValueKind::Collection(Rc::new(rest)));
+            values.append(&mut tail);
+            Ok(values)
+        }
+    }
+}
+
+fn delete_target(target: &Expr, ctx: &Rc<RefCell<ExecutionContext>>) -> Result<()> {
+    match target {
+        Expr::Variable(name) => ctx.borrow_mut().remove(name),
+        Expr::Wrapped(inner) => delete_target(&inner.kind, ctx),
+        Expr::Attribute { value, attr } => match value.kind.eval(ctx.clone())? {
+            ValueKind::Object(obj) => match ctx.borrow_mut().remove_attribute(&obj, attr) {
+                true => Ok(()),
+                false => Err(SandboxExecutionError::AttributeNotFoundError),
+            },
+            _ => Err(SandboxExecutionError::AttributeNotFoundError),
+        },
+        Expr::Subscript { value, slice } => {
+            let key = slice.kind.eval(ctx.clone())?;
+            update_place(&value.kind, ctx, Box::new(move |container| {
+                set_item(container, &key, None, &mut ctx.borrow_mut())
+            }))
+        }
+        Expr::Collection(targets) => {
+            for target in targets {
+                delete_target(&target.kind, ctx)?;
+            }
+            Ok(())
+        }
+        _ => Err(SandboxExecutionError::InvalidSyntaxError),
+    }
+}
+
+/// An in-place update of a stored value, see [`update_place`].
+type Update<'a> = Box<dyn FnOnce(&mut ValueKind) -> Result<()> + 'a>;
+
+/// Lets `f` update the value stored at `place` in place. Collections are copied on write, so the
+/// value is moved out of its binding while `f` runs: writing `a[0][1]` then only copies the levels
+/// that are shared with another binding.
+fn update_place(
+    place: &Expr,
+    ctx: &Rc<RefCell<ExecutionContext>>,
+    f: Update<'_>,
+) -> Result<()> {
+    match place {
+        Expr::Variable(name) => {
+            let binding = ctx.borrow().get(name)?;
+            update_binding(&binding, ctx, f)
+        }
+        Expr::Wrapped(inner) => update_place(&inner.kind, ctx, f),
+        Expr::Attribute { value, attr } => match value.kind.eval(ctx.clone())? {
+            ValueKind::Object(obj) => match obj.get(attr) {
+                Some(binding) => update_binding(&binding, ctx, f),
+                None => Err(SandboxExecutionError::AttributeNotFoundError),
+            },
+            ValueKind::Native(native) => {
+                let mut current = native
+                    .get_attribute(attr)
+                    .ok_or(SandboxExecutionError::AttributeNotFoundError)?;
+                f(&mut current)?;
+                assign_target(place, current, ctx)
+            }
+            _ => Err(SandboxExecutionError::AttributeNotFoundError),
+        },
+        Expr::Subscript { value, slice } => {
+            let key = slice.kind.eval(ctx.clone())?;
+            update_place(&value.kind, ctx, Box::new(move |container| match container {
+                ValueKind::Collection(items) => {
+                    let idx = resolve_index(&key, items.len())?;
+                    let items = ctx.borrow_mut().make_mut(items);
+                    f(&mut items[idx])
+                }
+                ValueKind::Object(obj) => {
+                    let ValueKind::String(key) = &key else {
+                        return Err(SandboxExecutionError::InvalidTypeError);
+                    };
+                    match obj.get(key) {
+                        Some(binding) => update_binding(&binding, ctx, f),
+                        None => Err(SandboxExecutionError::SubscriptKeyError),
+                    }
+                }
+                _ => Err(SandboxExecutionError::InvalidTypeError),
+            }))
+        }
+        _ => Err(SandboxExecutionError::InvalidSyntaxError),
+    }
+}
+
+/// Runs `f` on the value of a variable or attribute binding, putting it back even when `f` fails.
+/// The binding keeps its charge, as `f` accounts for whatever it changes.
+fn update_binding(
+    binding: &Rc<RefCell<ValueContainer>>,
+    ctx: &Rc<RefCell<ExecutionContext>>,
+    f: Update<'_>,
+) -> Result<()> {
+    ctx.borrow_mut().journal_value(binding);
+    let mut current = std::mem::replace(&mut binding.borrow_mut().kind, ValueKind::None);
+    let result = f(&mut current);
+    binding.borrow_mut().kind = current;
+    result
+}
+
+pub(crate) fn resolve_index(index: &ValueKind, len: usize) -> Result<usize> {
+    let ValueKind::Int(index) = index else {
+        return Err(SandboxExecutionError::InvalidTypeError);
+    };
+    let resolved = if index.value < 0 {
+        len as i64 + index.value
+    } else {
+        index.value
+    };
+    if resolved < 0 || resolved >= len as i64 {
+        return Err(SandboxExecutionError::SubscriptKeyError);
+    }
+    Ok(resolved as usize)
+}
+
+pub(crate) fn get_item(container: ValueKind, key: &ValueKind) -> Result<ValueKind> {
+    match (container, key) {
+        (ValueKind::Collection(items), _) => {
+            let idx = resolve_index(key, items.len())?;
+            Ok(items[idx].clone())
+        }
+        (ValueKind::String(s), _) => {
+            let idx = resolve_index(key, s.chars().count())?;
+            Ok(ValueKind::String(s.chars().nth(idx).unwrap().to_string()))
+        }
+        (ValueKind::Object(obj), ValueKind::String(key)) => match obj.get(key) {
+            Some(v) => Ok(v.borrow().kind.clone()),
+            None => Err(SandboxExecutionError::SubscriptKeyError),
+        },
+        _ => Err(SandboxExecutionError::InvalidTypeError),
+    }
+}
+
+/// Sets `key` of `container` to `value`, or removes it when `value` is `None`.
+pub(crate) fn set_item(
+    container: &mut ValueKind,
+    key: &ValueKind,
+    value: Option<ValueKind>,
+    ctx: &mut ExecutionContext,
+) -> Result<()> {
+    match (container, key) {
+        (ValueKind::Collection(items), _) => {
+            let idx = resolve_index(key, items.len())?;
+            let items = ctx.make_mut(items);
+            let previous = match value {
+                Some(value) => {
+                    ctx.charge_nested(None, Some(&value));
+                    std::mem::replace(&mut items[idx], value)
+                }
+                None => items.remove(idx),
+            };
+            ctx.charge_nested(Some(&previous), None);
+            Ok(())
+        }
+        (ValueKind::Object(obj), ValueKind::String(key)) => {
+            match value {
+                Some(value) => ctx.set_attribute(obj, key.clone(), value),
+                None => {
+                    if !ctx.remove_attribute(obj, key) {
+                        return Err(SandboxExecutionError::SubscriptKeyError);
+                    }
+                }
+            }
+            Ok(())
+        }
+        _ => Err(SandboxExecutionError::InvalidTypeError),
     }
 }
@@ -1 +1,3 @@
+pub mod binary;
 pub mod core;
+pub mod optimize;
@@ -0,0 +1,224 @@
+//! An optional pass over a [`Module`] for scripts that are parsed once and run many times:
+//! [`optimize`] folds operators applied to literals, drops the branches of an `if` that a literal
+//! test can never take, and removes the [`Expr::Wrapped`] nodes that parentheses leave behind.
+//!
+//! The optimized module assigns the same variables and fails with the same errors, but it is
+//! cheaper to run. A folded expression costs what a single literal costs, an eliminated branch
+//! costs nothing, and the nodes that are gone no longer count towards
+//! [`Limits::max_nesting_depth`](crate::exec_ctx::Limits::max_nesting_depth). A script that ran
+//! out of TTL or nesting depth may therefore succeed once optimized. Folded values are still accounted
+//! when the literal is evaluated, so the memory and length limits apply to them as before.
+
+use crate::ast::core::{BinaryOperator, Expr, Literal, MatchArm, Module, Node, Span, Stmt};
+use crate::base::{ValueContainer, ValueKind};
+use crate::error::Result;
+use crate::op::OperatorTable;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// Optimizes `module` for a context that uses `operators`, which are called while folding and
+/// must therefore not have side effects. Operators that fail or panic are left for the script to
+/// run, so that the error is raised where it was.
+pub fn optimize(module: Module, operators: &OperatorTable) -> Module {
+    Module {
+        body: Optimizer { operators }.body(module.body),
+        span: module.span,
+    }
+}
+
+struct Optimizer<'a> {
+    operators: &'a OperatorTable,
+}
+
+impl Optimizer<'_> {
+    fn body(&self, body: Vec<Node<Stmt>>) -> Vec<Node<Stmt>> {
+        body.into_iter().filter_map(|stmt| self.stmt(stmt)).collect()
+    }
+
+    /// Returns `None` when the statement would not do anything.
+    fn stmt(&self, stmt: Node<Stmt>) -> Option<Node<Stmt>> {
+        let kind = match stmt.kind {
+            Stmt::Expression(expr) => Stmt::Expression(self.expr(expr)),
+            Stmt::Assign { targets, value } => Stmt::Assign {
+                targets: targets.into_iter().map(|target| self.expr(target)).collect(),
+                value: self.expr(value),
+            },
+            Stmt::If {
+                test,
+                body,
+                otherwise,
+            } => {
+                let test = self.expr(test);
+                // The branch taken keeps a block of its own, as it has one under `ScopeMode::Block`
+                match &test.kind {
+                    Expr::Literal(Literal::Bool(true)) => Stmt::Scoped(self.body(body)),
+                    Expr::Literal(Literal::Bool(false) | Literal::None) => {
+                        Stmt::Scoped(self.body(otherwise?))
+                    }
+                    _ => Stmt::If {
+                        test,
+                        body: self.body(body),
+                        otherwise: otherwise.map(|otherwise| self.body(otherwise)),
+                    },
+                }
+            }
+            Stmt::Scoped(body) => Stmt::Scoped(self.body(body)),
+            Stmt::Delete(targets) => {
+                Stmt::Delete(targets.into_iter().map(|target| self.expr(target)).collect())
+            }
+            Stmt::Match { subject, arms } => Stmt::Match {
+                subject: self.expr(subject),
+                arms: arms
+                    .into_iter()
+                    .map(|arm| MatchArm {
+                        pattern: arm.pattern,
+                        guard: arm.guard.map(|guard| self.expr(guard)),
+                        body: self.body(arm.body),
+                    })
+                    .collect(),
+            },
+            kind @ (Stmt::Pass | Stmt::Global(_) | Stmt::Nonlocal(_)) => kind,
+        };
+        Some(Node {
+            kind,
+            span: stmt.span,
+        })
+    }
+
+    fn expr(&self, expr: Node<Expr>) -> Node<Expr> {
+        let kind = match expr.kind {
+            // `(*a)` is not a starred item, so it keeps its parentheses
+            Expr::Wrapped(inner) if !matches!(inner.kind, Expr::Starred(_)) => {
+                return self.expr(*inner);
+            }
+            Expr::Wrapped(inner) => Expr::Wrapped(Box::new(self.expr(*inner))),
+            Expr::UnaryOp { op, operand } => {
+                let operand = self.expr(*operand);
+                let folded = match &operand.kind {
+                    Expr::Literal(literal) => {
+                        let operand = &ValueContainer::new(literal.value());
+                        fold(|| self.operators.eval_unary(op, operand))
+                    }
+                    _ => None,
+                };
+                match folded {
+                    Some(literal) => Expr::Literal(literal),
+                    None => Expr::UnaryOp {
+                        op,
+                        operand: Box::new(operand),
+                    },
+                }
+            }
+            Expr::BinaryOp { left, op, right } => {
+                return self.binary(*left, op, *right, expr.span);
+            }
+            Expr::Collection(items) => {
+                Expr::Collection(items.into_iter().map(|item| self.expr(item)).collect())
+            }
+            Expr::Starred(inner) => Expr::Starred(Box::new(self.expr(*inner))),
+            Expr::Attribute { value, attr } => Expr::Attribute {
+                value: Box::new(self.expr(*value)),
+                attr,
+            },
+            Expr::Subscript { value, slice } => Expr::Subscript {
+                value: Box::new(self.expr(*value)),
+                slice: Box::new(self.expr(*slice)),
+            },
+            Expr::Call { function, args } => {
+                // Only a bare attribute can be called, so `(obj.method)(args)` keeps failing
+                let function = match function.kind {
+                    Expr::Wrapped(inner) => Node {
+                        kind: Expr::Wrapped(Box::new(self.expr(*inner))),
+                        span: function.span,
+                    },
+                    _ => self.expr(*function),
+                };
+                Expr::Call {
+                    function: Box::new(function),
+                    args: args.into_iter().map(|arg| self.expr(arg)).collect(),
+                }
+            }
+            kind @ (Expr::Literal(_) | Expr::Variable(_)) => kind,
+        };
+        Node {
+            kind,
+            span: expr.span,
+        }
+    }
+
+    fn binary(
+        &self,
+        left: Node<Expr>,
+        op: BinaryOperator,
+        right: Node<Expr>,
+        span: Option<Span>,
+    ) -> Node<Expr> {
+        let left = self.expr(left);
+        let right = self.expr(right);
+        let literal = |literal| Node {
+            kind: Expr::Literal(literal),
+            span,
+        };
+        let Expr::Literal(lhs) = &left.kind else {
+            return Node {
+                kind: Expr::BinaryOp {
+                    left: Box::new(left),
+                    op,
+                    right: Box::new(right),
+                },
+                span,
+            };
+        };
+        // `&&` and `||` decide on a literal left operand alone, whatever the right one is
+        match (op, lhs) {
+            (BinaryOperator::And, Literal::Bool(false) | Literal::None) => {
+                return literal(Literal::Bool(false));
+            }
+            (BinaryOperator::Or, Literal::Bool(true)) => return literal(Literal::Bool(true)),
+            (BinaryOperator::Or, Literal::Bool(false) | Literal::None)
+            | (BinaryOperator::And, Literal::Bool(true)) => return right,
+            _ => {}
+        }
+        let folded = match &right.kind {
+            Expr::Literal(rhs) => {
+                let lhs = &ValueContainer::new(lhs.value());
+                let rhs = &ValueContainer::new(rhs.value());
+                fold(|| self.operators.eval_binary(op, lhs, rhs))
+            }
+            _ => None,
+        };
+        match folded {
+            Some(folded) => literal(folded),
+            None => Node {
+                kind: Expr::BinaryOp {
+                    left: Box::new(left),
+                    op,
+                    right: Box::new(right),
+                },
+                span,
+            },
+        }
+    }
+}
+
+/// Applies an operator at parse time, returning the literal it evaluates to. Operators that panic,
+/// such as an overflowing `+`, are left for the run to rewind like any other panic.
+fn fold(operator: impl FnOnce() -> Result<ValueKind>) -> Option<Literal> {
+    catch_unwind(AssertUnwindSafe(operator))
+        .ok()?
+        .ok()
+        .and_then(literal_of)
+}
+
+/// The literal that evaluates to `value`, for the values a literal can hold. NaNs are left to
+/// the run, which settles them in deterministic mode, so as not to bake in the NaN of this CPU.
+fn literal_of(value: ValueKind) -> Option<Literal> {
+    match value {
+        ValueKind::Int(v) => Some(Literal::Int(v.value)),
+        ValueKind::Float(v) if v.value.is_nan() => None,
+        ValueKind::Float(v) => Some(Literal::Float(v.value)),
+        ValueKind::String(v) => Some(Literal::String(v)),
+        ValueKind::Bool(v) => Some(Literal::Bool(v)),
+        ValueKind::None => Some(Literal::None),
+        _ => None,
+    }
+}
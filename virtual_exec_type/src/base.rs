@@ -1,28 +1,111 @@
 use crate::builtin::{VirPyFloat, VirPyInt, VirPyObject};
+use crate::native::NativeObject;
 use crate::error::SandboxExecutionError;
-use bumpalo::Bump;
+use std::collections::HashSet;
 use std::fmt::Debug;
+use std::rc::Rc;
 
-pub type Value<'ctx> = &'ctx ValueContainer<'ctx>;
+/// A borrowed view of a value, as taken by [`Downcast`] and the operator implementations.
+pub type Value<'ctx> = &'ctx ValueContainer;
 
 #[derive(Debug, Clone)]
-pub enum ValueKind<'ctx> {
+pub enum ValueKind {
     Int(VirPyInt),
     Float(VirPyFloat),
-    Object(VirPyObject<'ctx>),
+    Object(VirPyObject),
     ErrorWrapped(SandboxExecutionError),
     Bool(bool),
     String(String),
-    Collection(Vec<ValueKind<'ctx>>),
+    /// Shared between every binding holding it and copied only when one of them is changed, so
+    /// that reading a large collection does not copy it.
+    Collection(Rc<Vec<ValueKind>>),
+    /// A Rust value exposed through [`crate::native::SandboxObject`].
+    Native(NativeObject),
     None,
 }
 
+/// The type of a [`ValueKind`] without its payload, used to key operator implementations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValueTag {
+    Int,
+    Float,
+    Object,
+    ErrorWrapped,
+    Bool,
+    String,
+    Collection,
+    Native,
+    None,
+}
+
+impl ValueTag {
+    /// Every tag, in the order of their discriminants.
+    pub const ALL: [ValueTag; 9] = [
+        ValueTag::Int,
+        ValueTag::Float,
+        ValueTag::Object,
+        ValueTag::ErrorWrapped,
+        ValueTag::Bool,
+        ValueTag::String,
+        ValueTag::Collection,
+        ValueTag::Native,
+        ValueTag::None,
+    ];
+}
+
+impl ValueKind {
+    pub fn tag(&self) -> ValueTag {
+        match self {
+            ValueKind::Int(_) => ValueTag::Int,
+            ValueKind::Float(_) => ValueTag::Float,
+            ValueKind::Object(_) => ValueTag::Object,
+            ValueKind::ErrorWrapped(_) => ValueTag::ErrorWrapped,
+            ValueKind::Bool(_) => ValueTag::Bool,
+            ValueKind::String(_) => ValueTag::String,
+            ValueKind::Collection(_) => ValueTag::Collection,
+            ValueKind::Native(_) => ValueTag::Native,
+            ValueKind::None => ValueTag::None,
+        }
+    }
+
+    /// Estimated number of bytes this value owns on the heap, outside of its own `ValueKind`.
+    /// Objects are shared by reference, so their attributes are accounted for where they are
+    /// stored. A collection held several times, as in `[a, a]`, is counted once.
+    pub fn heap_size(&self) -> usize {
+        self.heap_size_excluding(&mut HashSet::new())
+    }
+
+    /// [`Self::heap_size`], leaving out the collections already in `counted`, by address, and
+    /// adding the ones it counts.
+    pub(crate) fn heap_size_excluding(&self, counted: &mut HashSet<usize>) -> usize {
+        match self {
+            ValueKind::String(s) => s.capacity(),
+            ValueKind::Collection(items) if !counted.insert(Rc::as_ptr(items) as usize) => 0,
+            ValueKind::Collection(items) => {
+                items.capacity() * size_of::<ValueKind>()
+                    + items.iter().map(|item| item.heap_size_excluding(counted)).sum::<usize>()
+            }
+            _ => 0,
+        }
+    }
+}
+
 pub trait Downcast<'ctx>: Sized {
     fn from_value(value: Value<'ctx>) -> Option<&'ctx Self>;
 }
 
-pub trait Upcast<'ctx>: Sized {
-    fn from_value(&'ctx self) -> ValueKind<'ctx>;
+/// The [`ValueTag`] of the values a [`Downcast`] type is read from.
+pub trait Tagged {
+    const TAG: ValueTag;
+}
+
+pub trait Upcast: Sized {
+    #[allow(clippy::wrong_self_convention)]
+    fn from_value(&self) -> ValueKind;
+}
+
+impl Tagged for bool {
+    const TAG: ValueTag = ValueTag::Bool;
 }
 
 impl<'ctx> Downcast<'ctx> for bool {
@@ -31,70 +114,76 @@ impl<'ctx> Downcast<'ctx> for bool {
     }
 }
 
-impl<'ctx> Upcast<'ctx> for bool {
-    fn from_value(&'ctx self) -> ValueKind<'ctx> {
+impl Upcast for bool {
+    fn from_value(&self) -> ValueKind {
         ValueKind::Bool(*self)
     }
 }
 
-impl<'ctx> Downcast<'ctx> for Vec<ValueKind<'ctx>> {
+impl Tagged for Vec<ValueKind> {
+    const TAG: ValueTag = ValueTag::Collection;
+}
+
+impl<'ctx> Downcast<'ctx> for Vec<ValueKind> {
     fn from_value(value: Value<'ctx>) -> Option<&'ctx Self> {
         value.as_collection()
     }
 }
 
-impl<'ctx> Upcast<'ctx> for Vec<ValueKind<'ctx>> {
-    fn from_value(&'ctx self) -> ValueKind<'ctx> {
-        ValueKind::Collection(self.clone())
+impl Upcast for ValueKind {
+    fn from_value(&self) -> ValueKind {
+        self.clone()
+    }
+}
+
+impl<T: Upcast> Upcast for Vec<T> {
+    fn from_value(&self) -> ValueKind {
+        ValueKind::Collection(Rc::new(self.iter().map(Upcast::from_value).collect()))
     }
 }
 
+impl Tagged for String {
+    const TAG: ValueTag = ValueTag::String;
+}
+
 impl<'ctx> Downcast<'ctx> for String {
     fn from_value(value: Value<'ctx>) -> Option<&'ctx Self> {
         value.as_string()
     }
 }
 
-impl<'ctx> Upcast<'ctx> for String {
-    fn from_value(&'ctx self) -> ValueKind<'ctx> {
+impl Upcast for String {
+    fn from_value(&self) -> ValueKind {
         ValueKind::String((*self).clone())
     }
 }
 
+impl Tagged for () {
+    const TAG: ValueTag = ValueTag::None;
+}
+
 impl<'ctx> Downcast<'ctx> for () {
     fn from_value(value: Value<'ctx>) -> Option<&'ctx Self> {
         value.as_none()
     }
 }
 
-impl<'ctx> Upcast<'ctx> for () {
-    fn from_value(&'ctx self) -> ValueKind<'ctx> {
+impl Upcast for () {
+    fn from_value(&self) -> ValueKind {
         ValueKind::None
     }
 }
 
-#[derive(Debug)]
-pub struct ValueContainer<'ctx> {
-    pub kind: ValueKind<'ctx>,
+/// The storage slot of a value. Scopes and objects hold these behind `Rc<RefCell<_>>`, so a value
+/// is freed as soon as the last binding to it goes away.
+#[derive(Debug, Clone)]
+pub struct ValueContainer {
+    pub kind: ValueKind,
 }
 
-impl<'ctx> ValueContainer<'ctx> {
-    pub fn new(kind: ValueKind<'ctx>, arena: &'ctx Bump) -> Value<'ctx> {
-        arena.alloc(ValueContainer { kind })
-    }
-
-    pub fn clone_in_arena(&self, arena: &'ctx Bump) -> Value<'ctx> {
-        let new_kind = match &self.kind {
-            ValueKind::Int(i) => ValueKind::Int(*i),
-            ValueKind::Float(f) => ValueKind::Float(*f),
-            ValueKind::Object(o) => ValueKind::Object(o.clone()),
-            ValueKind::ErrorWrapped(e) => ValueKind::ErrorWrapped(e.clone()),
-            ValueKind::Bool(b) => ValueKind::Bool(*b),
-            ValueKind::String(s) => ValueKind::String(s.clone()),
-            ValueKind::None => ValueKind::None,
-            ValueKind::Collection(c) => ValueKind::Collection(c.clone()),
-        };
-        ValueContainer::new(new_kind, arena)
+impl ValueContainer {
+    pub fn new(kind: ValueKind) -> Self {
+        ValueContainer { kind }
     }
 
     pub fn as_int(&self) -> Option<&VirPyInt> {
@@ -111,7 +200,7 @@ impl<'ctx> ValueContainer<'ctx> {
         }
     }
 
-    pub fn as_object(&self) -> Option<&VirPyObject<'ctx>> {
+    pub fn as_object(&self) -> Option<&VirPyObject> {
         match &self.kind {
             ValueKind::Object(o) => Some(o),
             _ => None,
@@ -146,9 +235,16 @@ impl<'ctx> ValueContainer<'ctx> {
         }
     }
 
-    pub fn as_collection(&self) -> Option<&Vec<ValueKind<'ctx>>> {
+    pub fn as_collection(&self) -> Option<&Vec<ValueKind>> {
+        match &self.kind {
+            ValueKind::Collection(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+
+    pub fn as_native(&self) -> Option<&NativeObject> {
         match &self.kind {
-            ValueKind::Collection(e) => Some(e),
+            ValueKind::Native(n) => Some(n),
             _ => None,
         }
     }
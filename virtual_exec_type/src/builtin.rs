@@ -1,4 +1,4 @@
-use crate::base::{Downcast, Upcast, Value, ValueKind};
+use crate::base::{Downcast, Tagged, Upcast, Value, ValueContainer, ValueKind, ValueTag};
 use crate::error::Result;
 use std::cell::RefCell;
 use std::collections::HashMap;
@@ -25,64 +25,111 @@ impl VirPyFloat {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Mapping<'ctx> {
-    pub mapping: HashMap<String, Rc<RefCell<Value<'ctx>>>>,
+/// How a scope resolves a name that was declared with `global` or `nonlocal`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Declaration {
+    Global,
+    Nonlocal,
 }
 
 #[derive(Debug, Clone)]
-pub struct VirPyObject<'ctx> {
-    pub mapping: Rc<RefCell<Mapping<'ctx>>>,
+pub struct Mapping {
+    pub mapping: HashMap<String, Rc<RefCell<ValueContainer>>>,
+    /// `global`/`nonlocal` declarations made in this scope, unused for object attributes.
+    pub declarations: HashMap<String, Declaration>,
 }
 
-impl<'ctx> VirPyObject<'ctx> {
+impl Mapping {
     pub fn new() -> Self {
         Self {
-            mapping: Rc::new(RefCell::new(Mapping {
-                mapping: HashMap::new(),
-            })),
+            mapping: HashMap::new(),
+            declarations: HashMap::new(),
         }
     }
-    pub fn get(&self, key: &str) -> Option<Rc<RefCell<Value<'ctx>>>> {
-        self.mapping.borrow().mapping.get(key).cloned()
+
+    /// The bindings sorted by name, the order to list them in wherever it can be observed, as
+    /// the order of `mapping` differs from one process to the next.
+    pub fn sorted(&self) -> Vec<(&String, &Rc<RefCell<ValueContainer>>)> {
+        let mut bindings: Vec<_> = self.mapping.iter().collect();
+        bindings.sort_by(|a, b| a.0.cmp(b.0));
+        bindings
+    }
+}
+
+impl Default for Mapping {
+    fn default() -> Self {
+        Self::new()
     }
-    pub fn set(&self, key: String, value: Value<'ctx>) {
-        let value_cell = Rc::new(RefCell::new(value));
-        self.mapping.borrow_mut().mapping.insert(key, value_cell);
+}
+
+#[derive(Debug, Clone)]
+pub struct VirPyObject {
+    pub mapping: Rc<RefCell<Mapping>>,
+    /// The name used by class patterns in `match`, `None` for plain attribute bags.
+    pub class_name: Option<String>,
+}
+
+impl VirPyObject {
+    pub fn new() -> Self {
+        Self {
+            mapping: Rc::new(RefCell::new(Mapping::new())),
+            class_name: None,
+        }
     }
-    pub fn clone(&self) -> Self {
+    pub fn with_class(class_name: impl Into<String>) -> Self {
         Self {
-            mapping: Rc::clone(&self.mapping),
+            class_name: Some(class_name.into()),
+            ..Self::new()
         }
     }
+    pub fn get(&self, key: &str) -> Option<Rc<RefCell<ValueContainer>>> {
+        self.mapping.borrow().mapping.get(key).cloned()
+    }
+    /// Binds `key` to `value`, returning the value it replaced.
+    pub fn set(&self, key: String, value: ValueKind) -> Option<ValueKind> {
+        let value_cell = Rc::new(RefCell::new(ValueContainer::new(value)));
+        let previous = self.mapping.borrow_mut().mapping.insert(key, value_cell);
+        previous.map(|cell| cell.borrow().kind.clone())
+    }
+    pub fn remove(&self, key: &str) -> Option<Rc<RefCell<ValueContainer>>> {
+        self.mapping.borrow_mut().mapping.remove(key)
+    }
 }
 
-impl<'ctx> Default for VirPyObject<'ctx> {
+impl Default for VirPyObject {
     fn default() -> Self {
         Self::new()
     }
 }
 
+impl Tagged for VirPyInt {
+    const TAG: ValueTag = ValueTag::Int;
+}
+
 impl<'ctx> Downcast<'ctx> for VirPyInt {
     fn from_value(value: Value<'ctx>) -> Option<&'ctx Self> {
         value.as_int()
     }
 }
 
+impl Tagged for VirPyFloat {
+    const TAG: ValueTag = ValueTag::Float;
+}
+
 impl<'ctx> Downcast<'ctx> for VirPyFloat {
     fn from_value(value: Value<'ctx>) -> Option<&'ctx Self> {
         value.as_float()
     }
 }
 
-impl<'ctx> Upcast<'ctx> for VirPyInt {
-    fn from_value(&'ctx self) -> ValueKind<'ctx> {
+impl Upcast for VirPyInt {
+    fn from_value(&self) -> ValueKind {
         ValueKind::Int(*self)
     }
 }
 
-impl<'ctx> Upcast<'ctx> for VirPyFloat {
-    fn from_value(&'ctx self) -> ValueKind<'ctx> {
+impl Upcast for VirPyFloat {
+    fn from_value(&self) -> ValueKind {
         ValueKind::Float(*self)
     }
 }
@@ -19,7 +19,21 @@ pub enum SandboxExecutionError {
     InvalidTypeError,
     InvalidSyntaxError,
     SubscriptKeyError,
-    AttributeNotFoundError
+    AttributeNotFoundError,
+    /// Raised when assigning to an attribute that a native object only exposes for reading.
+    ReadOnlyAttributeError,
+    /// Raised when a method is called with the wrong number of arguments.
+    ArgumentCountError { expected: usize, actual: usize },
+    /// Raised when the number of values does not match the unpacking targets.
+    /// With a starred target, `expected` is the minimum number of values required.
+    UnpackingError { expected: usize, actual: usize },
+    NestingDepthExceededError,
+    CollectionLengthExceededError,
+    StringLengthExceededError,
+    MemoryLimitExceededError,
+    DeadlineExceededError,
+    /// Raised when a script calls an async native function outside of an async run.
+    AsyncFunctionError,
 }
 
 pub type Result<T> = ::core::result::Result<T, SandboxExecutionError>;
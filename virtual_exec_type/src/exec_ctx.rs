@@ -1,11 +1,16 @@
-use crate::base::{Value, ValueContainer, ValueKind};
-use crate::builtin::Mapping;
+use crate::base::{ValueContainer, ValueKind};
+use crate::builtin::{Declaration, Mapping, VirPyFloat, VirPyInt, VirPyObject};
 use crate::error::SandboxExecutionError;
-use bumpalo::Bump;
+use crate::native::NativeFunction;
+use crate::op::OperatorTable;
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::rc::Rc;
-use crate::exec_ctx::RsValue::Vector;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::num::NonZeroU32;
+use std::ops::Range;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
 
 pub type Result<T> = core::result::Result<T, SandboxExecutionError>;
 
@@ -13,14 +18,59 @@ pub type Result<T> = core::result::Result<T, SandboxExecutionError>;
 pub enum RsValue {
     Int(i64),
     Float(f64),
-    Object(HashMap<String, RsValue>),
+    /// The attributes of an object, in sorted order.
+    Object(BTreeMap<String, RsValue>),
     Bool(bool),
     String(String),
     Vector(Vec<RsValue>),
     None,
 }
 
-fn value_kind_to_rs_value(kind: &ValueKind) -> RsValue {
+/// A canonical rendering, identical on every machine: object attributes are listed in sorted
+/// order, and floats in their shortest form that parses back to the same bits.
+impl fmt::Display for RsValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RsValue::Int(i) => write!(f, "{i}"),
+            RsValue::Float(v) => write!(f, "{v:?}"),
+            RsValue::Bool(b) => write!(f, "{b}"),
+            RsValue::String(s) => write!(f, "{s:?}"),
+            RsValue::None => f.write_str("None"),
+            RsValue::Vector(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_str("]")
+            }
+            RsValue::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{key}: {value}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+/// Converts a sandbox value into its host representation, copying objects by their attributes.
+///
+/// Scripts can make an object hold itself, as in `cfg.me = cfg;`, which an `RsValue` cannot
+/// represent: an attribute leading back to an object that is still being copied is copied as
+/// `RsValue::None`.
+pub fn value_kind_to_rs_value(kind: &ValueKind) -> RsValue {
+    to_rs_value(kind, &mut HashSet::new())
+}
+
+/// [`value_kind_to_rs_value`], with `copying` holding the address of every object being copied.
+fn to_rs_value(kind: &ValueKind, copying: &mut HashSet<usize>) -> RsValue {
     match kind {
         ValueKind::Int(i) => RsValue::Int(i.value),
         ValueKind::Float(f) => RsValue::Float(f.value),
@@ -28,55 +78,899 @@ fn value_kind_to_rs_value(kind: &ValueKind) -> RsValue {
         ValueKind::String(s) => RsValue::String(s.clone()),
         ValueKind::None => RsValue::None,
         ValueKind::Object(o) => {
-            let mut map = HashMap::new();
+            let key = mapping_key(&o.mapping);
+            if !copying.insert(key) {
+                return RsValue::None;
+            }
+            let mut map = BTreeMap::new();
             for (key, value_rc) in o.mapping.borrow().mapping.iter() {
                 let value_ref = value_rc.borrow();
-                map.insert(key.clone(), value_kind_to_rs_value(&value_ref.kind));
+                map.insert(key.clone(), to_rs_value(&value_ref.kind, copying));
             }
+            copying.remove(&key);
             RsValue::Object(map)
         }
         // Errors are not representable as a PyValue and are skipped.
         ValueKind::ErrorWrapped(_) => RsValue::None,
         ValueKind::Collection(v) => {
             let mut vec = Vec::new();
-            for value in v {
-                vec.push(value_kind_to_rs_value(value));
+            for value in v.iter() {
+                vec.push(to_rs_value(value, copying));
             }
             RsValue::Vector(vec)
         }
+        ValueKind::Native(n) => RsValue::Object(
+            n.attribute_names()
+                .into_iter()
+                .filter_map(|name| {
+                    let value = n.get_attribute(name)?;
+                    Some((name.to_string(), to_rs_value(&value, copying)))
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// The inverse of [`value_kind_to_rs_value`], used to pass host values into a script.
+///
+/// Each `RsValue::Object` becomes a fresh [`VirPyObject`]. Keeping a clone of it (from the
+/// returned `ValueKind::Object`) lets the host read back whatever the script assigned to its
+/// attributes, as objects are shared rather than copied.
+pub fn rs_value_to_value_kind(value: RsValue) -> ValueKind {
+    match value {
+        RsValue::Int(i) => ValueKind::Int(VirPyInt::new(i)),
+        RsValue::Float(f) => ValueKind::Float(VirPyFloat::new(f)),
+        RsValue::Bool(b) => ValueKind::Bool(b),
+        RsValue::String(s) => ValueKind::String(s),
+        RsValue::None => ValueKind::None,
+        RsValue::Vector(items) => {
+            ValueKind::Collection(Rc::new(items.into_iter().map(rs_value_to_value_kind).collect()))
+        }
+        RsValue::Object(fields) => {
+            let obj = VirPyObject::new();
+            for (key, value) in fields {
+                obj.set(key, rs_value_to_value_kind(value));
+            }
+            ValueKind::Object(obj)
+        }
+    }
+}
+
+/// Decides which statements introduce a new scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScopeMode {
+    /// Python-like: blocks share the scope they appear in, so `{ x = 1; };` leaves `x` defined.
+    #[default]
+    Function,
+    /// Rust-like: `if`/`else` bodies and `{ ... };` blocks push a scope that is popped when the
+    /// block ends. Assigning to a name from an enclosing scope still rebinds it there.
+    Block,
+}
+
+/// Resource caps for a single run. `None` leaves the resource unbounded, and every cap reports
+/// its own [`SandboxExecutionError`] variant when hit.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// Operation budget, replacing the context's `ttl`. Raises `TimeoutError`.
+    pub max_operations: Option<i64>,
+    /// Maximum nesting of statements, blocks and expressions being evaluated at once. Scripts
+    /// have no calls of their own, so this bounds how deep the script is, not a call stack.
+    /// Raises `NestingDepthExceededError`.
+    pub max_nesting_depth: Option<usize>,
+    /// Maximum number of items in a collection value. Raises `CollectionLengthExceededError`.
+    pub max_collection_length: Option<usize>,
+    /// Maximum length of a string value in bytes. Raises `StringLengthExceededError`.
+    pub max_string_length: Option<usize>,
+    /// Maximum number of bytes of live data, counting every binding together with the buffers of
+    /// its strings and collections. Raises `MemoryLimitExceededError`.
+    pub max_memory_bytes: Option<usize>,
+    /// Maximum wall-clock time of `Module::eval`, checked every [`DEADLINE_CHECK_EVERY`] units of
+    /// TTL. Raises `DeadlineExceededError`.
+    pub max_duration: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExecOptions {
+    pub scope_mode: ScopeMode,
+    pub limits: Limits,
+    /// How many units of TTL an async run consumes between two yields to the executor,
+    /// [`DEFAULT_YIELD_EVERY`] when unset.
+    pub yield_every: Option<NonZeroU32>,
+    /// Runs every script as a transaction: the writes it makes to variables, attributes and
+    /// items are journaled, and undone if the run fails, so that the host only ever sees the
+    /// state before the run or the state after it.
+    pub transactional: bool,
+    /// Makes every run reproducible bit for bit across machines, see [`Deterministic`].
+    pub deterministic: Option<Deterministic>,
+}
+
+/// The sources of deterministic mode, in which a script run twice on the same state, whether on
+/// the same machine or on another, gives bit-identical results:
+///
+/// - Scripts may call the functions of [`stdlib`](crate::stdlib), which only exist in this mode:
+///   `random()` draws from a generator seeded with `seed`, and `time()` always returns `time`.
+///   Registering a host function under either name injects another source.
+/// - Operators that produce a NaN produce the canonical one, rather than the one of the CPU.
+///
+/// Names are listed in sorted order in every mode (see [`Mapping::sorted`] and
+/// [`ExecutionContext::to_btreemap`]) and [`RsValue`] displays floats by their shortest
+/// round-trip form, so neither depends on the option. [`Limits::max_duration`] is the only
+/// remaining dependency on the machine, as it measures wall-clock time.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Deterministic {
+    pub seed: u64,
+    /// Seconds since the Unix epoch.
+    pub time: f64,
+}
+
+/// The default of [`ExecOptions::yield_every`].
+pub const DEFAULT_YIELD_EVERY: u32 = 1024;
+
+/// How many units of TTL a run consumes between two reads of the clock under
+/// [`Limits::max_duration`].
+pub const DEADLINE_CHECK_EVERY: i64 = 64;
+
+/// Objects a context tracks before the end of a run collects cycles, so that the collection,
+/// which walks every live object, is paid for by the objects created since the last one.
+const COLLECTION_THRESHOLD: usize = 1024;
+
+/// A write made during a transaction, undone by [`ExecutionContext::rollback`].
+#[derive(Debug, Clone)]
+pub(crate) enum JournalEntry {
+    /// `name` was bound to `previous` in `mapping`, or unbound when `previous` is `None`.
+    Binding {
+        mapping: Rc<RefCell<Mapping>>,
+        name: String,
+        previous: Option<Rc<RefCell<ValueContainer>>>,
+    },
+    /// `binding` held `previous`.
+    Value {
+        binding: Rc<RefCell<ValueContainer>>,
+        previous: ValueKind,
+    },
+    Declaration {
+        mapping: Rc<RefCell<Mapping>>,
+        name: String,
+        previous: Option<Declaration>,
+    },
+}
+
+/// The writes of the transaction in progress.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Journal {
+    pub(crate) entries: Vec<JournalEntry>,
+    /// Bindings whose value was already saved, by address. Only the first value matters, and
+    /// each saved binding is kept alive by its entry, so addresses are not reused.
+    saved: HashSet<usize>,
+    /// Whether a run started the transaction, rather than the host.
+    pub(crate) by_run: bool,
+}
+
+impl Journal {
+    /// A transaction that already made the writes of `entries`, oldest first.
+    pub(crate) fn new(entries: Vec<JournalEntry>, by_run: bool) -> Self {
+        let saved = entries
+            .iter()
+            .filter_map(|entry| match entry {
+                JournalEntry::Value { binding, .. } => Some(Rc::as_ptr(binding) as usize),
+                _ => None,
+            })
+            .collect();
+        Self {
+            entries,
+            saved,
+            by_run,
+        }
     }
 }
 
+/// The scope of a block entered by a [`Program`](crate::vm::Program) run under
+/// [`ScopeMode::Block`], holding its variables by their slot in the program's name table rather
+/// than by name.
 #[derive(Debug, Clone)]
-pub struct ExecutionContext<'ctx> {
-    pub arena: Rc<RefCell<Bump>>,
+pub(crate) struct LocalScope {
+    /// The name of each slot, shared with the program.
+    pub(crate) names: Rc<[String]>,
+    pub(crate) bindings: Vec<Option<Rc<RefCell<ValueContainer>>>>,
+    pub(crate) declarations: Vec<Option<Declaration>>,
+}
+
+impl LocalScope {
+    pub(crate) fn new(names: Rc<[String]>) -> Self {
+        Self {
+            bindings: vec![None; names.len()],
+            declarations: vec![None; names.len()],
+            names,
+        }
+    }
+
+    /// The variables bound in the scope, by name.
+    fn named(&self) -> impl Iterator<Item = (&String, &Rc<RefCell<ValueContainer>>)> {
+        self.names
+            .iter()
+            .zip(&self.bindings)
+            .filter_map(|(name, binding)| Some((name, binding.as_ref()?)))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExecutionContext {
     pub ttl: i64,
-    pub mapping: Vec<Rc<RefCell<Mapping<'ctx>>>>, // Top layer ([0]): most local scope
+    pub mapping: Vec<Rc<RefCell<Mapping>>>, // Top layer ([0]): most local scope
+    pub options: ExecOptions,
+    /// The operators scripts run by this context may use.
+    pub operators: OperatorTable,
+    /// The host functions scripts run by this context may call, by name.
+    pub functions: HashMap<String, NativeFunction>,
+    /// The state of the generator behind `random()`, seeded by [`Deterministic::seed`].
+    pub(crate) random_state: u64,
+    depth: usize,
+    deadline: Option<Instant>,
+    /// Units of TTL left before the clock is read again.
+    until_deadline_check: i64,
+    /// Estimated bytes held by the bindings of every scope and object attribute.
+    live_bytes: usize,
+    /// Every object mapping seen by the context, keyed by address, for [`Self::collect_cycles`].
+    objects: HashMap<usize, Weak<RefCell<Mapping>>>,
+    /// Objects tracked since the last cycle collection.
+    tracked_since_collection: usize,
+    /// Set while a transaction is in progress.
+    journal: Option<Journal>,
+    /// The number of scopes and the nesting depth the run in progress started from.
+    /// The block scopes of the VM runs in progress, innermost last. They are more local than
+    /// every scope in `mapping`.
+    pub(crate) locals: Vec<LocalScope>,
+    /// The number of scopes, block scopes and the nesting depth the run in progress started from.
+    run_scopes: usize,
+    pub(crate) run_locals: usize,
+    run_depth: usize,
 }
 
 // By implementing RefUnwindSafe, we are asserting that even if a panic
 // occurs during a method call that mutates ExecutionContext through a
 // shared reference, the context is left in a state that won't cause
-// undefined behavior *for the caller*. This is acceptable for our interpreter
-// because the API contract of Module::eval is that if a panic is caught,
-// the entire ExecutionContext must be discarded by the caller.
-impl<'ctx> std::panic::RefUnwindSafe for ExecutionContext<'ctx> {}
-
-impl<'ctx> ExecutionContext<'ctx> {
-    pub fn new(
-        arena: Rc<RefCell<Bump>>,
-        ttl: i64,
-        mapping: Vec<Rc<RefCell<Mapping<'ctx>>>>,
-    ) -> Self {
-        Self {
-            arena,
+// undefined behavior *for the caller*. Both engines catch the panic and
+// call `unwind_run`, which pops the scopes and resets the nesting depth the
+// run left behind, so the context can run again. As with an error, writes
+// made before the panic are kept unless the run is transactional.
+impl std::panic::RefUnwindSafe for ExecutionContext {}
+
+/// Bytes charged for one binding of `value` in a scope or object.
+fn binding_size(value: &ValueKind) -> usize {
+    size_of::<ValueContainer>() + value.heap_size()
+}
+
+/// Bytes released by dropping a binding of `value`: collections that are still shared with
+/// other values stay alive, and keep counting.
+fn released_size(value: &ValueKind) -> usize {
+    size_of::<ValueContainer>() + owned_heap_size(value)
+}
+
+/// The part of [`ValueKind::heap_size`] that `value` does not share with any other value.
+fn owned_heap_size(value: &ValueKind) -> usize {
+    match value {
+        ValueKind::Collection(items) if Rc::strong_count(items) > 1 => 0,
+        ValueKind::Collection(items) => {
+            items.capacity() * size_of::<ValueKind>()
+                + items.iter().map(owned_heap_size).sum::<usize>()
+        }
+        value => value.heap_size(),
+    }
+}
+
+/// Calls `f` with every object directly held by `value`, looking through collections. A
+/// collection held several times is looked through once.
+fn for_each_object(value: &ValueKind, f: &mut impl FnMut(&VirPyObject)) {
+    visit_objects(value, f, &mut HashSet::new());
+}
+
+fn visit_objects(value: &ValueKind, f: &mut impl FnMut(&VirPyObject), seen: &mut HashSet<usize>) {
+    match value {
+        ValueKind::Object(obj) => f(obj),
+        ValueKind::Collection(items) if seen.insert(collection_key(items)) => {
+            for item in items.iter() {
+                visit_objects(item, f, seen);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn mapping_key(mapping: &Rc<RefCell<Mapping>>) -> usize {
+    Rc::as_ptr(mapping) as usize
+}
+
+fn collection_key(items: &Rc<Vec<ValueKind>>) -> usize {
+    Rc::as_ptr(items) as usize
+}
+
+/// Counts the references `value` holds to objects and collections, keyed by address, and keeps
+/// every collection it reaches in `collections`. A collection is shared by all of its holders,
+/// so its items are counted once however many attributes hold it.
+fn count_references(
+    value: &ValueKind,
+    internal: &mut HashMap<usize, usize>,
+    collections: &mut HashMap<usize, Rc<Vec<ValueKind>>>,
+) {
+    match value {
+        ValueKind::Object(obj) => *internal.entry(mapping_key(&obj.mapping)).or_default() += 1,
+        ValueKind::Collection(items) => {
+            let key = collection_key(items);
+            *internal.entry(key).or_default() += 1;
+            if let Entry::Vacant(entry) = collections.entry(key) {
+                entry.insert(items.clone());
+                for item in items.iter() {
+                    count_references(item, internal, collections);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Builds an [`ExecutionContext`] with its scopes and variables seeded up front, so that embedders
+/// never have to assemble `Mapping`s by hand.
+///
+/// ```
+/// use virtual_exec_type::base::ValueKind;
+/// use virtual_exec_type::builtin::VirPyInt;
+/// use virtual_exec_type::exec_ctx::ExecutionContext;
+///
+/// let ctx = ExecutionContext::builder()
+///     .ttl(1000)
+///     .variable("a", ValueKind::Int(VirPyInt::new(1)))
+///     .build();
+/// assert!(ctx.get("a").is_ok());
+/// ```
+#[derive(Debug, Default)]
+pub struct ExecutionContextBuilder {
+    ttl: Option<i64>,
+    options: ExecOptions,
+    operators: Option<OperatorTable>,
+    functions: HashMap<String, NativeFunction>,
+    global: Mapping,
+    scopes: Vec<Rc<RefCell<Mapping>>>,
+}
+
+impl ExecutionContextBuilder {
+    /// Sets the operation budget, unbounded by default. [`Limits::max_operations`] takes
+    /// precedence when both are given.
+    pub fn ttl(mut self, ttl: i64) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn options(mut self, options: ExecOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Replaces the default [`OperatorTable`], e.g. to restrict or extend the operators of one
+    /// tenant.
+    pub fn operators(mut self, operators: OperatorTable) -> Self {
+        self.operators = Some(operators);
+        self
+    }
+
+    /// Lets scripts call `function` as `name(args)`.
+    pub fn function(mut self, name: impl Into<String>, function: NativeFunction) -> Self {
+        self.functions.insert(name.into(), function);
+        self
+    }
+
+    /// Binds `name` to `value` in the global scope.
+    pub fn variable(mut self, name: impl Into<String>, value: ValueKind) -> Self {
+        self.global.mapping.insert(
+            name.into(),
+            Rc::new(RefCell::new(ValueContainer::new(value))),
+        );
+        self
+    }
+
+    /// Adds a scope nested inside the ones added before it, with the last one added becoming
+    /// the most local scope. The global scope is always the outermost one.
+    pub fn scope(mut self, scope: Rc<RefCell<Mapping>>) -> Self {
+        self.scopes.push(scope);
+        self
+    }
+
+    pub fn build(self) -> ExecutionContext {
+        let mut mapping: Vec<_> = self.scopes.into_iter().rev().collect();
+        mapping.push(Rc::new(RefCell::new(self.global)));
+        let mut ctx =
+            ExecutionContext::new(self.ttl.unwrap_or(i64::MAX), mapping).with_options(self.options);
+        if let Some(operators) = self.operators {
+            ctx.operators = operators;
+        }
+        ctx.functions = self.functions;
+        ctx
+    }
+}
+
+impl ExecutionContext {
+    pub fn builder() -> ExecutionContextBuilder {
+        ExecutionContextBuilder::default()
+    }
+
+    pub fn new(ttl: i64, mapping: Vec<Rc<RefCell<Mapping>>>) -> Self {
+        let mut ctx = Self {
             ttl,
             mapping,
+            options: ExecOptions::default(),
+            operators: OperatorTable::default(),
+            functions: HashMap::new(),
+            random_state: 0,
+            depth: 0,
+            deadline: None,
+            until_deadline_check: 0,
+            live_bytes: 0,
+            objects: HashMap::new(),
+            tracked_since_collection: 0,
+            journal: None,
+            locals: Vec::new(),
+            run_scopes: 0,
+            run_locals: 0,
+            run_depth: 0,
+        };
+        ctx.track_scopes();
+        ctx.recount_memory();
+        ctx
+    }
+
+    pub fn with_options(mut self, options: ExecOptions) -> Self {
+        if let Some(max_operations) = options.limits.max_operations {
+            self.ttl = max_operations;
+        }
+        self.random_state = options.deterministic.map_or(0, |deterministic| deterministic.seed);
+        self.options = options;
+        self
+    }
+
+    /// Refills the operation budget from [`Limits::max_operations`] so that the context can be
+    /// reused for another run.
+    pub fn reset_budget(&mut self) {
+        self.ttl = self.options.limits.max_operations.unwrap_or(i64::MAX);
+        self.depth = 0;
+    }
+
+    /// Lets scripts call `function` as `name(args)`, replacing the function registered under the
+    /// same name.
+    pub fn register_function(&mut self, name: impl Into<String>, function: NativeFunction) {
+        self.functions.insert(name.into(), function);
+    }
+
+    /// Replaces a NaN produced by an operator with the canonical one in deterministic mode, as
+    /// CPUs disagree on the sign and payload of the NaNs they produce.
+    pub(crate) fn settle(&self, value: ValueKind) -> ValueKind {
+        match value {
+            ValueKind::Float(f) if f.value.is_nan() && self.options.deterministic.is_some() => {
+                ValueKind::Float(VirPyFloat::new(f64::NAN))
+            }
+            value => value,
+        }
+    }
+
+    /// Adds `operations` to the remaining budget, e.g. to resume a suspended
+    /// [`Run`](crate::vm::Run).
+    pub fn top_up(&mut self, operations: i64) {
+        self.ttl = self.ttl.saturating_add(operations);
+    }
+
+    /// Starts the wall-clock budget from [`Limits::max_duration`], called when a run begins.
+    pub fn start_clock(&mut self) {
+        self.deadline = self
+            .options
+            .limits
+            .max_duration
+            .map(|duration| Instant::now() + duration);
+        self.until_deadline_check = 0;
+    }
+
+    pub fn enter_frame(&mut self) -> Result<()> {
+        self.check_frames(0)?;
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Fails as [`Self::enter_frame`] would with `frames` more frames entered, without entering
+    /// any. Lets the bytecode VM, which knows each node's depth in advance, skip the bookkeeping.
+    pub fn check_frames(&self, frames: usize) -> Result<()> {
+        if let Some(max_depth) = self.options.limits.max_nesting_depth
+            && self.depth + frames >= max_depth
+        {
+            return Err(SandboxExecutionError::NestingDepthExceededError);
+        }
+        Ok(())
+    }
+
+    pub fn leave_frame(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+    }
+
+    /// Bytes held by live data: the bindings of every scope and of every object attribute.
+    /// Values that are unbound or overwritten stop counting as soon as they are released. The
+    /// attributes of objects that scripts can no longer reach count until cycles are collected.
+    pub fn memory_used(&self) -> usize {
+        self.live_bytes
+    }
+
+    /// Checks that `extra` bytes fit next to the live data, collecting cycles once before
+    /// giving up.
+    fn check_memory(&mut self, extra: usize) -> Result<()> {
+        let Some(max_bytes) = self.options.limits.max_memory_bytes else {
+            return Ok(());
+        };
+        if self.live_bytes + extra <= max_bytes {
+            return Ok(());
+        }
+        self.collect_cycles();
+        if self.live_bytes + extra <= max_bytes {
+            return Ok(());
+        }
+        Err(SandboxExecutionError::MemoryLimitExceededError)
+    }
+
+    /// Checks a freshly produced value against the memory budget and the size limits that apply
+    /// to it. The value is only charged to [`Self::memory_used`] once it is bound. A collection
+    /// that is still shared with a binding, as read from a variable, is not new memory.
+    pub fn account_value(&mut self, value: &ValueKind) -> Result<()> {
+        let shared = matches!(value, ValueKind::Collection(items) if Rc::strong_count(items) > 1);
+        if !shared {
+            self.check_memory(value.heap_size())?;
+        }
+        let limits = &self.options.limits;
+        match value {
+            ValueKind::String(s) if limits.max_string_length.is_some_and(|max| s.len() > max) => {
+                Err(SandboxExecutionError::StringLengthExceededError)
+            }
+            ValueKind::Collection(items)
+                if limits.max_collection_length.is_some_and(|max| items.len() > max) =>
+            {
+                Err(SandboxExecutionError::CollectionLengthExceededError)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn charge(&mut self, added: Option<&ValueKind>, released: Option<&ValueKind>) {
+        if let Some(value) = released {
+            self.live_bytes = self.live_bytes.saturating_sub(released_size(value));
+        }
+        if let Some(value) = added {
+            self.live_bytes += binding_size(value);
+            self.track(value);
+        }
+    }
+
+    /// Registers the objects held by `value` (and, transitively, by their attributes) with the
+    /// cycle collector.
+    fn track(&mut self, value: &ValueKind) {
+        let mut pending = Vec::new();
+        for_each_object(value, &mut |obj| pending.push(obj.mapping.clone()));
+        while let Some(mapping) = pending.pop() {
+            let key = mapping_key(&mapping);
+            if self.objects.get(&key).and_then(Weak::upgrade).is_some() {
+                continue;
+            }
+            self.objects.insert(key, Rc::downgrade(&mapping));
+            self.tracked_since_collection += 1;
+            for cell in mapping.borrow().mapping.values() {
+                for_each_object(&cell.borrow().kind, &mut |obj| pending.push(obj.mapping.clone()));
+            }
+        }
+    }
+
+    /// Every binding of the scopes and block scopes, the roots of the live data.
+    fn scope_bindings(&self) -> Vec<Rc<RefCell<ValueContainer>>> {
+        let scopes = self
+            .mapping
+            .iter()
+            .flat_map(|scope| scope.borrow().mapping.values().cloned().collect::<Vec<_>>());
+        let locals = self.locals.iter().flat_map(|scope| scope.bindings.iter().flatten().cloned());
+        scopes.chain(locals).collect()
+    }
+
+    fn track_scopes(&mut self) {
+        for cell in self.scope_bindings() {
+            self.track(&cell.borrow().kind);
         }
     }
 
-    pub fn to_hashmap(&self) -> HashMap<String, RsValue> {
-        let mut dict = HashMap::new();
+    /// Recomputes [`Self::memory_used`] from everything reachable from the scopes, counting a
+    /// collection shared by several bindings once.
+    fn recount_memory(&mut self) {
+        let mut total = 0;
+        let mut visited = HashSet::new();
+        let mut counted = HashSet::new();
+        let mut pending = Vec::new();
+        for cell in self.scope_bindings() {
+            let value = &cell.borrow().kind;
+            total += size_of::<ValueContainer>() + value.heap_size_excluding(&mut counted);
+            for_each_object(value, &mut |obj| pending.push(obj.mapping.clone()));
+        }
+        while let Some(mapping) = pending.pop() {
+            if !visited.insert(mapping_key(&mapping)) {
+                continue;
+            }
+            for cell in mapping.borrow().mapping.values() {
+                let value = &cell.borrow().kind;
+                total += size_of::<ValueContainer>() + value.heap_size_excluding(&mut counted);
+                for_each_object(value, &mut |obj| pending.push(obj.mapping.clone()));
+            }
+        }
+        self.live_bytes = total;
+    }
+
+    /// Frees objects that are only kept alive by reference cycles among themselves, returning
+    /// how many were freed.
+    ///
+    /// Objects share their attributes through `Rc`, so `a.me = a; del a;` would otherwise leak.
+    /// This uses trial deletion: references held by tracked objects are subtracted from each
+    /// object's strong count, anything left over is an outside reference (a scope, a temporary
+    /// or the host), and whatever cannot be reached from such an object is garbage.
+    pub fn collect_cycles(&mut self) -> usize {
+        self.track_scopes();
+        let objects: Vec<Rc<RefCell<Mapping>>> =
+            self.objects.values().filter_map(Weak::upgrade).collect();
+
+        let mut internal: HashMap<usize, usize> = HashMap::new();
+        let mut collections = HashMap::new();
+        for mapping in &objects {
+            for cell in mapping.borrow().mapping.values() {
+                count_references(&cell.borrow().kind, &mut internal, &mut collections);
+            }
+        }
+
+        // `objects` and `collections` hold one strong reference of their own to each value
+        let outside =
+            |key: usize, strong: usize| strong - 1 > internal.get(&key).copied().unwrap_or(0);
+        let mut pending: Vec<Rc<RefCell<Mapping>>> = objects
+            .iter()
+            .filter(|mapping| outside(mapping_key(mapping), Rc::strong_count(mapping)))
+            .cloned()
+            .collect();
+        // A collection held from outside, e.g. by a scope, keeps the objects in it alive
+        for (key, items) in &collections {
+            if outside(*key, Rc::strong_count(items)) {
+                for item in items.iter() {
+                    for_each_object(item, &mut |obj| pending.push(obj.mapping.clone()));
+                }
+            }
+        }
+        drop(collections);
+        let mut reachable = HashSet::new();
+        while let Some(mapping) = pending.pop() {
+            if !reachable.insert(mapping_key(&mapping)) {
+                continue;
+            }
+            for cell in mapping.borrow().mapping.values() {
+                for_each_object(&cell.borrow().kind, &mut |obj| pending.push(obj.mapping.clone()));
+            }
+        }
+
+        let mut freed = 0;
+        for mapping in &objects {
+            if reachable.contains(&mapping_key(mapping)) {
+                continue;
+            }
+            let attributes = std::mem::take(&mut mapping.borrow_mut().mapping);
+            drop(attributes);
+            freed += 1;
+        }
+        drop(objects);
+        self.objects.retain(|_, mapping| mapping.strong_count() > 0);
+        self.tracked_since_collection = 0;
+        self.recount_memory();
+        freed
+    }
+
+    /// Collects cycles as [`Self::collect_cycles`] does once enough objects were tracked since
+    /// the last collection, called when a run ends. Runs that create few objects thus cost the
+    /// same whatever the amount of live data, and cycles they leave are freed by a later run or
+    /// once memory runs short.
+    pub(crate) fn collect_cycles_if_due(&mut self) {
+        if self.tracked_since_collection >= COLLECTION_THRESHOLD {
+            self.collect_cycles();
+        }
+    }
+
+    /// Starts journaling writes, so that [`Self::rollback`] can undo them. Runs do this
+    /// themselves under [`ExecOptions::transactional`], unless the host already started a
+    /// transaction, which is then left for the host to end. A transaction already in progress is
+    /// committed first.
+    pub fn begin_transaction(&mut self) {
+        self.journal = Some(Journal::default());
+    }
+
+    /// Keeps the writes of the transaction in progress, if any.
+    pub fn commit(&mut self) {
+        self.journal = None;
+    }
+
+    /// Undoes every write of the transaction in progress, if any, latest first.
+    pub fn rollback(&mut self) {
+        let Some(journal) = self.journal.take() else {
+            return;
+        };
+        for entry in journal.entries.into_iter().rev() {
+            match entry {
+                JournalEntry::Binding {
+                    mapping,
+                    name,
+                    previous,
+                } => {
+                    let mut mapping = mapping.borrow_mut();
+                    match previous {
+                        Some(previous) => mapping.mapping.insert(name, previous),
+                        None => mapping.mapping.remove(&name),
+                    };
+                }
+                JournalEntry::Value { binding, previous } => binding.borrow_mut().kind = previous,
+                JournalEntry::Declaration {
+                    mapping,
+                    name,
+                    previous,
+                } => {
+                    let mut mapping = mapping.borrow_mut();
+                    match previous {
+                        Some(previous) => mapping.declarations.insert(name, previous),
+                        None => mapping.declarations.remove(&name),
+                    };
+                }
+            }
+        }
+        self.recount_memory();
+    }
+
+    /// The transaction in progress, if any.
+    pub(crate) fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    /// Replaces the transaction in progress, e.g. with the one restored from a
+    /// [`Snapshot`](crate::vm::snapshot::Snapshot).
+    pub(crate) fn set_journal(&mut self, journal: Journal) {
+        self.journal = Some(journal);
+    }
+
+    /// Starts a run: records the scopes, block scopes and nesting depth to return to if it
+    /// panics, and starts a transaction for it when [`ExecOptions::transactional`] is set.
+    pub(crate) fn begin_run(&mut self) {
+        self.run_scopes = self.mapping.len();
+        self.run_locals = self.locals.len();
+        self.run_depth = self.depth;
+        if self.options.transactional && self.journal.is_none() {
+            self.journal = Some(Journal {
+                by_run: true,
+                ..Journal::default()
+            });
+        }
+    }
+
+    /// Returns to the scopes, block scopes and nesting depth recorded by [`Self::begin_run`],
+    /// after a panic unwound the run out of the blocks and frames it was inside of.
+    pub(crate) fn unwind_run(&mut self) {
+        while self.mapping.len() > self.run_scopes {
+            self.pop_scope();
+        }
+        while self.locals.len() > self.run_locals {
+            self.pop_local_scope();
+        }
+        self.depth = self.run_depth;
+    }
+
+    /// Ends the transaction started by [`Self::begin_run`], keeping its writes only if the run
+    /// `succeeded`.
+    pub(crate) fn end_run(&mut self, succeeded: bool) {
+        if !self.journal.as_ref().is_some_and(|journal| journal.by_run) {
+            return;
+        }
+        if succeeded {
+            self.commit();
+        } else {
+            self.rollback();
+        }
+    }
+
+    /// Saves the value of `binding` before it is changed in place, the first time it is within
+    /// the transaction in progress.
+    pub(crate) fn journal_value(&mut self, binding: &Rc<RefCell<ValueContainer>>) {
+        if let Some(journal) = &mut self.journal
+            && journal.saved.insert(Rc::as_ptr(binding) as usize)
+        {
+            journal.entries.push(JournalEntry::Value {
+                binding: binding.clone(),
+                previous: binding.borrow().kind.clone(),
+            });
+        }
+    }
+
+    /// Records that `name` was bound to `previous` in `mapping` before being rebound or removed.
+    fn journal_binding(
+        &mut self,
+        mapping: &Rc<RefCell<Mapping>>,
+        name: &str,
+        previous: Option<Rc<RefCell<ValueContainer>>>,
+    ) {
+        if let Some(journal) = &mut self.journal {
+            journal.entries.push(JournalEntry::Binding {
+                mapping: mapping.clone(),
+                name: name.to_string(),
+                previous,
+            });
+        }
+    }
+
+    /// Replaces every scope and block scope, e.g. with the ones restored from a
+    /// [`Snapshot`](crate::vm::snapshot::Snapshot), and recounts the memory they hold.
+    pub(crate) fn replace_scopes(
+        &mut self,
+        mapping: Vec<Rc<RefCell<Mapping>>>,
+        locals: Vec<LocalScope>,
+    ) {
+        self.mapping = mapping;
+        self.locals = locals;
+        self.objects.clear();
+        self.track_scopes();
+        self.recount_memory();
+    }
+
+    pub fn push_scope(&mut self) {
+        self.mapping.insert(0, Rc::new(RefCell::new(Mapping::new())));
+    }
+
+    pub fn pop_scope(&mut self) -> Option<Rc<RefCell<Mapping>>> {
+        if self.mapping.is_empty() {
+            return None;
+        }
+        let scope = self.mapping.remove(0);
+        for cell in scope.borrow().mapping.values() {
+            self.charge(None, Some(&cell.borrow().kind));
+        }
+        Some(scope)
+    }
+
+    /// The number of scopes code running in the context sees, counting the block scopes a VM run
+    /// is inside of, which are not in `mapping`.
+    pub fn scope_count(&self) -> usize {
+        self.mapping.len() + self.locals.len()
+    }
+
+    /// Enters a block scope of a VM run, whose variables are named by `names`.
+    pub(crate) fn push_local_scope(&mut self, names: Rc<[String]>) {
+        self.locals.push(LocalScope::new(names));
+    }
+
+    pub(crate) fn pop_local_scope(&mut self) {
+        let Some(scope) = self.locals.pop() else {
+            return;
+        };
+        for cell in scope.bindings.iter().flatten() {
+            self.charge(None, Some(&cell.borrow().kind));
+        }
+    }
+
+    /// Binds the variable in `slot` of the innermost block scope to `value`.
+    pub(crate) fn bind_local(&mut self, slot: usize, value: ValueKind) {
+        self.charge(Some(&value), None);
+        let binding = Rc::new(RefCell::new(ValueContainer::new(value)));
+        if let Some(scope) = self.locals.last_mut() {
+            scope.bindings[slot] = Some(binding);
+        }
+    }
+
+    /// Unbinds the variable in `slot` of the block scope at `scope` in `locals`.
+    pub(crate) fn remove_local(&mut self, scope: usize, slot: usize) {
+        if let Some(previous) = self.locals[scope].bindings[slot].take() {
+            self.charge(None, Some(&previous.borrow().kind));
+        }
+    }
+
+    /// Every variable bound in the scopes of the context, in sorted order, with the innermost
+    /// binding of a name shadowing the outer ones. The variables of the block scopes a VM run is
+    /// inside of get their names back from the program.
+    pub fn to_btreemap(&self) -> BTreeMap<String, RsValue> {
+        let mut dict = BTreeMap::new();
         for scope_rc in self.mapping.iter().rev() {
             let scope = scope_rc.borrow();
             for (key, value_rc) in scope.mapping.iter() {
@@ -84,6 +978,11 @@ impl<'ctx> ExecutionContext<'ctx> {
                 dict.insert(key.clone(), value_kind_to_rs_value(&value_ref.kind));
             }
         }
+        for scope in &self.locals {
+            for (key, value_rc) in scope.named() {
+                dict.insert(key.clone(), value_kind_to_rs_value(&value_rc.borrow().kind));
+            }
+        }
         dict
     }
 
@@ -96,17 +995,92 @@ impl<'ctx> ExecutionContext<'ctx> {
             return Err(SandboxExecutionError::TimeoutError);
         }
         self.ttl -= amount;
-        Ok(())
+        if let Some(deadline) = self.deadline {
+            self.until_deadline_check -= amount;
+            if self.until_deadline_check <= 0 {
+                self.until_deadline_check = DEADLINE_CHECK_EVERY;
+                if Instant::now() >= deadline {
+                    return Err(SandboxExecutionError::DeadlineExceededError);
+                }
+            }
+        }
+        self.check_memory(0)
     }
 
-    pub fn get(&self, name: &str) -> Result<Rc<RefCell<Value<'ctx>>>> {
-        let mut r: Option<Rc<RefCell<Value<'ctx>>>> = None;
-        for mapping in self.mapping.clone() {
-            if mapping.borrow().mapping.contains_key(name) {
-                r = Some(mapping.borrow().mapping.get(name).unwrap().clone());
-                break; // Found in the most local scope, stop searching
+    /// Returns the position in `mapping` of the scope that a `global`/`nonlocal` declaration in
+    /// the current scope redirects `name` to, or `None` if the name was not declared.
+    pub(crate) fn declared_scope(&self, name: &str) -> Result<Option<usize>> {
+        let declaration = match self.mapping.first() {
+            Some(local) => local.borrow().declarations.get(name).copied(),
+            None => None,
+        };
+        match declaration {
+            None => Ok(None),
+            Some(Declaration::Global) => Ok(Some(self.mapping.len() - 1)),
+            Some(Declaration::Nonlocal) => {
+                let enclosing = self.mapping.len().saturating_sub(1);
+                match self.mapping[..enclosing]
+                    .iter()
+                    .skip(1)
+                    .position(|scope| scope.borrow().mapping.contains_key(name))
+                {
+                    Some(idx) => Ok(Some(idx + 1)),
+                    None => Err(SandboxExecutionError::ReferenceNotExistError(
+                        name.to_string(),
+                    )),
+                }
             }
         }
+    }
+
+    /// Records a `global` or `nonlocal` declaration for `name` in the most local scope.
+    pub fn declare(&mut self, name: &str, declaration: Declaration) -> Result<()> {
+        let Some(local) = self.mapping.first() else {
+            return Err(SandboxExecutionError::ReferenceNotExistError(
+                name.to_string(),
+            ));
+        };
+        if self.mapping.len() == 1 {
+            // `global` is a no-op at the top level, while there is nothing for `nonlocal` to bind to
+            return match declaration {
+                Declaration::Global => Ok(()),
+                Declaration::Nonlocal => Err(SandboxExecutionError::InvalidSyntaxError),
+            };
+        }
+        if local.borrow().mapping.contains_key(name) {
+            return Err(SandboxExecutionError::InvalidSyntaxError);
+        }
+        let previous = local
+            .borrow_mut()
+            .declarations
+            .insert(name.to_string(), declaration);
+        if let Some(journal) = &mut self.journal {
+            journal.entries.push(JournalEntry::Declaration {
+                mapping: local.clone(),
+                name: name.to_string(),
+                previous,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<Rc<RefCell<ValueContainer>>> {
+        Ok(self.resolve(name)?.1)
+    }
+
+    /// Returns the binding `name` resolves to from the current scope, along with the position in
+    /// `mapping` of the scope holding it.
+    pub fn resolve(&self, name: &str) -> Result<(usize, Rc<RefCell<ValueContainer>>)> {
+        let r = match self.declared_scope(name)? {
+            Some(idx) => self.mapping[idx]
+                .borrow()
+                .mapping
+                .get(name)
+                .map(|binding| (idx, binding.clone())),
+            None => self.mapping.iter().enumerate().find_map(|(idx, mapping)| {
+                mapping.borrow().mapping.get(name).map(|binding| (idx, binding.clone()))
+            }),
+        };
         match r {
             Some(v) => Ok(v),
             None => Err(SandboxExecutionError::ReferenceNotExistError(
@@ -115,30 +1089,168 @@ impl<'ctx> ExecutionContext<'ctx> {
         }
     }
 
+    /// Binds `name` to `value` in the scope it resolves to, or in the most local scope when it
+    /// is not bound yet.
     pub fn get_ignore_missing(
         &mut self,
         name: &str,
-        value: Value<'ctx>,
-    ) -> Result<Rc<RefCell<Value<'ctx>>>> {
-        for mapping in &self.mapping {
-            if mapping.borrow().mapping.contains_key(name) {
-                let r = mapping.borrow().mapping.get(name).unwrap().clone();
-                r.replace(value);
-                return Ok(r);
+        value: ValueKind,
+    ) -> Result<Rc<RefCell<ValueContainer>>> {
+        let target = match self.declared_scope(name)? {
+            Some(idx) => idx,
+            None => match self.find(0..self.mapping.len(), name) {
+                Some((idx, _)) => idx,
+                None if !self.mapping.is_empty() => 0,
+                None => {
+                    return Err(SandboxExecutionError::ReferenceNotExistError(
+                        name.to_string(),
+                    ));
+                }
+            },
+        };
+        Ok(self.bind_in(target, name, value))
+    }
+
+    /// Returns the binding of `name` in the most local of the scopes at `scopes` in `mapping`
+    /// that holds one, along with the position of that scope, ignoring declarations.
+    pub(crate) fn find(
+        &self,
+        scopes: Range<usize>,
+        name: &str,
+    ) -> Option<(usize, Rc<RefCell<ValueContainer>>)> {
+        self.mapping[scopes.clone()]
+            .iter()
+            .zip(scopes)
+            .find_map(|(mapping, idx)| Some((idx, mapping.borrow().mapping.get(name)?.clone())))
+    }
+
+    /// Binds `name` to `value` in the scope at `idx` in `mapping`, rebinding it if it is bound
+    /// there already.
+    pub(crate) fn bind_in(
+        &mut self,
+        idx: usize,
+        name: &str,
+        value: ValueKind,
+    ) -> Rc<RefCell<ValueContainer>> {
+        let target = self.mapping[idx].clone();
+        let existing = target.borrow().mapping.get(name).cloned();
+        if let Some(r) = existing {
+            self.rebind(&r, value);
+            return r;
+        }
+        self.charge(Some(&value), None);
+        self.journal_binding(&target, name, None);
+        let new_value = Rc::new(RefCell::new(ValueContainer::new(value)));
+        target
+            .borrow_mut()
+            .mapping
+            .insert(name.to_string(), new_value.clone());
+        new_value
+    }
+
+    /// Replaces the value of an existing binding, charging the difference.
+    pub fn rebind(&mut self, binding: &Rc<RefCell<ValueContainer>>, value: ValueKind) {
+        self.journal_value(binding);
+        let previous = binding.replace(ValueContainer::new(value));
+        self.charge(Some(&binding.borrow().kind), Some(&previous.kind));
+    }
+
+    /// Binds `name` to `value` in the global scope, as done by the host between runs.
+    pub fn set_global(&mut self, name: impl Into<String>, value: ValueKind) {
+        let Some(global) = self.mapping.last().cloned() else {
+            return;
+        };
+        self.charge(Some(&value), None);
+        let name = name.into();
+        let previous = global
+            .borrow_mut()
+            .mapping
+            .insert(name.clone(), Rc::new(RefCell::new(ValueContainer::new(value))));
+        if let Some(previous) = &previous {
+            self.charge(None, Some(&previous.borrow().kind));
+        }
+        self.journal_binding(&global, &name, previous);
+    }
+
+    /// Accounts for a value nested in a binding, such as one element of a collection, being
+    /// replaced by `value` (or removed when `value` is `None`) without rebinding the whole value.
+    pub fn charge_nested(&mut self, previous: Option<&ValueKind>, value: Option<&ValueKind>) {
+        if let Some(previous) = previous {
+            self.live_bytes = self.live_bytes.saturating_sub(owned_heap_size(previous));
+        }
+        if let Some(value) = value {
+            self.live_bytes += value.heap_size();
+            self.track(value);
+        }
+    }
+
+    /// Like [`Rc::make_mut`], charging the copy made of a collection that is shared with other
+    /// values, as writing to one alias of a collection leaves the others as they were.
+    pub fn make_mut<'a>(&mut self, items: &'a mut Rc<Vec<ValueKind>>) -> &'a mut Vec<ValueKind> {
+        if Rc::strong_count(items) > 1 {
+            // The copy shares the collections in it, and copies everything else
+            let copied: usize = items
+                .iter()
+                .map(|item| match item {
+                    ValueKind::Collection(_) => 0,
+                    item => item.heap_size(),
+                })
+                .sum();
+            self.live_bytes += items.len() * size_of::<ValueKind>() + copied;
+        }
+        Rc::make_mut(items)
+    }
+
+    /// Sets an attribute of `obj`, charging it against the memory budget like any other binding.
+    pub fn set_attribute(&mut self, obj: &VirPyObject, key: String, value: ValueKind) {
+        self.charge(Some(&value), None);
+        let previous = obj.get(&key);
+        if let Some(previous) = &previous {
+            self.charge(None, Some(&previous.borrow().kind));
+        }
+        self.journal_binding(&obj.mapping, &key, previous);
+        obj.set(key, value);
+    }
+
+    /// Removes an attribute of `obj`, returning whether it existed.
+    pub fn remove_attribute(&mut self, obj: &VirPyObject, key: &str) -> bool {
+        match obj.remove(key) {
+            Some(previous) => {
+                self.charge(None, Some(&previous.borrow().kind));
+                self.journal_binding(&obj.mapping, key, Some(previous));
+                true
             }
+            None => false,
         }
+    }
 
-        if self.mapping.is_empty() {
-            return Err(SandboxExecutionError::ReferenceNotExistError(
+    /// Unbinds `name` from the scope it resolves to, as done by `del name`.
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        let scope = match self.declared_scope(name)? {
+            Some(idx) => Some(idx),
+            None => self.find(0..self.mapping.len(), name).map(|(idx, _)| idx),
+        };
+        match scope {
+            Some(idx) => self.remove_from(idx, name),
+            None => Err(SandboxExecutionError::ReferenceNotExistError(
                 name.to_string(),
-            ));
+            )),
         }
+    }
 
-        let new_value = Rc::new(RefCell::new(value));
-        self.mapping[0]
-            .borrow_mut()
-            .mapping
-            .insert(name.to_string(), new_value.clone());
-        Ok(new_value)
+    /// Unbinds `name` from the scope at `idx` in `mapping`.
+    pub(crate) fn remove_from(&mut self, idx: usize, name: &str) -> Result<()> {
+        let scope = self.mapping[idx].clone();
+        let previous = scope.borrow_mut().mapping.remove(name);
+        match previous {
+            Some(previous) => {
+                self.charge(None, Some(&previous.borrow().kind));
+                self.journal_binding(&scope, name, Some(previous));
+                Ok(())
+            }
+            None => Err(SandboxExecutionError::ReferenceNotExistError(
+                name.to_string(),
+            )),
+        }
     }
 }
@@ -1,5 +1,303 @@
+use crate::base::ValueKind;
+use crate::exec_ctx::{value_kind_to_rs_value, RsValue};
+
 /// A trait for types that can be exported to a standard Rust type `T`.
 pub trait Export<T> {
     /// Performs the conversion.
     fn export(&self) -> T;
 }
+
+impl Export<RsValue> for ValueKind {
+    fn export(&self) -> RsValue {
+        value_kind_to_rs_value(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+pub use self::serde_support::{from_value, DeserializeError, ValueDeserializer};
+#[cfg(feature = "serde")]
+pub use serde::de::DeserializeOwned;
+
+#[cfg(feature = "serde")]
+mod serde_support {
+    use super::Export;
+    use crate::base::ValueKind;
+    use crate::exec_ctx::RsValue;
+    use serde::de::value::{MapDeserializer, SeqDeserializer};
+    use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+    use serde::ser::{SerializeMap, SerializeSeq};
+    use serde::{forward_to_deserialize_any, Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+    use std::fmt;
+    use std::rc::Rc;
+
+    /// Raised when a sandbox value does not have the shape of the requested Rust type.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct DeserializeError(String);
+
+    impl fmt::Display for DeserializeError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(&self.0)
+        }
+    }
+
+    impl std::error::Error for DeserializeError {}
+
+    impl de::Error for DeserializeError {
+        fn custom<T: fmt::Display>(msg: T) -> Self {
+            DeserializeError(msg.to_string())
+        }
+    }
+
+    /// Deserializes any `T` straight from a sandbox value, without going through [`RsValue`].
+    pub fn from_value<T: DeserializeOwned>(value: &ValueKind) -> Result<T, DeserializeError> {
+        T::deserialize(ValueDeserializer::new(value))
+    }
+
+    impl<T: DeserializeOwned> Export<Result<T, DeserializeError>> for ValueKind {
+        fn export(&self) -> Result<T, DeserializeError> {
+            from_value(self)
+        }
+    }
+
+    /// A serde [`Deserializer`] reading a [`ValueKind`], e.g. one bound in a finished
+    /// `ExecutionContext`. Objects are read as maps of their attributes and collections as
+    /// sequences. An object that holds itself, directly or not, fails with "cyclic object".
+    pub struct ValueDeserializer<'a> {
+        value: &'a ValueKind,
+        /// The objects `value` is nested in.
+        reading: Option<&'a Reading<'a>>,
+    }
+
+    /// An object being read, linked to the one it is nested in.
+    struct Reading<'a> {
+        /// The address of the object's mapping.
+        mapping: usize,
+        outer: Option<&'a Reading<'a>>,
+    }
+
+    impl<'a> ValueDeserializer<'a> {
+        pub fn new(value: &'a ValueKind) -> Self {
+            Self {
+                value,
+                reading: None,
+            }
+        }
+
+        fn nested(value: &'a ValueKind, reading: Option<&'a Reading<'a>>) -> Self {
+            Self { value, reading }
+        }
+    }
+
+    impl<'de> IntoDeserializer<'de, DeserializeError> for ValueDeserializer<'_> {
+        type Deserializer = Self;
+
+        fn into_deserializer(self) -> Self {
+            self
+        }
+    }
+
+    impl<'de> Deserializer<'de> for ValueDeserializer<'_> {
+        type Error = DeserializeError;
+
+        fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+            match self.value {
+                ValueKind::Int(i) => visitor.visit_i64(i.value),
+                ValueKind::Float(f) => visitor.visit_f64(f.value),
+                ValueKind::Bool(b) => visitor.visit_bool(*b),
+                ValueKind::String(s) => visitor.visit_str(s),
+                ValueKind::None => visitor.visit_unit(),
+                ValueKind::Collection(items) => {
+                    let mut seq = SeqDeserializer::new(
+                        items
+                            .iter()
+                            .map(|item| ValueDeserializer::nested(item, self.reading)),
+                    );
+                    let value = visitor.visit_seq(&mut seq)?;
+                    seq.end()?;
+                    Ok(value)
+                }
+                ValueKind::Object(obj) => {
+                    let mapping = Rc::as_ptr(&obj.mapping) as usize;
+                    let mut outer = self.reading;
+                    while let Some(reading) = outer {
+                        if reading.mapping == mapping {
+                            return Err(de::Error::custom("cyclic object"));
+                        }
+                        outer = reading.outer;
+                    }
+                    let reading = Reading {
+                        mapping,
+                        outer: self.reading,
+                    };
+                    let entries: Vec<(String, ValueKind)> = obj
+                        .mapping
+                        .borrow()
+                        .sorted()
+                        .into_iter()
+                        .map(|(key, value)| (key.clone(), value.borrow().kind.clone()))
+                        .collect();
+                    let mut map = MapDeserializer::new(
+                        entries
+                            .iter()
+                            .map(|(key, value)| {
+                                (key.as_str(), ValueDeserializer::nested(value, Some(&reading)))
+                            }),
+                    );
+                    let value = visitor.visit_map(&mut map)?;
+                    map.end()?;
+                    Ok(value)
+                }
+                ValueKind::Native(n) => {
+                    let entries: Vec<(&'static str, ValueKind)> = n
+                        .attribute_names()
+                        .into_iter()
+                        .filter_map(|name| Some((name, n.get_attribute(name)?)))
+                        .collect();
+                    let mut map = MapDeserializer::new(
+                        entries
+                            .iter()
+                            .map(|(key, value)| {
+                                (*key, ValueDeserializer::nested(value, self.reading))
+                            }),
+                    );
+                    let value = visitor.visit_map(&mut map)?;
+                    map.end()?;
+                    Ok(value)
+                }
+                ValueKind::ErrorWrapped(err) => Err(de::Error::custom(format!(
+                    "cannot deserialize the error {err:?}"
+                ))),
+            }
+        }
+
+        fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+            match self.value {
+                ValueKind::None => visitor.visit_none(),
+                _ => visitor.visit_some(self),
+            }
+        }
+
+        fn deserialize_enum<V: Visitor<'de>>(
+            self,
+            name: &'static str,
+            variants: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            match self.value {
+                ValueKind::String(s) => {
+                    visitor.visit_enum(IntoDeserializer::<DeserializeError>::into_deserializer(
+                        s.as_str(),
+                    ))
+                }
+                _ => self.deserialize_any(visitor).map_err(|_| {
+                    de::Error::custom(format!("expected one of {variants:?} for {name}"))
+                }),
+            }
+        }
+
+        forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf unit unit_struct newtype_struct seq tuple
+            tuple_struct map struct identifier ignored_any
+        }
+    }
+
+    impl Serialize for RsValue {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self {
+                RsValue::Int(i) => serializer.serialize_i64(*i),
+                RsValue::Float(f) => serializer.serialize_f64(*f),
+                RsValue::Bool(b) => serializer.serialize_bool(*b),
+                RsValue::String(s) => serializer.serialize_str(s),
+                RsValue::None => serializer.serialize_none(),
+                RsValue::Vector(items) => {
+                    let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                    for item in items {
+                        seq.serialize_element(item)?;
+                    }
+                    seq.end()
+                }
+                RsValue::Object(fields) => {
+                    let mut fields: Vec<_> = fields.iter().collect();
+                    fields.sort_by(|a, b| a.0.cmp(b.0));
+                    let mut map = serializer.serialize_map(Some(fields.len()))?;
+                    for (key, value) in fields {
+                        map.serialize_entry(key, value)?;
+                    }
+                    map.end()
+                }
+            }
+        }
+    }
+
+    struct RsValueVisitor;
+
+    impl<'de> Visitor<'de> for RsValueVisitor {
+        type Value = RsValue;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a value representable in the sandbox")
+        }
+
+        fn visit_bool<E: de::Error>(self, v: bool) -> Result<RsValue, E> {
+            Ok(RsValue::Bool(v))
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<RsValue, E> {
+            Ok(RsValue::Int(v))
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<RsValue, E> {
+            i64::try_from(v)
+                .map(RsValue::Int)
+                .map_err(|_| E::custom(format!("integer {v} does not fit in an i64")))
+        }
+
+        fn visit_f64<E: de::Error>(self, v: f64) -> Result<RsValue, E> {
+            Ok(RsValue::Float(v))
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<RsValue, E> {
+            Ok(RsValue::String(v.to_string()))
+        }
+
+        fn visit_string<E: de::Error>(self, v: String) -> Result<RsValue, E> {
+            Ok(RsValue::String(v))
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<RsValue, E> {
+            Ok(RsValue::None)
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<RsValue, E> {
+            Ok(RsValue::None)
+        }
+
+        fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<RsValue, D::Error> {
+            RsValue::deserialize(deserializer)
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<RsValue, A::Error> {
+            let mut items = Vec::new();
+            while let Some(item) = seq.next_element()? {
+                items.push(item);
+            }
+            Ok(RsValue::Vector(items))
+        }
+
+        fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<RsValue, A::Error> {
+            let mut fields = BTreeMap::new();
+            while let Some((key, value)) = map.next_entry()? {
+                fields.insert(key, value);
+            }
+            Ok(RsValue::Object(fields))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for RsValue {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_any(RsValueVisitor)
+        }
+    }
+}
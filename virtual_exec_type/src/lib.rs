@@ -1,3 +1,5 @@
+#![forbid(unsafe_code)]
+
 #[macro_use]
 pub mod op;
 
@@ -7,4 +9,7 @@ pub mod builtin;
 pub mod error;
 pub mod exec_ctx;
 pub mod export;
+pub mod native;
 mod op_impl;
+pub mod stdlib;
+pub mod vm;
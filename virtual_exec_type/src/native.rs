@@ -0,0 +1,254 @@
+use crate::base::{Upcast, ValueKind};
+use crate::builtin::{VirPyFloat, VirPyInt};
+use crate::error::{Result, SandboxExecutionError};
+use std::any::Any;
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+
+/// A Rust value exposed to scripts, usually implemented with `#[derive(SandboxObject)]` from
+/// `virtual_exec_macro`.
+///
+/// Like collections, native values have value semantics: assigning to an attribute or calling a
+/// method works on a copy that is then stored back to where the value came from, so the host's
+/// own copy is never modified behind its back.
+pub trait SandboxObject: Any {
+    /// The name matched by class patterns in `match`.
+    fn type_name(&self) -> &'static str;
+    fn as_any(&self) -> &dyn Any;
+    fn clone_object(&self) -> Box<dyn SandboxObject>;
+    /// Names of the attributes visible to scripts.
+    fn attribute_names(&self) -> Vec<&'static str>;
+    /// Reads a visible attribute, `None` if it is hidden or does not exist.
+    fn get_attribute(&self, name: &str) -> Option<ValueKind>;
+    fn set_attribute(&mut self, name: &str, value: ValueKind) -> Result<()>;
+    fn call_method(&mut self, name: &str, args: Vec<ValueKind>) -> Result<ValueKind> {
+        let _ = (name, args);
+        Err(SandboxExecutionError::AttributeNotFoundError)
+    }
+}
+
+/// Method bindings of a [`SandboxObject`], usually generated by `#[sandbox_methods]`.
+pub trait SandboxMethods {
+    fn call_method(&mut self, name: &str, args: Vec<ValueKind>) -> Result<ValueKind>;
+}
+
+/// A shared handle to an immutable [`SandboxObject`], as held by `ValueKind::Native`.
+#[derive(Clone)]
+pub struct NativeObject {
+    inner: Rc<dyn SandboxObject>,
+}
+
+impl NativeObject {
+    pub fn new<T: SandboxObject>(value: T) -> Self {
+        Self {
+            inner: Rc::new(value),
+        }
+    }
+
+    pub fn from_box(value: Box<dyn SandboxObject>) -> Self {
+        Self {
+            inner: Rc::from(value),
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.inner.type_name()
+    }
+
+    pub fn downcast_ref<T: SandboxObject>(&self) -> Option<&T> {
+        self.inner.as_any().downcast_ref()
+    }
+
+    pub fn get_attribute(&self, name: &str) -> Option<ValueKind> {
+        self.inner.get_attribute(name)
+    }
+
+    pub fn attribute_names(&self) -> Vec<&'static str> {
+        self.inner.attribute_names()
+    }
+
+    /// Returns a copy with `name` set to `value`.
+    pub fn with_attribute(&self, name: &str, value: ValueKind) -> Result<Self> {
+        let mut copy = self.inner.clone_object();
+        copy.set_attribute(name, value)?;
+        Ok(Self::from_box(copy))
+    }
+
+    /// Calls a method on a copy, returning the updated copy along with the method's result.
+    pub fn call_method(&self, name: &str, args: Vec<ValueKind>) -> Result<(Self, ValueKind)> {
+        let mut copy = self.inner.clone_object();
+        let result = copy.call_method(name, args)?;
+        Ok((Self::from_box(copy), result))
+    }
+}
+
+impl Debug for NativeObject {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NativeObject({})", self.type_name())
+    }
+}
+
+/// What an async [`NativeFunction`] returns.
+pub type NativeFuture = Pin<Box<dyn Future<Output = Result<ValueKind>>>>;
+
+/// A host function that scripts call by name, as in `lookup(key)`, registered with
+/// [`ExecutionContext::register_function`](crate::exec_ctx::ExecutionContext::register_function).
+#[derive(Clone)]
+pub enum NativeFunction {
+    /// Called with the context not borrowed by either engine, so that it may borrow the context.
+    Sync(Rc<dyn Fn(Vec<ValueKind>) -> Result<ValueKind>>),
+    /// Only callable from an async run, which waits for the future without blocking the
+    /// executor. Other runs fail with `AsyncFunctionError`.
+    Async(Rc<dyn Fn(Vec<ValueKind>) -> NativeFuture>),
+}
+
+impl NativeFunction {
+    pub fn new<F>(function: F) -> Self
+    where
+        F: Fn(Vec<ValueKind>) -> Result<ValueKind> + 'static,
+    {
+        NativeFunction::Sync(Rc::new(function))
+    }
+
+    pub fn new_async<F, Fut>(function: F) -> Self
+    where
+        F: Fn(Vec<ValueKind>) -> Fut + 'static,
+        Fut: Future<Output = Result<ValueKind>> + 'static,
+    {
+        NativeFunction::Async(Rc::new(move |args| Box::pin(function(args))))
+    }
+}
+
+impl Debug for NativeFunction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NativeFunction::Sync(_) => f.write_str("NativeFunction::Sync"),
+            NativeFunction::Async(_) => f.write_str("NativeFunction::Async"),
+        }
+    }
+}
+
+/// Conversion from a script value into an owned Rust value, used for native attributes and
+/// method arguments.
+pub trait FromValueKind: Sized {
+    fn from_value_kind(value: ValueKind) -> Result<Self>;
+}
+
+impl FromValueKind for ValueKind {
+    fn from_value_kind(value: ValueKind) -> Result<Self> {
+        Ok(value)
+    }
+}
+
+impl FromValueKind for i64 {
+    fn from_value_kind(value: ValueKind) -> Result<Self> {
+        match value {
+            ValueKind::Int(i) => Ok(i.value),
+            _ => Err(SandboxExecutionError::InvalidTypeError),
+        }
+    }
+}
+
+impl FromValueKind for f64 {
+    fn from_value_kind(value: ValueKind) -> Result<Self> {
+        match value {
+            ValueKind::Float(f) => Ok(f.value),
+            ValueKind::Int(i) => Ok(i.value as f64),
+            _ => Err(SandboxExecutionError::InvalidTypeError),
+        }
+    }
+}
+
+impl FromValueKind for VirPyInt {
+    fn from_value_kind(value: ValueKind) -> Result<Self> {
+        i64::from_value_kind(value).map(VirPyInt::new)
+    }
+}
+
+impl FromValueKind for VirPyFloat {
+    fn from_value_kind(value: ValueKind) -> Result<Self> {
+        f64::from_value_kind(value).map(VirPyFloat::new)
+    }
+}
+
+impl FromValueKind for bool {
+    fn from_value_kind(value: ValueKind) -> Result<Self> {
+        match value {
+            ValueKind::Bool(b) => Ok(b),
+            _ => Err(SandboxExecutionError::InvalidTypeError),
+        }
+    }
+}
+
+impl FromValueKind for String {
+    fn from_value_kind(value: ValueKind) -> Result<Self> {
+        match value {
+            ValueKind::String(s) => Ok(s),
+            _ => Err(SandboxExecutionError::InvalidTypeError),
+        }
+    }
+}
+
+impl FromValueKind for () {
+    fn from_value_kind(value: ValueKind) -> Result<Self> {
+        match value {
+            ValueKind::None => Ok(()),
+            _ => Err(SandboxExecutionError::InvalidTypeError),
+        }
+    }
+}
+
+impl<T: FromValueKind> FromValueKind for Option<T> {
+    fn from_value_kind(value: ValueKind) -> Result<Self> {
+        match value {
+            ValueKind::None => Ok(None),
+            value => T::from_value_kind(value).map(Some),
+        }
+    }
+}
+
+impl<T: FromValueKind> FromValueKind for Vec<T> {
+    fn from_value_kind(value: ValueKind) -> Result<Self> {
+        match value {
+            ValueKind::Collection(items) => Rc::unwrap_or_clone(items)
+                .into_iter()
+                .map(T::from_value_kind)
+                .collect(),
+            _ => Err(SandboxExecutionError::InvalidTypeError),
+        }
+    }
+}
+
+impl Upcast for i64 {
+    fn from_value(&self) -> ValueKind {
+        ValueKind::Int(VirPyInt::new(*self))
+    }
+}
+
+impl Upcast for f64 {
+    fn from_value(&self) -> ValueKind {
+        ValueKind::Float(VirPyFloat::new(*self))
+    }
+}
+
+impl<T: Upcast> Upcast for Option<T> {
+    fn from_value(&self) -> ValueKind {
+        match self {
+            Some(value) => value.from_value(),
+            None => ValueKind::None,
+        }
+    }
+}
+
+/// Checks the number of arguments given to a native method.
+pub fn expect_arguments(args: &[ValueKind], expected: usize) -> Result<()> {
+    if args.len() != expected {
+        return Err(SandboxExecutionError::ArgumentCountError {
+            expected,
+            actual: args.len(),
+        });
+    }
+    Ok(())
+}
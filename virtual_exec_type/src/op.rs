@@ -1,10 +1,12 @@
-use crate::base::Value;
-use bumpalo::Bump;
+use crate::ast::core::{BinaryOperator, UnaryOperator};
+use crate::base::{Value, ValueKind, ValueTag};
+use crate::error::{Result, SandboxExecutionError};
+use std::fmt::{Debug, Formatter};
+use std::rc::Rc;
 
-type BinaryOpFn =
-    for<'ctx> fn(lhs: Value<'ctx>, rhs: Value<'ctx>, arena: &'ctx Bump) -> Option<Value<'ctx>>;
+type BinaryOpFn = for<'ctx> fn(lhs: Value<'ctx>, rhs: Value<'ctx>) -> Option<ValueKind>;
 
-type UnaryOpFn = for<'ctx> fn(rhs: Value<'ctx>, arena: &'ctx Bump) -> Option<Value<'ctx>>;
+type UnaryOpFn = for<'ctx> fn(rhs: Value<'ctx>) -> Option<ValueKind>;
 
 #[macro_export]
 macro_rules! __binary_op_register {
@@ -19,60 +21,49 @@ macro_rules! __binary_op_register {
             fn _op_impl<'ctx>(
                 lhs: $crate::base::Value<'ctx>,
                 rhs: $crate::base::Value<'ctx>,
-                arena: &'ctx ::bumpalo::Bump,
-            ) -> Option<$crate::base::Value<'ctx>> {
+            ) -> Option<$crate::base::ValueKind> {
                 let lhs_val = <$lhs_type as $crate::base::Downcast>::from_value(lhs)?;
                 let rhs_val = <$rhs_type as $crate::base::Downcast>::from_value(rhs)?;
                 match $func(lhs_val.clone(), rhs_val.clone()) {
-                    Ok(result) => Some($crate::base::ValueContainer::new(
-                        $output_wrapper(result),
-                        arena,
-                    )),
-                    Err(err) => Some($crate::base::ValueContainer::new(
-                        $crate::base::ValueKind::ErrorWrapped(err),
-                        arena,
-                    )),
+                    Ok(result) => Some($output_wrapper(result)),
+                    Err(err) => Some($crate::base::ValueKind::ErrorWrapped(err)),
                 }
             }
 
             ::inventory::submit! {
-                $impl_path { function: _op_impl }
+                $impl_path {
+                    lhs: <$lhs_type as $crate::base::Tagged>::TAG,
+                    rhs: <$rhs_type as $crate::base::Tagged>::TAG,
+                    function: _op_impl,
+                }
             };
         };
     };
 }
 
 macro_rules! __binary_op_create {
-    ($name:tt, $alt_name:tt, $op:tt) => {
-        __binary_op_create!(@impl $name, @impl $alt_name, $op, $);
+    ($name:tt, $alt_name:tt, $op:tt, $variant:ident) => {
+        __binary_op_create!(@impl $name, @impl $alt_name, $op, $variant, $);
     };
-    (@impl $name:tt, @impl $alt_name:tt, $op:tt, $d:tt) => {
+    (@impl $name:tt, @impl $alt_name:tt, $op:tt, $variant:ident, $d:tt) => {
         ::paste::paste!{
-            pub struct [< Op $alt_name Impl>] {pub function: $crate::op::BinaryOpFn }
+            pub struct [< Op $alt_name Impl>] {
+                pub lhs: $crate::base::ValueTag,
+                pub rhs: $crate::base::ValueTag,
+                pub function: $crate::op::BinaryOpFn,
+            }
             ::inventory::collect!([< Op $alt_name Impl>]);
-            pub fn [< op_ $name>]<'ctx>(lhs: $crate::base::Value<'ctx>, rhs: $crate::base::Value<'ctx>, arena: &'ctx ::bumpalo::Bump) -> ::core::option::Option<$crate::base::Value<'ctx>> {
-                for implementation in ::inventory::iter::<[<Op $alt_name Impl>]> {
-                    if let ::core::option::Option::Some(result) = (implementation.function)(lhs, rhs, arena) {
-                        return Some(result);
-                    }
-                }
-                None
+
+            #[doc = concat!("Applies `", stringify!($op), "` with the operators of [`OperatorTable::default`], bypassing the table of")]
+            #[doc = "any context. `None` when no implementation matches the operands."]
+            pub fn [< op_ $name>]<'ctx>(lhs: $crate::base::Value<'ctx>, rhs: $crate::base::Value<'ctx>) -> ::core::option::Option<$crate::base::ValueKind> {
+                OperatorTable::default().call_binary(BinaryOperator::$variant, lhs, rhs)
             }
 
-            pub fn [<err_op_ $name>]<'ctx>(lhs: $crate::base::Value<'ctx>, rhs: $crate::base::Value<'ctx>, arena: &'ctx ::bumpalo::Bump) -> ::core::result::Result<$crate::base::Value<'ctx>, $crate::error::SandboxExecutionError> {
-                for implementation in ::inventory::iter::<[<Op $alt_name Impl>]> {
-                    if let ::core::option::Option::Some(result) = (implementation.function)(lhs, rhs, arena) {
-                       match &result.kind {
-                           $crate::base::ValueKind::ErrorWrapped(err) => {
-                               return Err(err.clone());
-                           }
-                           _ => {
-                               return Ok(result);
-                           }
-                       }
-                    }
-                }
-                return Err($crate::error::SandboxExecutionError::UndefinedOperatorMethodError)
+            #[doc = concat!("Like [`op_", stringify!($name), "`], failing with `UndefinedOperatorMethodError` when no implementation")]
+            #[doc = "matches. Scripts go through [`OperatorTable::eval_binary`] on the table of their context."]
+            pub fn [<err_op_ $name>]<'ctx>(lhs: $crate::base::Value<'ctx>, rhs: $crate::base::Value<'ctx>) -> ::core::result::Result<$crate::base::ValueKind, $crate::error::SandboxExecutionError> {
+                OperatorTable::default().eval_binary(BinaryOperator::$variant, lhs, rhs)
             }
 
             #[macro_export]
@@ -99,59 +90,51 @@ macro_rules! __unary_op_register {
         const _: () = {
             fn _op_impl<'ctx>(
                 rhs: $crate::base::Value<'ctx>,
-                arena: &'ctx ::bumpalo::Bump,
-            ) -> Option<$crate::base::Value<'ctx>> {
+            ) -> Option<$crate::base::ValueKind> {
                 let rhs_val = <$rhs_type as $crate::base::Downcast>::from_value(rhs)?;
                 match $func(rhs_val.clone()) {
-                    Ok(result) => Some($crate::base::ValueContainer::new(
-                        $output_wrapper(result),
-                        arena,
-                    )),
-                    Err(err) => Some($crate::base::ValueContainer::new(
-                        $crate::base::ValueKind::ErrorWrapped(err),
-                        arena,
-                    )),
+                    Ok(result) => Some($output_wrapper(result)),
+                    Err(err) => Some($crate::base::ValueKind::ErrorWrapped(err)),
                 }
             }
 
             ::inventory::submit! {
-                $impl_path { function: _op_impl }
+                $impl_path {
+                    operand: <$rhs_type as $crate::base::Tagged>::TAG,
+                    function: _op_impl,
+                }
             };
         };
     };
 }
 
 macro_rules! __unary_op_create {
+    ($name:tt, $alt_name:tt, $op:tt, $variant:ident) => {
+        __unary_op_create!($name, $alt_name, $op);
+        ::paste::paste!{
+            #[doc = concat!("Applies the unary `", stringify!($op), "` with the operators of [`OperatorTable::default`], bypassing the")]
+            #[doc = "table of any context. `None` when no implementation matches the operand."]
+            pub fn [< op_ $name>]<'ctx>(rhs: $crate::base::Value<'ctx>) -> ::core::option::Option<$crate::base::ValueKind> {
+                OperatorTable::default().call_unary(UnaryOperator::$variant, rhs)
+            }
+
+            #[doc = concat!("Like [`op_", stringify!($name), "`], failing with `UndefinedOperatorMethodError` when no implementation")]
+            #[doc = "matches. Scripts go through [`OperatorTable::eval_unary`] on the table of their context."]
+            pub fn [<err_op_ $name>]<'ctx>(rhs: $crate::base::Value<'ctx>) -> ::core::result::Result<$crate::base::ValueKind, $crate::error::SandboxExecutionError> {
+                OperatorTable::default().eval_unary(UnaryOperator::$variant, rhs)
+            }
+        }
+    };
     ($name:tt, $alt_name:tt, $op:tt) => {
         __unary_op_create!(@impl $name, @impl $alt_name, $op, $);
     };
     (@impl $name:tt, @impl $alt_name:tt, $op:tt, $d:tt) => {
         ::paste::paste!{
-            pub struct [< Op $alt_name Impl>] {pub function: $crate::op::UnaryOpFn }
-            ::inventory::collect!([< Op $alt_name Impl>]);
-            pub fn [< op_ $name>]<'ctx>(rhs: $crate::base::Value<'ctx>, arena: &'ctx ::bumpalo::Bump) -> ::core::option::Option<$crate::base::Value<'ctx>> {
-                for implementation in ::inventory::iter::<[<Op $alt_name Impl>]> {
-                    if let ::core::option::Option::Some(result) = (implementation.function)(rhs, arena) {
-                        return Some(result);
-                    }
-                }
-                None
-            }
-            pub fn [<err_op_ $name>]<'ctx>(rhs: $crate::base::Value<'ctx>, arena: &'ctx ::bumpalo::Bump) -> ::core::result::Result<$crate::base::Value<'ctx>, $crate::error::SandboxExecutionError> {
-                for implementation in ::inventory::iter::<[<Op $alt_name Impl>]> {
-                    if let ::core::option::Option::Some(result) = (implementation.function)(rhs, arena) {
-                       match &result.kind {
-                           $crate::base::ValueKind::ErrorWrapped(err) => {
-                               return Err(err.clone());
-                           }
-                           _ => {
-                               return Ok(result);
-                           }
-                       }
-                    }
-                }
-                return Err($crate::error::SandboxExecutionError::UndefinedOperatorMethodError)
+            pub struct [< Op $alt_name Impl>] {
+                pub operand: $crate::base::ValueTag,
+                pub function: $crate::op::UnaryOpFn,
             }
+            ::inventory::collect!([< Op $alt_name Impl>]);
 
             #[macro_export]
             macro_rules! [<register_op_ $name>] {
@@ -166,25 +149,291 @@ macro_rules! __unary_op_create {
     };
 }
 
-__binary_op_create!(add, Add, +);
-__binary_op_create!(sub, Sub, -);
-__binary_op_create!(mul, Mul, *);
-__binary_op_create!(div, Div, /);
-__binary_op_create!(eq, Eq, ==);
-__binary_op_create!(ge, Ge, >=);
-__binary_op_create!(gt, Gt, >);
-__binary_op_create!(le, Le, <=);
-__binary_op_create!(lt, Lt, <);
-__binary_op_create!(ne, Ne, !=);
-__binary_op_create!(moduls, Mod, %);
-__binary_op_create!(bsl, Bsl, <<);
-__binary_op_create!(bsr, Bsr, >>);
-__binary_op_create!(band, BitwiseAnd, &);
-__binary_op_create!(bor, BitwiseOr, |);
-__binary_op_create!(bxor, BitwiseXor, ^);
-__binary_op_create!(and, And, &&);
-__binary_op_create!(or, Or, ||);
-__unary_op_create!(not, Not, !);
-__unary_op_create!(pos, Pos, +);
-__unary_op_create!(neg, Neg, -);
-__unary_op_create!(bnot, BitwiseNot, ~);
+__binary_op_create!(add, Add, +, Add);
+__binary_op_create!(sub, Sub, -, Subtract);
+__binary_op_create!(mul, Mul, *, Multiply);
+__binary_op_create!(div, Div, /, Divide);
+__binary_op_create!(eq, Eq, ==, Eq);
+__binary_op_create!(ge, Ge, >=, Gte);
+__binary_op_create!(gt, Gt, >, Gt);
+__binary_op_create!(le, Le, <=, Lte);
+__binary_op_create!(lt, Lt, <, Lt);
+__binary_op_create!(ne, Ne, !=, NotEq);
+__binary_op_create!(moduls, Mod, %, Modulo);
+__binary_op_create!(bsl, Bsl, <<, LeftShift);
+__binary_op_create!(bsr, Bsr, >>, RightShift);
+__binary_op_create!(band, BitwiseAnd, &, BitwiseAnd);
+__binary_op_create!(bor, BitwiseOr, |, BitwiseOr);
+__binary_op_create!(bxor, BitwiseXor, ^, Xor);
+__binary_op_create!(and, And, &&, And);
+__binary_op_create!(or, Or, ||, Or);
+__unary_op_create!(not, Not, !, Not);
+__unary_op_create!(pos, Pos, +, Positive);
+__unary_op_create!(neg, Neg, -, Negative);
+__unary_op_create!(bnot, BitwiseNot, ~, BitwiseNot);
+
+const TAG_COUNT: usize = ValueTag::ALL.len();
+
+const BINARY_OPERATOR_COUNT: usize = BinaryOperator::ALL.len();
+
+const UNARY_OPERATOR_COUNT: usize = UnaryOperator::ALL.len();
+
+fn binary_slot(lhs: ValueTag, rhs: ValueTag) -> usize {
+    lhs as usize * TAG_COUNT + rhs as usize
+}
+
+type CustomBinaryFn = dyn for<'ctx> Fn(Value<'ctx>, Value<'ctx>) -> Result<ValueKind>;
+
+type CustomUnaryFn = dyn for<'ctx> Fn(Value<'ctx>) -> Result<ValueKind>;
+
+/// An implementation held by an [`OperatorTable`]. Registered implementations are kept as plain
+/// function pointers so that the default operators are called without going through an `Rc`.
+#[derive(Clone)]
+enum BinaryHandler {
+    Registered(BinaryOpFn),
+    Custom(Rc<CustomBinaryFn>),
+}
+
+impl BinaryHandler {
+    fn call<'ctx>(&self, lhs: Value<'ctx>, rhs: Value<'ctx>) -> Option<ValueKind> {
+        match self {
+            BinaryHandler::Registered(function) => function(lhs, rhs),
+            BinaryHandler::Custom(function) => {
+                Some(function(lhs, rhs).unwrap_or_else(ValueKind::ErrorWrapped))
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+enum UnaryHandler {
+    Registered(UnaryOpFn),
+    Custom(Rc<CustomUnaryFn>),
+}
+
+impl UnaryHandler {
+    fn call<'ctx>(&self, rhs: Value<'ctx>) -> Option<ValueKind> {
+        match self {
+            UnaryHandler::Registered(function) => function(rhs),
+            UnaryHandler::Custom(function) => Some(function(rhs).unwrap_or_else(ValueKind::ErrorWrapped)),
+        }
+    }
+}
+
+/// The operator implementations visible to one [`crate::exec_ctx::ExecutionContext`].
+///
+/// [`OperatorTable::default`] is seeded with every implementation submitted through the
+/// `register_op_*!` macros. Changes made with [`OperatorTable::register`] and
+/// [`OperatorTable::unregister`] only affect the contexts owning the changed table.
+///
+/// Implementations are stored in one slot per operator and operand tags, so dispatching an
+/// operator is a single index into the table whatever the number of registered types.
+///
+/// ```
+/// use virtual_exec_type::ast::core::BinaryOperator;
+/// use virtual_exec_type::base::ValueTag;
+/// use virtual_exec_type::op::OperatorTable;
+///
+/// let mut operators = OperatorTable::default();
+/// // No string concatenation for this context
+/// assert!(operators.unregister(BinaryOperator::Add, ValueTag::String, ValueTag::String));
+/// ```
+#[derive(Clone)]
+pub struct OperatorTable {
+    /// Shared between the contexts seeded from the same table until one of them changes it.
+    binary: Rc<Vec<Vec<BinaryHandler>>>,
+    unary: Rc<Vec<Vec<UnaryHandler>>>,
+}
+
+fn binary_index(op: BinaryOperator, lhs: ValueTag, rhs: ValueTag) -> usize {
+    op as usize * TAG_COUNT * TAG_COUNT + binary_slot(lhs, rhs)
+}
+
+fn unary_index(op: UnaryOperator, operand: ValueTag) -> usize {
+    op as usize * TAG_COUNT + operand as usize
+}
+
+macro_rules! __seed_binary {
+    ($table:ident, $($op:ident => $impl_ty:ident),* $(,)?) => {
+        $(
+            for implementation in ::inventory::iter::<$impl_ty> {
+                $table[binary_index(BinaryOperator::$op, implementation.lhs, implementation.rhs)]
+                    .push(BinaryHandler::Registered(implementation.function));
+            }
+        )*
+    };
+}
+
+macro_rules! __seed_unary {
+    ($table:ident, $($op:ident => $impl_ty:ident),* $(,)?) => {
+        $(
+            for implementation in ::inventory::iter::<$impl_ty> {
+                $table[unary_index(UnaryOperator::$op, implementation.operand)]
+                    .push(UnaryHandler::Registered(implementation.function));
+            }
+        )*
+    };
+}
+
+impl OperatorTable {
+    /// A table without any operator, for hosts that want to allow operators one by one.
+    pub fn empty() -> Self {
+        Self {
+            binary: Rc::new(vec![Vec::new(); BINARY_OPERATOR_COUNT * TAG_COUNT * TAG_COUNT]),
+            unary: Rc::new(vec![Vec::new(); UNARY_OPERATOR_COUNT * TAG_COUNT]),
+        }
+    }
+
+    /// Implements `op` for operands tagged `lhs` and `rhs`, replacing the previous implementation
+    /// for these tags.
+    pub fn register<F>(&mut self, op: BinaryOperator, lhs: ValueTag, rhs: ValueTag, function: F)
+    where
+        F: for<'ctx> Fn(Value<'ctx>, Value<'ctx>) -> Result<ValueKind> + 'static,
+    {
+        Rc::make_mut(&mut self.binary)[binary_index(op, lhs, rhs)] =
+            vec![BinaryHandler::Custom(Rc::new(function))];
+    }
+
+    /// Removes the implementation of `op` for operands tagged `lhs` and `rhs`. Returns whether
+    /// there was one.
+    pub fn unregister(&mut self, op: BinaryOperator, lhs: ValueTag, rhs: ValueTag) -> bool {
+        if !self.contains(op, lhs, rhs) {
+            return false;
+        }
+        Rc::make_mut(&mut self.binary)[binary_index(op, lhs, rhs)].clear();
+        true
+    }
+
+    /// Implements the unary `op` for operands tagged `operand`, replacing the previous
+    /// implementation for this tag.
+    pub fn register_unary<F>(&mut self, op: UnaryOperator, operand: ValueTag, function: F)
+    where
+        F: for<'ctx> Fn(Value<'ctx>) -> Result<ValueKind> + 'static,
+    {
+        Rc::make_mut(&mut self.unary)[unary_index(op, operand)] =
+            vec![UnaryHandler::Custom(Rc::new(function))];
+    }
+
+    /// Removes the implementation of the unary `op` for operands tagged `operand`. Returns
+    /// whether there was one.
+    pub fn unregister_unary(&mut self, op: UnaryOperator, operand: ValueTag) -> bool {
+        if !self.contains_unary(op, operand) {
+            return false;
+        }
+        Rc::make_mut(&mut self.unary)[unary_index(op, operand)].clear();
+        true
+    }
+
+    pub fn contains(&self, op: BinaryOperator, lhs: ValueTag, rhs: ValueTag) -> bool {
+        !self.binary[binary_index(op, lhs, rhs)].is_empty()
+    }
+
+    pub fn contains_unary(&self, op: UnaryOperator, operand: ValueTag) -> bool {
+        !self.unary[unary_index(op, operand)].is_empty()
+    }
+
+    /// Applies `op`, failing with `UndefinedOperatorMethodError` when it is not implemented for
+    /// the operands.
+    pub fn eval_binary<'ctx>(
+        &self,
+        op: BinaryOperator,
+        lhs: Value<'ctx>,
+        rhs: Value<'ctx>,
+    ) -> Result<ValueKind> {
+        match self.call_binary(op, lhs, rhs) {
+            Some(result) => unwrap_result(result),
+            None => Err(SandboxExecutionError::UndefinedOperatorMethodError),
+        }
+    }
+
+    /// Applies the unary `op`, failing with `UndefinedOperatorMethodError` when it is not
+    /// implemented for the operand.
+    pub fn eval_unary<'ctx>(&self, op: UnaryOperator, rhs: Value<'ctx>) -> Result<ValueKind> {
+        match self.call_unary(op, rhs) {
+            Some(result) => unwrap_result(result),
+            None => Err(SandboxExecutionError::UndefinedOperatorMethodError),
+        }
+    }
+
+    /// The result of the first implementation of `op` accepting the operands, with errors still
+    /// wrapped in `ValueKind::ErrorWrapped`.
+    fn call_binary<'ctx>(
+        &self,
+        op: BinaryOperator,
+        lhs: Value<'ctx>,
+        rhs: Value<'ctx>,
+    ) -> Option<ValueKind> {
+        self.binary[binary_index(op, lhs.kind.tag(), rhs.kind.tag())]
+            .iter()
+            .find_map(|handler| handler.call(lhs, rhs))
+    }
+
+    fn call_unary<'ctx>(&self, op: UnaryOperator, rhs: Value<'ctx>) -> Option<ValueKind> {
+        self.unary[unary_index(op, rhs.kind.tag())]
+            .iter()
+            .find_map(|handler| handler.call(rhs))
+    }
+
+    fn seeded() -> Self {
+        let mut binary: Vec<Vec<BinaryHandler>> = vec![Vec::new(); BINARY_OPERATOR_COUNT * TAG_COUNT * TAG_COUNT];
+        __seed_binary!(binary,
+            Add => OpAddImpl,
+            Subtract => OpSubImpl,
+            Multiply => OpMulImpl,
+            Divide => OpDivImpl,
+            And => OpAndImpl,
+            Or => OpOrImpl,
+            Xor => OpBitwiseXorImpl,
+            Modulo => OpModImpl,
+            BitwiseAnd => OpBitwiseAndImpl,
+            BitwiseOr => OpBitwiseOrImpl,
+            Eq => OpEqImpl,
+            NotEq => OpNeImpl,
+            Lt => OpLtImpl,
+            Lte => OpLeImpl,
+            Gt => OpGtImpl,
+            Gte => OpGeImpl,
+            LeftShift => OpBslImpl,
+            RightShift => OpBsrImpl,
+        );
+        let mut unary: Vec<Vec<UnaryHandler>> = vec![Vec::new(); UNARY_OPERATOR_COUNT * TAG_COUNT];
+        __seed_unary!(unary,
+            Positive => OpPosImpl,
+            Negative => OpNegImpl,
+            Not => OpNotImpl,
+            BitwiseNot => OpBitwiseNotImpl,
+        );
+        Self {
+            binary: Rc::new(binary),
+            unary: Rc::new(unary),
+        }
+    }
+}
+
+thread_local! {
+    static DEFAULT_OPERATORS: OperatorTable = OperatorTable::seeded();
+}
+
+impl Default for OperatorTable {
+    /// The operators registered with the `register_op_*!` macros, built once per thread.
+    fn default() -> Self {
+        DEFAULT_OPERATORS.with(Clone::clone)
+    }
+}
+
+#[doc(hidden)]
+pub fn unwrap_result(result: ValueKind) -> Result<ValueKind> {
+    match result {
+        ValueKind::ErrorWrapped(err) => Err(err),
+        result => Ok(result),
+    }
+}
+
+impl Debug for OperatorTable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OperatorTable")
+            .field("binary", &self.binary.iter().map(Vec::len).sum::<usize>())
+            .field("unary", &self.unary.iter().map(Vec::len).sum::<usize>())
+            .finish()
+    }
+}
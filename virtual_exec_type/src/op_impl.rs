@@ -227,6 +227,9 @@ register_op_neg!(VirPyInt, ValueKind::Int, |a: VirPyInt| Ok(VirPyInt::new(
 register_op_neg!(VirPyFloat, ValueKind::Float, |a: VirPyFloat| Ok(
     VirPyFloat::new(-a.value)
 ));
+register_op_bnot!(VirPyInt, ValueKind::Int, |a: VirPyInt| Ok(VirPyInt::new(
+    !a.value
+)));
 
 register_op_add!(
     String,
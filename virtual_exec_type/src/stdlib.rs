@@ -0,0 +1,53 @@
+//! Functions scripts may call without the host registering them, which only exist in
+//! [deterministic mode](crate::exec_ctx::ExecOptions::deterministic), so that sandboxed scripts
+//! never see the clock or the randomness of the host. A host function registered under the same
+//! name takes precedence, e.g. to inject another source.
+//!
+//! - `random()` returns a float in `[0, 1)`, drawn from a generator seeded with
+//!   [`Deterministic::seed`]. The generator belongs to the context: it restarts from the seed when
+//!   its options are set, and is captured in [`Snapshot`](crate::vm::Snapshot)s.
+//! - `time()` returns [`Deterministic::time`], in seconds since the Unix epoch.
+
+use crate::base::ValueKind;
+use crate::builtin::VirPyFloat;
+use crate::exec_ctx::{Deterministic, ExecutionContext, Result};
+use crate::native::expect_arguments;
+
+/// Calls the function of this module named `name`, or returns `None` when there is none to call
+/// for `ctx`, in which case the host functions are looked up as usual.
+pub(crate) fn call(
+    ctx: &mut ExecutionContext,
+    name: &str,
+    args: &[ValueKind],
+) -> Option<Result<ValueKind>> {
+    let deterministic = ctx.options.deterministic?;
+    if ctx.functions.contains_key(name) {
+        return None;
+    }
+    let value = match name {
+        "random" => random,
+        "time" => time,
+        _ => return None,
+    };
+    Some(expect_arguments(args, 0).map(|()| {
+        ValueKind::Float(VirPyFloat::new(value(ctx, deterministic)))
+    }))
+}
+
+fn random(ctx: &mut ExecutionContext, _: Deterministic) -> f64 {
+    // The top 53 bits, the precision of an f64
+    (split_mix(&mut ctx.random_state) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn time(_: &mut ExecutionContext, deterministic: Deterministic) -> f64 {
+    deterministic.time
+}
+
+/// SplitMix64, a small generator whose output only depends on its seed.
+fn split_mix(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
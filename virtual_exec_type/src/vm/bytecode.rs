@@ -0,0 +1,127 @@
+use crate::ast::core::{BinaryOperator, Pattern, UnaryOperator};
+use crate::base::ValueKind;
+use crate::builtin::Declaration;
+use crate::error::SandboxExecutionError;
+use crate::vm::resolver::Address;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// An index into one of the tables of a [`Program`].
+pub type Slot = u32;
+
+/// The index of an instruction in [`Program::code`], as a jump target.
+pub type Label = u32;
+
+/// A single VM instruction. Operands are popped from the top of the stack, so the last operand
+/// listed is the one on top.
+#[derive(Debug, Clone)]
+pub enum Instruction {
+    /// Enters the frame of a node nested `depth` frames deep, then consumes `cost` units of TTL.
+    Tick { depth: u32, cost: u8 },
+    /// Consumes one unit of TTL, as a body does after each statement and a `match` per arm tried.
+    Consume,
+    /// Pushes a constant.
+    Const(Slot),
+    /// Pushes the value of a variable.
+    Load(Address),
+    /// Pops a value and assigns it to a variable.
+    Store(Address),
+    /// Removes a variable.
+    DeleteVar(Address),
+    /// Declares a name `global` or `nonlocal` in the current scope.
+    Declare(Slot, Declaration),
+    /// Checks the value on top of the stack against the memory and length limits.
+    Account,
+    Pop,
+    Dup,
+    Unary(UnaryOperator),
+    Binary(BinaryOperator),
+    /// Looks at the left operand of `&&`/`||` on top of the stack and jumps to `end` when it
+    /// decides the result on its own.
+    ShortCircuit { op: BinaryOperator, end: Label },
+    /// Pops both operands of a `&&`/`||` that was not short-circuited.
+    Logical(BinaryOperator),
+    /// Pushes an empty collection.
+    NewCollection { capacity: u32 },
+    /// Pops a value and appends it to the collection below it.
+    Append,
+    /// Pops a collection and appends its items to the collection below it.
+    Extend,
+    /// Pops an object and pushes one of its attributes.
+    GetAttr(Slot),
+    /// Pops a key and a container, and pushes the item.
+    GetItem,
+    /// Pops a native object and `argc` arguments, and pushes what the method returned. The
+    /// updated native is dropped as the receiver is not an assignable place.
+    Call { method: Slot, argc: u32 },
+    /// Pops `argc` arguments and pushes what the host function registered as `function`
+    /// returned. An async function suspends the run until its future is ready.
+    CallFunction { function: Slot, argc: u32 },
+    /// Pops an object and a value. Objects get the attribute set, while natives jump to `native`
+    /// with the value left on the stack, to store it through the place the object was read from.
+    SetAttr { attr: Slot, native: Label },
+    /// Pops an object and removes one of its attributes.
+    DeleteAttr(Slot),
+    /// Pops the object of a [`Place::Attribute`], `keys` subscript keys (the innermost on top)
+    /// and the operands of `op`, then runs `op` on the value stored at the place.
+    Update { place: Place, keys: u32, op: Update },
+    /// Pops a collection and pushes one value per unpacking target, the first one on top.
+    Unpack { count: u32, star: Option<u32>, ambiguous: bool },
+    /// Pops a value, falling through on `True` and jumping on `False` or `None`.
+    Branch { otherwise: Label },
+    Jump(Label),
+    /// Gives a block its own scope under [`ScopeMode::Block`](crate::exec_ctx::ScopeMode::Block).
+    EnterBlock,
+    LeaveBlock,
+    /// Tests the subject on top of the stack against a pattern, jumping to `next` on a mismatch.
+    MatchPattern { pattern: Slot, next: Label },
+    /// Assigns the names captured by the last successful [`Instruction::MatchPattern`].
+    Bind,
+    Raise(SandboxExecutionError),
+}
+
+/// Where an [`Instruction::Update`] finds the value it changes.
+#[derive(Debug, Clone, Copy)]
+pub enum Place {
+    Variable(Address),
+    /// An attribute of the object on top of the stack. An attribute of a native is updated on a
+    /// copy, which is left on the stack for the code at `native` to store back.
+    Attribute { attr: Slot, native: Label },
+}
+
+/// What an [`Instruction::Update`] does to the value at its place.
+#[derive(Debug, Clone, Copy)]
+pub enum Update {
+    /// Operands: the value and the key.
+    SetItem,
+    /// Operand: the key.
+    DeleteItem,
+    /// Operand: the value.
+    SetAttribute(Slot),
+    /// Operands: `argc` arguments. Pushes the result.
+    Call { method: Slot, argc: u32 },
+}
+
+/// A [`Module`](crate::ast::core::Module) lowered to bytecode by [`Program::compile`], to be run
+/// any number of times with [`Program::run`].
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub(crate) code: Vec<Instruction>,
+    pub(crate) constants: Vec<ValueKind>,
+    /// Shared with the block scopes of runs, which hold their variables by slot.
+    pub(crate) names: Rc<[String]>,
+    /// The slot of each name in `names`.
+    pub(crate) slots: HashMap<String, Slot>,
+    pub(crate) patterns: Vec<Pattern>,
+}
+
+impl Program {
+    pub fn code(&self) -> &[Instruction] {
+        &self.code
+    }
+
+    /// The names that [`Slot`] operands of variable and attribute instructions refer to.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+}
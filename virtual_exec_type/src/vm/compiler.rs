@@ -0,0 +1,441 @@
+use crate::ast::core::{star_index, BinaryOperator, Expr, Module, Node, Pattern, Stmt};
+use crate::base::ValueKind;
+use crate::builtin::Declaration;
+use crate::error::SandboxExecutionError;
+use crate::vm::bytecode::{Instruction, Label, Place, Program, Slot, Update};
+use crate::vm::resolver::{Address, Resolver};
+use std::collections::HashMap;
+
+/// Lowers the AST to bytecode. Every node that `ASTNode::eval` enters a frame for gets a
+/// [`Instruction::Tick`] with the depth it is evaluated at, and the code evaluates subexpressions in
+/// the same order as the tree-walker, so that both engines charge TTL and fail identically.
+pub(crate) struct Compiler {
+    code: Vec<Instruction>,
+    constants: Vec<ValueKind>,
+    names: Vec<String>,
+    slots: HashMap<String, Slot>,
+    patterns: Vec<Pattern>,
+    resolver: Resolver,
+    /// The lexical scope being compiled, numbered as the resolver numbered them.
+    scope: usize,
+    scopes_entered: usize,
+}
+
+impl Compiler {
+    pub(crate) fn compile(module: &Module) -> Program {
+        let mut compiler = Compiler {
+            code: Vec::new(),
+            constants: Vec::new(),
+            names: Vec::new(),
+            slots: HashMap::new(),
+            patterns: Vec::new(),
+            resolver: Resolver::resolve(module),
+            scope: 0,
+            scopes_entered: 0,
+        };
+        compiler.body(&module.body, 0);
+        Program {
+            code: compiler.code,
+            constants: compiler.constants,
+            names: compiler.names.into(),
+            slots: compiler.slots,
+            patterns: compiler.patterns,
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> Label {
+        self.code.push(instruction);
+        (self.code.len() - 1) as Label
+    }
+
+    fn here(&self) -> Label {
+        self.code.len() as Label
+    }
+
+    /// Points the jump emitted at `at` to the next instruction.
+    fn patch(&mut self, at: Label) {
+        let target = self.here();
+        match &mut self.code[at as usize] {
+            Instruction::Jump(label)
+            | Instruction::ShortCircuit { end: label, .. }
+            | Instruction::SetAttr { native: label, .. }
+            | Instruction::Update { place: Place::Attribute { native: label, .. }, .. }
+            | Instruction::Branch { otherwise: label }
+            | Instruction::MatchPattern { next: label, .. } => *label = target,
+            instruction => unreachable!("{instruction:?} does not jump"),
+        }
+    }
+
+    fn name(&mut self, name: &str) -> Slot {
+        if let Some(slot) = self.slots.get(name) {
+            return *slot;
+        }
+        let slot = self.names.len() as Slot;
+        self.names.push(name.to_string());
+        self.slots.insert(name.to_string(), slot);
+        slot
+    }
+
+    fn variable(&mut self, name: &str) -> Address {
+        Address {
+            depth: self.resolver.depth(self.scope, name),
+            index: self.name(name),
+        }
+    }
+
+    /// Compiles `f` in the next lexical scope, which must be entered in the resolver's order.
+    fn scoped(&mut self, f: impl FnOnce(&mut Self)) {
+        let parent = self.scope;
+        self.scopes_entered += 1;
+        self.scope = self.scopes_entered;
+        self.emit(Instruction::EnterBlock);
+        f(self);
+        self.scope = parent;
+    }
+
+    fn tick(&mut self, depth: u32, cost: u8) {
+        self.emit(Instruction::Tick { depth, cost });
+    }
+
+    /// Statements of a body at `depth`, each followed by the unit a body charges per statement.
+    fn body(&mut self, body: &[Node<Stmt>], depth: u32) {
+        for stmt in body {
+            self.stmt(&stmt.kind, depth);
+            self.emit(Instruction::Consume);
+        }
+    }
+
+    fn block(&mut self, body: &[Node<Stmt>], depth: u32) {
+        self.scoped(|compiler| {
+            compiler.body(body, depth);
+            compiler.emit(Instruction::LeaveBlock);
+        });
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, depth: u32) {
+        self.tick(depth, 1);
+        let inner = depth + 1;
+        match stmt {
+            Stmt::Expression(expr) => {
+                self.expr(&expr.kind, inner);
+                self.emit(Instruction::Pop);
+            }
+            Stmt::Assign { targets, value } => {
+                self.expr(&value.kind, inner);
+                for (idx, target) in targets.iter().enumerate() {
+                    if idx + 1 < targets.len() {
+                        self.emit(Instruction::Dup);
+                    }
+                    self.assign(&target.kind, inner);
+                }
+            }
+            Stmt::If {
+                test,
+                body,
+                otherwise,
+            } => {
+                self.expr(&test.kind, inner);
+                let branch = self.emit(Instruction::Branch { otherwise: 0 });
+                self.block(body, inner);
+                match otherwise {
+                    Some(otherwise) => {
+                        let jump = self.emit(Instruction::Jump(0));
+                        self.patch(branch);
+                        self.block(otherwise, inner);
+                        self.patch(jump);
+                    }
+                    None => self.patch(branch),
+                }
+            }
+            Stmt::Scoped(body) => self.block(body, inner),
+            Stmt::Delete(targets) => {
+                for target in targets {
+                    self.delete(&target.kind, inner);
+                }
+            }
+            Stmt::Pass => {}
+            Stmt::Global(names) => self.declare(names, Declaration::Global),
+            Stmt::Nonlocal(names) => self.declare(names, Declaration::Nonlocal),
+            Stmt::Match { subject, arms } => {
+                self.expr(&subject.kind, inner);
+                let mut taken = Vec::new();
+                for arm in arms {
+                    self.emit(Instruction::Consume);
+                    let pattern = self.patterns.len() as Slot;
+                    self.captures(&arm.pattern);
+                    self.patterns.push(arm.pattern.clone());
+                    let mismatch = self.emit(Instruction::MatchPattern { pattern, next: 0 });
+                    self.scoped(|compiler| {
+                        compiler.emit(Instruction::Bind);
+                        let guard = arm.guard.as_ref().map(|guard| {
+                            compiler.expr(&guard.kind, inner);
+                            compiler.emit(Instruction::Branch { otherwise: 0 })
+                        });
+                        compiler.body(&arm.body, inner);
+                        compiler.emit(Instruction::LeaveBlock);
+                        taken.push(compiler.emit(Instruction::Jump(0)));
+                        if let Some(guard) = guard {
+                            compiler.patch(guard);
+                            compiler.emit(Instruction::LeaveBlock);
+                        }
+                    });
+                    self.patch(mismatch);
+                }
+                for jump in taken {
+                    self.patch(jump);
+                }
+                self.emit(Instruction::Pop);
+            }
+        }
+    }
+
+    /// Gives a slot to every name `pattern` may capture, for [`Instruction::Bind`] to bind it by.
+    fn captures(&mut self, pattern: &Pattern) {
+        match pattern {
+            Pattern::Capture(name) | Pattern::Star(Some(name)) => {
+                self.name(name);
+            }
+            Pattern::Binding(name, inner) => {
+                self.name(name);
+                self.captures(inner);
+            }
+            Pattern::Sequence(patterns) | Pattern::Or(patterns) => {
+                for pattern in patterns {
+                    self.captures(pattern);
+                }
+            }
+            Pattern::Mapping { entries, rest } => {
+                for (_, pattern) in entries {
+                    self.captures(pattern);
+                }
+                if let Some(rest) = rest {
+                    self.name(rest);
+                }
+            }
+            Pattern::Class { fields, .. } => {
+                for (_, pattern) in fields {
+                    self.captures(pattern);
+                }
+            }
+            Pattern::Literal(_) | Pattern::Wildcard | Pattern::Star(None) => {}
+        }
+    }
+
+    fn declare(&mut self, names: &[String], declaration: Declaration) {
+        for name in names {
+            let slot = self.name(name);
+            self.emit(Instruction::Declare(slot, declaration));
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr, depth: u32) {
+        // A literal charges once for the expression and once for itself
+        let cost = if matches!(expr, Expr::Literal(_)) { 2 } else { 1 };
+        self.tick(depth, cost);
+        let inner = depth + 1;
+        match expr {
+            Expr::Literal(literal) => {
+                let slot = self.constants.len() as Slot;
+                self.constants.push(literal.value());
+                self.emit(Instruction::Const(slot));
+            }
+            Expr::Variable(name) => {
+                let address = self.variable(name);
+                self.emit(Instruction::Load(address));
+            }
+            Expr::UnaryOp { op, operand } => {
+                self.expr(&operand.kind, inner);
+                self.emit(Instruction::Unary(*op));
+            }
+            Expr::BinaryOp { left, op, right } => {
+                self.expr(&left.kind, inner);
+                if matches!(op, BinaryOperator::And | BinaryOperator::Or) {
+                    let short = self.emit(Instruction::ShortCircuit { op: *op, end: 0 });
+                    self.expr(&right.kind, inner);
+                    self.emit(Instruction::Logical(*op));
+                    self.patch(short);
+                } else {
+                    self.expr(&right.kind, inner);
+                    self.emit(Instruction::Binary(*op));
+                }
+            }
+            Expr::Wrapped(expr) => self.expr(&expr.kind, inner),
+            Expr::Collection(items) => {
+                self.emit(Instruction::NewCollection {
+                    capacity: items.len() as u32,
+                });
+                for item in items {
+                    match &item.kind {
+                        Expr::Starred(spread) => {
+                            self.expr(&spread.kind, inner);
+                            self.emit(Instruction::Extend);
+                        }
+                        kind => {
+                            self.expr(kind, inner);
+                            self.emit(Instruction::Append);
+                        }
+                    }
+                }
+            }
+            Expr::Starred(_) => {
+                self.emit(Instruction::Raise(SandboxExecutionError::InvalidSyntaxError));
+            }
+            Expr::Attribute { value, attr } => {
+                self.expr(&value.kind, inner);
+                let slot = self.name(attr);
+                self.emit(Instruction::GetAttr(slot));
+            }
+            Expr::Call { function, args } => {
+                let argc = args.len() as u32;
+                match &function.kind {
+                    Expr::Attribute { value: receiver, attr } => {
+                        for arg in args {
+                            self.expr(&arg.kind, inner);
+                        }
+                        let method = self.name(attr);
+                        self.call(&receiver.kind, method, argc, inner);
+                    }
+                    Expr::Variable(name) => {
+                        for arg in args {
+                            self.expr(&arg.kind, inner);
+                        }
+                        let function = self.name(name);
+                        self.emit(Instruction::CallFunction { function, argc });
+                    }
+                    _ => {
+                        self.emit(Instruction::Raise(SandboxExecutionError::InvalidTypeError));
+                        return;
+                    }
+                }
+            }
+            Expr::Subscript { value, slice } => {
+                self.expr(&value.kind, inner);
+                self.expr(&slice.kind, inner);
+                self.emit(Instruction::GetItem);
+            }
+        }
+        self.emit(Instruction::Account);
+    }
+
+    /// Calls a method on `receiver`, storing the updated native back when it is a place.
+    fn call(&mut self, receiver: &Expr, method: Slot, argc: u32, depth: u32) {
+        match receiver {
+            Expr::Wrapped(inner) => self.call(&inner.kind, method, argc, depth),
+            Expr::Variable(_) | Expr::Attribute { .. } | Expr::Subscript { .. } => {
+                self.update(receiver, Update::Call { method, argc }, 0, depth)
+            }
+            _ => {
+                self.expr(receiver, depth);
+                self.emit(Instruction::Call { method, argc });
+            }
+        }
+    }
+
+    /// Assigns the value on top of the stack to `target`.
+    fn assign(&mut self, target: &Expr, depth: u32) {
+        match target {
+            Expr::Variable(name) => {
+                let address = self.variable(name);
+                self.emit(Instruction::Store(address));
+            }
+            Expr::Wrapped(inner) => self.assign(&inner.kind, depth),
+            Expr::Attribute { value: obj, attr } => {
+                self.expr(&obj.kind, depth);
+                let attr = self.name(attr);
+                let native = self.emit(Instruction::SetAttr { attr, native: 0 });
+                let done = self.emit(Instruction::Jump(0));
+                self.patch(native);
+                self.update(&obj.kind, Update::SetAttribute(attr), 0, depth);
+                self.patch(done);
+            }
+            Expr::Subscript { value: container, slice } => {
+                self.expr(&slice.kind, depth);
+                self.update(&container.kind, Update::SetItem, 0, depth);
+            }
+            Expr::Collection(targets) => {
+                let (star, ambiguous) = match star_index(targets) {
+                    Ok(star) => (star.map(|idx| idx as u32), false),
+                    Err(_) => (None, true),
+                };
+                self.emit(Instruction::Unpack {
+                    count: targets.len() as u32,
+                    star,
+                    ambiguous,
+                });
+                for target in targets {
+                    match &target.kind {
+                        Expr::Starred(inner) => self.assign(&inner.kind, depth),
+                        kind => self.assign(kind, depth),
+                    }
+                }
+            }
+            _ => {
+                self.emit(Instruction::Raise(SandboxExecutionError::InvalidSyntaxError));
+            }
+        }
+    }
+
+    fn delete(&mut self, target: &Expr, depth: u32) {
+        match target {
+            Expr::Variable(name) => {
+                let address = self.variable(name);
+                self.emit(Instruction::DeleteVar(address));
+            }
+            Expr::Wrapped(inner) => self.delete(&inner.kind, depth),
+            Expr::Attribute { value, attr } => {
+                self.expr(&value.kind, depth);
+                let slot = self.name(attr);
+                self.emit(Instruction::DeleteAttr(slot));
+            }
+            Expr::Subscript { value, slice } => {
+                self.expr(&slice.kind, depth);
+                self.update(&value.kind, Update::DeleteItem, 0, depth);
+            }
+            Expr::Collection(targets) => {
+                for target in targets {
+                    self.delete(&target.kind, depth);
+                }
+            }
+            _ => {
+                self.emit(Instruction::Raise(SandboxExecutionError::InvalidSyntaxError));
+            }
+        }
+    }
+
+    /// Runs `op` on the value stored at `place`, below `keys` subscripts whose keys are already on
+    /// the stack. Keys are evaluated from the outermost subscript in, as the tree-walker does.
+    fn update(&mut self, place: &Expr, op: Update, keys: u32, depth: u32) {
+        match place {
+            Expr::Variable(name) => {
+                let address = self.variable(name);
+                self.emit(Instruction::Update {
+                    place: Place::Variable(address),
+                    keys,
+                    op,
+                });
+            }
+            Expr::Wrapped(inner) => self.update(&inner.kind, op, keys, depth),
+            Expr::Attribute { value, attr } => {
+                self.expr(&value.kind, depth);
+                let attr = self.name(attr);
+                let native = self.emit(Instruction::Update {
+                    place: Place::Attribute { attr, native: 0 },
+                    keys,
+                    op,
+                });
+                let done = self.emit(Instruction::Jump(0));
+                self.patch(native);
+                // The updated copy of a native's attribute is assigned back like any other value
+                self.assign(place, depth);
+                self.patch(done);
+            }
+            Expr::Subscript { value, slice } => {
+                self.expr(&slice.kind, depth);
+                self.update(&value.kind, op, keys + 1, depth);
+            }
+            _ => {
+                self.emit(Instruction::Raise(SandboxExecutionError::InvalidSyntaxError));
+            }
+        }
+    }
+}
@@ -0,0 +1,731 @@
+use crate::ast::core::{
+    call_native, get_item, match_pattern, native_function, resolve_index, set_item, unpack,
+    BinaryOperator,
+};
+use crate::base::{ValueContainer, ValueKind};
+use crate::builtin::Declaration;
+use crate::error::SandboxExecutionError;
+use crate::exec_ctx::{ExecutionContext, Result, ScopeMode};
+use crate::native::{NativeFunction, NativeFuture};
+use crate::stdlib;
+use crate::vm::bytecode::{Instruction, Place, Program, Slot, Update};
+use crate::vm::resolver::Address;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The runtime operands of an [`Update`].
+enum Operation<'p> {
+    SetItem { key: ValueKind, value: ValueKind },
+    DeleteItem { key: ValueKind },
+    SetAttribute { attr: &'p str, value: ValueKind },
+    Call { method: &'p str, args: Vec<ValueKind> },
+}
+
+pub(crate) type Binding = Rc<RefCell<ValueContainer>>;
+
+/// Where a variable is bound, as seen from the current scope.
+enum Location {
+    /// In the block scope at this position in `ctx.locals`.
+    Local(usize),
+    /// In the scope at this position in `ctx.mapping`, if it is bound there yet.
+    Scope(usize, Option<Binding>),
+    /// Nowhere yet, so that assigning it binds it in the current scope.
+    Unbound,
+}
+
+/// How [`Machine::execute`] stopped without an error.
+enum Exit {
+    Finished,
+    /// Out of TTL or fuel before the instruction at `pc`, which runs again on resume.
+    Suspended,
+    /// Called an async host function, whose result is pushed before resuming.
+    Awaiting(NativeFuture),
+    /// Called a sync host function, whose result is pushed before resuming.
+    Calling(Call),
+}
+
+/// How [`Machine::run_slice`] stopped without an error.
+pub(crate) enum Paused {
+    Finished,
+    Suspended(Suspended),
+    Awaiting(Suspended, NativeFuture),
+    Calling(Suspended, Call),
+}
+
+/// A call to a sync host function, made by the driver of the machine once the context is no
+/// longer borrowed, so that the function may borrow it as it does under the tree-walker.
+pub(crate) struct Call {
+    function: Rc<dyn Fn(Vec<ValueKind>) -> Result<ValueKind>>,
+    args: Vec<ValueKind>,
+}
+
+impl Call {
+    pub(crate) fn invoke(self) -> Result<ValueKind> {
+        (self.function)(self.args)
+    }
+}
+
+/// Everything a suspended [`Machine`] needs to pick up where it stopped, apart from the program
+/// and the context.
+pub(crate) struct Suspended {
+    pub(crate) pc: usize,
+    pub(crate) stack: Vec<ValueKind>,
+    pub(crate) cache: Vec<Option<Binding>>,
+    pub(crate) declared: Vec<bool>,
+    pub(crate) captures: Vec<(String, ValueKind)>,
+    pub(crate) blocks: usize,
+    pub(crate) resumable: bool,
+    pub(crate) fuel: i64,
+}
+
+impl Suspended {
+    /// Makes the machine suspend when it runs out of TTL rather than fail.
+    pub(crate) fn set_resumable(&mut self) {
+        self.resumable = true;
+    }
+
+    /// Lets the machine consume `fuel` units of TTL before suspending.
+    pub(crate) fn refuel(&mut self, fuel: i64) {
+        self.fuel = fuel;
+    }
+
+    /// Pushes the result of the host function the machine is waiting for.
+    pub(crate) fn push(&mut self, value: ValueKind) {
+        self.stack.push(value);
+    }
+
+    /// Forgets every cached binding, as a host function may have rebound names through the
+    /// context.
+    pub(crate) fn forget_bindings(&mut self) {
+        self.cache.fill(None);
+    }
+
+    /// Gives up on the run, popping the block scopes it is inside of.
+    pub(crate) fn abandon(self, ctx: &mut ExecutionContext) {
+        for _ in 0..self.blocks {
+            ctx.pop_local_scope();
+        }
+    }
+}
+
+/// Runs a [`Program`] with the context borrowed until the run ends or calls a host function.
+pub(crate) struct Machine<'p, 'c> {
+    program: &'p Program,
+    ctx: &'c mut ExecutionContext,
+    stack: Vec<ValueKind>,
+    /// The binding each name slot resolved to in the scopes of the context, which hold the
+    /// variables of the module and of the host by name. It stays the one the name resolves to
+    /// there until the run binds or unbinds that name in the context, or a host function runs.
+    /// The variables of blocks live in the block scopes of the run, `ctx.locals`, by slot.
+    cache: Vec<Option<Binding>>,
+    /// Names declared `global` or `nonlocal`, which resolve differently from one scope to the
+    /// next and are never cached.
+    declared: Vec<bool>,
+    /// Captures of the last pattern that matched, waiting for [`Instruction::Bind`].
+    captures: Vec<(String, ValueKind)>,
+    scoped: bool,
+    /// Block scopes currently pushed onto `ctx.locals`, popped again if the program fails inside
+    /// them.
+    blocks: usize,
+    pc: usize,
+    /// Whether running out of TTL suspends the machine rather than failing.
+    resumable: bool,
+    /// Units of TTL left before the machine suspends, to yield to the host.
+    fuel: i64,
+}
+
+impl<'p, 'c> Machine<'p, 'c> {
+    pub(crate) fn new(program: &'p Program, ctx: &'c mut ExecutionContext) -> Self {
+        let scoped = ctx.options.scope_mode == ScopeMode::Block;
+        let mut declared = vec![false; program.names.len()];
+        for scope in &ctx.mapping {
+            for name in scope.borrow().declarations.keys() {
+                if let Some(slot) = program.slots.get(name) {
+                    declared[*slot as usize] = true;
+                }
+            }
+        }
+        Self {
+            program,
+            ctx,
+            stack: Vec::new(),
+            cache: vec![None; program.names.len()],
+            declared,
+            captures: Vec::new(),
+            scoped,
+            blocks: 0,
+            pc: 0,
+            resumable: false,
+            fuel: i64::MAX,
+        }
+    }
+
+    /// A machine picking up from `suspended`.
+    pub(crate) fn resume(
+        program: &'p Program,
+        ctx: &'c mut ExecutionContext,
+        suspended: Suspended,
+    ) -> Self {
+        Self {
+            program,
+            scoped: ctx.options.scope_mode == ScopeMode::Block,
+            ctx,
+            stack: suspended.stack,
+            cache: suspended.cache,
+            declared: suspended.declared,
+            captures: suspended.captures,
+            blocks: suspended.blocks,
+            pc: suspended.pc,
+            resumable: suspended.resumable,
+            fuel: suspended.fuel,
+        }
+    }
+
+    pub(crate) fn suspend(self) -> Suspended {
+        Suspended {
+            pc: self.pc,
+            stack: self.stack,
+            cache: self.cache,
+            declared: self.declared,
+            captures: self.captures,
+            blocks: self.blocks,
+            resumable: self.resumable,
+            fuel: self.fuel,
+        }
+    }
+
+    /// Runs until the program ends, the machine runs out of fuel or, if resumable, of TTL, or
+    /// a host function is called. Returns the state to resume from unless it ended.
+    pub(crate) fn run_slice(mut self) -> Result<Paused> {
+        match self.execute() {
+            Ok(Exit::Suspended) => Ok(Paused::Suspended(self.suspend())),
+            Ok(Exit::Awaiting(future)) => Ok(Paused::Awaiting(self.suspend(), future)),
+            Ok(Exit::Calling(call)) => Ok(Paused::Calling(self.suspend(), call)),
+            result => {
+                self.pop_blocks();
+                result.map(|_| Paused::Finished)
+            }
+        }
+    }
+
+    fn pop_blocks(&mut self) {
+        for _ in 0..self.blocks {
+            self.ctx.pop_local_scope();
+        }
+        self.blocks = 0;
+    }
+
+    /// Whether the machine lacks the fuel or, if resumable, the TTL to consume `cost` units, in
+    /// which case the instruction that consumes them is left to run on resume.
+    fn starved(&self, cost: u8) -> bool {
+        let cost = i64::from(cost);
+        self.fuel < cost || (self.resumable && self.ctx.ttl < cost)
+    }
+
+    fn pop(&mut self) -> ValueKind {
+        self.stack.pop().expect("the compiler balances the stack")
+    }
+
+    fn pop_many(&mut self, count: u32) -> Vec<ValueKind> {
+        let at = self.stack.len() - count as usize;
+        self.stack.split_off(at)
+    }
+
+    fn top(&mut self) -> &mut ValueKind {
+        self.stack.last_mut().expect("the compiler balances the stack")
+    }
+
+    fn name(&self, slot: u32) -> &'p str {
+        &self.program.names[slot as usize]
+    }
+
+    /// The innermost of the `scopes` innermost block scopes of the run binding the variable in
+    /// `slot`, by position in `ctx.locals`. An address only needs the scopes up to its depth
+    /// searched, as the ones further out do not mention its name and never bind it.
+    fn local(&self, slot: usize, scopes: usize) -> Option<usize> {
+        let locals = &self.ctx.locals;
+        let mut searched = locals.len() - scopes.min(self.blocks)..locals.len();
+        searched.rfind(|&scope| locals[scope].bindings[slot].is_some())
+    }
+
+    fn local_binding(&self, scope: usize, slot: usize) -> Binding {
+        self.ctx.locals[scope].bindings[slot].clone().expect("the scope binds the slot")
+    }
+
+    /// Where the variable in `slot` is bound, looking through the `scopes` innermost block
+    /// scopes before the scopes of the context, unless the current scope declared it.
+    fn locate(&self, slot: usize, scopes: usize) -> Result<Location> {
+        let name = self.name(slot as Slot);
+        let declaration = match self.ctx.locals.last() {
+            Some(scope) if self.blocks > 0 => scope.declarations[slot],
+            // The scopes of the context resolve the declarations of the module
+            _ => None,
+        };
+        match declaration {
+            Some(Declaration::Global) => {
+                let Some(global) = self.ctx.mapping.len().checked_sub(1) else {
+                    return Err(SandboxExecutionError::ReferenceNotExistError(
+                        name.to_string(),
+                    ));
+                };
+                let binding = self.ctx.find(global..global + 1, name);
+                return Ok(Location::Scope(global, binding.map(|(_, binding)| binding)));
+            }
+            Some(Declaration::Nonlocal) => {
+                // The block scopes of the run enclosing this one, then those of the context
+                let locals = &self.ctx.locals;
+                let mut enclosing = locals.len() - self.blocks..locals.len() - 1;
+                if let Some(scope) = enclosing.rfind(|&idx| locals[idx].bindings[slot].is_some()) {
+                    return Ok(Location::Local(scope));
+                }
+                let enclosing = self.ctx.mapping.len().saturating_sub(1);
+                return match self.ctx.find(0..enclosing, name) {
+                    Some((idx, binding)) => Ok(Location::Scope(idx, Some(binding))),
+                    None => Err(SandboxExecutionError::ReferenceNotExistError(
+                        name.to_string(),
+                    )),
+                };
+            }
+            None => {}
+        }
+        if let Some(scope) = self.local(slot, scopes) {
+            return Ok(Location::Local(scope));
+        }
+        let declared = match self.blocks {
+            0 => self.ctx.declared_scope(name)?,
+            _ => None,
+        };
+        let found = match declared {
+            Some(idx) => Some((idx, self.ctx.find(idx..idx + 1, name).map(|(_, binding)| binding))),
+            None => self
+                .ctx
+                .find(0..self.ctx.mapping.len(), name)
+                .map(|(idx, binding)| (idx, Some(binding))),
+        };
+        Ok(match found {
+            Some((idx, binding)) => Location::Scope(idx, binding),
+            None => Location::Unbound,
+        })
+    }
+
+    fn lookup(&mut self, address: Address) -> Result<Binding> {
+        let slot = address.index as usize;
+        let scopes = address.depth as usize + 1;
+        if !self.declared[slot] {
+            if let Some(scope) = self.local(slot, scopes) {
+                return Ok(self.local_binding(scope, slot));
+            }
+            if let Some(binding) = &self.cache[slot] {
+                return Ok(binding.clone());
+            }
+        }
+        match self.locate(slot, scopes)? {
+            Location::Local(scope) => Ok(self.local_binding(scope, slot)),
+            Location::Scope(_, Some(binding)) => {
+                if !self.declared[slot] {
+                    self.cache[slot] = Some(binding.clone());
+                }
+                Ok(binding)
+            }
+            Location::Scope(_, None) | Location::Unbound => Err(
+                SandboxExecutionError::ReferenceNotExistError(self.name(address.index).to_string()),
+            ),
+        }
+    }
+
+    /// Assigns `value` to the variable in `slot`, looking through the `scopes` innermost block
+    /// scopes for it first.
+    fn store(&mut self, slot: usize, scopes: usize, value: ValueKind) -> Result<()> {
+        if !self.declared[slot]
+            && self.local(slot, scopes).is_none()
+            && let Some(binding) = &self.cache[slot]
+        {
+            let binding = binding.clone();
+            self.ctx.rebind(&binding, value);
+            return Ok(());
+        }
+        match self.locate(slot, scopes)? {
+            Location::Local(scope) => {
+                let binding = self.local_binding(scope, slot);
+                self.ctx.rebind(&binding, value);
+            }
+            Location::Scope(_, Some(binding)) => self.ctx.rebind(&binding, value),
+            Location::Scope(idx, None) => {
+                self.ctx.bind_in(idx, self.name(slot as Slot), value);
+                self.cache[slot] = None;
+            }
+            Location::Unbound if self.blocks > 0 => self.ctx.bind_local(slot, value),
+            Location::Unbound if self.ctx.mapping.is_empty() => {
+                return Err(SandboxExecutionError::ReferenceNotExistError(
+                    self.name(slot as Slot).to_string(),
+                ));
+            }
+            Location::Unbound => {
+                self.ctx.bind_in(0, self.name(slot as Slot), value);
+                self.cache[slot] = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self) -> Result<Exit> {
+        let program = self.program;
+        let code = &program.code;
+        let mut pc = self.pc;
+        while let Some(instruction) = code.get(pc) {
+            pc += 1;
+            match instruction {
+                Instruction::Tick { cost, .. } if self.starved(*cost) => {
+                    self.pc = pc - 1;
+                    return Ok(Exit::Suspended);
+                }
+                Instruction::Consume if self.starved(1) => {
+                    self.pc = pc - 1;
+                    return Ok(Exit::Suspended);
+                }
+                Instruction::Tick { depth, cost } => {
+                    self.ctx.check_frames(*depth as usize)?;
+                    for _ in 0..*cost {
+                        self.ctx.consume_one()?;
+                    }
+                    self.fuel -= i64::from(*cost);
+                }
+                Instruction::Consume => {
+                    self.ctx.consume_one()?;
+                    self.fuel -= 1;
+                }
+                Instruction::Const(slot) => {
+                    self.stack.push(program.constants[*slot as usize].clone());
+                }
+                Instruction::Load(address) => {
+                    let value = self.lookup(*address)?.borrow().kind.clone();
+                    self.stack.push(value);
+                }
+                Instruction::Store(address) => {
+                    let value = self.pop();
+                    self.store(address.index as usize, address.depth as usize + 1, value)?;
+                }
+                Instruction::DeleteVar(address) => {
+                    let slot = address.index as usize;
+                    match self.locate(slot, address.depth as usize + 1)? {
+                        Location::Local(scope) => self.ctx.remove_local(scope, slot),
+                        Location::Scope(idx, Some(_)) => {
+                            self.ctx.remove_from(idx, self.name(address.index))?;
+                            self.cache[slot] = None;
+                        }
+                        Location::Scope(_, None) | Location::Unbound => {
+                            return Err(SandboxExecutionError::ReferenceNotExistError(
+                                self.name(address.index).to_string(),
+                            ));
+                        }
+                    }
+                }
+                Instruction::Declare(slot, declaration) => {
+                    let index = *slot as usize;
+                    match self.ctx.locals.last_mut() {
+                        Some(scope) if self.blocks > 0 => {
+                            if scope.bindings[index].is_some() {
+                                return Err(SandboxExecutionError::InvalidSyntaxError);
+                            }
+                            scope.declarations[index] = Some(*declaration);
+                        }
+                        _ => self.ctx.declare(self.name(*slot), *declaration)?,
+                    }
+                    self.declared[index] = true;
+                }
+                Instruction::Account => {
+                    let value = self.stack.last().expect("the compiler balances the stack");
+                    self.ctx.account_value(value)?;
+                }
+                Instruction::Pop => {
+                    self.pop();
+                }
+                Instruction::Dup => {
+                    let value = self.top().clone();
+                    self.stack.push(value);
+                }
+                Instruction::Unary(op) => {
+                    let rhs = &ValueContainer::new(self.pop());
+                    let value = self.ctx.operators.eval_unary(*op, rhs)?;
+                    self.stack.push(self.ctx.settle(value));
+                }
+                Instruction::Binary(op) => {
+                    let rhs = &ValueContainer::new(self.pop());
+                    let lhs = &ValueContainer::new(self.pop());
+                    let value = self.ctx.operators.eval_binary(*op, lhs, rhs)?;
+                    self.stack.push(self.ctx.settle(value));
+                }
+                Instruction::ShortCircuit { op, end } => match (op, self.top()) {
+                    (BinaryOperator::And, ValueKind::Bool(false) | ValueKind::None) => {
+                        *self.top() = ValueKind::Bool(false);
+                        pc = *end as usize;
+                    }
+                    (BinaryOperator::Or, ValueKind::Bool(true)) => pc = *end as usize,
+                    _ => {}
+                },
+                Instruction::Logical(op) => {
+                    let rhs = self.pop();
+                    let lhs = self.pop();
+                    let value = match (op, &lhs) {
+                        // The left operand let the right one decide
+                        (BinaryOperator::Or, ValueKind::None | ValueKind::Bool(false))
+                        | (BinaryOperator::And, ValueKind::Bool(true)) => rhs,
+                        _ => self.ctx.operators.eval_binary(
+                            *op,
+                            &ValueContainer::new(lhs),
+                            &ValueContainer::new(rhs),
+                        )?,
+                    };
+                    self.stack.push(self.ctx.settle(value));
+                }
+                Instruction::NewCollection { capacity } => {
+                    let items = Vec::with_capacity(*capacity as usize);
+                    self.stack.push(ValueKind::Collection(Rc::new(items)));
+                }
+                Instruction::Append => {
+                    let value = self.pop();
+                    if let ValueKind::Collection(items) = self.top() {
+                        Rc::make_mut(items).push(value);
+                    }
+                }
+                Instruction::Extend => {
+                    let ValueKind::Collection(spread) = self.pop() else {
+                        return Err(SandboxExecutionError::InvalidTypeError);
+                    };
+                    if let ValueKind::Collection(items) = self.top() {
+                        Rc::make_mut(items).extend(spread.iter().cloned());
+                    }
+                }
+                Instruction::GetAttr(slot) => {
+                    let attr = self.name(*slot);
+                    let value = match self.pop() {
+                        ValueKind::Object(obj) => match obj.get(attr) {
+                            Some(v) => v.borrow().kind.clone(),
+                            None => return Err(SandboxExecutionError::AttributeNotFoundError),
+                        },
+                        ValueKind::Native(native) => native
+                            .get_attribute(attr)
+                            .ok_or(SandboxExecutionError::AttributeNotFoundError)?,
+                        _ => return Err(SandboxExecutionError::AttributeNotFoundError),
+                    };
+                    self.stack.push(value);
+                }
+                Instruction::GetItem => {
+                    let key = self.pop();
+                    let container = self.pop();
+                    self.stack.push(get_item(container, &key)?);
+                }
+                Instruction::Call { method, argc } => {
+                    let native = self.pop();
+                    let args = self.pop_many(*argc);
+                    let (_, value) = call_native(&native, self.name(*method), args)?;
+                    self.stack.push(value);
+                }
+                Instruction::CallFunction { function, argc } => {
+                    let args = self.pop_many(*argc);
+                    let name = self.name(*function);
+                    if let Some(result) = stdlib::call(self.ctx, name, &args) {
+                        self.stack.push(result?);
+                        continue;
+                    }
+                    match native_function(self.ctx, name)? {
+                        NativeFunction::Sync(function) => {
+                            self.pc = pc;
+                            return Ok(Exit::Calling(Call { function, args }));
+                        }
+                        NativeFunction::Async(function) => {
+                            self.pc = pc;
+                            return Ok(Exit::Awaiting(function(args)));
+                        }
+                    }
+                }
+                Instruction::SetAttr { attr, native } => match self.pop() {
+                    ValueKind::Object(obj) => {
+                        let value = self.pop();
+                        self.ctx.set_attribute(&obj, self.name(*attr).to_string(), value);
+                    }
+                    ValueKind::Native(_) => pc = *native as usize,
+                    _ => return Err(SandboxExecutionError::AttributeNotFoundError),
+                },
+                Instruction::DeleteAttr(slot) => match self.pop() {
+                    ValueKind::Object(obj) if self.ctx.remove_attribute(&obj, self.name(*slot)) => {}
+                    _ => return Err(SandboxExecutionError::AttributeNotFoundError),
+                },
+                Instruction::Update { place, keys, op } => {
+                    if let Some(native) = self.update(*place, *keys, *op)? {
+                        pc = native as usize;
+                    }
+                }
+                Instruction::Unpack {
+                    count,
+                    star,
+                    ambiguous,
+                } => {
+                    let ValueKind::Collection(values) = self.pop() else {
+                        return Err(SandboxExecutionError::InvalidTypeError);
+                    };
+                    if *ambiguous {
+                        return Err(SandboxExecutionError::InvalidSyntaxError);
+                    }
+                    let values = Rc::unwrap_or_clone(values);
+                    let values = unpack(*count as usize, star.map(|idx| idx as usize), values)?;
+                    self.stack.extend(values.into_iter().rev());
+                }
+                Instruction::Branch { otherwise } => match self.pop() {
+                    ValueKind::Bool(true) => {}
+                    ValueKind::Bool(false) | ValueKind::None => pc = *otherwise as usize,
+                    _ => return Err(SandboxExecutionError::InvalidTypeError),
+                },
+                Instruction::Jump(label) => pc = *label as usize,
+                Instruction::EnterBlock => {
+                    if self.scoped {
+                        self.ctx.push_local_scope(program.names.clone());
+                        self.blocks += 1;
+                    }
+                }
+                Instruction::LeaveBlock => {
+                    if self.scoped {
+                        self.ctx.pop_local_scope();
+                        self.blocks -= 1;
+                    }
+                }
+                Instruction::MatchPattern { pattern, next } => {
+                    let subject = self.stack.last().expect("the compiler balances the stack");
+                    self.captures.clear();
+                    let pattern = &program.patterns[*pattern as usize];
+                    if !match_pattern(pattern, subject, &mut self.captures) {
+                        pc = *next as usize;
+                    }
+                }
+                Instruction::Bind => {
+                    // Captures are bound in the scope of the arm unless an enclosing one binds them
+                    for (name, value) in std::mem::take(&mut self.captures) {
+                        let slot = program.slots[&name] as usize;
+                        self.store(slot, self.blocks, value)?;
+                    }
+                }
+                Instruction::Raise(error) => return Err(error.clone()),
+            }
+        }
+        self.pc = pc;
+        Ok(Exit::Finished)
+    }
+
+    /// Runs an [`Instruction::Update`]. Returns where to jump to store the updated copy of a
+    /// native's attribute, if the place was one.
+    fn update(&mut self, place: Place, keys: u32, op: Update) -> Result<Option<u32>> {
+        let obj = match place {
+            Place::Attribute { .. } => Some(self.pop()),
+            Place::Variable(_) => None,
+        };
+        let path = self.pop_many(keys);
+        let operation = match op {
+            Update::SetItem => {
+                let key = self.pop();
+                let value = self.pop();
+                Operation::SetItem { key, value }
+            }
+            Update::DeleteItem => Operation::DeleteItem { key: self.pop() },
+            Update::SetAttribute(attr) => Operation::SetAttribute {
+                attr: self.name(attr),
+                value: self.pop(),
+            },
+            Update::Call { method, argc } => Operation::Call {
+                method: self.name(method),
+                args: self.pop_many(argc),
+            },
+        };
+        let (result, native) = match (place, obj) {
+            (Place::Variable(address), _) => {
+                let binding = self.lookup(address)?;
+                (apply_binding(self.ctx, &binding, &path, operation)?, None)
+            }
+            (Place::Attribute { attr, native }, Some(obj)) => match obj {
+                ValueKind::Object(obj) => match obj.get(self.name(attr)) {
+                    Some(binding) => (apply_binding(self.ctx, &binding, &path, operation)?, None),
+                    None => return Err(SandboxExecutionError::AttributeNotFoundError),
+                },
+                ValueKind::Native(obj) => {
+                    let mut current = obj
+                        .get_attribute(self.name(attr))
+                        .ok_or(SandboxExecutionError::AttributeNotFoundError)?;
+                    let result = apply(self.ctx, &mut current, &path, operation)?;
+                    (result, Some((native, current)))
+                }
+                _ => return Err(SandboxExecutionError::AttributeNotFoundError),
+            },
+            (Place::Attribute { .. }, None) => unreachable!("attribute places pop their object"),
+        };
+        self.stack.extend(result);
+        Ok(native.map(|(native, current)| {
+            self.stack.push(current);
+            native
+        }))
+    }
+}
+
+/// Applies `operation` to the value of a binding, putting it back even when it fails.
+fn apply_binding(
+    ctx: &mut ExecutionContext,
+    binding: &Rc<RefCell<ValueContainer>>,
+    path: &[ValueKind],
+    operation: Operation<'_>,
+) -> Result<Option<ValueKind>> {
+    ctx.journal_value(binding);
+    let mut current = std::mem::replace(&mut binding.borrow_mut().kind, ValueKind::None);
+    let result = apply(ctx, &mut current, path, operation);
+    binding.borrow_mut().kind = current;
+    result
+}
+
+/// Follows `path` down from `current`, innermost key last, then applies `operation` there.
+fn apply(
+    ctx: &mut ExecutionContext,
+    current: &mut ValueKind,
+    path: &[ValueKind],
+    operation: Operation<'_>,
+) -> Result<Option<ValueKind>> {
+    let Some((key, rest)) = path.split_last() else {
+        return perform(ctx, current, operation);
+    };
+    match current {
+        ValueKind::Collection(items) => {
+            let idx = resolve_index(key, items.len())?;
+            let items = ctx.make_mut(items);
+            apply(ctx, &mut items[idx], rest, operation)
+        }
+        ValueKind::Object(obj) => {
+            let ValueKind::String(key) = key else {
+                return Err(SandboxExecutionError::InvalidTypeError);
+            };
+            match obj.get(key) {
+                Some(binding) => apply_binding(ctx, &binding, rest, operation),
+                None => Err(SandboxExecutionError::SubscriptKeyError),
+            }
+        }
+        _ => Err(SandboxExecutionError::InvalidTypeError),
+    }
+}
+
+fn perform(
+    ctx: &mut ExecutionContext,
+    current: &mut ValueKind,
+    operation: Operation<'_>,
+) -> Result<Option<ValueKind>> {
+    match operation {
+        Operation::SetItem { key, value } => set_item(current, &key, Some(value), ctx)?,
+        Operation::DeleteItem { key } => set_item(current, &key, None, ctx)?,
+        Operation::SetAttribute { attr, value } => {
+            let ValueKind::Native(native) = current else {
+                return Err(SandboxExecutionError::AttributeNotFoundError);
+            };
+            *native = native.with_attribute(attr, value)?;
+        }
+        Operation::Call { method, args } => {
+            let (updated, value) = call_native(current, method, args)?;
+            *current = updated;
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,311 @@
+//! An optional second engine: [`Program::compile`] lowers a [`Module`] to bytecode once, and
+//! [`Program::run`] executes it on a stack VM that borrows the context once per run instead of
+//! once per node, releasing it only while host functions run. Both engines charge the same TTL
+//! and fail with the same errors.
+//!
+//! Variables are resolved to an [`Address`] at compile time. Under
+//! [`ScopeMode::Block`](crate::exec_ctx::ScopeMode::Block), the variables of a block live in the
+//! block scope the run pushes for it, in the slot of the address, and are read with an indexed
+//! access into the few block scopes the address's depth allows. The variables of the module
+//! live in the scopes of the context, keyed by name, where the host and later runs find them, and
+//! the run caches the binding a name resolved to there. [`ExecutionContext::to_btreemap`] gives
+//! the variables of block scopes their names back from the program. Names declared `global` or
+//! `nonlocal` are always looked up by name.
+//!
+//! As the VM keeps its operands and block scopes on explicit stacks rather than recursing, a run
+//! can stop between any two instructions. A [`Run`] uses this to suspend when the TTL runs out,
+//! instead of failing with `TimeoutError`, until the host tops the budget up and resumes it.
+//! [`Program::run_async`] uses it to yield to the executor every few operations, and while it
+//! waits for async host functions. A suspended run can also be captured as a
+//! [`Snapshot`](snapshot::Snapshot) and restored later.
+
+pub mod bytecode;
+mod compiler;
+mod machine;
+mod resolver;
+pub mod snapshot;
+
+pub use bytecode::{Instruction, Label, Place, Program, Slot, Update};
+pub use resolver::Address;
+pub use snapshot::{Snapshot, SnapshotError};
+
+use crate::ast::core::Module;
+use crate::base::ValueKind;
+use crate::error::SandboxExecutionError;
+use crate::exec_ctx::{ExecutionContext, Result, DEFAULT_YIELD_EVERY};
+use compiler::Compiler;
+use machine::{Call, Machine, Paused, Suspended};
+use std::cell::RefCell;
+use std::future::Future;
+use std::num::NonZeroU32;
+use std::panic::catch_unwind;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+impl Program {
+    pub fn compile(module: &Module) -> Program {
+        Compiler::compile(module)
+    }
+
+    /// Runs the program in `ctx`, as [`Module::eval`](crate::ast::core::ASTNode::eval) runs the
+    /// module it was compiled from.
+    pub fn run(&self, ctx: Rc<RefCell<ExecutionContext>>) -> Result<ValueKind> {
+        ctx.borrow_mut().start_clock();
+        ctx.borrow_mut().begin_run();
+        let mut state = Machine::new(self, &mut ctx.borrow_mut()).suspend();
+        let result = loop {
+            let paused = catch_unwind(std::panic::AssertUnwindSafe(|| {
+                Machine::resume(self, &mut ctx.borrow_mut(), state).run_slice()
+            }));
+            match paused {
+                Ok(Ok(Paused::Finished)) => break Ok(ValueKind::None),
+                Ok(Ok(Paused::Calling(suspended, call))) => {
+                    match call_host(&ctx, suspended, call) {
+                        Ok(suspended) => state = suspended,
+                        Err(e) => break Err(e),
+                    }
+                }
+                Ok(Ok(Paused::Awaiting(suspended, _))) => {
+                    suspended.abandon(&mut ctx.borrow_mut());
+                    break Err(SandboxExecutionError::AsyncFunctionError);
+                }
+                Ok(Ok(Paused::Suspended(_))) => {
+                    unreachable!("runs that are not resumable never run out of fuel")
+                }
+                Ok(Err(e)) => break Err(e),
+                Err(_) => break Err(unwound(&ctx)),
+            }
+        };
+        if let Ok(mut ctx) = ctx.try_borrow_mut() {
+            ctx.end_run(result.is_ok());
+            ctx.collect_cycles_if_due();
+        }
+        result
+    }
+
+    /// Runs the program in `ctx` as [`Program::run`] does, yielding to the executor every
+    /// [`ExecOptions::yield_every`](crate::exec_ctx::ExecOptions::yield_every) units of TTL.
+    /// Scripts may call async host functions, whose futures are awaited without blocking the
+    /// executor. The future is not `Send`, and is meant for a local executor. Dropping it before
+    /// it is ready abandons the run as dropping a [`Run`] does.
+    pub async fn run_async(&self, ctx: Rc<RefCell<ExecutionContext>>) -> Result<ValueKind> {
+        let yield_every =
+            ctx.borrow().options.yield_every.map_or(DEFAULT_YIELD_EVERY, NonZeroU32::get);
+        ctx.borrow_mut().start_clock();
+        ctx.borrow_mut().begin_run();
+        let mut run = AsyncRun {
+            ctx: &ctx,
+            state: None,
+        };
+        let mut state = Machine::new(self, &mut ctx.borrow_mut()).suspend();
+        state.refuel(i64::from(yield_every));
+        let result = loop {
+            let paused = catch_unwind(std::panic::AssertUnwindSafe(|| {
+                Machine::resume(self, &mut ctx.borrow_mut(), state).run_slice()
+            }));
+            // The run is left in `run` while awaiting, in case the future is dropped
+            match paused {
+                Ok(Ok(Paused::Finished)) => break Ok(ValueKind::None),
+                Ok(Ok(Paused::Suspended(suspended))) => {
+                    run.state = Some(suspended);
+                    YieldNow { yielded: false }.await;
+                    state = run.state.take().expect("awaits keep the run suspended");
+                    state.refuel(i64::from(yield_every));
+                }
+                Ok(Ok(Paused::Calling(suspended, call))) => {
+                    match call_host(&ctx, suspended, call) {
+                        Ok(suspended) => state = suspended,
+                        Err(e) => break Err(e),
+                    }
+                }
+                Ok(Ok(Paused::Awaiting(suspended, future))) => {
+                    run.state = Some(suspended);
+                    let value = future.await;
+                    state = run.state.take().expect("awaits keep the run suspended");
+                    match value {
+                        Ok(value) => state.push(value),
+                        Err(e) => {
+                            state.abandon(&mut ctx.borrow_mut());
+                            break Err(e);
+                        }
+                    }
+                    state.refuel(i64::from(yield_every));
+                }
+                Ok(Err(e)) => break Err(e),
+                Err(_) => break Err(unwound(&ctx)),
+            }
+        };
+        if let Ok(mut ctx) = ctx.try_borrow_mut() {
+            ctx.end_run(result.is_ok());
+            ctx.collect_cycles_if_due();
+        }
+        result
+    }
+}
+
+/// Ends a run that panicked, leaving the blocks the machine was inside of, as its state was lost
+/// with the panic.
+fn unwound(ctx: &Rc<RefCell<ExecutionContext>>) -> SandboxExecutionError {
+    if let Ok(mut ctx) = ctx.try_borrow_mut() {
+        ctx.unwind_run();
+    }
+    SandboxExecutionError::GenericPanicRewindError
+}
+
+/// Calls a sync host function for the machine suspended in `state`, with the context not
+/// borrowed, and pushes its result. If the call fails or panics, the run is abandoned.
+fn call_host(
+    ctx: &Rc<RefCell<ExecutionContext>>,
+    mut state: Suspended,
+    call: Call,
+) -> Result<Suspended> {
+    let result = catch_unwind(std::panic::AssertUnwindSafe(|| call.invoke()))
+        .unwrap_or(Err(SandboxExecutionError::GenericPanicRewindError));
+    match result {
+        Ok(value) => {
+            state.push(value);
+            state.forget_bindings();
+            Ok(state)
+        }
+        Err(e) => {
+            state.abandon(&mut ctx.borrow_mut());
+            Err(e)
+        }
+    }
+}
+
+/// The run of a [`Program::run_async`] future while it awaits. If the future is dropped there,
+/// e.g. as it was cancelled, the run is abandoned as a dropped [`Run`] is.
+struct AsyncRun<'a> {
+    ctx: &'a Rc<RefCell<ExecutionContext>>,
+    state: Option<Suspended>,
+}
+
+impl Drop for AsyncRun<'_> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take()
+            && let Ok(mut ctx) = self.ctx.try_borrow_mut()
+        {
+            state.abandon(&mut ctx);
+            ctx.end_run(false);
+            ctx.collect_cycles_if_due();
+        }
+    }
+}
+
+/// Returns `Pending` once, waking its task straight away, so that the executor gets to run other
+/// tasks first.
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// What [`Run::resume`] left the run at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Out of TTL. Resuming after [`ExecutionContext::top_up`] continues where it stopped.
+    Suspended,
+    Finished,
+}
+
+/// A run of a [`Program`] that suspends when its context runs out of TTL, so that the host can
+/// time-slice many scripts on one thread.
+///
+/// Slices consume exactly the TTL a single run would, as an instruction is only started once
+/// the budget covers its cost. The context belongs to the run until it finishes: the block
+/// scopes the run is inside of stay pushed while it is suspended, and are popped if the run is
+/// dropped before finishing. [`Limits::max_duration`](crate::exec_ctx::Limits::max_duration)
+/// applies to each slice on its own. Under
+/// [`ExecOptions::transactional`](crate::exec_ctx::ExecOptions::transactional), the writes of a
+/// suspended run are visible to the host, and are undone if the run fails or is dropped.
+pub struct Run {
+    program: Rc<Program>,
+    ctx: Rc<RefCell<ExecutionContext>>,
+    /// `None` once the run finished or failed.
+    state: Option<Suspended>,
+}
+
+impl Run {
+    pub fn new(program: Rc<Program>, ctx: Rc<RefCell<ExecutionContext>>) -> Self {
+        ctx.borrow_mut().begin_run();
+        let mut state = Machine::new(&program, &mut ctx.borrow_mut()).suspend();
+        state.set_resumable();
+        Self {
+            program,
+            ctx,
+            state: Some(state),
+        }
+    }
+
+    /// Runs the program until it ends or the context runs out of TTL. Resuming a run that
+    /// finished or failed does nothing. As runs are synchronous, calling an async host function
+    /// fails with `AsyncFunctionError`.
+    pub fn resume(&mut self) -> Result<Status> {
+        let Some(state) = self.state.take() else {
+            return Ok(Status::Finished);
+        };
+        self.ctx.borrow_mut().start_clock();
+        let mut state = state;
+        let result = loop {
+            let paused = catch_unwind(std::panic::AssertUnwindSafe(|| {
+                Machine::resume(&self.program, &mut self.ctx.borrow_mut(), state).run_slice()
+            }));
+            match paused {
+                Ok(Ok(Paused::Suspended(state))) => {
+                    self.state = Some(state);
+                    return Ok(Status::Suspended);
+                }
+                Ok(Ok(Paused::Calling(suspended, call))) => {
+                    match call_host(&self.ctx, suspended, call) {
+                        Ok(suspended) => state = suspended,
+                        Err(e) => break Err(e),
+                    }
+                }
+                Ok(Ok(Paused::Awaiting(state, _))) => {
+                    state.abandon(&mut self.ctx.borrow_mut());
+                    break Err(SandboxExecutionError::AsyncFunctionError);
+                }
+                Ok(Ok(Paused::Finished)) => break Ok(Status::Finished),
+                Ok(Err(e)) => break Err(e),
+                Err(_) => break Err(unwound(&self.ctx)),
+            }
+        };
+        if let Ok(mut ctx) = self.ctx.try_borrow_mut() {
+            ctx.end_run(result.is_ok());
+            ctx.collect_cycles_if_due();
+        }
+        result
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.is_none()
+    }
+
+    pub fn context(&self) -> &Rc<RefCell<ExecutionContext>> {
+        &self.ctx
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take()
+            && let Ok(mut ctx) = self.ctx.try_borrow_mut()
+        {
+            state.abandon(&mut ctx);
+            ctx.end_run(false);
+        }
+    }
+}
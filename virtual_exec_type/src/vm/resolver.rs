@@ -0,0 +1,187 @@
+use crate::ast::core::{Expr, MatchArm, Module, Node, Pattern, Stmt};
+use crate::vm::bytecode::Slot;
+use std::collections::HashSet;
+
+/// Where a variable is bound: in the block scopes up to `depth` levels out from the reference,
+/// the innermost binding it first, at `index`, the name's slot in
+/// [`Program::names`](super::Program::names). A variable none of them binds is looked up by name
+/// in the scopes of the context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    pub depth: u32,
+    pub index: Slot,
+}
+
+/// A lexical scope: the module, or a block that gets its own scope under
+/// [`ScopeMode::Block`](crate::exec_ctx::ScopeMode::Block).
+struct Scope {
+    parent: Option<usize>,
+    names: HashSet<String>,
+}
+
+/// The resolver pass: collects the names mentioned directly in each lexical scope, numbering the
+/// scopes in the order the compiler enters them (the module first, then blocks in pre-order).
+pub(crate) struct Resolver {
+    scopes: Vec<Scope>,
+}
+
+impl Resolver {
+    pub(crate) fn resolve(module: &Module) -> Self {
+        let mut resolver = Resolver {
+            scopes: vec![Scope {
+                parent: None,
+                names: HashSet::new(),
+            }],
+        };
+        resolver.body(&module.body, 0);
+        resolver
+    }
+
+    /// How many scopes out from `scope` a binding of `name` may be: the outermost enclosing scope
+    /// that mentions the name, as scopes that do not mention a name never bind it.
+    pub(crate) fn depth(&self, scope: usize, name: &str) -> u32 {
+        let mut depth = 0;
+        let mut current = scope;
+        let mut hops = 0;
+        while let Some(parent) = self.scopes[current].parent {
+            hops += 1;
+            if self.scopes[parent].names.contains(name) {
+                depth = hops;
+            }
+            current = parent;
+        }
+        depth
+    }
+
+    fn enter(&mut self, parent: usize) -> usize {
+        self.scopes.push(Scope {
+            parent: Some(parent),
+            names: HashSet::new(),
+        });
+        self.scopes.len() - 1
+    }
+
+    fn mention(&mut self, scope: usize, name: &str) {
+        self.scopes[scope].names.insert(name.to_string());
+    }
+
+    fn body(&mut self, body: &[Node<Stmt>], scope: usize) {
+        for stmt in body {
+            self.stmt(&stmt.kind, scope);
+        }
+    }
+
+    fn block(&mut self, body: &[Node<Stmt>], parent: usize) {
+        let scope = self.enter(parent);
+        self.body(body, scope);
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, scope: usize) {
+        match stmt {
+            Stmt::Expression(expr) => self.expr(&expr.kind, scope),
+            Stmt::Assign { targets, value } => {
+                self.expr(&value.kind, scope);
+                for target in targets {
+                    self.expr(&target.kind, scope);
+                }
+            }
+            Stmt::If {
+                test,
+                body,
+                otherwise,
+            } => {
+                self.expr(&test.kind, scope);
+                self.block(body, scope);
+                if let Some(otherwise) = otherwise {
+                    self.block(otherwise, scope);
+                }
+            }
+            Stmt::Scoped(body) => self.block(body, scope),
+            Stmt::Delete(targets) => {
+                for target in targets {
+                    self.expr(&target.kind, scope);
+                }
+            }
+            Stmt::Pass => {}
+            Stmt::Global(names) | Stmt::Nonlocal(names) => {
+                for name in names {
+                    self.mention(scope, name);
+                }
+            }
+            Stmt::Match { subject, arms } => {
+                self.expr(&subject.kind, scope);
+                for arm in arms {
+                    self.arm(arm, scope);
+                }
+            }
+        }
+    }
+
+    fn arm(&mut self, arm: &MatchArm, parent: usize) {
+        let scope = self.enter(parent);
+        self.pattern(&arm.pattern, scope);
+        if let Some(guard) = &arm.guard {
+            self.expr(&guard.kind, scope);
+        }
+        self.body(&arm.body, scope);
+    }
+
+    fn pattern(&mut self, pattern: &Pattern, scope: usize) {
+        match pattern {
+            Pattern::Capture(name) | Pattern::Star(Some(name)) => self.mention(scope, name),
+            Pattern::Binding(name, inner) => {
+                self.mention(scope, name);
+                self.pattern(inner, scope);
+            }
+            Pattern::Sequence(patterns) | Pattern::Or(patterns) => {
+                for pattern in patterns {
+                    self.pattern(pattern, scope);
+                }
+            }
+            Pattern::Mapping { entries, rest } => {
+                for (_, pattern) in entries {
+                    self.pattern(pattern, scope);
+                }
+                if let Some(rest) = rest {
+                    self.mention(scope, rest);
+                }
+            }
+            Pattern::Class { fields, .. } => {
+                for (_, pattern) in fields {
+                    self.pattern(pattern, scope);
+                }
+            }
+            Pattern::Literal(_) | Pattern::Wildcard | Pattern::Star(None) => {}
+        }
+    }
+
+    /// Expressions, including assignment and deletion targets, mention every variable in them.
+    fn expr(&mut self, expr: &Expr, scope: usize) {
+        match expr {
+            Expr::Literal(_) => {}
+            Expr::Variable(name) => self.mention(scope, name),
+            Expr::UnaryOp { operand, .. } => self.expr(&operand.kind, scope),
+            Expr::BinaryOp { left, right, .. } => {
+                self.expr(&left.kind, scope);
+                self.expr(&right.kind, scope);
+            }
+            Expr::Wrapped(inner) | Expr::Starred(inner) => self.expr(&inner.kind, scope),
+            Expr::Attribute { value, .. } => self.expr(&value.kind, scope),
+            Expr::Collection(items) => {
+                for item in items {
+                    self.expr(&item.kind, scope);
+                }
+            }
+            Expr::Subscript { value, slice } => {
+                self.expr(&value.kind, scope);
+                self.expr(&slice.kind, scope);
+            }
+            Expr::Call { function, args } => {
+                self.expr(&function.kind, scope);
+                for arg in args {
+                    self.expr(&arg.kind, scope);
+                }
+            }
+        }
+    }
+}
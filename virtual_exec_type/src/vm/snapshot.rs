@@ -0,0 +1,1203 @@
+//! Checkpoints of a suspended [`Run`]: the scopes of its context, the block scopes it is inside
+//! of and every object they reach, the stacks and program counter of the VM, the remaining TTL
+//! and the state of the generator behind `random()`. A [`Snapshot`] holds no pointers, so that
+//! it can be stored with [`Snapshot::to_bytes`] and resumed later with [`Run::restore`],
+//! possibly by another process.
+//!
+//! Mappings (scopes and the attributes of objects) and bindings are numbered in the order they
+//! are first reached, and referred to by number wherever they are shared, so that aliases and
+//! reference cycles come back as they were. Names are visited in sorted order, and the variables
+//! of block scopes in slot order, so the same state always gives the same bytes.
+//!
+//! An encoded snapshot starts with [`MAGIC`] and the little-endian `u16` [`FORMAT_VERSION`],
+//! and is written with the same primitives as [`crate::ast::binary`]. The transaction of a run
+//! under [`ExecOptions::transactional`](crate::exec_ctx::ExecOptions::transactional) is captured
+//! with the values it would restore, so that a restored run that fails still undoes the writes
+//! made before the snapshot. The state of the host is not part of it: the restored run uses the
+//! options, operators and functions of the context it is restored into. Native objects and
+//! wrapped errors only exist on the host, and a run holding one cannot be captured.
+
+use crate::ast::binary::{DecodeError, Decoder, Encoder, MAX_NESTING};
+use crate::ast::core::Literal;
+use crate::base::{ValueContainer, ValueKind};
+use crate::builtin::{Declaration, Mapping, VirPyFloat, VirPyInt, VirPyObject};
+use crate::error::SandboxExecutionError;
+use crate::exec_ctx::{ExecutionContext, Journal, JournalEntry, LocalScope, ScopeMode};
+use crate::vm::bytecode::{Instruction, Place, Update};
+use crate::vm::machine::{Binding, Suspended};
+use crate::vm::resolver::Address;
+use crate::vm::{Program, Run};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// The bytes every encoded snapshot starts with.
+pub const MAGIC: [u8; 4] = *b"VXSS";
+
+/// The version of the format written by [`Snapshot::to_bytes`], the only one
+/// [`Snapshot::from_bytes`] reads.
+pub const FORMAT_VERSION: u16 = 5;
+
+/// Why a run could not be captured or restored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The run finished or failed, leaving nothing to resume.
+    Finished,
+    /// The run holds a value that only exists on the host, named by its kind.
+    UnsupportedValue(&'static str),
+    /// The program given to [`Run::restore`] is not the one the snapshot was taken from.
+    ProgramMismatch,
+    /// The blob does not start with [`MAGIC`].
+    NotASnapshot,
+    /// The blob was written in a format version this build does not read.
+    UnsupportedVersion { found: u16, supported: u16 },
+    /// The blob is not a well-formed snapshot.
+    Malformed(DecodeError),
+    /// The blob refers to a mapping or binding it does not hold.
+    DanglingReference,
+    /// The stacks or scopes of the run are not the ones the program has where it suspended.
+    InconsistentState,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Finished => f.write_str("the run has already finished"),
+            SnapshotError::UnsupportedValue(kind) => write!(f, "cannot capture a {kind}"),
+            SnapshotError::ProgramMismatch => {
+                f.write_str("the snapshot was taken from another program")
+            }
+            SnapshotError::NotASnapshot => f.write_str("not an encoded snapshot"),
+            SnapshotError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "snapshot encoded with format version {found}, but only version {supported} is supported"
+            ),
+            SnapshotError::Malformed(e) => write!(f, "malformed snapshot: {e}"),
+            SnapshotError::DanglingReference => {
+                f.write_str("snapshot refers to a mapping or binding it does not hold")
+            }
+            SnapshotError::InconsistentState => {
+                f.write_str("snapshot state does not match the program where it suspended")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<DecodeError> for SnapshotError {
+    fn from(e: DecodeError) -> Self {
+        SnapshotError::Malformed(e)
+    }
+}
+
+/// A value, with objects referring to their mapping by number.
+#[derive(Debug, Clone)]
+enum Value {
+    None,
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    Collection(Vec<Value>),
+    Object {
+        mapping: usize,
+        class_name: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+struct MappingState {
+    /// The binding of each name, by number.
+    bindings: Vec<(String, usize)>,
+    declarations: Vec<(String, Declaration)>,
+}
+
+/// A block scope of the run, as in [`LocalScope`], with bindings by number.
+#[derive(Debug, Clone)]
+struct LocalState {
+    bindings: Vec<Option<usize>>,
+    declarations: Vec<Option<Declaration>>,
+}
+
+/// A write of the transaction of the run, as in [`JournalEntry`].
+#[derive(Debug, Clone)]
+enum EntryState {
+    Binding {
+        mapping: usize,
+        name: String,
+        previous: Option<usize>,
+    },
+    Value {
+        binding: usize,
+        previous: Value,
+    },
+    Declaration {
+        mapping: usize,
+        name: String,
+        previous: Option<Declaration>,
+    },
+}
+
+/// The transaction in progress in the context of the run, as in [`Journal`].
+#[derive(Debug, Clone)]
+struct JournalState {
+    entries: Vec<EntryState>,
+    by_run: bool,
+}
+
+/// The state of a suspended [`Run`], taken with [`Run::snapshot`].
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// The [`fingerprint`] of the program.
+    program: u64,
+    ttl: i64,
+    /// The state of the generator behind [`stdlib`](crate::stdlib)'s `random()`.
+    random_state: u64,
+    mappings: Vec<MappingState>,
+    /// The value of each binding.
+    bindings: Vec<Value>,
+    /// The mappings of the scopes of the context, the most local first.
+    scopes: Vec<usize>,
+    pc: usize,
+    stack: Vec<Value>,
+    /// The block scopes the run is inside of, the outermost first.
+    locals: Vec<LocalState>,
+    cache: Vec<Option<usize>>,
+    declared: Vec<bool>,
+    captures: Vec<(String, Value)>,
+    blocks: usize,
+    journal: Option<JournalState>,
+}
+
+/// Identifies a program, so that a snapshot is only restored into the program it was taken
+/// from. This is FNV-1a over an explicit encoding of the bytecode and tables of the program.
+fn fingerprint(program: &Program) -> u64 {
+    let mut encoder = Encoder { bytes: Vec::new() };
+    encoder.program(program);
+    encoder.bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl Encoder {
+    fn program(&mut self, program: &Program) {
+        self.len(program.code.len());
+        for instruction in &program.code {
+            self.instruction(instruction);
+        }
+        self.len(program.constants.len());
+        for constant in &program.constants {
+            let literal = match constant {
+                ValueKind::Int(i) => Literal::Int(i.value),
+                ValueKind::Float(f) => Literal::Float(f.value),
+                ValueKind::String(s) => Literal::String(s.clone()),
+                ValueKind::Bool(b) => Literal::Bool(*b),
+                ValueKind::None => Literal::None,
+                _ => unreachable!("constants are the values of literals"),
+            };
+            self.literal(&literal);
+        }
+        self.strs(&program.names);
+        self.patterns(&program.patterns);
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        match *instruction {
+            Instruction::Tick { depth, cost } => {
+                self.tag(0);
+                self.u64(depth.into());
+                self.tag(cost);
+            }
+            Instruction::Consume => self.tag(1),
+            Instruction::Const(slot) => {
+                self.tag(2);
+                self.u64(slot.into());
+            }
+            Instruction::Load(address) => {
+                self.tag(3);
+                self.address(address);
+            }
+            Instruction::Store(address) => {
+                self.tag(4);
+                self.address(address);
+            }
+            Instruction::DeleteVar(address) => {
+                self.tag(5);
+                self.address(address);
+            }
+            Instruction::Declare(slot, declaration) => {
+                self.tag(6);
+                self.u64(slot.into());
+                self.tag(declaration as u8);
+            }
+            Instruction::Account => self.tag(7),
+            Instruction::Pop => self.tag(8),
+            Instruction::Dup => self.tag(9),
+            Instruction::Unary(op) => {
+                self.tag(10);
+                self.unary_operator(&op);
+            }
+            Instruction::Binary(op) => {
+                self.tag(11);
+                self.binary_operator(&op);
+            }
+            Instruction::ShortCircuit { op, end } => {
+                self.tag(12);
+                self.binary_operator(&op);
+                self.u64(end.into());
+            }
+            Instruction::Logical(op) => {
+                self.tag(13);
+                self.binary_operator(&op);
+            }
+            Instruction::NewCollection { capacity } => {
+                self.tag(14);
+                self.u64(capacity.into());
+            }
+            Instruction::Append => self.tag(15),
+            Instruction::Extend => self.tag(16),
+            Instruction::GetAttr(slot) => {
+                self.tag(17);
+                self.u64(slot.into());
+            }
+            Instruction::GetItem => self.tag(18),
+            Instruction::Call { method, argc } => {
+                self.tag(19);
+                self.u64(method.into());
+                self.u64(argc.into());
+            }
+            Instruction::CallFunction { function, argc } => {
+                self.tag(20);
+                self.u64(function.into());
+                self.u64(argc.into());
+            }
+            Instruction::SetAttr { attr, native } => {
+                self.tag(21);
+                self.u64(attr.into());
+                self.u64(native.into());
+            }
+            Instruction::DeleteAttr(slot) => {
+                self.tag(22);
+                self.u64(slot.into());
+            }
+            Instruction::Update { place, keys, op } => {
+                self.tag(23);
+                match place {
+                    Place::Variable(address) => {
+                        self.tag(0);
+                        self.address(address);
+                    }
+                    Place::Attribute { attr, native } => {
+                        self.tag(1);
+                        self.u64(attr.into());
+                        self.u64(native.into());
+                    }
+                }
+                self.u64(keys.into());
+                match op {
+                    Update::SetItem => self.tag(0),
+                    Update::DeleteItem => self.tag(1),
+                    Update::SetAttribute(slot) => {
+                        self.tag(2);
+                        self.u64(slot.into());
+                    }
+                    Update::Call { method, argc } => {
+                        self.tag(3);
+                        self.u64(method.into());
+                        self.u64(argc.into());
+                    }
+                }
+            }
+            Instruction::Unpack { count, star, ambiguous } => {
+                self.tag(24);
+                self.u64(count.into());
+                match star {
+                    None => self.tag(0),
+                    Some(star) => {
+                        self.tag(1);
+                        self.u64(star.into());
+                    }
+                }
+                self.tag(u8::from(ambiguous));
+            }
+            Instruction::Branch { otherwise } => {
+                self.tag(25);
+                self.u64(otherwise.into());
+            }
+            Instruction::Jump(label) => {
+                self.tag(26);
+                self.u64(label.into());
+            }
+            Instruction::EnterBlock => self.tag(27),
+            Instruction::LeaveBlock => self.tag(28),
+            Instruction::MatchPattern { pattern, next } => {
+                self.tag(29);
+                self.u64(pattern.into());
+                self.u64(next.into());
+            }
+            Instruction::Bind => self.tag(30),
+            Instruction::Raise(ref error) => {
+                self.tag(31);
+                self.tag(match error {
+                    SandboxExecutionError::InvalidSyntaxError => 0,
+                    SandboxExecutionError::InvalidTypeError => 1,
+                    _ => unreachable!("the compiler only raises syntax and type errors"),
+                });
+            }
+        }
+    }
+
+    fn address(&mut self, address: Address) {
+        self.u64(address.depth.into());
+        self.u64(address.index.into());
+    }
+}
+
+impl Run {
+    /// Captures the state of this run, which must be suspended.
+    pub fn snapshot(&self) -> Result<Snapshot, SnapshotError> {
+        let state = self.state.as_ref().ok_or(SnapshotError::Finished)?;
+        Capture::default().snapshot(&self.program, &self.ctx.borrow(), state)
+    }
+
+    /// Resumes the run captured in `snapshot`, in `ctx`. The scopes, TTL, generator state and
+    /// transaction of the context are replaced with those of the snapshot, while its options,
+    /// operators and functions are kept.
+    pub fn restore(
+        program: Rc<Program>,
+        ctx: Rc<RefCell<ExecutionContext>>,
+        snapshot: &Snapshot,
+    ) -> Result<Self, SnapshotError> {
+        let state = snapshot.rebuild(&program, &mut ctx.borrow_mut())?;
+        Ok(Self {
+            program,
+            ctx,
+            state: Some(state),
+        })
+    }
+}
+
+/// A mapping or binding that was numbered but not captured yet.
+enum Pending {
+    Mapping(usize, Rc<RefCell<Mapping>>),
+    Binding(usize, Binding),
+}
+
+/// Numbers the mappings and bindings reached from a run, by address.
+#[derive(Default)]
+struct Capture {
+    mappings: Vec<MappingState>,
+    mapping_ids: HashMap<usize, usize>,
+    bindings: Vec<Value>,
+    binding_ids: HashMap<usize, usize>,
+    pending: Vec<Pending>,
+}
+
+impl Capture {
+    fn snapshot(
+        mut self,
+        program: &Program,
+        ctx: &ExecutionContext,
+        state: &Suspended,
+    ) -> Result<Snapshot, SnapshotError> {
+        let scopes = ctx.mapping.iter().map(|scope| self.mapping(scope)).collect();
+        let stack = self.values(&state.stack)?;
+        let run_locals = &ctx.locals[ctx.locals.len() - state.blocks..];
+        let locals = run_locals
+            .iter()
+            .map(|scope| LocalState {
+                bindings: self.slots(&scope.bindings),
+                declarations: scope.declarations.clone(),
+            })
+            .collect();
+        let cache = self.slots(&state.cache);
+        let captures = state
+            .captures
+            .iter()
+            .map(|(name, value)| Ok((name.clone(), self.value(value, 1)?)))
+            .collect::<Result<_, SnapshotError>>()?;
+        let journal = ctx
+            .journal()
+            .map(|journal| self.journal(journal))
+            .transpose()?;
+        // Mappings and bindings are captured last, as numbering them is what finds them
+        while let Some(pending) = self.pending.pop() {
+            match pending {
+                Pending::Mapping(id, mapping) => self.capture_mapping(id, &mapping.borrow()),
+                Pending::Binding(id, binding) => {
+                    self.bindings[id] = self.value(&binding.borrow().kind, 1)?;
+                }
+            }
+        }
+        Ok(Snapshot {
+            program: fingerprint(program),
+            ttl: ctx.ttl,
+            random_state: ctx.random_state,
+            mappings: self.mappings,
+            bindings: self.bindings,
+            scopes,
+            pc: state.pc,
+            stack,
+            locals,
+            cache,
+            declared: state.declared.clone(),
+            captures,
+            blocks: state.blocks,
+            journal,
+        })
+    }
+
+    fn journal(&mut self, journal: &Journal) -> Result<JournalState, SnapshotError> {
+        let entries = journal
+            .entries
+            .iter()
+            .map(|entry| {
+                Ok(match entry {
+                    JournalEntry::Binding {
+                        mapping,
+                        name,
+                        previous,
+                    } => EntryState::Binding {
+                        mapping: self.mapping(mapping),
+                        name: name.clone(),
+                        previous: previous.as_ref().map(|binding| self.binding(binding)),
+                    },
+                    JournalEntry::Value { binding, previous } => EntryState::Value {
+                        binding: self.binding(binding),
+                        previous: self.value(previous, 1)?,
+                    },
+                    JournalEntry::Declaration {
+                        mapping,
+                        name,
+                        previous,
+                    } => EntryState::Declaration {
+                        mapping: self.mapping(mapping),
+                        name: name.clone(),
+                        previous: *previous,
+                    },
+                })
+            })
+            .collect::<Result<_, SnapshotError>>()?;
+        Ok(JournalState {
+            entries,
+            by_run: journal.by_run,
+        })
+    }
+
+    fn mapping(&mut self, mapping: &Rc<RefCell<Mapping>>) -> usize {
+        let key = Rc::as_ptr(mapping) as usize;
+        if let Some(id) = self.mapping_ids.get(&key) {
+            return *id;
+        }
+        let id = self.mappings.len();
+        self.mapping_ids.insert(key, id);
+        self.mappings.push(MappingState::default());
+        self.pending.push(Pending::Mapping(id, mapping.clone()));
+        id
+    }
+
+    fn binding(&mut self, binding: &Binding) -> usize {
+        let key = Rc::as_ptr(binding) as usize;
+        if let Some(id) = self.binding_ids.get(&key) {
+            return *id;
+        }
+        let id = self.bindings.len();
+        self.binding_ids.insert(key, id);
+        self.bindings.push(Value::None);
+        self.pending.push(Pending::Binding(id, binding.clone()));
+        id
+    }
+
+    fn slots(&mut self, slots: &[Option<Binding>]) -> Vec<Option<usize>> {
+        slots
+            .iter()
+            .map(|binding| binding.as_ref().map(|binding| self.binding(binding)))
+            .collect()
+    }
+
+    fn capture_mapping(&mut self, id: usize, mapping: &Mapping) {
+        let mut names: Vec<_> = mapping.mapping.iter().collect();
+        names.sort_by(|a, b| a.0.cmp(b.0));
+        let bindings = names
+            .into_iter()
+            .map(|(name, binding)| (name.clone(), self.binding(binding)))
+            .collect();
+        let mut declarations: Vec<_> = mapping
+            .declarations
+            .iter()
+            .map(|(name, declaration)| (name.clone(), *declaration))
+            .collect();
+        declarations.sort_by(|a, b| a.0.cmp(&b.0));
+        self.mappings[id] = MappingState {
+            bindings,
+            declarations,
+        };
+    }
+
+    fn values(&mut self, values: &[ValueKind]) -> Result<Vec<Value>, SnapshotError> {
+        values.iter().map(|value| self.value(value, 1)).collect()
+    }
+
+    /// Captures a value nested `level` collections deep, failing where decoding it would.
+    fn value(&mut self, value: &ValueKind, level: usize) -> Result<Value, SnapshotError> {
+        if level > MAX_NESTING {
+            return Err(SnapshotError::UnsupportedValue("collection nested this deep"));
+        }
+        Ok(match value {
+            ValueKind::None => Value::None,
+            ValueKind::Int(i) => Value::Int(i.value),
+            ValueKind::Float(f) => Value::Float(f.value),
+            ValueKind::Bool(b) => Value::Bool(*b),
+            ValueKind::String(s) => Value::String(s.clone()),
+            ValueKind::Collection(items) => Value::Collection(
+                items
+                    .iter()
+                    .map(|item| self.value(item, level + 1))
+                    .collect::<Result<_, _>>()?,
+            ),
+            ValueKind::Object(obj) => Value::Object {
+                mapping: self.mapping(&obj.mapping),
+                class_name: obj.class_name.clone(),
+            },
+            ValueKind::Native(_) => return Err(SnapshotError::UnsupportedValue("native object")),
+            ValueKind::ErrorWrapped(_) => {
+                return Err(SnapshotError::UnsupportedValue("wrapped error"));
+            }
+        })
+    }
+}
+
+impl Snapshot {
+    /// The TTL the run had left when it was captured.
+    pub fn ttl(&self) -> i64 {
+        self.ttl
+    }
+
+    fn rebuild(
+        &self,
+        program: &Program,
+        ctx: &mut ExecutionContext,
+    ) -> Result<Suspended, SnapshotError> {
+        let slots = program.names.len();
+        if self.program != fingerprint(program)
+            || self.pc > program.code.len()
+            || self.declared.len() != slots
+            || self.cache.len() != slots
+            || self.locals.iter().any(|scope| {
+                scope.bindings.len() != slots || scope.declarations.len() != slots
+            })
+        {
+            return Err(SnapshotError::ProgramMismatch);
+        }
+        self.check_state(program, ctx.options.scope_mode)?;
+
+        let mappings: Vec<Rc<RefCell<Mapping>>> =
+            self.mappings.iter().map(|_| Rc::default()).collect();
+        let bindings: Vec<Binding> = self
+            .bindings
+            .iter()
+            .map(|value| Rc::new(RefCell::new(ValueContainer::new(value.rebuild(&mappings)))))
+            .collect();
+        for (mapping, state) in mappings.iter().zip(&self.mappings) {
+            let mut mapping = mapping.borrow_mut();
+            for (name, id) in &state.bindings {
+                mapping.mapping.insert(name.clone(), bindings[*id].clone());
+            }
+            for (name, declaration) in &state.declarations {
+                mapping.declarations.insert(name.clone(), *declaration);
+            }
+        }
+
+        ctx.ttl = self.ttl;
+        ctx.random_state = self.random_state;
+        let slot = |id: &Option<usize>| id.map(|id| bindings[id].clone());
+        let locals = self
+            .locals
+            .iter()
+            .map(|state| LocalScope {
+                names: program.names.clone(),
+                bindings: state.bindings.iter().map(slot).collect(),
+                declarations: state.declarations.clone(),
+            })
+            .collect();
+        ctx.replace_scopes(self.scopes.iter().map(|id| mappings[*id].clone()).collect(), locals);
+        if let Some(journal) = &self.journal {
+            ctx.set_journal(journal.rebuild(&mappings, &bindings));
+        }
+        ctx.begin_run();
+        // The run started outside of the blocks it is inside of
+        ctx.run_locals -= self.blocks;
+        Ok(Suspended {
+            pc: self.pc,
+            stack: self.stack.iter().map(|value| value.rebuild(&mappings)).collect(),
+            cache: self.cache.iter().map(slot).collect(),
+            declared: self.declared.clone(),
+            captures: self
+                .captures
+                .iter()
+                .map(|(name, value)| (name.clone(), value.rebuild(&mappings)))
+                .collect(),
+            blocks: self.blocks,
+            resumable: true,
+            fuel: i64::MAX,
+        })
+    }
+
+    /// Checks that the run is where a run of `program` can suspend, with the operands and block
+    /// scopes the program has there, so that the VM never finds a stack or block scope missing.
+    fn check_state(&self, program: &Program, scope_mode: ScopeMode) -> Result<(), SnapshotError> {
+        let suspends = matches!(
+            program.code.get(self.pc),
+            Some(Instruction::Tick { .. } | Instruction::Consume)
+        );
+        let consistent = match expected_depths(program).get(self.pc).copied().flatten() {
+            Some((stack, blocks)) => {
+                // Without block scopes, blocks share the scope of the module and are not counted
+                let blocks = if scope_mode == ScopeMode::Block { blocks } else { 0 };
+                suspends
+                    && self.stack.len() == stack
+                    && self.blocks == blocks
+                    && self.locals.len() == blocks
+                    && !self.scopes.is_empty()
+            }
+            None => false,
+        };
+        if !consistent {
+            return Err(SnapshotError::InconsistentState);
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder { bytes: Vec::new() };
+        encoder.bytes.extend_from_slice(&MAGIC);
+        encoder.bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        encoder.u64(self.program);
+        encoder.u64(self.ttl as u64);
+        encoder.u64(self.random_state);
+        encoder.len(self.mappings.len());
+        for mapping in &self.mappings {
+            encoder.len(mapping.bindings.len());
+            for (name, id) in &mapping.bindings {
+                encoder.str(name);
+                encoder.u64(*id as u64);
+            }
+            encoder.len(mapping.declarations.len());
+            for (name, declaration) in &mapping.declarations {
+                encoder.str(name);
+                encode_declaration(&mut encoder, *declaration);
+            }
+        }
+        encode_values(&mut encoder, &self.bindings);
+        encoder.len(self.scopes.len());
+        for id in &self.scopes {
+            encoder.u64(*id as u64);
+        }
+        encoder.u64(self.pc as u64);
+        encode_values(&mut encoder, &self.stack);
+        encoder.len(self.locals.len());
+        for scope in &self.locals {
+            encode_slots(&mut encoder, &scope.bindings);
+            encoder.len(scope.declarations.len());
+            for declaration in &scope.declarations {
+                match declaration {
+                    None => encoder.tag(0),
+                    Some(declaration) => {
+                        encoder.tag(1);
+                        encode_declaration(&mut encoder, *declaration);
+                    }
+                }
+            }
+        }
+        encode_slots(&mut encoder, &self.cache);
+        encoder.len(self.declared.len());
+        for declared in &self.declared {
+            encoder.tag(u8::from(*declared));
+        }
+        encoder.len(self.captures.len());
+        for (name, value) in &self.captures {
+            encoder.str(name);
+            encode_value(&mut encoder, value);
+        }
+        encoder.u64(self.blocks as u64);
+        match &self.journal {
+            None => encoder.tag(0),
+            Some(journal) => {
+                encoder.tag(1);
+                encoder.tag(u8::from(journal.by_run));
+                encoder.len(journal.entries.len());
+                for entry in &journal.entries {
+                    encode_entry(&mut encoder, entry);
+                }
+            }
+        }
+        encoder.bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut decoder = Decoder::new(bytes);
+        if decoder.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = u16::from_le_bytes(decoder.array()?);
+        if version != FORMAT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion {
+                found: version,
+                supported: FORMAT_VERSION,
+            });
+        }
+        let program = decoder.u64()?;
+        let ttl = decoder.u64()? as i64;
+        let random_state = decoder.u64()?;
+        let mappings = (0..decoder.len()?)
+            .map(|_| {
+                let bindings = (0..decoder.len()?)
+                    .map(|_| Ok((decoder.string()?, decode_index(&mut decoder)?)))
+                    .collect::<Result<_, DecodeError>>()?;
+                let declarations = (0..decoder.len()?)
+                    .map(|_| Ok((decoder.string()?, decode_declaration(&mut decoder)?)))
+                    .collect::<Result<_, DecodeError>>()?;
+                Ok(MappingState {
+                    bindings,
+                    declarations,
+                })
+            })
+            .collect::<Result<_, DecodeError>>()?;
+        let bindings = decode_values(&mut decoder)?;
+        let scopes = (0..decoder.len()?)
+            .map(|_| decode_index(&mut decoder))
+            .collect::<Result<_, _>>()?;
+        let pc = decode_index(&mut decoder)?;
+        let stack = decode_values(&mut decoder)?;
+        let locals = (0..decoder.len()?)
+            .map(|_| {
+                let bindings = decode_slots(&mut decoder)?;
+                let declarations = (0..decoder.len()?)
+                    .map(|_| match decoder.flag("declaration")? {
+                        false => Ok(None),
+                        true => decode_declaration(&mut decoder).map(Some),
+                    })
+                    .collect::<Result<_, DecodeError>>()?;
+                Ok(LocalState {
+                    bindings,
+                    declarations,
+                })
+            })
+            .collect::<Result<_, DecodeError>>()?;
+        let cache = decode_slots(&mut decoder)?;
+        let declared = (0..decoder.len()?)
+            .map(|_| decoder.flag("declared"))
+            .collect::<Result<_, _>>()?;
+        let captures = (0..decoder.len()?)
+            .map(|_| Ok((decoder.string()?, decode_value(&mut decoder)?)))
+            .collect::<Result<_, DecodeError>>()?;
+        let blocks = decode_index(&mut decoder)?;
+        let journal = match decoder.flag("journal")? {
+            false => None,
+            true => {
+                let by_run = decoder.flag("journal owner")?;
+                let entries = (0..decoder.len()?)
+                    .map(|_| decode_entry(&mut decoder))
+                    .collect::<Result<_, _>>()?;
+                Some(JournalState { entries, by_run })
+            }
+        };
+        if !decoder.bytes.is_empty() {
+            return Err(DecodeError::TrailingBytes.into());
+        }
+
+        let snapshot = Snapshot {
+            program,
+            ttl,
+            random_state,
+            mappings,
+            bindings,
+            scopes,
+            pc,
+            stack,
+            locals,
+            cache,
+            declared,
+            captures,
+            blocks,
+            journal,
+        };
+        snapshot.check_references()?;
+        Ok(snapshot)
+    }
+
+    /// Checks that every mapping and binding number is in range, so that rebuilding the run
+    /// cannot fail halfway.
+    fn check_references(&self) -> Result<(), SnapshotError> {
+        let mapping = |id: &usize| *id < self.mappings.len();
+        let binding = |id: &usize| *id < self.bindings.len();
+        let values = self
+            .bindings
+            .iter()
+            .chain(&self.stack)
+            .chain(self.captures.iter().map(|(_, value)| value));
+        let valid = self.scopes.iter().all(mapping)
+            && self
+                .mappings
+                .iter()
+                .all(|state| state.bindings.iter().all(|(_, id)| binding(id)))
+            && self
+                .locals
+                .iter()
+                .flat_map(|scope| &scope.bindings)
+                .chain(&self.cache)
+                .flatten()
+                .all(binding)
+            && values.into_iter().all(|value| value.refers_within(self.mappings.len()))
+            && self
+                .journal
+                .iter()
+                .flat_map(|journal| &journal.entries)
+                .all(|entry| match entry {
+                    EntryState::Binding {
+                        mapping: id,
+                        previous,
+                        ..
+                    } => mapping(id) && previous.iter().all(binding),
+                    EntryState::Value {
+                        binding: id,
+                        previous,
+                    } => binding(id) && previous.refers_within(self.mappings.len()),
+                    EntryState::Declaration { mapping: id, .. } => mapping(id),
+                });
+        if !valid {
+            return Err(SnapshotError::DanglingReference);
+        }
+        Ok(())
+    }
+}
+
+impl JournalState {
+    fn rebuild(&self, mappings: &[Rc<RefCell<Mapping>>], bindings: &[Binding]) -> Journal {
+        let entries = self
+            .entries
+            .iter()
+            .map(|entry| match entry {
+                EntryState::Binding {
+                    mapping,
+                    name,
+                    previous,
+                } => JournalEntry::Binding {
+                    mapping: mappings[*mapping].clone(),
+                    name: name.clone(),
+                    previous: previous.map(|id| bindings[id].clone()),
+                },
+                EntryState::Value { binding, previous } => JournalEntry::Value {
+                    binding: bindings[*binding].clone(),
+                    previous: previous.rebuild(mappings),
+                },
+                EntryState::Declaration {
+                    mapping,
+                    name,
+                    previous,
+                } => JournalEntry::Declaration {
+                    mapping: mappings[*mapping].clone(),
+                    name: name.clone(),
+                    previous: *previous,
+                },
+            })
+            .collect();
+        Journal::new(entries, self.by_run)
+    }
+}
+
+impl Value {
+    fn rebuild(&self, mappings: &[Rc<RefCell<Mapping>>]) -> ValueKind {
+        match self {
+            Value::None => ValueKind::None,
+            Value::Int(i) => ValueKind::Int(VirPyInt::new(*i)),
+            Value::Float(f) => ValueKind::Float(VirPyFloat::new(*f)),
+            Value::Bool(b) => ValueKind::Bool(*b),
+            Value::String(s) => ValueKind::String(s.clone()),
+            Value::Collection(items) => ValueKind::Collection(Rc::new(
+                items.iter().map(|item| item.rebuild(mappings)).collect(),
+            )),
+            Value::Object {
+                mapping,
+                class_name,
+            } => ValueKind::Object(VirPyObject {
+                mapping: mappings[*mapping].clone(),
+                class_name: class_name.clone(),
+            }),
+        }
+    }
+
+    fn refers_within(&self, mappings: usize) -> bool {
+        match self {
+            Value::Collection(items) => items.iter().all(|item| item.refers_within(mappings)),
+            Value::Object { mapping, .. } => *mapping < mappings,
+            _ => true,
+        }
+    }
+}
+
+/// The depth of the operand stack and the number of blocks entered before each instruction of
+/// `program`, or `None` for instructions no run reaches. The compiler balances the stack, so
+/// every path to an instruction agrees on both.
+fn expected_depths(program: &Program) -> Vec<Option<(usize, usize)>> {
+    let code = &program.code;
+    let mut depths = vec![None; code.len() + 1];
+    let mut pending: Vec<(usize, (usize, usize))> = vec![(0, (0, 0))];
+    while let Some((pc, (stack, blocks))) = pending.pop() {
+        if pc > code.len() || depths[pc].is_some() {
+            continue;
+        }
+        depths[pc] = Some((stack, blocks));
+        let Some(instruction) = code.get(pc) else {
+            continue;
+        };
+        // Operands popped and pushed when falling through, and where else the instruction
+        // jumps to, with the stack depth there
+        let (pops, pushes, jump) = match *instruction {
+            Instruction::Tick { .. }
+            | Instruction::Consume
+            | Instruction::DeleteVar(_)
+            | Instruction::Declare(..)
+            | Instruction::Account
+            | Instruction::Unary(_)
+            | Instruction::GetAttr(_)
+            | Instruction::Bind
+            | Instruction::EnterBlock
+            | Instruction::LeaveBlock => (0, 0, None),
+            Instruction::Const(_) | Instruction::Load(_) | Instruction::NewCollection { .. } => {
+                (0, 1, None)
+            }
+            Instruction::Dup => (1, 2, None),
+            Instruction::Store(_)
+            | Instruction::Pop
+            | Instruction::Append
+            | Instruction::Extend
+            | Instruction::DeleteAttr(_) => (1, 0, None),
+            Instruction::Binary(_) | Instruction::Logical(_) | Instruction::GetItem => (2, 1, None),
+            Instruction::ShortCircuit { end, .. } => (1, 1, Some((end, stack))),
+            Instruction::MatchPattern { next, .. } => (1, 1, Some((next, stack))),
+            Instruction::Branch { otherwise } => (1, 0, Some((otherwise, stack - 1))),
+            Instruction::Jump(label) => {
+                pending.push((label as usize, (stack, blocks)));
+                continue;
+            }
+            Instruction::Raise(_) => continue,
+            Instruction::Call { argc, .. } => (argc as usize + 1, 1, None),
+            Instruction::CallFunction { argc, .. } => (argc as usize, 1, None),
+            // A native leaves the value on the stack for the code at `native` to store
+            Instruction::SetAttr { native, .. } => (2, 0, Some((native, stack - 1))),
+            Instruction::Unpack { count, .. } => (1, count as usize, None),
+            Instruction::Update { place, keys, op } => {
+                let (operands, result) = match op {
+                    Update::SetItem => (2, 0),
+                    Update::DeleteItem | Update::SetAttribute(_) => (1, 0),
+                    Update::Call { argc, .. } => (argc as usize, 1),
+                };
+                let pops = keys as usize + operands;
+                match place {
+                    Place::Variable(_) => (pops, result, None),
+                    // The updated copy of the native's attribute is pushed for `native` to store
+                    Place::Attribute { native, .. } => {
+                        (pops + 1, result, Some((native, stack + result - pops)))
+                    }
+                }
+            }
+        };
+        let blocks = match instruction {
+            Instruction::EnterBlock => blocks + 1,
+            Instruction::LeaveBlock => blocks - 1,
+            _ => blocks,
+        };
+        if let Some((label, stack)) = jump {
+            pending.push((label as usize, (stack, blocks)));
+        }
+        pending.push((pc + 1, (stack + pushes - pops, blocks)));
+    }
+    depths
+}
+
+fn encode_values(encoder: &mut Encoder, values: &[Value]) {
+    encoder.len(values.len());
+    for value in values {
+        encode_value(encoder, value);
+    }
+}
+
+fn encode_value(encoder: &mut Encoder, value: &Value) {
+    match value {
+        Value::None => encoder.tag(0),
+        Value::Int(i) => {
+            encoder.tag(1);
+            encoder.u64(*i as u64);
+        }
+        Value::Float(f) => {
+            encoder.tag(2);
+            encoder.u64(f.to_bits());
+        }
+        Value::Bool(b) => {
+            encoder.tag(3);
+            encoder.tag(u8::from(*b));
+        }
+        Value::String(s) => {
+            encoder.tag(4);
+            encoder.str(s);
+        }
+        Value::Collection(items) => {
+            encoder.tag(5);
+            encode_values(encoder, items);
+        }
+        Value::Object {
+            mapping,
+            class_name,
+        } => {
+            encoder.tag(6);
+            encoder.u64(*mapping as u64);
+            match class_name {
+                None => encoder.tag(0),
+                Some(class_name) => {
+                    encoder.tag(1);
+                    encoder.str(class_name);
+                }
+            }
+        }
+    }
+}
+
+/// Encodes binding numbers by slot, each tagged with whether the slot is bound.
+fn encode_slots(encoder: &mut Encoder, slots: &[Option<usize>]) {
+    encoder.len(slots.len());
+    for binding in slots {
+        match binding {
+            None => encoder.tag(0),
+            Some(id) => {
+                encoder.tag(1);
+                encoder.u64(*id as u64);
+            }
+        }
+    }
+}
+
+fn encode_declaration(encoder: &mut Encoder, declaration: Declaration) {
+    encoder.tag(match declaration {
+        Declaration::Global => 0,
+        Declaration::Nonlocal => 1,
+    });
+}
+
+fn encode_entry(encoder: &mut Encoder, entry: &EntryState) {
+    match entry {
+        EntryState::Binding {
+            mapping,
+            name,
+            previous,
+        } => {
+            encoder.tag(0);
+            encoder.u64(*mapping as u64);
+            encoder.str(name);
+            match previous {
+                None => encoder.tag(0),
+                Some(id) => {
+                    encoder.tag(1);
+                    encoder.u64(*id as u64);
+                }
+            }
+        }
+        EntryState::Value { binding, previous } => {
+            encoder.tag(1);
+            encoder.u64(*binding as u64);
+            encode_value(encoder, previous);
+        }
+        EntryState::Declaration {
+            mapping,
+            name,
+            previous,
+        } => {
+            encoder.tag(2);
+            encoder.u64(*mapping as u64);
+            encoder.str(name);
+            match previous {
+                None => encoder.tag(0),
+                Some(declaration) => {
+                    encoder.tag(1);
+                    encode_declaration(encoder, *declaration);
+                }
+            }
+        }
+    }
+}
+
+fn decode_slots(decoder: &mut Decoder) -> Result<Vec<Option<usize>>, DecodeError> {
+    (0..decoder.len()?)
+        .map(|_| match decoder.flag("binding")? {
+            false => Ok(None),
+            true => decode_index(decoder).map(Some),
+        })
+        .collect()
+}
+
+fn decode_declaration(decoder: &mut Decoder) -> Result<Declaration, DecodeError> {
+    match decoder.tag()? {
+        0 => Ok(Declaration::Global),
+        1 => Ok(Declaration::Nonlocal),
+        tag => Err(DecodeError::InvalidTag { node: "declaration", tag }),
+    }
+}
+
+fn decode_entry(decoder: &mut Decoder) -> Result<EntryState, DecodeError> {
+    Ok(match decoder.tag()? {
+        0 => EntryState::Binding {
+            mapping: decode_index(decoder)?,
+            name: decoder.string()?,
+            previous: match decoder.flag("previous binding")? {
+                false => None,
+                true => Some(decode_index(decoder)?),
+            },
+        },
+        1 => EntryState::Value {
+            binding: decode_index(decoder)?,
+            previous: decode_value(decoder)?,
+        },
+        2 => EntryState::Declaration {
+            mapping: decode_index(decoder)?,
+            name: decoder.string()?,
+            previous: match decoder.flag("previous declaration")? {
+                false => None,
+                true => Some(decode_declaration(decoder)?),
+            },
+        },
+        tag => return Err(DecodeError::InvalidTag { node: "journal entry", tag }),
+    })
+}
+
+fn decode_index(decoder: &mut Decoder) -> Result<usize, DecodeError> {
+    let index = decoder.u64()?;
+    // An index that does not fit cannot be in range either
+    Ok(usize::try_from(index).unwrap_or(usize::MAX))
+}
+
+fn decode_values(decoder: &mut Decoder) -> Result<Vec<Value>, DecodeError> {
+    (0..decoder.len()?).map(|_| decode_value(decoder)).collect()
+}
+
+fn decode_value(decoder: &mut Decoder) -> Result<Value, DecodeError> {
+    decoder.nested(|decoder| {
+        Ok(match decoder.tag()? {
+            0 => Value::None,
+            1 => Value::Int(decoder.u64()? as i64),
+            2 => Value::Float(f64::from_bits(decoder.u64()?)),
+            3 => Value::Bool(decoder.flag("bool")?),
+            4 => Value::String(decoder.string()?),
+            5 => Value::Collection(decode_values(decoder)?),
+            6 => Value::Object {
+                mapping: decode_index(decoder)?,
+                class_name: match decoder.flag("class name")? {
+                    false => None,
+                    true => Some(decoder.string()?),
+                },
+            },
+            tag => return Err(DecodeError::InvalidTag { node: "value", tag }),
+        })
+    })
+}
@@ -1,12 +1,12 @@
-use bumpalo::Bump;
+use std::collections::BTreeMap;
 use virtual_exec_type::base::{ValueContainer, ValueKind};
 use virtual_exec_type::builtin::VirPyInt;
+use virtual_exec_type::exec_ctx::{rs_value_to_value_kind, value_kind_to_rs_value, RsValue};
 
 #[test]
 fn test_value_creation_and_downcast() {
-    let arena = Bump::new();
     let int_kind = ValueKind::Int(VirPyInt::new(42));
-    let value_handle = ValueContainer::new(int_kind, &arena);
+    let value_handle = ValueContainer::new(int_kind);
     let extracted_int = value_handle.as_int().expect("Downcast to Int failed");
     assert_eq!(extracted_int.value, 42);
     println!(
@@ -14,3 +14,16 @@ fn test_value_creation_and_downcast() {
         value_handle
     );
 }
+
+#[test]
+fn test_rs_value_round_trip() {
+    let value = RsValue::Object(BTreeMap::from([
+        ("n".to_string(), RsValue::Int(1)),
+        ("f".to_string(), RsValue::Float(0.5)),
+        ("items".to_string(), RsValue::Vector(vec![RsValue::Bool(true), RsValue::None])),
+        ("s".to_string(), RsValue::String("text".to_string())),
+    ]));
+    let kind = rs_value_to_value_kind(value.clone());
+    assert!(matches!(kind, ValueKind::Object(_)));
+    assert_eq!(value_kind_to_rs_value(&kind), value);
+}
@@ -1,30 +1,47 @@
-use bumpalo::Bump;
 use virtual_exec_type::base::{ValueContainer, ValueKind};
 use virtual_exec_type::builtin::{VirPyFloat, VirPyInt};
-use virtual_exec_type::op::op_add;
+use virtual_exec_type::error::SandboxExecutionError;
+use virtual_exec_type::op::{err_op_add, err_op_lt, err_op_neg, op_add};
 
 #[test]
 fn test_op_add_functionality() {
-    let arena = Bump::new();
+    let lhs_int = ValueContainer::new(ValueKind::Int(VirPyInt::new(15)));
+    let rhs_int = ValueContainer::new(ValueKind::Int(VirPyInt::new(27)));
 
-    let lhs_int = ValueContainer::new(ValueKind::Int(VirPyInt::new(15)), &arena);
-    let rhs_int = ValueContainer::new(ValueKind::Int(VirPyInt::new(27)), &arena);
+    let lhs_float = ValueContainer::new(ValueKind::Float(VirPyFloat::new(1.5)));
+    let rhs_float = ValueContainer::new(ValueKind::Float(VirPyFloat::new(2.25)));
 
-    let lhs_float = ValueContainer::new(ValueKind::Float(VirPyFloat::new(1.5)), &arena);
-    let rhs_float = ValueContainer::new(ValueKind::Float(VirPyFloat::new(2.25)), &arena);
-
-    let result_int = op_add(lhs_int, rhs_int, &arena).expect("op_add for Int+Int should succeed");
-    assert_eq!(result_int.as_int().unwrap().value, 42);
+    let result_int = op_add(&lhs_int, &rhs_int).expect("op_add for Int+Int should succeed");
+    assert_eq!(ValueContainer::new(result_int).as_int().unwrap().value, 42);
     println!("Int + Int works as expected.");
 
     let result_float =
-        op_add(lhs_float, rhs_float, &arena).expect("op_add for Float+Float should succeed");
+        op_add(&lhs_float, &rhs_float).expect("op_add for Float+Float should succeed");
     // Use a small epsilon for float comparison
-    assert!((result_float.as_float().unwrap().value - 3.75).abs() < f64::EPSILON);
+    assert!((ValueContainer::new(result_float).as_float().unwrap().value - 3.75).abs() < f64::EPSILON);
     println!("Float + Float works as expected.");
 
     let result_unsupported =
-        op_add(lhs_int, lhs_float, &arena).expect("op_add for Int+Float should succeed");
-    assert!(result_unsupported.as_float().unwrap().value - 16.5 < f64::EPSILON);
+        op_add(&lhs_int, &lhs_float).expect("op_add for Int+Float should succeed");
+    assert!((ValueContainer::new(result_unsupported).as_float().unwrap().value - 16.5).abs() < f64::EPSILON);
     println!("Int + Float works as expected.");
 }
+
+#[test]
+fn test_dispatch_by_operand_types() {
+    let int = ValueContainer::new(ValueKind::Int(VirPyInt::new(2)));
+    let float = ValueContainer::new(ValueKind::Float(VirPyFloat::new(2.5)));
+    let string = ValueContainer::new(ValueKind::String("ab".to_string()));
+    let boolean = ValueContainer::new(ValueKind::Bool(true));
+
+    assert!(matches!(err_op_lt(&int, &float), Ok(ValueKind::Bool(true))));
+    assert!(matches!(err_op_add(&string, &string), Ok(ValueKind::String(s)) if s == "abab"));
+    assert!(matches!(
+        err_op_add(&boolean, &int),
+        Err(SandboxExecutionError::UndefinedOperatorMethodError)
+    ));
+    assert!(matches!(
+        err_op_neg(&string),
+        Err(SandboxExecutionError::UndefinedOperatorMethodError)
+    ));
+}